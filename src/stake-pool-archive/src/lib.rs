@@ -0,0 +1,316 @@
+// src/lib.rs
+//! Archive of old stake pool transactions. The pool appends its oldest
+//! transactions here together with their ICRC-3 blocks, drops them from its
+//! own log, and forwards queries for them to this canister.
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::{BoundedStorable, Storable},
+    DefaultMemoryImpl, StableBTreeMap,
+};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc3::blocks::{BlockWithId, GetBlocksRequest, GetBlocksResult};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Transactions or blocks returned per query.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct UserKey {
+    pub principal: Principal,
+    pub subaccount: Subaccount,
+}
+
+/// Mirrors `TransactionKind` of the pool interface.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    EarlyWithdrawal,
+    Penalty,
+    RewardDistribution,
+    RewardClaim,
+    Compound,
+    Donation,
+    DepositTransfer { from: UserKey, deposit_id: u64 },
+    PriceSnapshot { usd_e8s: u64, fetched_at: u64 },
+    AdminChange { action: String },
+    ReferralBonus { referrer: Principal },
+}
+
+/// Mirrors `Transaction` of the pool interface.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub id: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub kind: TransactionKind,
+    pub account: Option<UserKey>,
+    pub amount: u64,
+    pub block_index: Option<u64>,
+    pub token: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArchivedTransaction {
+    pub transaction: Transaction,
+    pub block: ICRC3Value,
+}
+
+impl Storable for ArchivedTransaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ArchivedTransaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ArchivedTransaction")
+    }
+}
+
+impl BoundedStorable for ArchivedTransaction {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransactionsOfArgs {
+    pub principal: Principal,
+    pub start: u64,
+    pub end: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransactionsOfPage {
+    pub transactions: Vec<Transaction>,
+    pub total: u64,
+}
+
+/// Index key of a transaction in the per-principal view, encoded as the
+/// pool encodes it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalTransaction {
+    principal: Principal,
+    id: u64,
+}
+
+impl Storable for PrincipalTransaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = vec![0u8; 38];
+        bytes[0] = principal.len() as u8;
+        bytes[1..1 + principal.len()].copy_from_slice(principal);
+        bytes[30..].copy_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        PrincipalTransaction {
+            principal: Principal::from_slice(&bytes[1..1 + len]),
+            id: u64::from_be_bytes(bytes[30..38].try_into().expect("invalid id bytes")),
+        }
+    }
+}
+
+impl BoundedStorable for PrincipalTransaction {
+    const MAX_SIZE: u32 = 38;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ENTRIES: RefCell<StableBTreeMap<u64, ArchivedTransaction, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))),
+    );
+
+    static BY_PRINCIPAL: RefCell<StableBTreeMap<PrincipalTransaction, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))),
+    );
+
+    static POOL: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+fn pool() -> Principal {
+    POOL.with(|p| p.borrow().expect("pool canister not set"))
+}
+
+// Appending an entry again replaces it, so the pool may retry a batch.
+fn append(entries: Vec<ArchivedTransaction>) {
+    for entry in entries {
+        let (id, caller) = (entry.transaction.id, entry.transaction.caller);
+        let owner = entry.transaction.account.as_ref().map(|a| a.principal);
+        BY_PRINCIPAL.with(|m| {
+            let mut m = m.borrow_mut();
+            for principal in std::iter::once(caller).chain(owner.filter(|o| *o != caller)) {
+                m.insert(PrincipalTransaction { principal, id }, ());
+            }
+        });
+        ENTRIES.with(|m| m.borrow_mut().insert(id, entry));
+    }
+}
+
+fn log_length() -> u64 {
+    ENTRIES.with(|m| m.borrow().last_key_value().map_or(0, |(id, _)| id + 1))
+}
+
+fn transactions_of(args: TransactionsOfArgs) -> TransactionsOfPage {
+    let ids: Vec<u64> = BY_PRINCIPAL.with(|m| {
+        m.borrow()
+            .range(
+                PrincipalTransaction {
+                    principal: args.principal,
+                    id: args.start,
+                }..PrincipalTransaction {
+                    principal: args.principal,
+                    id: args.end,
+                },
+            )
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    let transactions = ids
+        .iter()
+        .skip(args.offset as usize)
+        .take(args.limit.min(MAX_PAGE_SIZE) as usize)
+        .filter_map(|id| ENTRIES.with(|m| m.borrow().get(id)))
+        .map(|entry| entry.transaction)
+        .collect();
+    TransactionsOfPage {
+        transactions,
+        total: ids.len() as u64,
+    }
+}
+
+/// `pool` is the stake pool canister allowed to append transactions.
+#[ic_cdk::init]
+fn init(pool: Principal) {
+    POOL.with(|p| *p.borrow_mut() = Some(pool));
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(pool: Principal) {
+    POOL.with(|p| *p.borrow_mut() = Some(pool));
+}
+
+/// Stores transactions handed over by the pool. Only the pool may call this.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn append_transactions(entries: Vec<ArchivedTransaction>) {
+    if ic_cdk::caller() != pool() {
+        ic_cdk::trap("only the pool may append transactions");
+    }
+    append(entries);
+}
+
+/// Returns the archived transactions with ids `start..start + length`, at
+/// most 100.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_transactions(start: u64, length: u64) -> Vec<Transaction> {
+    ENTRIES.with(|m| {
+        m.borrow()
+            .range(start..start.saturating_add(length.min(MAX_PAGE_SIZE)))
+            .map(|(_, entry)| entry.transaction)
+            .collect()
+    })
+}
+
+/// Returns up to 100 archived transactions of a principal among the given
+/// ids, with how many there are in total.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_transactions_of(args: TransactionsOfArgs) -> TransactionsOfPage {
+    transactions_of(args)
+}
+
+/// Returns up to 100 archived blocks across `requests`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc3_get_blocks(requests: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    let mut budget = MAX_PAGE_SIZE;
+    let mut blocks = Vec::new();
+    for request in requests {
+        let Ok((start, length)) = request.as_start_and_length() else {
+            continue;
+        };
+        let end = start.saturating_add(length.min(budget));
+        ENTRIES.with(|m| {
+            blocks.extend(m.borrow().range(start..end).map(|(id, entry)| BlockWithId {
+                id: Nat::from(id),
+                block: entry.block,
+            }))
+        });
+        budget -= end - start;
+        if budget == 0 {
+            break;
+        }
+    }
+    GetBlocksResult {
+        log_length: Nat::from(log_length()),
+        blocks,
+        archived_blocks: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, caller: u8, owner: Option<u8>) -> ArchivedTransaction {
+        ArchivedTransaction {
+            transaction: Transaction {
+                id,
+                timestamp: id,
+                caller: Principal::from_slice(&[caller]),
+                kind: TransactionKind::Deposit,
+                account: owner.map(|o| UserKey {
+                    principal: Principal::from_slice(&[o]),
+                    subaccount: Subaccount([0; 32]),
+                }),
+                amount: 100,
+                block_index: None,
+                token: None,
+            },
+            block: ICRC3Value::Nat(Nat::from(id)),
+        }
+    }
+
+    #[test]
+    fn test_appended_transactions_are_served() {
+        append(vec![
+            entry(10, 1, Some(1)),
+            entry(11, 2, None),
+            entry(12, 2, Some(1)),
+        ]);
+        // A retried batch does not duplicate entries.
+        append(vec![entry(12, 2, Some(1))]);
+
+        assert_eq!(get_transactions(11, 5).len(), 2);
+        let args = |offset, limit| TransactionsOfArgs {
+            principal: Principal::from_slice(&[1]),
+            start: 10,
+            end: 13,
+            offset,
+            limit,
+        };
+        let page = transactions_of(args(1, 10));
+        assert_eq!(page.total, 2);
+        assert_eq!(page.transactions[0].id, 12);
+        assert!(transactions_of(args(2, 10)).transactions.is_empty());
+
+        let result = icrc3_get_blocks(vec![GetBlocksRequest {
+            start: Nat::from(11u64),
+            length: Nat::from(10u64),
+        }]);
+        assert_eq!(result.log_length, Nat::from(13u64));
+        assert_eq!(result.blocks[1].block, ICRC3Value::Nat(Nat::from(12u64)));
+    }
+}
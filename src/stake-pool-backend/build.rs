@@ -0,0 +1,33 @@
+// build.rs
+//! Builds the method table `inspect.rs` filters ingress messages by from the
+//! service of `stake-pool-backend.did`, so the filter knows every endpoint
+//! the interface declares and whether it is a query.
+use candid_parser::utils::CandidSource;
+use std::path::PathBuf;
+
+const DID: &str = "stake-pool-backend.did";
+
+fn main() {
+    println!("cargo:rerun-if-changed={DID}");
+    let (env, actor) = CandidSource::File(DID.as_ref())
+        .load()
+        .unwrap_or_else(|e| panic!("cannot parse {DID}: {e}"));
+    let actor = actor.unwrap_or_else(|| panic!("{DID} declares no service"));
+    let mut methods: Vec<(String, bool)> = env
+        .as_service(&actor)
+        .expect("the service of the interface is not a service type")
+        .iter()
+        .map(|(name, func)| {
+            let func = env.as_func(func).expect("service entries are functions");
+            (name.clone(), func.is_query())
+        })
+        .collect();
+    // `inspect::check` looks methods up by binary search.
+    methods.sort();
+    let table: String = methods
+        .iter()
+        .map(|(name, is_query)| format!("    ({name:?}, {is_query}),\n"))
+        .collect();
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("methods.rs");
+    std::fs::write(out, format!("&[\n{table}]\n")).unwrap();
+}
@@ -0,0 +1,244 @@
+// src/account_migration.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, ACCOUNT_MIGRATIONS_MEMORY_ID};
+use crate::state_hash;
+use crate::{
+    analytics, certification, compounding, denylist, distribution, escheat, liquid, notifications,
+    positions, receipts, validators, withdrawal_queue, DepositKey, PrincipalKey, UserKey, DEPOSITS,
+    STAKE_BALANCE_MAP,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Time the new principal has to accept a migration before it lapses.
+pub const MIGRATION_EXPIRY_SECS: u64 = 7 * 86400;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountMigration {
+    pub new_principal: Principal,
+    pub initiated_at: u64,
+}
+
+impl Storable for AccountMigration {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode AccountMigration"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode AccountMigration")
+    }
+}
+
+impl BoundedStorable for AccountMigration {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by the principal moving away.
+    static ACCOUNT_MIGRATIONS: RefCell<StableBTreeMap<PrincipalKey, AccountMigration, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ACCOUNT_MIGRATIONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "account_migrations",
+        ACCOUNT_MIGRATIONS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Moves every entry of `from` in `map` to the same subaccount of `to`,
+/// combining it with an existing entry of `to` through `merge`.
+pub(crate) fn rekey<V: BoundedStorable>(
+    map: &mut StableBTreeMap<UserKey, V, Memory>,
+    from: Principal,
+    to: Principal,
+    merge: impl Fn(V, V) -> V,
+) {
+    let keys: Vec<UserKey> = map
+        .range(UserKey::range_of_principal(from))
+        .map(|(key, _)| key)
+        .collect();
+    for key in keys {
+        let Some(value) = map.remove(&key) else {
+            continue;
+        };
+        let new_key = UserKey {
+            principal: to,
+            subaccount: key.subaccount,
+        };
+        let value = match map.remove(&new_key) {
+            Some(existing) => merge(existing, value),
+            None => value,
+        };
+        map.insert(new_key, value);
+    }
+}
+
+fn rekey_all(from: Principal, to: Principal) {
+    DEPOSITS.with(|m| {
+        let mut m = m.borrow_mut();
+        let keys: Vec<DepositKey> = m
+            .range(DepositKey::range_of_principal(from))
+            .map(|(k, _)| k)
+            .collect();
+        for key in keys {
+            let deposit = m.remove(&key).expect("deposit listed above");
+            let user = UserKey {
+                principal: to,
+                subaccount: key.user.subaccount,
+            };
+            m.insert(DepositKey::new(&user, key.deposit_id), deposit);
+        }
+    });
+    let staked: Vec<UserKey> = STAKE_BALANCE_MAP.with(|m| {
+        m.borrow()
+            .range(UserKey::range_of_principal(from))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    STAKE_BALANCE_MAP.with(|m| rekey(&mut m.borrow_mut(), from, to, |a, b| a + b));
+    for key in staked {
+        certification::touch(&UserKey {
+            principal: to,
+            subaccount: key.subaccount,
+        });
+        certification::touch(&key);
+    }
+    compounding::rekey_principal(from, to);
+    liquid::rekey_principal(from, to);
+    receipts::rekey_principal(from, to);
+    validators::rekey_principal(from, to);
+    withdrawal_queue::rekey_principal(from, to);
+    escheat::rekey_principal(from, to);
+    analytics::rekey_principal(from, to);
+    notifications::rekey_principal(from, to);
+    positions::rekey_principal(from, to);
+}
+
+pub fn initiate(old: Principal, new: Principal, now: u64) -> Result<(), DepositError> {
+    if new == old || new == Principal::anonymous() {
+        return Err(DepositError::InvalidArgument(
+            "new principal must differ from the current one and not be anonymous".to_string(),
+        ));
+    }
+    denylist::ensure_not_denied(old)?;
+    denylist::ensure_not_denied(new)?;
+    ACCOUNT_MIGRATIONS.with(|m| {
+        m.borrow_mut().insert(
+            PrincipalKey(old),
+            AccountMigration {
+                new_principal: new,
+                initiated_at: now,
+            },
+        )
+    });
+    Ok(())
+}
+
+pub fn accept(new: Principal, old: Principal, now: u64) -> Result<(), DepositError> {
+    let pending = ACCOUNT_MIGRATIONS.with(|m| m.borrow().get(&PrincipalKey(old)));
+    match pending {
+        Some(m) if m.new_principal == new && now < m.initiated_at + MIGRATION_EXPIRY_SECS => {}
+        _ => {
+            return Err(DepositError::InvalidArgument(
+                "no pending migration to the caller".to_string(),
+            ))
+        }
+    }
+    denylist::ensure_not_denied(new)?;
+    // Payouts of a running distribution still target the old keys.
+    distribution::ensure_idle()?;
+    ACCOUNT_MIGRATIONS.with(|m| m.borrow_mut().remove(&PrincipalKey(old)));
+    rekey_all(old, new);
+    events::record(now, EventKind::AccountMigrated { from: old, to: new });
+    Ok(())
+}
+
+/// Starts moving all of the caller's deposits, balances and reward state to
+/// `new_principal`, which completes the move with `accept_account_migration`
+/// within 7 days. A new call replaces any pending migration.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If `new_principal` is the caller or anonymous.
+/// * `DepositError::Denied`: If either principal is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn initiate_account_migration(new_principal: Principal) -> Result<(), DepositError> {
+    initiate(ic_cdk::caller(), new_principal, crate::now_secs())
+}
+
+/// Completes a migration started by `old_principal` towards the caller. Any
+/// positions the caller already holds are merged with the migrated ones.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If no unexpired migration to the caller is pending.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+/// * `DepositError::DistributionInProgress`: If a reward distribution is running.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn accept_account_migration(old_principal: Principal) -> Result<(), DepositError> {
+    accept(ic_cdk::caller(), old_principal, crate::now_secs())
+}
+
+/// Returns the migration the caller has started, if any.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_account_migration() -> Option<AccountMigration> {
+    ACCOUNT_MIGRATIONS.with(|m| m.borrow().get(&PrincipalKey(ic_cdk::caller())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_migration_rekeys_deposits_and_balances() {
+        let old = Principal::from_slice(&[1]);
+        let new = Principal::from_slice(&[2]);
+        let sub = Subaccount([3u8; 32]);
+        crate::deposit_internal(old, sub, 90, 500, 0).unwrap();
+        crate::deposit_internal(new, sub, 180, 200, 0).unwrap();
+
+        assert!(accept(new, old, 10).is_err());
+        initiate(old, new, 10).unwrap();
+        assert!(accept(Principal::from_slice(&[9]), old, 20).is_err());
+        assert!(accept(new, old, 10 + MIGRATION_EXPIRY_SECS).is_err());
+        let checkpoint = events::len();
+        accept(new, old, 20).unwrap();
+
+        let new_key = UserKey {
+            principal: new,
+            subaccount: sub,
+        };
+        let old_key = UserKey {
+            principal: old,
+            subaccount: sub,
+        };
+        assert_eq!(
+            STAKE_BALANCE_MAP.with(|m| m.borrow().get(&new_key)),
+            Some(700)
+        );
+        assert_eq!(STAKE_BALANCE_MAP.with(|m| m.borrow().get(&old_key)), None);
+        assert_eq!(crate::deposits_of(&new_key).len(), 2);
+        assert!(accept(new, old, 30).is_err());
+
+        // Backups learn that the old account emptied into the new one.
+        let changes = crate::backup::collect_changes(checkpoint, 10);
+        let accounts: Vec<_> = changes
+            .accounts
+            .iter()
+            .map(|a| (a.key.clone(), a.stake_balance, a.deposits.len()))
+            .collect();
+        assert_eq!(accounts, vec![(old_key, 0, 0), (new_key, 700, 2)]);
+    }
+}
@@ -0,0 +1,555 @@
+// src/accrual.rs
+//! Pull-based reward accounting. A distribution only raises a global
+//! reward-per-share index; every deposit remembers the index it last settled
+//! at, so its accrued reward is `shares * (index - index_at)`. Rewards move
+//! to the account's pending rewards when a deposit is settled and leave the
+//! pool when the owner claims them.
+//!
+//! Rewards are weighted by time staked: a distribution closes an epoch and
+//! splits over the share-seconds held since the previous one. Deposits present
+//! for the whole epoch are paid through the index; one that joined or was
+//! resized during the epoch records the share-seconds it missed, which are
+//! taken off what the index pays it once the epoch closes. A deposit removed
+//! before the distribution forfeits its part of the epoch.
+use crate::compounding;
+use crate::error::DepositError;
+use crate::loyalty;
+use crate::memory::{
+    get_memory, Memory, ACCRUAL_STATE_MEMORY_ID, DEPOSIT_ACCRUALS_MEMORY_ID,
+    DEPOSIT_EPOCHS_MEMORY_ID, EPOCH_RATES_MEMORY_ID, EPOCH_STATE_MEMORY_ID,
+};
+use crate::multipliers;
+use crate::referrals;
+use crate::rewards;
+use crate::state_hash;
+use crate::{config, deposits_of, Deposit, DepositKey, UserKey, DEPOSITS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+/// Fixed-point scale of the reward-per-share index.
+const INDEX_SCALE: u128 = 1_000_000_000_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct AccrualState {
+    /// Rewards paid per share since the pool started, scaled by 1e18.
+    pub reward_per_share: u128,
+    pub total_shares: u128,
+}
+
+impl Storable for AccrualState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode AccrualState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode AccrualState")
+    }
+}
+
+/// Reward position of a single deposit.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct DepositAccrual {
+    /// Weighted amount of the deposit when it was last settled.
+    shares: u64,
+    index_at: u128,
+}
+
+impl Storable for DepositAccrual {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DepositAccrual"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DepositAccrual")
+    }
+}
+
+impl BoundedStorable for DepositAccrual {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The distribution epoch in progress.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct EpochState {
+    pub epoch: u64,
+    /// `None` until the first distribution after time weighting was
+    /// introduced; that epoch is split by shares alone.
+    pub start: Option<u64>,
+    /// Share-seconds missed by deposits during this epoch.
+    pub total_missed: i128,
+}
+
+impl Storable for EpochState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode EpochState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode EpochState")
+    }
+}
+
+/// Share-seconds a deposit missed during `epoch`. Negative after its shares
+/// were cut during the epoch.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct MissedStake {
+    epoch: u64,
+    share_secs: i128,
+}
+
+impl Storable for MissedStake {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode MissedStake"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode MissedStake")
+    }
+}
+
+impl BoundedStorable for MissedStake {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ACCRUAL_STATE: RefCell<StableCell<AccrualState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(ACCRUAL_STATE_MEMORY_ID), AccrualState::default())
+            .expect("Failed to init accrual state cell"),
+    );
+
+    // Keyed by deposit id.
+    static DEPOSIT_ACCRUALS: RefCell<StableBTreeMap<u64, DepositAccrual, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_ACCRUALS_MEMORY_ID)));
+
+    static EPOCH_STATE: RefCell<StableCell<EpochState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(EPOCH_STATE_MEMORY_ID), EpochState::default())
+            .expect("Failed to init epoch state cell"),
+    );
+
+    // Reward per share-second of each closed epoch, scaled by 1e18.
+    static EPOCH_RATES: RefCell<StableBTreeMap<u64, u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EPOCH_RATES_MEMORY_ID)));
+
+    // Keyed by deposit id.
+    static DEPOSIT_EPOCHS: RefCell<StableBTreeMap<u64, MissedStake, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_EPOCHS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "accrual_state",
+            ACCRUAL_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "deposit_accruals",
+            DEPOSIT_ACCRUALS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "epoch_state",
+            EPOCH_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "epoch_rates",
+            EPOCH_RATES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "deposit_epochs",
+            DEPOSIT_EPOCHS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn state() -> AccrualState {
+    ACCRUAL_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn set_state(state: AccrualState) {
+    ACCRUAL_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist accrual state");
+    });
+}
+
+pub fn epoch_state() -> EpochState {
+    EPOCH_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn set_epoch_state(state: EpochState) {
+    EPOCH_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist epoch state");
+    });
+}
+
+/// Records that a deposit's shares changed by `delta` at `now`, so that it
+/// missed `delta` shares for the part of the epoch already passed.
+fn record_missed(deposit_id: u64, delta: i128, now: u64) {
+    let mut epoch = epoch_state();
+    let Some(start) = epoch.start else {
+        return;
+    };
+    let share_secs = delta * now.saturating_sub(start) as i128;
+    if share_secs == 0 {
+        return;
+    }
+    epoch.total_missed += share_secs;
+    DEPOSIT_EPOCHS.with(|m| {
+        let mut m = m.borrow_mut();
+        let mut missed = m
+            .get(&deposit_id)
+            .filter(|s| s.epoch == epoch.epoch)
+            .unwrap_or(MissedStake {
+                epoch: epoch.epoch,
+                share_secs: 0,
+            });
+        missed.share_secs += share_secs;
+        m.insert(deposit_id, missed);
+    });
+    set_epoch_state(epoch);
+}
+
+/// Drops what a removed deposit missed in the open epoch from its total.
+fn forget_missed(deposit_id: u64) {
+    let Some(missed) = DEPOSIT_EPOCHS.with(|m| m.borrow_mut().remove(&deposit_id)) else {
+        return;
+    };
+    let mut epoch = epoch_state();
+    if missed.epoch == epoch.epoch {
+        epoch.total_missed -= missed.share_secs;
+        set_epoch_state(epoch);
+    }
+}
+
+/// Rewards the index pays a deposit for share-seconds it missed in a closed
+/// epoch; zero while that epoch is open.
+fn missed_reward(deposit_id: u64) -> i128 {
+    let Some(missed) = DEPOSIT_EPOCHS.with(|m| m.borrow().get(&deposit_id)) else {
+        return 0;
+    };
+    let rate = EPOCH_RATES
+        .with(|m| m.borrow().get(&missed.epoch))
+        .unwrap_or(0);
+    missed.share_secs.saturating_mul(rate as i128) / INDEX_SCALE as i128
+}
+
+fn shares_of(owner: Principal, deposit: &Deposit, now: u64) -> u64 {
+    let decay = config::get().inactivity_decay;
+    let weighted = rewards::weighted_amount(deposit, now, decay.as_ref());
+    let weighted = multipliers::apply(deposit.lock_period_days, weighted);
+    loyalty::apply(owner, weighted, now) as u64
+}
+
+fn earned(deposit_id: u64, accrual: &DepositAccrual, index: u128) -> u64 {
+    let delta = index - accrual.index_at;
+    let gross = (accrual.shares as u128)
+        .checked_mul(delta)
+        .map_or(u64::MAX as u128, |v| v / INDEX_SCALE) as i128;
+    (gross - missed_reward(deposit_id)).clamp(0, u64::MAX as i128) as u64
+}
+
+/// Clears the missed share-seconds of a settled deposit once their epoch
+/// closed.
+fn clear_settled_missed(deposit_id: u64) {
+    let open = epoch_state().epoch;
+    DEPOSIT_EPOCHS.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.get(&deposit_id).is_some_and(|s| s.epoch != open) {
+            m.remove(&deposit_id);
+        }
+    });
+}
+
+/// Starts accruing rewards for a new deposit of `owner` from the current
+/// index.
+pub fn register(owner: Principal, deposit: &Deposit, now: u64) {
+    let shares = shares_of(owner, deposit, now);
+    let mut state = state();
+    state.total_shares += shares as u128;
+    DEPOSIT_ACCRUALS.with(|m| {
+        m.borrow_mut().insert(
+            deposit.id,
+            DepositAccrual {
+                shares,
+                index_at: state.reward_per_share,
+            },
+        )
+    });
+    set_state(state);
+    record_missed(deposit.id, shares as i128, now);
+}
+
+/// Stops accruing for a removed deposit and credits what it earned to `key`.
+pub fn release(key: &UserKey, deposit_id: u64, now: u64) -> Result<(), DepositError> {
+    let Some(accrual) = DEPOSIT_ACCRUALS.with(|m| m.borrow_mut().remove(&deposit_id)) else {
+        return Ok(());
+    };
+    let mut state = state();
+    state.total_shares -= accrual.shares as u128;
+    let reward = earned(deposit_id, &accrual, state.reward_per_share);
+    set_state(state);
+    forget_missed(deposit_id);
+    let reward = referrals::take_share(deposit_id, reward, now)?;
+    referrals::forget(deposit_id, now);
+    if reward > 0 {
+        compounding::accrue(key, reward, now)?;
+    }
+    Ok(())
+}
+
+/// Settles `key` and moves `deposit` to the shares its current amount earns.
+pub fn resize(key: &UserKey, deposit: &Deposit, now: u64) -> Result<(), DepositError> {
+    settle_account(key, now)?;
+    let shares = shares_of(key.principal, deposit, now);
+    let mut state = state();
+    let previous = DEPOSIT_ACCRUALS.with(|m| {
+        let mut m = m.borrow_mut();
+        let mut accrual = m.get(&deposit.id)?;
+        let previous = accrual.shares;
+        state.total_shares = state.total_shares - previous as u128 + shares as u128;
+        accrual.shares = shares;
+        m.insert(deposit.id, accrual);
+        Some(previous)
+    });
+    set_state(state);
+    if let Some(previous) = previous {
+        record_missed(deposit.id, shares as i128 - previous as i128, now);
+    }
+    Ok(())
+}
+
+// What a deposit earned net of its referrer's share.
+fn earned_net(deposit_id: u64, accrual: &DepositAccrual, index: u128) -> u64 {
+    let reward = earned(deposit_id, accrual, index);
+    reward - referrals::share_of(deposit_id, reward)
+}
+
+/// Rewards one deposit earned since its account was last settled.
+pub fn accrued_of_deposit(deposit_id: u64) -> u64 {
+    let index = state().reward_per_share;
+    DEPOSIT_ACCRUALS
+        .with(|m| m.borrow().get(&deposit_id))
+        .map_or(0, |a| earned_net(deposit_id, &a, index))
+}
+
+/// Rewards `key` earned since its deposits were last settled.
+pub fn accrued_of(key: &UserKey) -> u64 {
+    let index = state().reward_per_share;
+    DEPOSIT_ACCRUALS.with(|m| {
+        let m = m.borrow();
+        deposits_of(key)
+            .iter()
+            .filter_map(|d| m.get(&d.id).map(|a| earned_net(d.id, &a, index)))
+            .sum()
+    })
+}
+
+/// Rewards `key` can claim: pending plus accrued but not yet settled.
+pub fn claimable(key: &UserKey) -> u64 {
+    compounding::pending_of(key).amount + accrued_of(key)
+}
+
+pub fn total_accrued() -> u64 {
+    let index = state().reward_per_share;
+    DEPOSIT_ACCRUALS.with(|m| m.borrow().iter().map(|(id, a)| earned(id, &a, index)).sum())
+}
+
+/// Moves everything the deposits of `key` earned to its pending rewards,
+/// compounding them if the account's preferences say so.
+pub fn settle_account(key: &UserKey, now: u64) -> Result<u64, DepositError> {
+    let index = state().reward_per_share;
+    let earned: Vec<(u64, u64)> = DEPOSIT_ACCRUALS.with(|m| {
+        let mut m = m.borrow_mut();
+        deposits_of(key)
+            .iter()
+            .filter_map(|d| {
+                let mut accrual = m.get(&d.id)?;
+                let reward = earned(d.id, &accrual, index);
+                accrual.index_at = index;
+                m.insert(d.id, accrual);
+                clear_settled_missed(d.id);
+                Some((d.id, reward))
+            })
+            .collect()
+    });
+    let mut reward = 0;
+    for (deposit_id, earned) in earned {
+        reward += referrals::take_share(deposit_id, earned, now)?;
+    }
+    if reward > 0 {
+        compounding::accrue(key, reward, now)?;
+    }
+    Ok(reward)
+}
+
+/// Accounts of `principal` that hold deposits.
+pub fn keys_of(principal: Principal) -> Vec<UserKey> {
+    let mut keys: Vec<UserKey> = DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(principal))
+            .map(|(k, _)| k.user)
+            .collect()
+    });
+    keys.dedup();
+    keys
+}
+
+/// Brings the shares of deposits whose inactivity weight, lock multiplier or
+/// loyalty bonus changed up to date, settling them first at their old weight.
+pub fn reweigh(now: u64) -> Result<(), DepositError> {
+    reweigh_batch(None, usize::MAX, now).map(|_| ())
+}
+
+/// Same as `reweigh` for at most `limit` deposits following `after`.
+/// Returns the key to resume from, or `None` once every deposit was looked
+/// at.
+pub fn reweigh_batch(
+    after: Option<&DepositKey>,
+    limit: usize,
+    now: u64,
+) -> Result<Option<DepositKey>, DepositError> {
+    let start = after.map_or(Bound::Unbounded, |k| Bound::Excluded(k.clone()));
+    let page: Vec<(DepositKey, Deposit)> = DEPOSITS.with(|m| {
+        m.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .collect()
+    });
+    for (key, deposit) in &page {
+        let stale = DEPOSIT_ACCRUALS.with(|m| {
+            m.borrow()
+                .get(&deposit.id)
+                .is_some_and(|a| a.shares != shares_of(key.user.principal, deposit, now))
+        });
+        if stale {
+            resize(&key.user, deposit, now)?;
+        }
+    }
+    if page.len() < limit {
+        return Ok(None);
+    }
+    Ok(page.last().map(|(key, _)| key.clone()))
+}
+
+/// Closes the epoch and splits `amount` over the share-seconds held during
+/// it. Returns the part of `amount` lost to index rounding.
+pub fn distribute(amount: u64, now: u64) -> Result<u64, DepositError> {
+    let mut state = state();
+    if state.total_shares == 0 {
+        return Err(DepositError::NoStakerFound);
+    }
+    let mut epoch = epoch_state();
+    let length = epoch.start.map_or(0, |start| now.saturating_sub(start)) as u128;
+    let share_secs = (state.total_shares * length) as i128 - epoch.total_missed;
+    // An epoch without elapsed time or held stake is split by shares alone.
+    let (increment, rate, allocated) = if share_secs > 0 {
+        let rate = amount as u128 * INDEX_SCALE / share_secs as u128;
+        (rate * length, rate, rate * share_secs as u128 / INDEX_SCALE)
+    } else {
+        let increment = amount as u128 * INDEX_SCALE / state.total_shares;
+        (increment, 0, increment * state.total_shares / INDEX_SCALE)
+    };
+    state.reward_per_share += increment;
+    set_state(state);
+    EPOCH_RATES.with(|m| m.borrow_mut().insert(epoch.epoch, rate));
+    epoch.epoch += 1;
+    epoch.start = Some(now);
+    epoch.total_missed = 0;
+    set_epoch_state(epoch);
+    Ok(amount - allocated as u64)
+}
+
+/// Registers deposits that predate accrual accounting, so they earn from the
+/// current index on.
+pub fn backfill(now: u64) {
+    let missing: Vec<(Principal, Deposit)> = DEPOSITS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(key, d)| (key.user.principal, d))
+            .filter(|(_, d)| DEPOSIT_ACCRUALS.with(|a| !a.borrow().contains_key(&d.id)))
+            .collect()
+    });
+    for (owner, deposit) in missing {
+        register(owner, &deposit, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_rewards_accrue_by_share_and_settle_on_removal() {
+        let alice = UserKey {
+            principal: Principal::from_slice(&[1]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let bob = UserKey {
+            principal: Principal::from_slice(&[2]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let a = crate::deposit_internal(alice.principal, alice.subaccount, 90, 100, 0).unwrap();
+        crate::deposit_internal(bob.principal, bob.subaccount, 90, 300, 0).unwrap();
+
+        assert_eq!(distribute(400, 0), Ok(0));
+        assert_eq!(accrued_of(&alice), 100);
+        assert_eq!(accrued_of(&bob), 300);
+
+        // Later stake does not share in earlier rewards.
+        let carol = UserKey {
+            principal: Principal::from_slice(&[3]),
+            subaccount: Subaccount([0; 32]),
+        };
+        crate::deposit_internal(carol.principal, carol.subaccount, 90, 400, 0).unwrap();
+        distribute(80, 0).unwrap();
+        assert_eq!(accrued_of(&alice), 110);
+        assert_eq!(accrued_of(&carol), 40);
+
+        crate::remove_deposit(&alice, a.id, 0).unwrap();
+        assert_eq!(accrued_of(&alice), 0);
+        assert_eq!(claimable(&alice), 110);
+        assert_eq!(settle_account(&bob, 0), Ok(330));
+        assert_eq!(claimable(&bob), 330);
+        assert_eq!(state().total_shares, 700);
+    }
+
+    #[test]
+    fn test_rewards_are_weighted_by_time_staked() {
+        let key = |i: u8| UserKey {
+            principal: Principal::from_slice(&[100 + i]),
+            subaccount: Subaccount([0; 32]),
+        };
+        crate::deposit_internal(key(1).principal, key(1).subaccount, 90, 100, 0).unwrap();
+        // The first epoch predates time weighting.
+        distribute(100, 0).unwrap();
+
+        // Deposited 90% of the way into the epoch.
+        let late =
+            crate::deposit_internal(key(2).principal, key(2).subaccount, 90, 100, 90).unwrap();
+        assert_eq!(distribute(1_100, 100), Ok(0));
+        assert_eq!(accrued_of(&key(1)), 1_100);
+        assert_eq!(accrued_of(&key(2)), 100);
+
+        // A full epoch later both are paid alike.
+        assert_eq!(settle_account(&key(2), 150), Ok(100));
+        distribute(200, 200).unwrap();
+        assert_eq!(accrued_of(&key(2)), 100);
+
+        // Leaving before the distribution forfeits the open epoch.
+        crate::remove_deposit(&key(2), late.id, 250).unwrap();
+        distribute(300, 300).unwrap();
+        assert_eq!(accrued_of(&key(1)), 1_100 + 100 + 300);
+        assert_eq!(claimable(&key(2)), 200);
+    }
+}
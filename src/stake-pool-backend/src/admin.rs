@@ -0,0 +1,259 @@
+// src/admin.rs
+use crate::circuit_breaker::BreakerConfig;
+use crate::config::{self, PoolConfig};
+use crate::denylist;
+use crate::error::DepositError;
+use crate::loyalty::LoyaltyTier;
+use crate::penalty::PenaltyCurve;
+use crate::rate_model::RateModel;
+use crate::referrals::ReferralReward;
+use crate::rewards::InactivityDecay;
+use crate::withdrawal_queue::QueuePolicy;
+use candid::{CandidType, Deserialize, Principal};
+
+/// Partial config update. Fields left as `None` keep their current value.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ConfigPatch {
+    pub min_cycles_headroom: Option<u128>,
+    pub max_heap_bytes: Option<u64>,
+    pub max_stable_memory_bytes: Option<u64>,
+    pub early_exit_penalty: Option<PenaltyCurve>,
+    /// `Some(None)` disables the decay policy.
+    pub inactivity_decay: Option<Option<InactivityDecay>>,
+    pub withdrawal_queue_policy: Option<QueuePolicy>,
+    /// `Some(None)` removes the hourly outflow limit.
+    pub max_hourly_outflow: Option<Option<u64>>,
+    pub rate_model: Option<RateModel>,
+    pub closed_lock_tiers: Option<Vec<u16>>,
+    pub neuron_staking_enabled: Option<bool>,
+    /// `Some(None)` disables escheat.
+    pub escheat_after_days: Option<Option<u32>>,
+    /// `Some(None)` disables the circuit breaker.
+    pub circuit_breaker: Option<Option<BreakerConfig>>,
+    pub treasury_approvals_required: Option<u8>,
+    pub read_replicas: Option<Vec<Principal>>,
+    /// `Some(None)` creates archive canisters from the archive wasm.
+    pub archive_canister: Option<Option<Principal>>,
+    pub maintenance_notice_secs: Option<u64>,
+    pub min_stake: Option<u64>,
+    pub withdrawals_while_paused: Option<bool>,
+    /// `Some(None)` disables automatic reward distribution.
+    pub reward_sweep_interval_secs: Option<Option<u64>>,
+    pub min_deposit: Option<u64>,
+    /// `Some(None)` removes the per-user maximum.
+    pub max_deposit_per_user: Option<Option<u64>>,
+    /// `Some(None)` removes the total value locked cap.
+    pub max_total_value_locked: Option<Option<u64>>,
+    /// `Some(None)` disables staker proposals.
+    pub proposal_voting_secs: Option<Option<u64>>,
+    pub proposal_quorum_bps: Option<u16>,
+    /// `Some(None)` disables referrals.
+    pub referral_reward: Option<Option<ReferralReward>>,
+    pub loyalty_tiers: Option<Vec<LoyaltyTier>>,
+    /// `Some(None)` disables low-cycles alerts.
+    pub low_cycles_threshold: Option<Option<u128>>,
+    /// `Some(None)` stops posting alerts.
+    pub cycles_alert_webhook: Option<Option<String>>,
+    /// `Some(None)` disables cycles top-ups.
+    pub cycles_top_up_amount: Option<Option<u64>>,
+    pub trusted_origins: Option<Vec<String>>,
+}
+
+impl ConfigPatch {
+    pub fn apply(&self, config: &mut PoolConfig) {
+        if let Some(v) = self.min_cycles_headroom {
+            config.min_cycles_headroom = v;
+        }
+        if let Some(v) = self.max_heap_bytes {
+            config.max_heap_bytes = v;
+        }
+        if let Some(v) = self.max_stable_memory_bytes {
+            config.max_stable_memory_bytes = v;
+        }
+        if let Some(v) = &self.early_exit_penalty {
+            config.early_exit_penalty = v.clone();
+        }
+        if let Some(v) = &self.inactivity_decay {
+            config.inactivity_decay = v.clone();
+        }
+        if let Some(v) = self.withdrawal_queue_policy {
+            config.withdrawal_queue_policy = v;
+        }
+        if let Some(v) = self.max_hourly_outflow {
+            config.max_hourly_outflow = v;
+        }
+        if let Some(v) = &self.rate_model {
+            config.rate_model = v.clone();
+        }
+        if let Some(v) = &self.closed_lock_tiers {
+            config.closed_lock_tiers = v.clone();
+        }
+        if let Some(v) = self.neuron_staking_enabled {
+            config.neuron_staking_enabled = v;
+        }
+        if let Some(v) = self.escheat_after_days {
+            config.escheat_after_days = v;
+        }
+        if let Some(v) = &self.circuit_breaker {
+            config.circuit_breaker = v.clone();
+        }
+        if let Some(v) = self.treasury_approvals_required {
+            config.treasury_approvals_required = v;
+        }
+        if let Some(v) = &self.read_replicas {
+            config.read_replicas = v.clone();
+        }
+        if let Some(v) = self.archive_canister {
+            config.archive_canister = v;
+        }
+        if let Some(v) = self.maintenance_notice_secs {
+            config.maintenance_notice_secs = v;
+        }
+        if let Some(v) = self.min_stake {
+            config.min_stake = v;
+        }
+        if let Some(v) = self.withdrawals_while_paused {
+            config.withdrawals_while_paused = v;
+        }
+        if let Some(v) = self.reward_sweep_interval_secs {
+            config.reward_sweep_interval_secs = v;
+        }
+        if let Some(v) = self.min_deposit {
+            config.min_deposit = v;
+        }
+        if let Some(v) = self.max_deposit_per_user {
+            config.max_deposit_per_user = v;
+        }
+        if let Some(v) = self.max_total_value_locked {
+            config.max_total_value_locked = v;
+        }
+        if let Some(v) = self.proposal_voting_secs {
+            config.proposal_voting_secs = v;
+        }
+        if let Some(v) = self.proposal_quorum_bps {
+            config.proposal_quorum_bps = v;
+        }
+        if let Some(v) = &self.referral_reward {
+            config.referral_reward = v.clone();
+        }
+        if let Some(v) = &self.loyalty_tiers {
+            config.loyalty_tiers = v.clone();
+        }
+        if let Some(v) = self.low_cycles_threshold {
+            config.low_cycles_threshold = v;
+        }
+        if let Some(v) = &self.cycles_alert_webhook {
+            config.cycles_alert_webhook = v.clone();
+        }
+        if let Some(v) = self.cycles_top_up_amount {
+            config.cycles_top_up_amount = v;
+        }
+        if let Some(v) = &self.trusted_origins {
+            config.trusted_origins = v.clone();
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminOp {
+    DenylistAdd(Principal),
+    DenylistRemove(Principal),
+    PatchConfig(Box<ConfigPatch>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminOpResult {
+    Applied,
+    Rejected(DepositError),
+    /// Valid on its own, but skipped because another op in the batch was rejected.
+    NotApplied,
+}
+
+/// Validates every op against the state the batch would produce and applies
+/// them only if all are valid, so a batch either fully lands or leaves no trace.
+pub fn execute_batch(ops: Vec<AdminOp>) -> Vec<AdminOpResult> {
+    let mut staged_config = config::get();
+    let verdicts: Vec<Result<(), DepositError>> = ops
+        .iter()
+        .map(|op| match op {
+            AdminOp::DenylistAdd(_) | AdminOp::DenylistRemove(_) => Ok(()),
+            AdminOp::PatchConfig(patch) => {
+                patch.apply(&mut staged_config);
+                staged_config.validate()
+            }
+        })
+        .collect();
+
+    if verdicts.iter().any(|v| v.is_err()) {
+        return verdicts
+            .into_iter()
+            .map(|v| match v {
+                Ok(()) => AdminOpResult::NotApplied,
+                Err(e) => AdminOpResult::Rejected(e),
+            })
+            .collect();
+    }
+
+    for op in &ops {
+        match op {
+            AdminOp::DenylistAdd(p) => denylist::add(*p),
+            AdminOp::DenylistRemove(p) => denylist::remove(*p),
+            AdminOp::PatchConfig(_) => {}
+        }
+    }
+    config::set(staged_config);
+    vec![AdminOpResult::Applied; ops.len()]
+}
+
+/// Executes a list of admin operations atomically, returning one result per
+/// op. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn admin_batch(ops: Vec<AdminOp>) -> Result<Vec<AdminOpResult>, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("admin_batch", Ok(execute_batch(ops)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_applies_all_or_nothing() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+
+        let results = execute_batch(vec![
+            AdminOp::DenylistAdd(alice),
+            AdminOp::PatchConfig(Box::new(ConfigPatch {
+                max_heap_bytes: Some(0),
+                ..ConfigPatch::default()
+            })),
+        ]);
+        assert_eq!(results[0], AdminOpResult::NotApplied);
+        assert!(matches!(results[1], AdminOpResult::Rejected(_)));
+        assert!(!denylist::contains(alice));
+
+        let results = execute_batch(vec![
+            AdminOp::DenylistAdd(alice),
+            AdminOp::DenylistAdd(bob),
+            AdminOp::DenylistRemove(bob),
+            AdminOp::PatchConfig(Box::new(ConfigPatch {
+                max_heap_bytes: Some(1_000_000),
+                ..ConfigPatch::default()
+            })),
+        ]);
+        assert_eq!(results, vec![AdminOpResult::Applied; 4]);
+        assert!(denylist::contains(alice));
+        assert!(!denylist::contains(bob));
+        assert_eq!(config::get().max_heap_bytes, 1_000_000);
+        assert_eq!(
+            denylist::ensure_not_denied(alice),
+            Err(DepositError::Denied)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// src/allowlist.rs
+//! Private pool mode. While enabled, only principals a controller added may
+//! bring new funds into the pool.
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, ALLOWLIST_MEMORY_ID, ALLOWLIST_MODE_MEMORY_ID};
+use crate::state_hash;
+use crate::transactions;
+use crate::PrincipalKey;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{storable::Storable, StableBTreeMap, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct AllowlistMode {
+    enabled: bool,
+}
+
+impl Storable for AllowlistMode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode AllowlistMode"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode AllowlistMode")
+    }
+}
+
+thread_local! {
+    static ALLOWLIST: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALLOWLIST_MEMORY_ID)));
+
+    static MODE: RefCell<StableCell<AllowlistMode, Memory>> = RefCell::new(
+        StableCell::init(get_memory(ALLOWLIST_MODE_MEMORY_ID), AllowlistMode::default())
+            .expect("Failed to init allowlist mode cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "allowlist",
+            ALLOWLIST.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "allowlist_mode",
+            MODE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn is_enabled() -> bool {
+    MODE.with(|m| m.borrow().get().enabled)
+}
+
+pub fn set_enabled(enabled: bool) {
+    MODE.with(|m| {
+        m.borrow_mut()
+            .set(AllowlistMode { enabled })
+            .expect("Failed to persist allowlist mode");
+    });
+}
+
+pub fn add(principal: Principal) {
+    ALLOWLIST.with(|a| a.borrow_mut().insert(PrincipalKey(principal), ()));
+}
+
+pub fn remove(principal: Principal) {
+    ALLOWLIST.with(|a| a.borrow_mut().remove(&PrincipalKey(principal)));
+}
+
+pub fn contains(principal: Principal) -> bool {
+    ALLOWLIST.with(|a| a.borrow().contains_key(&PrincipalKey(principal)))
+}
+
+/// Fails if the pool is private and `principal` is not on the allowlist.
+pub fn ensure_allowed(principal: Principal) -> Result<(), DepositError> {
+    if is_enabled() && !contains(principal) {
+        Err(DepositError::NotAllowlisted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Turns private pool mode on or off. Only canister controllers may call
+/// this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_allowlist_enabled(enabled: bool) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let action = if enabled {
+        "enable_allowlist"
+    } else {
+        "disable_allowlist"
+    };
+    set_enabled(enabled);
+    transactions::admin(action, Ok(()))
+}
+
+/// Adds principals to the allowlist. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn add_to_allowlist(principals: Vec<Principal>) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    principals.into_iter().for_each(add);
+    transactions::admin("add_to_allowlist", Ok(()))
+}
+
+/// Removes principals from the allowlist. Their existing deposits are not
+/// affected. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn remove_from_allowlist(principals: Vec<Principal>) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    principals.into_iter().for_each(remove);
+    transactions::admin("remove_from_allowlist", Ok(()))
+}
+
+/// Returns whether `principal` is on the allowlist.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn is_allowlisted(principal: Principal) -> bool {
+    contains(principal)
+}
+
+/// Returns whether private pool mode is on.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn is_allowlist_enabled() -> bool {
+    is_enabled()
+}
+
+/// Returns the principals allowed to deposit while private pool mode is on.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_allowlist() -> Vec<Principal> {
+    ALLOWLIST.with(|a| a.borrow().iter().map(|(k, _)| k.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist_only_applies_when_enabled() {
+        let alice = Principal::from_slice(&[1]);
+        assert_eq!(ensure_allowed(alice), Ok(()));
+
+        set_enabled(true);
+        assert_eq!(ensure_allowed(alice), Err(DepositError::NotAllowlisted));
+        add(alice);
+        assert_eq!(ensure_allowed(alice), Ok(()));
+        remove(alice);
+        assert_eq!(ensure_allowed(alice), Err(DepositError::NotAllowlisted));
+
+        set_enabled(false);
+        assert_eq!(ensure_allowed(alice), Ok(()));
+    }
+}
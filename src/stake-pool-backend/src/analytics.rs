@@ -0,0 +1,200 @@
+// src/analytics.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, FIRST_SEEN_MEMORY_ID};
+use crate::state_hash;
+use crate::PrincipalKey;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// Upper bound on the number of periods a single growth query may cover.
+pub const MAX_GROWTH_PERIODS: u64 = 366;
+
+thread_local! {
+    // Time of the first deposit of every principal that ever deposited.
+    static FIRST_SEEN: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FIRST_SEEN_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "first_seen",
+        FIRST_SEEN.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Remembers `now` as the first-seen time of `principal` unless it deposited before.
+pub fn record_depositor(principal: Principal, now: u64) {
+    FIRST_SEEN.with(|m| {
+        let mut m = m.borrow_mut();
+        if !m.contains_key(&PrincipalKey(principal)) {
+            m.insert(PrincipalKey(principal), now);
+        }
+    });
+}
+
+/// Keeps the earlier first-seen time of the two principals for `to`.
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    FIRST_SEEN.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(seen) = m.remove(&PrincipalKey(from)) {
+            let seen = m.get(&PrincipalKey(to)).map_or(seen, |s| s.min(seen));
+            m.insert(PrincipalKey(to), seen);
+        }
+    });
+}
+
+pub fn first_seen(principal: Principal) -> Option<u64> {
+    FIRST_SEEN.with(|m| m.borrow().get(&PrincipalKey(principal)))
+}
+
+/// Time span `[start, end)` split into periods of `period_secs`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GrowthRange {
+    pub start: u64,
+    pub end: u64,
+    pub period_secs: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeriodStats {
+    pub period_start: u64,
+    /// Principals whose first deposit falls in this period.
+    pub new_depositors: u64,
+    /// Principals depositing in this period who first deposited earlier.
+    pub returning_depositors: u64,
+    /// Principals that deposited in the previous period but not in this one.
+    pub churned: u64,
+}
+
+/// Principals first seen in one period, and how many of them deposited again
+/// in each later period of the range.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cohort {
+    pub period_start: u64,
+    pub size: u64,
+    pub retained: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GrowthStats {
+    pub periods: Vec<PeriodStats>,
+    pub cohorts: Vec<Cohort>,
+}
+
+/// Computes growth stats from the set of depositing principals of each period.
+pub fn compute_growth(
+    range: &GrowthRange,
+    active: &[BTreeSet<Principal>],
+    first_seen: impl Fn(Principal) -> Option<u64>,
+) -> GrowthStats {
+    let period_of = |t: u64| {
+        (t >= range.start && t < range.end)
+            .then(|| ((t - range.start) / range.period_secs) as usize)
+    };
+    let empty = BTreeSet::new();
+
+    let mut periods = Vec::with_capacity(active.len());
+    let mut cohorts = Vec::with_capacity(active.len());
+    for (i, depositors) in active.iter().enumerate() {
+        let period_start = range.start + i as u64 * range.period_secs;
+        let cohort: BTreeSet<Principal> = depositors
+            .iter()
+            .copied()
+            .filter(|p| first_seen(*p).and_then(period_of) == Some(i))
+            .collect();
+        let previous = if i == 0 { &empty } else { &active[i - 1] };
+
+        periods.push(PeriodStats {
+            period_start,
+            new_depositors: cohort.len() as u64,
+            returning_depositors: (depositors.len() - cohort.len()) as u64,
+            churned: previous.difference(depositors).count() as u64,
+        });
+        cohorts.push(Cohort {
+            period_start,
+            size: cohort.len() as u64,
+            retained: active[i + 1..]
+                .iter()
+                .map(|later| cohort.intersection(later).count() as u64)
+                .collect(),
+        });
+    }
+    GrowthStats { periods, cohorts }
+}
+
+/// Returns new vs returning depositors, churn and retention cohorts for each
+/// period of `range`, derived from the deposit history in the event log.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the range is empty, `period_secs` is zero, or the
+///   range spans more than `MAX_GROWTH_PERIODS` periods.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_growth_stats(range: GrowthRange) -> Result<GrowthStats, DepositError> {
+    if range.period_secs == 0 || range.end <= range.start {
+        return Err(DepositError::InvalidArgument(
+            "growth range must be non-empty with a positive period".to_string(),
+        ));
+    }
+    let count = (range.end - range.start).div_ceil(range.period_secs);
+    if count > MAX_GROWTH_PERIODS {
+        return Err(DepositError::InvalidArgument(format!(
+            "growth range spans more than {} periods",
+            MAX_GROWTH_PERIODS
+        )));
+    }
+
+    let mut active = vec![BTreeSet::new(); count as usize];
+    for event in (0..events::len()).filter_map(events::get) {
+        if event.timestamp < range.start || event.timestamp >= range.end {
+            continue;
+        }
+        if let EventKind::Deposited { key, .. } = event.kind {
+            let i = ((event.timestamp - range.start) / range.period_secs) as usize;
+            active[i].insert(key.principal);
+        }
+    }
+    Ok(compute_growth(&range, &active, first_seen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_growth_periods_and_cohorts() {
+        let p = |n: u8| Principal::from_slice(&[n]);
+        // p1 is a pre-existing depositor; p2 and p3 join in period 0, p4 in period 1.
+        record_depositor(p(1), 0);
+        record_depositor(p(2), 100);
+        record_depositor(p(3), 150);
+        record_depositor(p(4), 250);
+        record_depositor(p(2), 260);
+
+        let range = GrowthRange {
+            start: 100,
+            end: 400,
+            period_secs: 100,
+        };
+        let active = vec![
+            BTreeSet::from([p(1), p(2), p(3)]),
+            BTreeSet::from([p(2), p(4)]),
+            BTreeSet::from([p(2), p(3)]),
+        ];
+        let stats = compute_growth(&range, &active, first_seen);
+
+        let summary: Vec<(u64, u64, u64)> = stats
+            .periods
+            .iter()
+            .map(|s| (s.new_depositors, s.returning_depositors, s.churned))
+            .collect();
+        assert_eq!(summary, vec![(2, 1, 0), (1, 1, 2), (0, 2, 1)]);
+        assert_eq!(stats.cohorts[0].size, 2);
+        assert_eq!(stats.cohorts[0].retained, vec![1, 2]);
+        assert_eq!(stats.cohorts[1].retained, vec![0]);
+    }
+}
@@ -0,0 +1,430 @@
+// src/archive.rs
+//! Moves old transactions to archive canisters. Once the log holds
+//! `ARCHIVE_TRIGGER` transactions past the last archive, a timer hands the
+//! oldest `BLOCKS_PER_ARCHIVE` of them to the archive canister set in the
+//! config, or to one it creates from the wasm a controller uploaded, and
+//! removes them from the pool. Transaction and ICRC-3 block queries on the
+//! pool reach into the archives for them.
+//!
+//! An archive is installed with the pool's principal as its argument and
+//! serves the interface of `stake-pool-archive`. It must sit on the pool's
+//! subnet, since composite queries cannot call across subnets.
+use crate::config;
+use crate::error::DepositError;
+use crate::icrc3;
+use crate::memory::{get_memory, Memory, ARCHIVES_MEMORY_ID, ARCHIVE_WASM_MEMORY_ID};
+use crate::state_hash;
+use crate::storage;
+use crate::transactions::{self, Transaction};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ops::{Range, RangeInclusive};
+use std::time::Duration;
+
+pub const BLOCKS_PER_ARCHIVE: u64 = 100_000;
+/// Transactions past the last archive before the oldest of them are archived.
+pub const ARCHIVE_TRIGGER: u64 = 2 * BLOCKS_PER_ARCHIVE;
+const ARCHIVE_CYCLES: u128 = 2_000_000_000_000;
+// Transactions sent to an archive per call, well below the message size limit.
+const APPEND_BATCH: u64 = 500;
+// Transactions hashed per timer tick while catching up with the log.
+const BACKFILL_BATCH: u64 = 10_000;
+const TICK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Transactions `start..=end` handed to `canister_id`. The first `appended`
+/// of them are served from there and no longer held by the pool.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArchiveRecord {
+    pub canister_id: Principal,
+    pub start: u64,
+    pub end: u64,
+    pub appended: u64,
+}
+
+impl ArchiveRecord {
+    fn is_complete(&self) -> bool {
+        self.start + self.appended > self.end
+    }
+
+    /// Ids the archive holds.
+    pub fn served(&self) -> Range<u64> {
+        self.start..self.start + self.appended
+    }
+}
+
+impl Storable for ArchiveRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ArchiveRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ArchiveRecord")
+    }
+}
+
+impl BoundedStorable for ArchiveRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A transaction as sent to an archive, with its ICRC-3 block.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArchivedTransaction {
+    pub transaction: Transaction,
+    pub block: ICRC3Value,
+}
+
+/// Transactions of `principal` among ids `start..end` of an archive,
+/// skipping the first `offset`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransactionsOfArgs {
+    pub principal: Principal,
+    pub start: u64,
+    pub end: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransactionsOfPage {
+    pub transactions: Vec<Transaction>,
+    /// Transactions of the principal in the requested ids, before paging.
+    pub total: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ArchiveWasm(Vec<u8>);
+
+impl Storable for ArchiveWasm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ArchiveWasm(bytes.into_owned())
+    }
+}
+
+thread_local! {
+    // Keyed by the first transaction of each archive.
+    static ARCHIVES: RefCell<StableBTreeMap<u64, ArchiveRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ARCHIVES_MEMORY_ID)));
+
+    static ARCHIVE_WASM: RefCell<StableCell<ArchiveWasm, Memory>> = RefCell::new(
+        StableCell::init(get_memory(ARCHIVE_WASM_MEMORY_ID), ArchiveWasm::default())
+            .expect("Failed to init archive wasm cell"),
+    );
+
+    static ARCHIVING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "archives",
+            ARCHIVES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "archive_wasm",
+            ARCHIVE_WASM.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+// Clears `ARCHIVING` when dropped, including when the job traps after an
+// await and the replica cleans up the call.
+struct ArchivingGuard;
+
+impl ArchivingGuard {
+    fn acquire() -> Option<Self> {
+        (!ARCHIVING.with(|a| a.replace(true))).then_some(ArchivingGuard)
+    }
+}
+
+impl Drop for ArchivingGuard {
+    fn drop(&mut self) {
+        ARCHIVING.with(|a| a.set(false));
+    }
+}
+
+pub(crate) fn put(archive: &ArchiveRecord) {
+    ARCHIVES.with(|m| m.borrow_mut().insert(archive.start, archive.clone()));
+}
+
+/// Archives holding at least one transaction, oldest first.
+pub fn records() -> Vec<ArchiveRecord> {
+    ARCHIVES.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.appended > 0)
+            .collect()
+    })
+}
+
+/// The archive serving transaction `id`, if it was archived.
+pub fn holding(id: u64) -> Option<ArchiveRecord> {
+    ARCHIVES.with(|m| {
+        m.borrow()
+            .range(..=id)
+            .last()
+            .map(|(_, a)| a)
+            .filter(|a| a.served().contains(&id))
+    })
+}
+
+fn call_error((code, message): (ic_cdk::api::call::RejectionCode, String)) -> String {
+    format!("{:?}: {}", code, message)
+}
+
+// Queries from the pool's composite queries cannot be answered without the
+// archive, so a failed call traps rather than returning a partial page.
+fn unavailable(archive: &ArchiveRecord, error: (ic_cdk::api::call::RejectionCode, String)) -> ! {
+    ic_cdk::trap(&format!(
+        "archive {} unavailable: {}",
+        archive.canister_id,
+        call_error(error)
+    ))
+}
+
+/// Fetches the transactions of `ids` held by `archive`.
+pub async fn fetch(archive: &ArchiveRecord, ids: Range<u64>) -> Vec<Transaction> {
+    let start = ids.start.max(archive.start);
+    let end = ids.end.min(archive.served().end);
+    if start >= end {
+        return Vec::new();
+    }
+    match ic_cdk::call(
+        archive.canister_id,
+        "get_transactions",
+        (start, end - start),
+    )
+    .await
+    {
+        Ok((transactions,)) => transactions,
+        Err(e) => unavailable(archive, e),
+    }
+}
+
+/// Fetches a page of the transactions of `principal` held by `archive`.
+pub async fn fetch_of(
+    archive: &ArchiveRecord,
+    principal: Principal,
+    offset: u64,
+    limit: u64,
+) -> TransactionsOfPage {
+    let args = TransactionsOfArgs {
+        principal,
+        start: archive.start,
+        end: archive.served().end,
+        offset,
+        limit,
+    };
+    match ic_cdk::call(archive.canister_id, "get_transactions_of", (args,)).await {
+        Ok((page,)) => page,
+        Err(e) => unavailable(archive, e),
+    }
+}
+
+async fn spawn_archive(wasm: Vec<u8>) -> Result<Principal, String> {
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id()]),
+        ..Default::default()
+    };
+    let (created,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        ARCHIVE_CYCLES,
+    )
+    .await
+    .map_err(call_error)?;
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: created.canister_id,
+        wasm_module: wasm,
+        arg: candid::encode_one(ic_cdk::id()).expect("Failed to encode archive argument"),
+    })
+    .await
+    .map_err(call_error)?;
+    Ok(created.canister_id)
+}
+
+// Appends the transactions an archive is missing and drops them from the
+// pool, recording progress per batch so a failed run resumes where it
+// stopped.
+async fn fill_archive(mut archive: ArchiveRecord) -> Result<ArchiveRecord, String> {
+    while !archive.is_complete() {
+        let from = archive.start + archive.appended;
+        let to = (from + APPEND_BATCH).min(archive.end + 1);
+        let batch: Vec<ArchivedTransaction> = (from..to)
+            .filter_map(|id| {
+                Some(ArchivedTransaction {
+                    transaction: transactions::get(id)?,
+                    block: icrc3::block_at(id)?,
+                })
+            })
+            .collect();
+        ic_cdk::call::<_, ()>(archive.canister_id, "append_transactions", (batch,))
+            .await
+            .map_err(call_error)?;
+        mark_appended(&mut archive, from..to);
+    }
+    Ok(archive)
+}
+
+// Records that `ids` reached the archive and drops them from the pool.
+fn mark_appended(archive: &mut ArchiveRecord, ids: Range<u64>) {
+    archive.appended += ids.end - ids.start;
+    put(archive);
+    transactions::prune(ids);
+}
+
+// Ids of the archive following `last`, once `ARCHIVE_TRIGGER` transactions
+// were recorded past it.
+fn next_range(last: Option<&ArchiveRecord>, log_length: u64) -> Option<RangeInclusive<u64>> {
+    let start = last.map_or(0, |a| a.end + 1);
+    (log_length >= start + ARCHIVE_TRIGGER).then(|| start..=start + BLOCKS_PER_ARCHIVE - 1)
+}
+
+/// Archives the oldest transactions once enough have accumulated and an
+/// archive is configured or can be created. An archive left incomplete by a
+/// failed run is filled first. Waits for the migration of the legacy
+/// transaction log, which would copy pruned transactions back.
+async fn archive_old_transactions() -> Result<Option<ArchiveRecord>, String> {
+    if storage::copying_transactions() {
+        return Ok(None);
+    }
+    let last = ARCHIVES.with(|m| m.borrow().last_key_value().map(|(_, a)| a));
+    if let Some(archive) = last.clone().filter(|a| !a.is_complete()) {
+        return fill_archive(archive).await.map(Some);
+    }
+    let Some(ids) = next_range(last.as_ref(), icrc3::log_length()) else {
+        return Ok(None);
+    };
+    let canister_id = match config::get().archive_canister {
+        Some(canister_id) => canister_id,
+        None => {
+            let wasm = ARCHIVE_WASM.with(|c| c.borrow().get().0.clone());
+            if wasm.is_empty() {
+                return Ok(None);
+            }
+            spawn_archive(wasm).await?
+        }
+    };
+    let archive = ArchiveRecord {
+        canister_id,
+        start: *ids.start(),
+        end: *ids.end(),
+        appended: 0,
+    };
+    put(&archive);
+    fill_archive(archive).await.map(Some)
+}
+
+/// Starts the hourly timer that hashes transactions the block chain trails
+/// and archives old transactions. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_archiving() {
+    ic_cdk_timers::set_timer_interval(TICK_INTERVAL, || {
+        icrc3::sync_hashes(BACKFILL_BATCH);
+        let Some(guard) = ArchivingGuard::acquire() else {
+            return;
+        };
+        ic_cdk::spawn(async move {
+            let _guard = guard;
+            if let Err(e) = archive_old_transactions().await {
+                ic_cdk::println!("archiving failed: {}", e);
+            }
+        });
+    });
+}
+
+/// Sets the wasm module archive canisters are created from when the config
+/// names no archive canister. An empty module turns creation off. Only
+/// canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_archive_wasm(wasm: Vec<u8>) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    ARCHIVE_WASM.with(|c| {
+        c.borrow_mut()
+            .set(ArchiveWasm(wasm))
+            .expect("Failed to persist archive wasm");
+    });
+    transactions::admin("set_archive_wasm", Ok(()))
+}
+
+/// Returns every archive and the transactions it was assigned.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_archives() -> Vec<ArchiveRecord> {
+    ARCHIVES.with(|m| m.borrow().iter().map(|(_, a)| a).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::TransactionKind;
+
+    #[test]
+    fn test_archives_take_the_oldest_transactions_and_drop_them() {
+        assert_eq!(next_range(None, ARCHIVE_TRIGGER - 1), None);
+        assert_eq!(
+            next_range(None, ARCHIVE_TRIGGER),
+            Some(0..=BLOCKS_PER_ARCHIVE - 1)
+        );
+        let first = ArchiveRecord {
+            canister_id: Principal::from_slice(&[7]),
+            start: 0,
+            end: BLOCKS_PER_ARCHIVE - 1,
+            appended: BLOCKS_PER_ARCHIVE,
+        };
+        assert_eq!(
+            next_range(Some(&first), BLOCKS_PER_ARCHIVE + ARCHIVE_TRIGGER - 1),
+            None
+        );
+        assert_eq!(
+            next_range(Some(&first), BLOCKS_PER_ARCHIVE + ARCHIVE_TRIGGER),
+            Some(BLOCKS_PER_ARCHIVE..=2 * BLOCKS_PER_ARCHIVE - 1)
+        );
+
+        let caller = Principal::from_slice(&[8]);
+        for _ in 0..5 {
+            transactions::record(1, caller, TransactionKind::Deposit, None, 10, None);
+        }
+        let mut archive = ArchiveRecord {
+            canister_id: Principal::from_slice(&[9]),
+            start: 0,
+            end: 3,
+            appended: 0,
+        };
+        put(&archive);
+        mark_appended(&mut archive, 0..2);
+        assert!(!archive.is_complete());
+        assert_eq!(holding(1), Some(archive.clone()));
+        assert_eq!(holding(2), None);
+        assert_eq!(transactions::get(1), None);
+        assert!(transactions::get(2).is_some());
+
+        mark_appended(&mut archive, 2..4);
+        assert!(archive.is_complete());
+        assert_eq!(records(), vec![archive]);
+        assert_eq!(transactions::of_principal(caller, 0, 10).len(), 1);
+        assert_eq!(transactions::len(), 5);
+    }
+}
@@ -0,0 +1,174 @@
+// src/backup.rs
+use crate::config;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::status::{self, PoolStatus};
+use crate::{Deposit, DepositKey, UserKey, DEPOSITS, STAKE_BALANCE_MAP};
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeSet;
+
+/// Maximum number of events scanned per `export_changes` call. Callers page
+/// through larger ranges by passing `next_seq` back in.
+pub const MAX_EVENTS_PER_EXPORT: u64 = 1_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountSnapshot {
+    pub key: UserKey,
+    pub deposits: Vec<Deposit>,
+    pub stake_balance: u64,
+}
+
+/// Current state of every account mutated by events in `[since_seq, next_seq)`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateChanges {
+    pub since_seq: u64,
+    pub next_seq: u64,
+    /// True when the range contains an event touching every account (such as a
+    /// slash), in which case `accounts` is a full dump.
+    pub full_snapshot: bool,
+    pub accounts: Vec<AccountSnapshot>,
+    pub deposit_id_counter: u64,
+}
+
+fn snapshot(key: &UserKey) -> AccountSnapshot {
+    AccountSnapshot {
+        key: key.clone(),
+        deposits: crate::deposits_of(key),
+        stake_balance: STAKE_BALANCE_MAP.with(|m| m.borrow().get(key).unwrap_or(0)),
+    }
+}
+
+// Keys of `principal` holding deposits or a stake balance.
+fn keys_of(principal: Principal) -> BTreeSet<UserKey> {
+    let mut keys: BTreeSet<UserKey> = STAKE_BALANCE_MAP.with(|m| {
+        m.borrow()
+            .range(UserKey::range_of_principal(principal))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    DEPOSITS.with(|m| {
+        keys.extend(
+            m.borrow()
+                .range(DepositKey::range_of_principal(principal))
+                .map(|(k, _)| k.user),
+        )
+    });
+    keys
+}
+
+pub fn collect_changes(since_seq: u64, max_events: u64) -> StateChanges {
+    let next_seq = since_seq.saturating_add(max_events).min(events::len());
+    let mut touched = BTreeSet::new();
+    let mut full_snapshot = false;
+
+    for event in events::range(since_seq, next_seq.saturating_sub(since_seq)) {
+        match event.kind {
+            EventKind::PoolSlashed { .. } => full_snapshot = true,
+            // The old keys are gone by now; they are the subaccounts the new
+            // principal took over, exported empty.
+            EventKind::AccountMigrated { from, to } => {
+                for key in keys_of(to) {
+                    touched.insert(UserKey {
+                        principal: from,
+                        subaccount: key.subaccount,
+                    });
+                    touched.insert(key);
+                }
+                touched.extend(keys_of(from));
+            }
+            ref kind => touched.extend(kind.accounts().into_iter().cloned()),
+        }
+    }
+
+    if full_snapshot {
+        touched = STAKE_BALANCE_MAP.with(|m| m.borrow().iter().map(|(k, _)| k).collect());
+        touched
+            .extend(DEPOSITS.with(|m| m.borrow().iter().map(|(k, _)| k.user).collect::<Vec<_>>()));
+    }
+
+    StateChanges {
+        since_seq,
+        next_seq,
+        full_snapshot,
+        accounts: touched.iter().map(snapshot).collect(),
+        deposit_id_counter: crate::deposit_id_counter(),
+    }
+}
+
+/// Exports the accounts mutated since event sequence number `since_seq`, for
+/// incremental backups and read replicas. Pass the returned `next_seq` to the
+/// following call; the export is complete once `next_seq` stops advancing.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is neither a controller, a
+///   configured read replica nor the successor the pool migrated to.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn export_changes(since_seq: u64) -> Result<StateChanges, DepositError> {
+    let caller = ic_cdk::caller();
+    let successor =
+        matches!(status::get(), PoolStatus::Migrated { successor } if successor == caller);
+    if !successor && !config::get().read_replicas.contains(&caller) {
+        crate::ensure_controller(caller)?;
+    }
+    Ok(collect_changes(since_seq, MAX_EVENTS_PER_EXPORT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deposit_internal, withdraw_internal};
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_export_changes_only_includes_touched_accounts() {
+        let principal = Principal::anonymous();
+        let sub_a = Subaccount([1u8; 32]);
+        let sub_b = Subaccount([2u8; 32]);
+        let timestamp = 1_000_000;
+
+        let d1 = deposit_internal(principal, sub_a, 90, 500, timestamp).unwrap();
+        let checkpoint = events::len();
+        deposit_internal(principal, sub_b, 90, 700, timestamp).unwrap();
+
+        let changes = collect_changes(checkpoint, MAX_EVENTS_PER_EXPORT);
+        assert!(!changes.full_snapshot);
+        assert_eq!(changes.accounts.len(), 1);
+        assert_eq!(changes.accounts[0].key.subaccount, sub_b);
+        assert_eq!(changes.accounts[0].stake_balance, 700);
+
+        let checkpoint = changes.next_seq;
+        withdraw_internal(principal, sub_a, d1.id, timestamp + 90 * 86400).unwrap();
+        let changes = collect_changes(checkpoint, MAX_EVENTS_PER_EXPORT);
+        assert_eq!(changes.accounts.len(), 1);
+        assert_eq!(changes.accounts[0].key.subaccount, sub_a);
+        assert!(changes.accounts[0].deposits.is_empty());
+        assert_eq!(changes.deposit_id_counter, 2);
+    }
+
+    #[test]
+    fn test_export_changes_includes_both_sides_of_a_transfer() {
+        let from = UserKey {
+            principal: Principal::from_slice(&[31]),
+            subaccount: Subaccount([1u8; 32]),
+        };
+        let to = UserKey {
+            principal: Principal::from_slice(&[32]),
+            subaccount: Subaccount([2u8; 32]),
+        };
+        let deposit = deposit_internal(from.principal, from.subaccount, 90, 500, 1_000).unwrap();
+        let checkpoint = events::len();
+        crate::deposit_transfer::transfer_internal(&from, &to, deposit.id, 2_000).unwrap();
+
+        let changes = collect_changes(checkpoint, MAX_EVENTS_PER_EXPORT);
+        assert!(!changes.full_snapshot);
+        let accounts: Vec<_> = changes
+            .accounts
+            .iter()
+            .map(|a| (a.key.clone(), a.stake_balance, a.deposits.len()))
+            .collect();
+        assert_eq!(accounts, vec![(from, 0, 0), (to, 500, 1)]);
+    }
+}
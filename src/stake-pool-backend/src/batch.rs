@@ -0,0 +1,151 @@
+// src/batch.rs
+//! Deposits and withdrawals of several positions in one call. Items are
+//! processed in order and one failing does not stop the others.
+use crate::error::DepositError;
+use crate::maintenance::{self, Operation};
+use crate::withdrawal_queue::WithdrawalOutcome;
+use crate::{
+    allowlist, config, cycles, denylist, locks, memory_guard, neurons, status, tiers, Deposit,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+
+pub const MAX_BATCH_SIZE: usize = 20;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DepositRequest {
+    pub subaccount: Subaccount,
+    pub lock_days: u16,
+    pub amount: u64,
+}
+
+fn check_size(len: usize) -> Result<(), DepositError> {
+    if len == 0 || len > MAX_BATCH_SIZE {
+        return Err(DepositError::InvalidArgument(format!(
+            "a batch must have 1 to {} items",
+            MAX_BATCH_SIZE
+        )));
+    }
+    Ok(())
+}
+
+// Checks that can fail without touching the ledger, so a bad item does not
+// cost the caller a transfer fee.
+fn check_request(caller: Principal, request: &DepositRequest) -> Result<(), DepositError> {
+    tiers::ensure_open(request.lock_days)?;
+    crate::ensure_deposit_allowed(caller, request.amount)
+}
+
+/// Deposits into several positions of the primary token in one call. Each
+/// request is pulled from its subaccount under the pool's ICRC-2 allowance,
+/// so a single approval can cover the whole batch.
+///
+/// # Returns
+///
+/// * The result of each request, in order.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the batch is empty or has more than 20 requests.
+/// * `DepositError::WithdrawalsOnly`: If the pool is low on cycles and only accepts withdrawals.
+/// * `DepositError::MemoryLimitReached`: If heap or stable memory usage reached the configured limits.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+/// * `DepositError::NotAllowlisted`: If the pool is private and the caller is not on its allowlist.
+/// * Any error of `deposit_funds` for an individual request.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn deposit_funds_batch(
+    requests: Vec<DepositRequest>,
+) -> Result<Vec<Result<Deposit, DepositError>>, DepositError> {
+    cycles::check_headroom();
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    check_size(requests.len())?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    allowlist::ensure_allowed(caller)?;
+    memory_guard::ensure_deposit_capacity(crate::now_secs())?;
+    let mut results = Vec::with_capacity(requests.len());
+    for request in requests {
+        let result = match check_request(caller, &request) {
+            Ok(()) => crate::pull_deposit(
+                caller,
+                request.subaccount,
+                request.lock_days,
+                request.amount,
+                crate::now_secs(),
+            )
+            .await
+            .map_err(DepositError::from),
+            Err(e) => Err(e),
+        };
+        if result.is_ok() && config::get().neuron_staking_enabled {
+            neurons::stake_deposit(request.lock_days, request.amount).await;
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Withdraws several matured deposits of the caller in one call, from
+/// whichever subaccounts hold them. Each withdrawal is paid or queued as
+/// with `withdraw_funds`.
+///
+/// # Returns
+///
+/// * The outcome of each withdrawal, in order.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the batch is empty or has more than 20 deposit IDs.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * Any error of `withdraw_funds` for an individual deposit.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn withdraw_funds_batch(
+    deposit_ids: Vec<u64>,
+) -> Result<Vec<Result<WithdrawalOutcome, DepositError>>, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    check_size(deposit_ids.len())?;
+    let caller = ic_cdk::caller();
+    let mut results = Vec::with_capacity(deposit_ids.len());
+    for deposit_id in deposit_ids {
+        let result = match locks::owner_key(caller, deposit_id) {
+            Some(key) => crate::withdraw_deposit(key, deposit_id, None).await,
+            None => Err(DepositError::NoDepositFound),
+        };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_are_checked_before_pulling() {
+        let caller = Principal::from_slice(&[51]);
+        let request = |lock_days, amount| DepositRequest {
+            subaccount: Subaccount([0; 32]),
+            lock_days,
+            amount,
+        };
+        assert!(check_size(0).is_err());
+        assert!(check_size(MAX_BATCH_SIZE + 1).is_err());
+        assert_eq!(check_request(caller, &request(90, 1_000)), Ok(()));
+        assert_eq!(
+            check_request(caller, &request(45, 1_000)),
+            Err(DepositError::InvalidLockPeriod)
+        );
+
+        let mut config = config::get();
+        config.min_deposit = 500;
+        config::set(config);
+        assert_eq!(
+            check_request(caller, &request(90, 100)),
+            Err(DepositError::AmountTooLow { minimum: 500 })
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// src/canister_stakers.rs
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, CLAIM_CALLBACKS_MEMORY_ID};
+use crate::state_hash;
+use crate::{PrincipalKey, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+pub const MAX_METHOD_LEN: usize = 64;
+
+/// Method a staking canister exposes to learn about claimable rewards.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClaimCallback {
+    pub method: String,
+}
+
+impl Storable for ClaimCallback {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ClaimCallback"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ClaimCallback")
+    }
+}
+
+impl BoundedStorable for ClaimCallback {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Argument of the claim callback. The canister collects the rewards with
+/// `claim_rewards(subaccount)`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClaimNotification {
+    pub subaccount: Subaccount,
+    pub claimable: u64,
+}
+
+thread_local! {
+    static CLAIM_CALLBACKS: RefCell<StableBTreeMap<PrincipalKey, ClaimCallback, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CLAIM_CALLBACKS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "claim_callbacks",
+        CLAIM_CALLBACKS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Canister ids are opaque principals: 10 bytes ending in the 0x01 tag.
+pub fn is_canister(principal: Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[9] == 0x01
+}
+
+pub fn callback_of(principal: Principal) -> Option<ClaimCallback> {
+    CLAIM_CALLBACKS.with(|m| m.borrow().get(&PrincipalKey(principal)))
+}
+
+/// Up to `limit` canisters with a claim callback, following `after`.
+pub fn registered_after(after: Option<Principal>, limit: usize) -> Vec<Principal> {
+    let start = after.map_or(Bound::Unbounded, |p| Bound::Excluded(PrincipalKey(p)));
+    CLAIM_CALLBACKS.with(|m| {
+        m.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(k, _)| k.0)
+            .collect()
+    })
+}
+
+pub fn set_callback(principal: Principal, method: Option<String>) -> Result<(), DepositError> {
+    if !is_canister(principal) {
+        return Err(DepositError::InvalidArgument(
+            "only canisters can register a claim callback".to_string(),
+        ));
+    }
+    match method {
+        Some(method) if method.is_empty() || method.len() > MAX_METHOD_LEN => {
+            Err(DepositError::InvalidArgument(format!(
+                "method name must be between 1 and {} bytes",
+                MAX_METHOD_LEN
+            )))
+        }
+        Some(method) => {
+            CLAIM_CALLBACKS.with(|m| {
+                m.borrow_mut()
+                    .insert(PrincipalKey(principal), ClaimCallback { method })
+            });
+            Ok(())
+        }
+        None => {
+            CLAIM_CALLBACKS.with(|m| m.borrow_mut().remove(&PrincipalKey(principal)));
+            Ok(())
+        }
+    }
+}
+
+/// Tells a staking canister that `claimable` rewards wait for it. One-way, so
+/// a slow or trapping callback cannot hold up the distribution.
+pub fn notify_claimable(key: &UserKey, claimable: u64) {
+    let Some(callback) = callback_of(key.principal) else {
+        return;
+    };
+    let notification = ClaimNotification {
+        subaccount: key.subaccount,
+        claimable,
+    };
+    if let Err(e) = ic_cdk::notify(key.principal, &callback.method, (notification,)) {
+        ic_cdk::println!("claim callback to {} failed: {:?}", key.principal, e);
+    }
+}
+
+/// Registers the method this canister wants called when rewards become
+/// claimable, or removes it when `None`. The callback is notified after every
+/// distribution with what the canister can collect through `claim_rewards`.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the caller is not a canister or the
+///   method name is empty or longer than 64 bytes.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_claim_callback(method: Option<String>) -> Result<(), DepositError> {
+    set_callback(ic_cdk::caller(), method)
+}
+
+/// Returns the claim callback registered by the caller.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_claim_callback() -> Option<ClaimCallback> {
+    callback_of(ic_cdk::caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_canisters_register_callbacks() {
+        let canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let user = Principal::self_authenticating([7u8; 32]);
+        assert!(is_canister(canister));
+        assert!(!is_canister(user));
+        assert!(!is_canister(Principal::anonymous()));
+
+        assert!(set_callback(user, Some("on_claim".to_string())).is_err());
+        assert!(set_callback(canister, Some(String::new())).is_err());
+        set_callback(canister, Some("on_claim".to_string())).unwrap();
+        assert_eq!(registered_after(None, 10), vec![canister]);
+        assert!(registered_after(Some(canister), 10).is_empty());
+        set_callback(canister, None).unwrap();
+        assert!(callback_of(canister).is_none());
+    }
+}
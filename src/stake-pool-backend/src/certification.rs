@@ -0,0 +1,351 @@
+// src/certification.rs
+//! Certified answers to the main queries. The canister's certified data is
+//! `node(snapshot_root, state_root)`: the root of the latest stake snapshot
+//! next to the root of a Merkle tree over the pool stats followed by the
+//! stake of every account, in the order the accounts were first certified.
+//! `get_pool_stats` and `get_stake_balance` return the path from their value
+//! to the certified data along with the system certificate, so clients can
+//! check them without an update call.
+//!
+//! The tree is built once per install or upgrade. After that the writers of
+//! stake balances mark the accounts they change, and a timer rehashes the
+//! leaves of at most `MAX_UPDATES_PER_REFRESH` of them every few seconds, so
+//! certified answers may trail live ones by as much.
+use crate::snapshot::{self, ProofStep, SnapshotLeaf};
+use crate::stats::{self, PoolStats};
+use crate::{UserKey, STAKE_BALANCE_MAP};
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// Most changed accounts rehashed per refresh; the rest wait for the next.
+const MAX_UPDATES_PER_REFRESH: usize = 1_000;
+const STATS_TAG: u8 = 2;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CertifiedPoolStats {
+    pub stats: PoolStats,
+    /// Path from `stats_hash(stats)` to the certified data.
+    pub path: Vec<ProofStep>,
+    pub certificate: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CertifiedBalance {
+    pub key: UserKey,
+    pub balance: u64,
+    /// Path from the hash of `SnapshotLeaf { key, weight: balance }` to the
+    /// certified data. Empty if the account had no stake balance when last
+    /// certified, in which case `balance` is 0.
+    pub path: Vec<ProofStep>,
+    pub certificate: Option<Vec<u8>>,
+}
+
+struct CertifiedState {
+    stats: PoolStats,
+    /// Leaf index and certified balance of every account in the tree. Leaf 0
+    /// holds the stats.
+    accounts: BTreeMap<UserKey, (usize, u64)>,
+    /// Hashes of the tree, leaves first.
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl CertifiedState {
+    fn new(stats: PoolStats) -> Self {
+        CertifiedState {
+            levels: vec![vec![stats_hash(&stats)]],
+            stats,
+            accounts: BTreeMap::new(),
+        }
+    }
+
+    fn root(&self) -> [u8; 32] {
+        self.levels.last().map_or([0; 32], |level| level[0])
+    }
+
+    // Sets leaf `index`, or appends it if it is one past the last leaf, and
+    // rehashes its ancestors.
+    fn set_leaf(&mut self, index: usize, hash: [u8; 32]) {
+        let (mut index, mut hash) = (index, hash);
+        for depth in 0.. {
+            if depth == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let level = &mut self.levels[depth];
+            match level.get_mut(index) {
+                Some(node) => *node = hash,
+                None => level.push(hash),
+            }
+            if level.len() == 1 {
+                self.levels.truncate(depth + 1);
+                return;
+            }
+            // An odd last node is carried up unchanged, as in `next_level`.
+            let left = index & !1;
+            hash = match level.get(left + 1) {
+                Some(right) => snapshot::node_hash(&level[left], right),
+                None => level[left],
+            };
+            index /= 2;
+        }
+    }
+
+    fn set_stats(&mut self, stats: PoolStats) {
+        self.set_leaf(0, stats_hash(&stats));
+        self.stats = stats;
+    }
+
+    fn set_balance(&mut self, key: UserKey, balance: u64) {
+        let leaf = SnapshotLeaf {
+            key: key.clone(),
+            weight: balance,
+        }
+        .hash();
+        let index = match self.accounts.get(&key) {
+            Some((index, _)) => *index,
+            None if balance == 0 => return,
+            None => self.levels[0].len(),
+        };
+        self.set_leaf(index, leaf);
+        self.accounts.insert(key, (index, balance));
+    }
+
+    // Path from leaf `index` to the certified data.
+    fn path(&self, index: usize) -> Vec<ProofStep> {
+        let mut path = Vec::new();
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                path.push(ProofStep {
+                    hash: level[sibling].to_vec(),
+                    is_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        path.push(ProofStep {
+            hash: snapshot_root().to_vec(),
+            is_left: true,
+        });
+        path
+    }
+}
+
+thread_local! {
+    // Rebuilt from stable state, so it does not need to survive upgrades.
+    static CERTIFIED: RefCell<Option<CertifiedState>> = const { RefCell::new(None) };
+    // Accounts whose stake balance changed since their leaf was last set.
+    static STALE: RefCell<BTreeSet<UserKey>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// `sha256(0x02 || candid(stats))`.
+pub fn stats_hash(stats: &PoolStats) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([STATS_TAG]);
+    hasher.update(candid::encode_one(stats).expect("Failed to encode PoolStats"));
+    hasher.finalize().into()
+}
+
+fn snapshot_root() -> [u8; 32] {
+    snapshot::latest()
+        .and_then(|s| s.root.try_into().ok())
+        .unwrap_or_else(|| snapshot::merkle_root(&[]))
+}
+
+/// Root of the tree over the pool stats and stake balances, once built.
+pub fn state_root() -> Option<[u8; 32]> {
+    CERTIFIED.with(|c| c.borrow().as_ref().map(CertifiedState::root))
+}
+
+/// Marks the stake balance of `key` as changed. Called by every writer of
+/// `STAKE_BALANCE_MAP`.
+pub(crate) fn touch(key: &UserKey) {
+    STALE.with(|s| s.borrow_mut().insert(key.clone()));
+}
+
+fn rebuild(stats: PoolStats) {
+    let mut state = CertifiedState::new(stats);
+    STAKE_BALANCE_MAP.with(|m| {
+        for (key, balance) in m.borrow().iter() {
+            state.set_balance(key, balance);
+        }
+    });
+    STALE.with(|s| s.borrow_mut().clear());
+    CERTIFIED.with(|c| *c.borrow_mut() = Some(state));
+}
+
+// Rehashes the stats if they changed and the leaves of up to
+// `MAX_UPDATES_PER_REFRESH` stale accounts. Returns whether the root changed.
+fn update(stats: PoolStats) -> bool {
+    CERTIFIED.with(|c| {
+        let mut c = c.borrow_mut();
+        let Some(state) = c.as_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        if state.stats != stats {
+            state.set_stats(stats);
+            changed = true;
+        }
+        for _ in 0..MAX_UPDATES_PER_REFRESH {
+            let Some(key) = STALE.with(|s| s.borrow_mut().pop_first()) else {
+                break;
+            };
+            let balance = STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key).unwrap_or(0));
+            state.set_balance(key, balance);
+            changed = true;
+        }
+        changed
+    })
+}
+
+/// Sets the certified data from the latest snapshot and the current tree.
+pub fn certify() {
+    let state_root = state_root().unwrap_or_else(|| snapshot::merkle_root(&[]));
+    ic_cdk::api::set_certified_data(&snapshot::node_hash(&snapshot_root(), &state_root));
+}
+
+/// Brings the tree up to date with the stats and the changed stake balances
+/// and certifies it. Must be called from `init` and `post_upgrade`, where it
+/// builds the tree, since certified data is not preserved across upgrades.
+pub fn refresh() {
+    let stats = stats::pool_stats();
+    if state_root().is_none() {
+        rebuild(stats);
+    } else if !update(stats) {
+        return;
+    }
+    certify();
+}
+
+/// Starts refreshing the certified tree. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_refresh() {
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, refresh);
+}
+
+fn pool_stats_proof() -> Option<(PoolStats, Vec<ProofStep>)> {
+    CERTIFIED.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map(|state| (state.stats.clone(), state.path(0)))
+    })
+}
+
+fn balance_proof(key: &UserKey) -> Option<(u64, Vec<ProofStep>)> {
+    CERTIFIED.with(|c| {
+        let c = c.borrow();
+        let state = c.as_ref()?;
+        let (index, balance) = state.accounts.get(key)?;
+        Some((*balance, state.path(*index)))
+    })
+}
+
+/// The pool stats as of the last certification, with the path to the
+/// certified data and the certificate over it.
+pub fn certified_pool_stats() -> CertifiedPoolStats {
+    let (stats, path) = pool_stats_proof().unwrap_or_else(|| (stats::pool_stats(), Vec::new()));
+    CertifiedPoolStats {
+        stats,
+        path,
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
+
+/// The stake balance of `key` as of the last certification, with the path
+/// to the certified data and the certificate over it.
+pub fn certified_balance(key: UserKey) -> CertifiedBalance {
+    let (balance, path) = balance_proof(&key).unwrap_or_default();
+    CertifiedBalance {
+        key,
+        balance,
+        path,
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    fn fold(leaf: [u8; 32], path: &[ProofStep]) -> [u8; 32] {
+        path.iter().fold(leaf, |acc, step| {
+            let sibling: [u8; 32] = step.hash.clone().try_into().unwrap();
+            match step.is_left {
+                true => snapshot::node_hash(&sibling, &acc),
+                false => snapshot::node_hash(&acc, &sibling),
+            }
+        })
+    }
+
+    #[test]
+    fn test_paths_lead_to_certified_data() {
+        let key = |byte| UserKey {
+            principal: Principal::from_slice(&[190]),
+            subaccount: Subaccount([byte; 32]),
+        };
+        let deposit = |byte, amount| {
+            crate::deposit_internal(key(byte).principal, key(byte).subaccount, 90, amount, 10)
+                .unwrap()
+        };
+        for (byte, amount) in [(1, 100), (2, 200), (3, 300)] {
+            deposit(byte, amount);
+        }
+        let stats = PoolStats {
+            total_staked: 600,
+            ..PoolStats::default()
+        };
+        rebuild(stats.clone());
+        let certified = snapshot::node_hash(&snapshot_root(), &state_root().unwrap());
+
+        let (certified_stats, path) = pool_stats_proof().unwrap();
+        assert_eq!(fold(stats_hash(&certified_stats), &path), certified);
+        let (balance, path) = balance_proof(&key(3)).unwrap();
+        assert_eq!(balance, 300);
+        let leaf = SnapshotLeaf {
+            key: key(3),
+            weight: balance,
+        };
+        assert_eq!(fold(leaf.hash(), &path), certified);
+        assert_ne!(
+            fold(
+                SnapshotLeaf {
+                    weight: 301,
+                    ..leaf
+                }
+                .hash(),
+                &path
+            ),
+            certified
+        );
+        assert!(balance_proof(&key(4)).is_none());
+
+        // Changed and new accounts are rehashed in place, leaving the same
+        // tree a full hash of the leaves gives.
+        deposit(2, 50);
+        deposit(4, 400);
+        assert!(update(stats.clone()));
+        assert!(!update(stats));
+        let certified = snapshot::node_hash(&snapshot_root(), &state_root().unwrap());
+        CERTIFIED.with(|c| {
+            let c = c.borrow();
+            let state = c.as_ref().unwrap();
+            assert_eq!(state.root(), snapshot::merkle_root(&state.levels[0]));
+        });
+        for (byte, amount) in [(1, 100), (2, 250), (4, 400)] {
+            let (balance, path) = balance_proof(&key(byte)).unwrap();
+            assert_eq!(balance, amount);
+            let leaf = SnapshotLeaf {
+                key: key(byte),
+                weight: balance,
+            };
+            assert_eq!(fold(leaf.hash(), &path), certified);
+        }
+    }
+}
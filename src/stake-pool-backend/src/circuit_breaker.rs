@@ -0,0 +1,309 @@
+// src/circuit_breaker.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::status::{self, PoolStatus};
+use crate::{
+    accrual, compounding, config, distribution, escheat, liquid, neurons, pending_withdrawals,
+    reward_streams, treasury, unstaking, STAKE_BALANCE_MAP,
+};
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::time::Duration;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+/// Length of the window over which ledger failures and TVL drops are measured.
+const WINDOW_SECS: u64 = 60 * 60;
+const RESERVE_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Thresholds at which the pool halts deposits and reward distributions.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BreakerConfig {
+    /// Share of failed ledger calls within a window that trips the breaker.
+    pub max_ledger_failure_bps: u16,
+    /// Ledger calls needed in a window before the failure rate is considered.
+    pub min_ledger_calls: u32,
+    /// Shortfall of held funds against liabilities tolerated before tripping.
+    pub reserve_tolerance_bps: u16,
+    /// Drop in total value locked within a window that trips the breaker.
+    pub max_tvl_drop_bps: u16,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_ledger_failure_bps: 5_000,
+            min_ledger_calls: 10,
+            reserve_tolerance_bps: 100,
+            max_tvl_drop_bps: 2_000,
+        }
+    }
+}
+
+impl BreakerConfig {
+    pub fn is_valid(&self) -> bool {
+        [
+            self.max_ledger_failure_bps,
+            self.reserve_tolerance_bps,
+            self.max_tvl_drop_bps,
+        ]
+        .iter()
+        .all(|&bps| bps as u128 <= BPS_DENOMINATOR)
+    }
+}
+
+/// The anomaly that halted the pool.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HaltReason {
+    LedgerFailures { calls: u32, failures: u32 },
+    ReserveDeficit { reserves: u64, liabilities: u64 },
+    TvlDrop { from: u64, to: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct LedgerWindow {
+    started_at: u64,
+    calls: u32,
+    failures: u32,
+}
+
+/// Heap state of the breaker, carried over upgrades so that an upgrade does
+/// not reset the observation windows.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct BreakerRuntime {
+    ledger_window: LedgerWindow,
+    tvl_baseline: Option<(u64, u64)>,
+}
+
+thread_local! {
+    static LEDGER_WINDOW: RefCell<LedgerWindow> = RefCell::new(LedgerWindow::default());
+    // (timestamp, tvl) at the start of the current TVL window.
+    static TVL_BASELINE: RefCell<Option<(u64, u64)>> = const { RefCell::new(None) };
+}
+
+pub fn runtime_state() -> BreakerRuntime {
+    BreakerRuntime {
+        ledger_window: LEDGER_WINDOW.with(|w| w.borrow().clone()),
+        tvl_baseline: TVL_BASELINE.with(|b| *b.borrow()),
+    }
+}
+
+pub fn restore_runtime_state(state: BreakerRuntime) {
+    LEDGER_WINDOW.with(|w| *w.borrow_mut() = state.ledger_window);
+    TVL_BASELINE.with(|b| *b.borrow_mut() = state.tvl_baseline);
+}
+
+/// Halts the pool for `reason` unless it is already halted or migrated.
+fn trip(reason: HaltReason, now: u64) {
+    let current = status::get();
+    if !matches!(current, PoolStatus::Active | PoolStatus::WithdrawalsOnly) {
+        return;
+    }
+    let next = PoolStatus::Halted { reason };
+    status::set(next);
+    events::record(now, EventKind::CircuitBreakerTripped { reason });
+    events::record(
+        now,
+        EventKind::PoolStatusChanged {
+            from: current,
+            to: next,
+        },
+    );
+}
+
+/// Counts a ledger call towards the failure rate of the current window.
+pub fn record_ledger_call(ok: bool, now: u64) {
+    let Some(breaker) = config::get().circuit_breaker else {
+        return;
+    };
+    let (calls, failures) = LEDGER_WINDOW.with(|w| {
+        let mut w = w.borrow_mut();
+        if now >= w.started_at + WINDOW_SECS {
+            *w = LedgerWindow {
+                started_at: now,
+                ..LedgerWindow::default()
+            };
+        }
+        w.calls += 1;
+        if !ok {
+            w.failures += 1;
+        }
+        (w.calls, w.failures)
+    });
+    if calls >= breaker.min_ledger_calls
+        && failures as u128 * BPS_DENOMINATOR
+            >= calls as u128 * breaker.max_ledger_failure_bps as u128
+    {
+        trip(HaltReason::LedgerFailures { calls, failures }, now);
+    }
+}
+
+/// Records the outcome of a ledger call and passes it through.
+pub fn observe<T, E>(result: Result<T, E>) -> Result<T, E> {
+    record_ledger_call(result.is_ok(), crate::now_secs());
+    result
+}
+
+/// Trips the breaker if held funds fall short of liabilities by more than the
+/// tolerance, or if `tvl` dropped too far since the start of the window.
+pub fn check(reserves: u64, liabilities: u64, tvl: u64, now: u64) {
+    let Some(breaker) = config::get().circuit_breaker else {
+        return;
+    };
+    let deficit = liabilities.saturating_sub(reserves) as u128;
+    if deficit * BPS_DENOMINATOR > liabilities as u128 * breaker.reserve_tolerance_bps as u128 {
+        trip(
+            HaltReason::ReserveDeficit {
+                reserves,
+                liabilities,
+            },
+            now,
+        );
+        return;
+    }
+
+    let baseline = TVL_BASELINE.with(|b| {
+        let mut b = b.borrow_mut();
+        match *b {
+            Some((at, from)) if now < at + WINDOW_SECS => from,
+            _ => {
+                *b = Some((now, tvl));
+                tvl
+            }
+        }
+    });
+    let drop = baseline.saturating_sub(tvl) as u128;
+    if drop * BPS_DENOMINATOR > baseline as u128 * breaker.max_tvl_drop_bps as u128 {
+        trip(
+            HaltReason::TvlDrop {
+                from: baseline,
+                to: tvl,
+            },
+            now,
+        );
+    }
+}
+
+/// Funds the pool owes: stakes, the liquid pool, unclaimed deposits, rewards
+/// credited for compounding or not yet distributed, reward streams not yet
+/// released and treasury inflows not yet swept out of the main account.
+fn liabilities() -> (u64, u64) {
+    let staked: u64 = STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s).sum());
+    let tvl = staked + liquid::state().total_underlying;
+    let owed = escheat::total_unclaimed()
+        + compounding::total_pending()
+        + accrual::total_accrued()
+        + distribution::residual()
+        + reward_streams::total_unreleased()
+        + pending_withdrawals::total_pending()
+        + treasury::state().unswept;
+    (tvl + owed, tvl)
+}
+
+async fn check_reserves() {
+    let balance = match unstaking::liquid_balance().await {
+        Ok(balance) => balance,
+        Err(e) => {
+            ic_cdk::println!("reserve check failed: {:?}", e);
+            return;
+        }
+    };
+    let in_neurons: u64 = neurons::all().iter().map(|n| n.staked).sum();
+    let (liabilities, tvl) = liabilities();
+    check(balance + in_neurons, liabilities, tvl, crate::now_secs());
+}
+
+/// Starts the periodic reserve and TVL checks. Must be called from `init`
+/// and `post_upgrade`, since timers do not survive upgrades.
+pub fn start_checks() {
+    ic_cdk_timers::set_timer_interval(RESERVE_CHECK_INTERVAL, || ic_cdk::spawn(check_reserves()));
+}
+
+fn resume(now: u64) -> Result<(), DepositError> {
+    let current = status::get();
+    if !matches!(current, PoolStatus::Halted { .. }) {
+        return Err(DepositError::InvalidArgument(
+            "pool is not halted".to_string(),
+        ));
+    }
+    LEDGER_WINDOW.with(|w| *w.borrow_mut() = LedgerWindow::default());
+    TVL_BASELINE.with(|b| *b.borrow_mut() = None);
+    status::set(PoolStatus::Active);
+    events::record(
+        now,
+        EventKind::PoolStatusChanged {
+            from: current,
+            to: PoolStatus::Active,
+        },
+    );
+    Ok(())
+}
+
+/// Reactivates a pool halted by the circuit breaker, after the anomaly has
+/// been reviewed. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the pool is not halted.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn resume_pool() -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("resume_pool", resume(crate::now_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger_failures_halt_until_resumed() {
+        for i in 0..9 {
+            record_ledger_call(i % 2 == 0, 100);
+        }
+        assert_eq!(status::get(), PoolStatus::Active);
+        record_ledger_call(false, 100);
+        assert_eq!(
+            status::get(),
+            PoolStatus::Halted {
+                reason: HaltReason::LedgerFailures {
+                    calls: 10,
+                    failures: 5
+                }
+            }
+        );
+        assert_eq!(status::ensure_active(), Err(DepositError::Halted));
+        assert_eq!(status::ensure_withdrawals_allowed(), Ok(()));
+
+        resume(200).unwrap();
+        assert_eq!(status::get(), PoolStatus::Active);
+        assert!(resume(300).is_err());
+    }
+
+    #[test]
+    fn test_reserve_deficit_and_tvl_drop() {
+        check(995, 1_000, 1_000, 0);
+        check(800, 800, 800, 60);
+        assert_eq!(status::get(), PoolStatus::Active);
+
+        check(700, 700, 700, 120);
+        assert_eq!(
+            status::get(),
+            PoolStatus::Halted {
+                reason: HaltReason::TvlDrop {
+                    from: 1_000,
+                    to: 700
+                }
+            }
+        );
+
+        resume(130).unwrap();
+        check(900, 1_000, 1_000, 140);
+        assert!(matches!(
+            status::get(),
+            PoolStatus::Halted {
+                reason: HaltReason::ReserveDeficit { .. }
+            }
+        ));
+    }
+}
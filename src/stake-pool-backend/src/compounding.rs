@@ -0,0 +1,481 @@
+// src/compounding.rs
+use crate::account_migration::rekey;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, COMPOUNDING_PREFS_MEMORY_ID, PENDING_REWARDS_MEMORY_ID};
+use crate::notifications;
+use crate::state_hash;
+use crate::tiers;
+use crate::transactions::{self, TransactionKind};
+use crate::{Deposit, UserKey, VALID_LOCKS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+/// How an account wants its rewards re-staked.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompoundingPrefs {
+    pub enabled: bool,
+    /// Lock period of the deposits created by compounding.
+    pub lock_days: u16,
+    /// Pending rewards must reach this amount before they are compounded.
+    pub min_pending: u64,
+    /// Minimum time between two compoundings of the same account.
+    pub min_interval_secs: u64,
+}
+
+impl Default for CompoundingPrefs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lock_days: VALID_LOCKS[0],
+            min_pending: 0,
+            min_interval_secs: 0,
+        }
+    }
+}
+
+impl Storable for CompoundingPrefs {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode CompoundingPrefs"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode CompoundingPrefs")
+    }
+}
+
+impl BoundedStorable for CompoundingPrefs {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Rewards credited to an account but not yet compounded or claimed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct PendingRewards {
+    pub amount: u64,
+    pub last_compounded_at: u64,
+}
+
+impl Storable for PendingRewards {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PendingRewards"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PendingRewards")
+    }
+}
+
+impl BoundedStorable for PendingRewards {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static COMPOUNDING_PREFS: RefCell<StableBTreeMap<UserKey, CompoundingPrefs, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(COMPOUNDING_PREFS_MEMORY_ID)));
+
+    static PENDING_REWARDS: RefCell<StableBTreeMap<UserKey, PendingRewards, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PENDING_REWARDS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "compounding_prefs",
+            COMPOUNDING_PREFS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "pending_rewards",
+            PENDING_REWARDS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn prefs_of(key: &UserKey) -> CompoundingPrefs {
+    COMPOUNDING_PREFS.with(|m| m.borrow().get(key).unwrap_or_default())
+}
+
+pub fn set_prefs(key: UserKey, prefs: CompoundingPrefs) -> Result<(), DepositError> {
+    tiers::ensure_open(prefs.lock_days)?;
+    COMPOUNDING_PREFS.with(|m| m.borrow_mut().insert(key, prefs));
+    Ok(())
+}
+
+/// Accounts that have compounding switched on, among at most `limit`
+/// accounts with preferences following `after`. Also returns the key to
+/// resume from, or `None` once every account was looked at.
+pub fn enabled_keys_after(
+    after: Option<&UserKey>,
+    limit: usize,
+) -> (Vec<UserKey>, Option<UserKey>) {
+    let start = after.map_or(Bound::Unbounded, |k| Bound::Excluded(k.clone()));
+    let page: Vec<(UserKey, CompoundingPrefs)> = COMPOUNDING_PREFS.with(|m| {
+        m.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .collect()
+    });
+    let next = (page.len() == limit)
+        .then(|| page.last().map(|(k, _)| k.clone()))
+        .flatten();
+    let enabled = page
+        .into_iter()
+        .filter(|(_, p)| p.enabled)
+        .map(|(k, _)| k)
+        .collect();
+    (enabled, next)
+}
+
+pub fn pending_of(key: &UserKey) -> PendingRewards {
+    PENDING_REWARDS.with(|m| m.borrow().get(key).unwrap_or_default())
+}
+
+pub fn total_pending() -> u64 {
+    PENDING_REWARDS.with(|m| m.borrow().iter().map(|(_, p)| p.amount).sum())
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    COMPOUNDING_PREFS.with(|m| rekey(&mut m.borrow_mut(), from, to, |existing, _| existing));
+    PENDING_REWARDS.with(|m| {
+        rekey(&mut m.borrow_mut(), from, to, |a, b| PendingRewards {
+            amount: a.amount + b.amount,
+            last_compounded_at: a.last_compounded_at.max(b.last_compounded_at),
+        })
+    });
+}
+
+/// Adds `amount` to the pending rewards of `key` without compounding it.
+pub(crate) fn credit_pending(key: &UserKey, amount: u64) {
+    let mut pending = pending_of(key);
+    pending.amount += amount;
+    set_pending(key, pending);
+}
+
+/// Clears the pending rewards of `key` and returns their amount.
+pub(crate) fn take_pending(key: &UserKey) -> u64 {
+    let pending = pending_of(key);
+    set_pending(
+        key,
+        PendingRewards {
+            amount: 0,
+            ..pending
+        },
+    );
+    pending.amount
+}
+
+fn set_pending(key: &UserKey, pending: PendingRewards) {
+    PENDING_REWARDS.with(|m| m.borrow_mut().insert(key.clone(), pending));
+}
+
+/// Credits `reward` to the account's pending rewards and compounds them into
+/// a new deposit once both the amount threshold and the minimum interval of
+/// the account's preferences are met.
+pub fn accrue(key: &UserKey, reward: u64, now: u64) -> Result<Option<Deposit>, DepositError> {
+    let prefs = prefs_of(key);
+    let mut pending = pending_of(key);
+    pending.amount += reward;
+    events::record(
+        now,
+        EventKind::Rewarded {
+            key: key.clone(),
+            amount: reward,
+        },
+    );
+
+    // Rewards wait in pending if the account's tier was closed since.
+    let due = prefs.enabled
+        && !tiers::is_closed(prefs.lock_days)
+        && pending.amount >= prefs.min_pending
+        && now.saturating_sub(pending.last_compounded_at) >= prefs.min_interval_secs;
+    if !due {
+        set_pending(key, pending);
+        return Ok(None);
+    }
+
+    let deposit = crate::record_deposit(
+        key.principal,
+        key.subaccount,
+        prefs.lock_days,
+        pending.amount,
+        now,
+    )?;
+    set_pending(
+        key,
+        PendingRewards {
+            amount: 0,
+            last_compounded_at: now,
+        },
+    );
+    Ok(Some(deposit))
+}
+
+/// Settles the rewards of `key` and restakes all of them as a new deposit
+/// locked for `lock_days`.
+pub fn compound(key: &UserKey, lock_days: u16, now: u64) -> Result<Deposit, DepositError> {
+    tiers::ensure_open(lock_days)?;
+    crate::accrual::settle_account(key, now)?;
+    let pending = pending_of(key);
+    if pending.amount == 0 {
+        return Err(DepositError::InvalidArgument(
+            "no rewards to compound".to_string(),
+        ));
+    }
+    let deposit = crate::record_deposit(
+        key.principal,
+        key.subaccount,
+        lock_days,
+        pending.amount,
+        now,
+    )?;
+    set_pending(
+        key,
+        PendingRewards {
+            amount: 0,
+            last_compounded_at: now,
+        },
+    );
+    Ok(deposit)
+}
+
+/// Sets how rewards of the caller's subaccount are compounded.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidLockPeriod`: If `lock_days` is not 90, 180, or 360 days.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_compounding_prefs(
+    subaccount: Subaccount,
+    prefs: CompoundingPrefs,
+) -> Result<(), DepositError> {
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    set_prefs(key, prefs)
+}
+
+/// Returns the compounding preferences of the caller's subaccount.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_compounding_prefs(subaccount: Subaccount) -> CompoundingPrefs {
+    prefs_of(&UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+/// Returns the rewards of the caller's subaccount awaiting compounding or a
+/// claim, including those accrued since the last settlement.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pending_rewards(subaccount: Subaccount) -> PendingRewards {
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    PendingRewards {
+        amount: crate::accrual::claimable(&key),
+        ..pending_of(&key)
+    }
+}
+
+/// Restakes the rewards the caller's subaccount accrued as a new deposit,
+/// without transferring them out and back in. Deposit limits do not apply,
+/// as with automatic compounding.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidLockPeriod`: If `lock_days` is not 90, 180, or 360 days.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+/// * `DepositError::InvalidArgument`: If the subaccount has no rewards to compound.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+/// * `DepositError::MemoryLimitReached`: If the pool cannot hold more deposits.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn compound_rewards(subaccount: Subaccount, lock_days: u16) -> Result<Deposit, DepositError> {
+    crate::status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    crate::denylist::ensure_not_denied(caller)?;
+    let now = crate::now_secs();
+    crate::memory_guard::ensure_deposit_capacity(now)?;
+    let key = UserKey {
+        principal: caller,
+        subaccount,
+    };
+    let deposit = compound(&key, lock_days, now)?;
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::Compound,
+        Some(key),
+        deposit.amount,
+        None,
+    );
+    Ok(deposit)
+}
+
+/// Transfers the rewards the caller's subaccount accrued out. Rewards are
+/// not pushed by distributions, so this is how stakers collect them.
+///
+/// # Returns
+///
+/// * The amount received, net of the ledger fee.
+///
+/// # Errors
+///
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * `DepositError::BelowLedgerFee`: If the rewards do not cover the ledger fee. They stay pending.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed. The rewards stay pending.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn claim_rewards(subaccount: Subaccount) -> Result<u64, DepositError> {
+    crate::status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Claims)?;
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    let now = crate::now_secs();
+    crate::accrual::settle_account(&key, now)?;
+    let pending = pending_of(&key);
+    if pending.amount == 0 {
+        return Ok(0);
+    }
+    let fee = crate::ledger::payout_fee(crate::ledger::ledger_id(), pending.amount).await?;
+    // Rewards may have been claimed while the fee was looked up.
+    let pending = pending_of(&key);
+    if pending.amount == 0 {
+        return Ok(0);
+    }
+    if pending.amount <= fee {
+        return Err(DepositError::BelowLedgerFee { fee });
+    }
+    set_pending(
+        &key,
+        PendingRewards {
+            amount: 0,
+            ..pending
+        },
+    );
+    let block = match crate::transfer_to_user(
+        crate::ledger::ledger_id(),
+        key.principal,
+        subaccount,
+        pending.amount,
+        fee,
+    )
+    .await
+    {
+        Ok(block) => block,
+        Err(e) => {
+            let mut restored = pending_of(&key);
+            restored.amount += pending.amount;
+            set_pending(&key, restored);
+            return Err(e);
+        }
+    };
+    transactions::record(
+        now,
+        key.principal,
+        TransactionKind::RewardClaim,
+        Some(key.clone()),
+        pending.amount,
+        Some(block),
+    );
+    notifications::dispatch(
+        key.principal,
+        notifications::Notification::Reward {
+            amount: pending.amount,
+        },
+        now,
+    );
+    Ok(pending.amount - fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_compounding_respects_threshold_and_interval() {
+        let key = UserKey {
+            principal: Principal::anonymous(),
+            subaccount: Subaccount([7u8; 32]),
+        };
+        assert_eq!(
+            set_prefs(
+                key.clone(),
+                CompoundingPrefs {
+                    lock_days: 30,
+                    ..CompoundingPrefs::default()
+                }
+            ),
+            Err(DepositError::InvalidLockPeriod)
+        );
+        set_prefs(
+            key.clone(),
+            CompoundingPrefs {
+                enabled: true,
+                lock_days: 180,
+                min_pending: 100,
+                min_interval_secs: 86400,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(accrue(&key, 60, 100_000), Ok(None));
+        let deposit = accrue(&key, 60, 100_000).unwrap().unwrap();
+        assert_eq!(deposit.amount, 120);
+        assert_eq!(deposit.lock_period_days, 180);
+        assert_eq!(pending_of(&key).amount, 0);
+
+        // Above the threshold but too soon after the last compounding.
+        assert_eq!(accrue(&key, 500, 100_000 + 3600), Ok(None));
+        assert_eq!(
+            accrue(&key, 0, 100_000 + 86400).unwrap().map(|d| d.amount),
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn test_compound_restakes_pending_rewards() {
+        let key = UserKey {
+            principal: Principal::anonymous(),
+            subaccount: Subaccount([8u8; 32]),
+        };
+        assert!(matches!(
+            compound(&key, 90, 1_000),
+            Err(DepositError::InvalidArgument(_))
+        ));
+
+        credit_pending(&key, 250);
+        assert_eq!(
+            compound(&key, 30, 1_000),
+            Err(DepositError::InvalidLockPeriod)
+        );
+        let deposit = compound(&key, 360, 1_000).unwrap();
+        assert_eq!(deposit.amount, 250);
+        assert_eq!(deposit.lock_period_days, 360);
+        assert_eq!(
+            pending_of(&key),
+            PendingRewards {
+                amount: 0,
+                last_compounded_at: 1_000
+            }
+        );
+    }
+}
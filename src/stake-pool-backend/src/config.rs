@@ -0,0 +1,322 @@
+// src/config.rs
+use crate::circuit_breaker::BreakerConfig;
+use crate::cycles;
+use crate::error::DepositError;
+use crate::escheat;
+use crate::icrc28;
+use crate::loyalty::{self, LoyaltyTier};
+use crate::memory::{get_memory, Memory, CONFIG_MEMORY_ID};
+use crate::penalty::PenaltyCurve;
+use crate::proposals;
+use crate::rate_model::RateModel;
+use crate::referrals::ReferralReward;
+use crate::rewards::InactivityDecay;
+use crate::scheduler;
+use crate::state_hash;
+use crate::withdrawal_queue::QueuePolicy;
+use crate::VALID_LOCKS;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Operator-tunable pool parameters, persisted in stable memory.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    /// Minimum number of cycles the canister must hold above its freezing
+    /// limit before it stops accepting deposits and reward distributions.
+    pub min_cycles_headroom: u128,
+    /// Wasm heap size at which new deposits are refused.
+    pub max_heap_bytes: u64,
+    /// Stable memory size at which new deposits are refused.
+    pub max_stable_memory_bytes: u64,
+    /// Penalty applied by `early_withdraw` to deposits that have not matured.
+    pub early_exit_penalty: PenaltyCurve,
+    /// Reward weight decay for matured deposits left idle. Disabled when `None`.
+    pub inactivity_decay: Option<InactivityDecay>,
+    /// Order in which `process_withdrawal_queue` serves queued withdrawals.
+    pub withdrawal_queue_policy: QueuePolicy,
+    /// Most `withdraw_funds` pays out per hour. Withdrawals beyond it are
+    /// queued and paid by a timer. Unlimited when `None`.
+    pub max_hourly_outflow: Option<u64>,
+    /// Curve mapping utilization of pool funds to the staker reward rate.
+    pub rate_model: RateModel,
+    /// Lock tiers closed to new deposits. Existing deposits keep their terms.
+    pub closed_lock_tiers: Vec<u16>,
+    /// Stake new deposits in the pool neuron backing their lock tier.
+    pub neuron_staking_enabled: bool,
+    /// Days after maturity at which an untouched deposit is moved to
+    /// unclaimed funds. Disabled when `None`.
+    pub escheat_after_days: Option<u32>,
+    /// Anomaly thresholds that halt the pool. Disabled when `None`.
+    pub circuit_breaker: Option<BreakerConfig>,
+    /// Controller approvals a treasury disbursement needs before it executes.
+    pub treasury_approvals_required: u8,
+    /// Read-replica canisters allowed to follow the change feed.
+    pub read_replicas: Vec<Principal>,
+    /// Archive canister old transactions are moved to. One is created from
+    /// the archive wasm for every batch when `None`.
+    pub archive_canister: Option<Principal>,
+    /// Minimum time between scheduling a maintenance window and its start.
+    pub maintenance_notice_secs: u64,
+    /// Smallest amount a partial withdrawal may leave in a deposit.
+    pub min_stake: u64,
+    /// Keep accepting withdrawals of matured deposits while the pool is paused.
+    pub withdrawals_while_paused: bool,
+    /// Interval at which the rewards subaccount is swept and distributed.
+    /// Disabled when `None`.
+    pub reward_sweep_interval_secs: Option<u64>,
+    /// Smallest amount a new deposit may have.
+    pub min_deposit: u64,
+    /// Most a principal may hold staked across its subaccounts. Unlimited
+    /// when `None`.
+    pub max_deposit_per_user: Option<u64>,
+    /// Most the pool may hold across stakers and the liquid pool. Unlimited
+    /// when `None`.
+    pub max_total_value_locked: Option<u64>,
+    /// Voting window of staker proposals. Proposals are disabled when `None`.
+    pub proposal_voting_secs: Option<u64>,
+    /// Share of the locked stake that must vote for a proposal to pass.
+    pub proposal_quorum_bps: u16,
+    /// Reward of referrers. Referrals are disabled when `None`.
+    pub referral_reward: Option<ReferralReward>,
+    /// Reward bonuses for continuous staking, by increasing `min_days`.
+    /// Loyalty boosts are disabled when empty.
+    pub loyalty_tiers: Vec<LoyaltyTier>,
+    /// Cycles balance below which a low-cycles alert is raised. Disabled
+    /// when `None`.
+    pub low_cycles_threshold: Option<u128>,
+    /// HTTPS endpoint low-cycles alerts are POSTed to as JSON.
+    pub cycles_alert_webhook: Option<String>,
+    /// Treasury amount converted to cycles, at most once a day, while the
+    /// balance is below `low_cycles_threshold`. Needs the primary ledger to
+    /// be the ICP ledger. Disabled when `None`.
+    pub cycles_top_up_amount: Option<u64>,
+    /// Origins returned by `icrc28_trusted_origins`, such as the pool's
+    /// frontend.
+    pub trusted_origins: Vec<String>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_cycles_headroom: 100_000_000_000,
+            // Leave room below the 4 GiB heap and 500 GiB stable memory hard limits.
+            max_heap_bytes: 3 * 1024 * 1024 * 1024,
+            max_stable_memory_bytes: 400 * 1024 * 1024 * 1024,
+            early_exit_penalty: PenaltyCurve::default(),
+            inactivity_decay: None,
+            withdrawal_queue_policy: QueuePolicy::default(),
+            max_hourly_outflow: None,
+            rate_model: RateModel::default(),
+            closed_lock_tiers: Vec::new(),
+            neuron_staking_enabled: false,
+            escheat_after_days: None,
+            circuit_breaker: Some(BreakerConfig::default()),
+            treasury_approvals_required: 2,
+            read_replicas: Vec::new(),
+            archive_canister: None,
+            maintenance_notice_secs: 86_400,
+            min_stake: 0,
+            withdrawals_while_paused: true,
+            reward_sweep_interval_secs: None,
+            min_deposit: 0,
+            max_deposit_per_user: None,
+            max_total_value_locked: None,
+            proposal_voting_secs: None,
+            proposal_quorum_bps: 2_000,
+            referral_reward: None,
+            loyalty_tiers: Vec::new(),
+            low_cycles_threshold: None,
+            cycles_alert_webhook: None,
+            cycles_top_up_amount: None,
+            trusted_origins: Vec::new(),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> Result<(), DepositError> {
+        if self.max_heap_bytes == 0 || self.max_stable_memory_bytes == 0 {
+            return Err(DepositError::InvalidConfig(
+                "memory limits must be positive".to_string(),
+            ));
+        }
+        if !self.early_exit_penalty.is_valid() {
+            return Err(DepositError::InvalidConfig(
+                "penalty rates must not exceed 10000 bps".to_string(),
+            ));
+        }
+        if let Some(decay) = &self.inactivity_decay {
+            if !decay.is_valid() {
+                return Err(DepositError::InvalidConfig(
+                    "inactivity decay needs a positive duration and a floor of at most 10000 bps"
+                        .to_string(),
+                ));
+            }
+        }
+        if !self.rate_model.is_valid() {
+            return Err(DepositError::InvalidConfig(
+                "rate model kink must be between 1 and 10000 bps".to_string(),
+            ));
+        }
+        if let Some(tier) = self
+            .closed_lock_tiers
+            .iter()
+            .find(|t| !VALID_LOCKS.contains(t))
+        {
+            return Err(DepositError::InvalidConfig(format!(
+                "unknown lock tier {}",
+                tier
+            )));
+        }
+        if self
+            .escheat_after_days
+            .is_some_and(|d| d < escheat::MIN_ESCHEAT_DAYS)
+        {
+            return Err(DepositError::InvalidConfig(format!(
+                "escheat period must be at least {} days",
+                escheat::MIN_ESCHEAT_DAYS
+            )));
+        }
+        if self.circuit_breaker.as_ref().is_some_and(|b| !b.is_valid()) {
+            return Err(DepositError::InvalidConfig(
+                "circuit breaker thresholds must not exceed 10000 bps".to_string(),
+            ));
+        }
+        if self
+            .reward_sweep_interval_secs
+            .is_some_and(|s| s < scheduler::MIN_SWEEP_INTERVAL_SECS)
+        {
+            return Err(DepositError::InvalidConfig(format!(
+                "reward sweep interval must be at least {} seconds",
+                scheduler::MIN_SWEEP_INTERVAL_SECS
+            )));
+        }
+        if self
+            .max_deposit_per_user
+            .is_some_and(|max| max < self.min_deposit)
+        {
+            return Err(DepositError::InvalidConfig(
+                "maximum deposit per user must not be below the minimum deposit".to_string(),
+            ));
+        }
+        if self
+            .proposal_voting_secs
+            .is_some_and(|s| s < proposals::MIN_VOTING_SECS)
+        {
+            return Err(DepositError::InvalidConfig(format!(
+                "proposal voting window must be at least {} seconds",
+                proposals::MIN_VOTING_SECS
+            )));
+        }
+        if self.max_hourly_outflow == Some(0) {
+            return Err(DepositError::InvalidConfig(
+                "hourly outflow limit must be positive".to_string(),
+            ));
+        }
+        if self.proposal_quorum_bps > 10_000 {
+            return Err(DepositError::InvalidConfig(
+                "proposal quorum must not exceed 10000 bps".to_string(),
+            ));
+        }
+        if self.referral_reward.as_ref().is_some_and(|r| !r.is_valid()) {
+            return Err(DepositError::InvalidConfig(
+                "referral reward must not exceed 10000 bps".to_string(),
+            ));
+        }
+        if !loyalty::is_valid(&self.loyalty_tiers) {
+            return Err(DepositError::InvalidConfig(format!(
+                "loyalty tiers must increase in days and grant between 1 and {} bps",
+                loyalty::MAX_LOYALTY_BONUS_BPS
+            )));
+        }
+        if self.cycles_alert_webhook.as_ref().is_some_and(|url| {
+            !url.starts_with("https://") || url.len() > cycles::MAX_WEBHOOK_URL_LEN
+        }) {
+            return Err(DepositError::InvalidConfig(format!(
+                "cycles alert webhook must be an HTTPS URL of at most {} bytes",
+                cycles::MAX_WEBHOOK_URL_LEN
+            )));
+        }
+        if self.cycles_top_up_amount == Some(0)
+            || (self.cycles_top_up_amount.is_some() && self.low_cycles_threshold.is_none())
+        {
+            return Err(DepositError::InvalidConfig(
+                "cycles top-ups need a positive amount and a low cycles threshold".to_string(),
+            ));
+        }
+        if !icrc28::is_valid(&self.trusted_origins) {
+            return Err(DepositError::InvalidConfig(format!(
+                "at most {} distinct trusted origins, each an HTTPS or localhost origin of at most {} bytes",
+                icrc28::MAX_TRUSTED_ORIGINS,
+                icrc28::MAX_ORIGIN_LEN
+            )));
+        }
+        if self.treasury_approvals_required == 0 {
+            return Err(DepositError::InvalidConfig(
+                "treasury disbursements need at least one approval".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Storable for PoolConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PoolConfig"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PoolConfig")
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<PoolConfig, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CONFIG_MEMORY_ID), PoolConfig::default())
+            .expect("Failed to init config cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "config",
+        CONFIG.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+pub fn get() -> PoolConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn set(config: PoolConfig) {
+    crate::stats::invalidate();
+    CONFIG.with(|c| {
+        c.borrow_mut()
+            .set(config)
+            .expect("Failed to persist config");
+    });
+}
+
+/// Returns the current pool configuration.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_config() -> PoolConfig {
+    get()
+}
+
+/// Replaces the pool configuration. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidConfig`: If the new configuration is inconsistent.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_config(config: PoolConfig) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    config.validate()?;
+    set(config);
+    crate::transactions::admin("set_config", Ok(()))
+}
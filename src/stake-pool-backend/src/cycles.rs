@@ -0,0 +1,546 @@
+// src/cycles.rs
+//! Cycles management. An hourly check keeps the pool out of the freezing
+//! zone, alerts operators when the balance drops below the configured
+//! threshold, and can convert treasury ICP to cycles through the cycles
+//! minting canister. Anyone can also send cycles to `wallet_receive`.
+use crate::config;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::ledger;
+use crate::status::{self, PoolStatus};
+use crate::treasury;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord};
+use ic_ledger_types::{Subaccount, MAINNET_CYCLES_MINTING_CANISTER_ID, MAINNET_LEDGER_CANISTER_ID};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const HEADROOM_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const TOP_UP_INTERVAL_SECS: u64 = 86_400;
+/// Memo the cycles minting canister expects on top-up transfers, "TPUP".
+const TOP_UP_MEMO: u64 = 0x5055_5054;
+pub const MAX_WEBHOOK_URL_LEN: usize = 256;
+const WEBHOOK_CYCLES: u128 = 2_000_000_000;
+const WEBHOOK_RESPONSE_BYTES: u64 = 1_024;
+
+thread_local! {
+    // Cycles the canister must keep to stay above its freezing threshold, as
+    // reported by the last successful `canister_status` call.
+    static FREEZING_LIMIT: RefCell<u128> = const { RefCell::new(0) };
+
+    // Set once a low-cycles alert has been raised so that the hourly check
+    // alerts once per excursion. Cleared when the balance recovers.
+    static LOW_CYCLES_ALERTED: RefCell<bool> = const { RefCell::new(false) };
+
+    // When treasury funds were last converted to cycles by the hourly check.
+    static LAST_TOP_UP: RefCell<Option<u64>> = const { RefCell::new(None) };
+
+    // A top-up transfer the cycles minting canister has not minted cycles
+    // for yet. The hourly check notifies it again.
+    static PENDING_TOP_UP: RefCell<Option<PendingTopUp>> = const { RefCell::new(None) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingTopUp {
+    pub block_index: u64,
+    pub amount: u64,
+}
+
+/// Cycles monitoring state kept on the heap, carried over upgrades.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct CyclesRuntime {
+    pub low_cycles_alerted: bool,
+    pub last_top_up: Option<u64>,
+    pub pending_top_up: Option<PendingTopUp>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CyclesStatus {
+    pub balance: u128,
+    pub freezing_limit: u128,
+    pub low_cycles_threshold: Option<u128>,
+    pub last_top_up: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WalletReceiveResult {
+    pub accepted: u64,
+}
+
+#[derive(CandidType)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+impl NotifyError {
+    // Whether notifying the same block again cannot mint cycles.
+    fn is_final(&self) -> bool {
+        !matches!(self, NotifyError::Processing | NotifyError::Other { .. })
+    }
+}
+
+/// What the hourly check does about a balance below the threshold.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct LowCyclesAction {
+    /// Threshold to alert about, once per excursion.
+    pub alert: Option<u128>,
+    /// Treasury amount to convert to cycles.
+    pub top_up: Option<u64>,
+}
+
+pub fn runtime_state() -> CyclesRuntime {
+    CyclesRuntime {
+        low_cycles_alerted: LOW_CYCLES_ALERTED.with(|a| *a.borrow()),
+        last_top_up: LAST_TOP_UP.with(|t| *t.borrow()),
+        pending_top_up: PENDING_TOP_UP.with(|p| p.borrow().clone()),
+    }
+}
+
+pub fn restore_runtime_state(state: CyclesRuntime) {
+    LOW_CYCLES_ALERTED.with(|a| *a.borrow_mut() = state.low_cycles_alerted);
+    LAST_TOP_UP.with(|t| *t.borrow_mut() = state.last_top_up);
+    PENDING_TOP_UP.with(|p| *p.borrow_mut() = state.pending_top_up);
+}
+
+/// Cycles needed to cover `freezing_threshold_secs` of idle burn.
+pub fn freezing_limit(idle_cycles_burned_per_day: u128, freezing_threshold_secs: u128) -> u128 {
+    idle_cycles_burned_per_day.saturating_mul(freezing_threshold_secs) / 86400
+}
+
+/// Moves the pool in or out of withdrawals-only mode depending on how many
+/// cycles are left above the freezing limit.
+pub fn apply_headroom_check(balance: u128, now: u64) -> PoolStatus {
+    let limit = FREEZING_LIMIT.with(|l| *l.borrow());
+    let headroom = balance.saturating_sub(limit);
+    let low = headroom < config::get().min_cycles_headroom;
+
+    let current = status::get();
+    let next = match (current, low) {
+        (PoolStatus::Active, true) => PoolStatus::WithdrawalsOnly,
+        (PoolStatus::WithdrawalsOnly, false) => PoolStatus::Active,
+        (current, _) => current,
+    };
+    if next != current {
+        status::set(next);
+        events::record(
+            now,
+            EventKind::PoolStatusChanged {
+                from: current,
+                to: next,
+            },
+        );
+    }
+    next
+}
+
+/// Re-evaluates headroom against the live cycles balance.
+pub fn check_headroom() -> PoolStatus {
+    apply_headroom_check(ic_cdk::api::canister_balance128(), crate::now_secs())
+}
+
+/// Raises a `LowCycles` event once per excursion below
+/// `low_cycles_threshold`, and decides on a top-up when one is configured
+/// and none happened in the last day.
+pub fn apply_low_cycles_check(balance: u128, now: u64) -> LowCyclesAction {
+    let config = config::get();
+    let Some(threshold) = config.low_cycles_threshold.filter(|t| balance < *t) else {
+        LOW_CYCLES_ALERTED.with(|a| *a.borrow_mut() = false);
+        return LowCyclesAction::default();
+    };
+    let already_alerted = LOW_CYCLES_ALERTED.with(|a| a.replace(true));
+    if !already_alerted {
+        events::record(now, EventKind::LowCycles { balance, threshold });
+    }
+    let top_up = config.cycles_top_up_amount.filter(|_| {
+        LAST_TOP_UP.with(|t| {
+            t.borrow()
+                .is_none_or(|last| now.saturating_sub(last) >= TOP_UP_INTERVAL_SECS)
+        })
+    });
+    if top_up.is_some() {
+        LAST_TOP_UP.with(|t| *t.borrow_mut() = Some(now));
+    }
+    LowCyclesAction {
+        alert: (!already_alerted).then_some(threshold),
+        top_up,
+    }
+}
+
+fn alert_body(canister_id: Principal, balance: u128, threshold: u128) -> Vec<u8> {
+    format!(
+        r#"{{"canister_id":"{}","cycles":{},"threshold":{}}}"#,
+        canister_id, balance, threshold
+    )
+    .into_bytes()
+}
+
+// Every replica of the subnet sends the request, so the receiver sees it
+// several times.
+async fn post_alert(url: String, balance: u128, threshold: u128) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(WEBHOOK_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(alert_body(ic_cdk::id(), balance, threshold)),
+        transform: Some(TransformContext::from_name(
+            "transform_alert_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let (response,) = http_request(request, WEBHOOK_CYCLES)
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+    if !(200..300).contains(&nat_to_u128(&response.status)) {
+        return Err(format!("HTTP status {}", response.status));
+    }
+    Ok(())
+}
+
+// Account of the cycles minting canister that tops up `canister_id`.
+fn top_up_account(canister_id: Principal) -> Account {
+    Account {
+        owner: MAINNET_CYCLES_MINTING_CANISTER_ID,
+        subaccount: Some(Subaccount::from(canister_id).0),
+    }
+}
+
+/// Converts `amount` of treasury ICP to cycles for this canister through the
+/// cycles minting canister, and returns the cycles minted.
+pub(crate) async fn top_up_from_treasury(amount: u64) -> Result<u128, DepositError> {
+    if ledger::ledger_id() != MAINNET_LEDGER_CANISTER_ID {
+        return Err(DepositError::InvalidArgument(
+            "cycles top-ups need the ICP ledger".to_string(),
+        ));
+    }
+    let canister_id = ic_cdk::id();
+    // The minting canister reads an ICRC-1 memo as a little-endian u64.
+    let memo = Memo::from(TOP_UP_MEMO.to_le_bytes().to_vec());
+    let block_index =
+        treasury::pay_with_memo(top_up_account(canister_id), amount, Some(memo)).await?;
+    let pending = PendingTopUp {
+        block_index,
+        amount,
+    };
+    PENDING_TOP_UP.with(|p| *p.borrow_mut() = Some(pending.clone()));
+    notify_top_up(pending).await
+}
+
+// Asks the cycles minting canister to mint the cycles of a top-up transfer.
+async fn notify_top_up(pending: PendingTopUp) -> Result<u128, DepositError> {
+    let result: Result<(Result<Nat, NotifyError>,), _> = ic_cdk::call(
+        MAINNET_CYCLES_MINTING_CANISTER_ID,
+        "notify_top_up",
+        (NotifyTopUpArg {
+            block_index: pending.block_index,
+            canister_id: ic_cdk::id(),
+        },),
+    )
+    .await;
+    let result = result
+        .map(|(r,)| r)
+        .map_err(|(code, message)| format!("{:?}: {}", code, message));
+    finish_top_up(pending, result, crate::now_secs())
+}
+
+/// Records the outcome of notifying the cycles minting canister of
+/// `pending`. The transfer stays pending for the next hourly check unless
+/// cycles were minted or the minting canister will never mint them.
+fn finish_top_up(
+    pending: PendingTopUp,
+    result: Result<Result<Nat, NotifyError>, String>,
+    now: u64,
+) -> Result<u128, DepositError> {
+    let done = match &result {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => e.is_final(),
+        Err(_) => false,
+    };
+    if done {
+        PENDING_TOP_UP.with(|p| {
+            let mut p = p.borrow_mut();
+            if p.as_ref() == Some(&pending) {
+                *p = None;
+            }
+        });
+    }
+    let cycles = match result {
+        Ok(Ok(cycles)) => nat_to_u128(&cycles),
+        Ok(Err(e)) => {
+            return Err(DepositError::LedgerTransferFailed(format!(
+                "notify_top_up of block {} failed: {:?}",
+                pending.block_index, e
+            )))
+        }
+        Err(e) => {
+            return Err(DepositError::LedgerTransferFailed(format!(
+                "notify_top_up of block {} failed: {}",
+                pending.block_index, e
+            )))
+        }
+    };
+    events::record(
+        now,
+        EventKind::CyclesToppedUp {
+            amount: pending.amount,
+            block_index: pending.block_index,
+            cycles,
+        },
+    );
+    Ok(cycles)
+}
+
+// Notifies the cycles minting canister again of a top-up it has not minted
+// cycles for.
+async fn retry_pending_top_up() {
+    let Some(pending) = PENDING_TOP_UP.with(|p| p.borrow().clone()) else {
+        return;
+    };
+    if let Err(e) = notify_top_up(pending).await {
+        ic_cdk::println!("cycles top-up retry failed: {:?}", e);
+    }
+}
+
+async fn check_low_cycles() {
+    let balance = ic_cdk::api::canister_balance128();
+    let action = apply_low_cycles_check(balance, crate::now_secs());
+    if let Some(threshold) = action.alert {
+        ic_cdk::println!("cycles balance {} is below {}", balance, threshold);
+        if let Some(url) = config::get().cycles_alert_webhook {
+            if let Err(e) = post_alert(url, balance, threshold).await {
+                ic_cdk::println!("cycles alert webhook failed: {}", e);
+            }
+        }
+    }
+    if let Some(amount) = action.top_up {
+        if let Err(e) = top_up_from_treasury(amount).await {
+            ic_cdk::println!("cycles top-up failed: {:?}", e);
+        }
+    }
+}
+
+fn nat_to_u128(n: &Nat) -> u128 {
+    u128::try_from(&n.0).unwrap_or(u128::MAX)
+}
+
+async fn refresh_freezing_limit() {
+    let arg = CanisterIdRecord {
+        canister_id: ic_cdk::id(),
+    };
+    // Requires the canister to be one of its own controllers. On failure the
+    // previously cached limit is kept.
+    match canister_status(arg).await {
+        Ok((status,)) => {
+            let limit = freezing_limit(
+                nat_to_u128(&status.idle_cycles_burned_per_day),
+                nat_to_u128(&status.settings.freezing_threshold),
+            );
+            FREEZING_LIMIT.with(|l| *l.borrow_mut() = limit);
+        }
+        Err(e) => ic_cdk::println!("canister_status failed: {:?}", e),
+    }
+}
+
+/// Starts the periodic freezing-threshold and low-cycles check. Must be
+/// called from `init` and `post_upgrade`, since timers do not survive
+/// upgrades.
+pub fn start_monitoring() {
+    ic_cdk_timers::set_timer_interval(HEADROOM_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            refresh_freezing_limit().await;
+            check_headroom();
+            retry_pending_top_up().await;
+            check_low_cycles().await;
+        })
+    });
+}
+
+/// Accepts the cycles attached to the call, as cycles wallets expect of
+/// the canisters they fund.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn wallet_receive() -> WalletReceiveResult {
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    let accepted = ic_cdk::api::call::msg_cycles_accept128(available);
+    if accepted > 0 {
+        events::record(
+            crate::now_secs(),
+            EventKind::CyclesReceived {
+                from: ic_cdk::caller(),
+                amount: accepted,
+            },
+        );
+    }
+    WalletReceiveResult {
+        accepted: accepted.min(u64::MAX as u128) as u64,
+    }
+}
+
+/// Converts `amount` of treasury ICP to cycles for the pool, net of the
+/// ledger fee, and returns the cycles minted. Only canister controllers may
+/// call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the primary ledger is not the ICP
+///   ledger or the amount is zero.
+/// * `DepositError::InsufficientBalance`: If the treasury holds less than
+///   `amount`.
+/// * `DepositError::LedgerTransferFailed`: If the transfer or the
+///   notification of the cycles minting canister failed. A transfer whose
+///   notification failed is notified again by the hourly check.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn top_up_cycles(amount: u64) -> Result<u128, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let result = top_up_from_treasury(amount).await;
+    crate::transactions::admin("top_up_cycles", result)
+}
+
+/// Returns the cycles balance and the limits it is checked against.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_cycles_status() -> CyclesStatus {
+    CyclesStatus {
+        balance: ic_cdk::api::canister_balance128(),
+        freezing_limit: FREEZING_LIMIT.with(|l| *l.borrow()),
+        low_cycles_threshold: config::get().low_cycles_threshold,
+        last_top_up: LAST_TOP_UP.with(|t| *t.borrow()),
+    }
+}
+
+/// Strips a webhook response down to its status, so that every replica of
+/// the subnet agrees on it.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn transform_alert_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freezing_limit() {
+        // 30 days of a 1B/day burn
+        assert_eq!(freezing_limit(1_000_000_000, 30 * 86400), 30_000_000_000);
+    }
+
+    #[test]
+    fn test_headroom_toggles_withdrawals_only() {
+        FREEZING_LIMIT.with(|l| *l.borrow_mut() = 30_000_000_000);
+        let margin = config::get().min_cycles_headroom;
+
+        assert_eq!(
+            apply_headroom_check(30_000_000_000 + margin - 1, 1),
+            PoolStatus::WithdrawalsOnly
+        );
+        assert_eq!(
+            status::ensure_active(),
+            Err(crate::error::DepositError::WithdrawalsOnly)
+        );
+
+        assert_eq!(
+            apply_headroom_check(30_000_000_000 + margin, 2),
+            PoolStatus::Active
+        );
+        assert_eq!(status::ensure_active(), Ok(()));
+        assert_eq!(events::range(0, 10).len(), 2);
+    }
+
+    #[test]
+    fn test_low_cycles_alerts_once_and_tops_up_daily() {
+        let mut config = config::get();
+        config.low_cycles_threshold = Some(1_000);
+        config.cycles_top_up_amount = Some(500);
+        config::set(config);
+
+        assert_eq!(apply_low_cycles_check(1_000, 1), LowCyclesAction::default());
+        assert_eq!(
+            apply_low_cycles_check(999, 2),
+            LowCyclesAction {
+                alert: Some(1_000),
+                top_up: Some(500),
+            }
+        );
+        // Still low an hour later: no new alert, and the top-up waits a day.
+        assert_eq!(
+            apply_low_cycles_check(900, 3_602),
+            LowCyclesAction::default()
+        );
+        assert_eq!(apply_low_cycles_check(900, 86_402).top_up, Some(500));
+        assert_eq!(
+            events::range(0, 10)
+                .iter()
+                .filter(|e| matches!(e.kind, EventKind::LowCycles { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_top_up_stays_pending_until_cycles_are_minted() {
+        let pending = PendingTopUp {
+            block_index: 7,
+            amount: 500,
+        };
+        PENDING_TOP_UP.with(|p| *p.borrow_mut() = Some(pending.clone()));
+
+        assert!(finish_top_up(pending.clone(), Err("timeout".to_string()), 1).is_err());
+        assert!(finish_top_up(pending.clone(), Ok(Err(NotifyError::Processing)), 2).is_err());
+        assert_eq!(runtime_state().pending_top_up, Some(pending.clone()));
+
+        assert_eq!(
+            finish_top_up(pending.clone(), Ok(Ok(Nat::from(9_000u64))), 3),
+            Ok(9_000)
+        );
+        assert_eq!(runtime_state().pending_top_up, None);
+        assert_eq!(
+            events::range(0, 10).last().map(|e| e.kind.clone()),
+            Some(EventKind::CyclesToppedUp {
+                amount: 500,
+                block_index: 7,
+                cycles: 9_000,
+            })
+        );
+
+        PENDING_TOP_UP.with(|p| *p.borrow_mut() = Some(pending.clone()));
+        let refunded = NotifyError::Refunded {
+            reason: "too small".to_string(),
+            block_index: None,
+        };
+        assert!(finish_top_up(pending, Ok(Err(refunded)), 4).is_err());
+        assert_eq!(runtime_state().pending_top_up, None);
+    }
+}
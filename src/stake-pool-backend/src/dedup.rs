@@ -0,0 +1,190 @@
+// src/dedup.rs
+//! Deduplication of retried deposits. A caller-chosen idempotency key is
+//! remembered for `DEDUP_WINDOW_SECS`; a retry within the window gets the
+//! original deposit back instead of pulling the tokens again.
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, DEPOSIT_DEDUP_MEMORY_ID};
+use crate::state_hash;
+use crate::Deposit;
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Matches the ledger's own transaction deduplication window.
+pub const DEDUP_WINDOW_SECS: u64 = 86_400;
+pub const MAX_KEY_LEN: usize = 32;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum DedupStatus {
+    /// The ledger transfer was issued but the deposit is not recorded yet.
+    Pending,
+    Completed(Deposit),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct DedupEntry {
+    lock_days: u16,
+    amount: u64,
+    recorded_at: u64,
+    status: DedupStatus,
+}
+
+impl Storable for DedupEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DedupEntry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DedupEntry")
+    }
+}
+
+impl BoundedStorable for DedupEntry {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by sha256 of (caller, subaccount, idempotency key).
+    static DEDUP: RefCell<StableBTreeMap<[u8; 32], DedupEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_DEDUP_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "deposit_dedup",
+        DEDUP.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Outcome of starting a deposit under an idempotency key.
+pub enum Begin {
+    /// First use of the key; finish with `complete` or `abandon`.
+    Started([u8; 32]),
+    /// The key already produced this deposit.
+    Replay(Deposit),
+}
+
+fn operation_id(caller: Principal, subaccount: Subaccount, key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([caller.as_slice().len() as u8]);
+    hasher.update(caller.as_slice());
+    hasher.update(subaccount.0);
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+fn prune(now: u64) {
+    DEDUP.with(|m| {
+        let mut m = m.borrow_mut();
+        let expired: Vec<[u8; 32]> = m
+            .iter()
+            .filter(|(_, e)| now.saturating_sub(e.recorded_at) >= DEDUP_WINDOW_SECS)
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            m.remove(&id);
+        }
+    });
+}
+
+/// Claims `key` for a deposit of `amount` locked for `lock_days`, unless the
+/// key was used within the window.
+pub fn begin(
+    caller: Principal,
+    subaccount: Subaccount,
+    key: &[u8],
+    lock_days: u16,
+    amount: u64,
+    now: u64,
+) -> Result<Begin, DepositError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(DepositError::InvalidArgument(format!(
+            "idempotency key must be between 1 and {} bytes",
+            MAX_KEY_LEN
+        )));
+    }
+    prune(now);
+    let id = operation_id(caller, subaccount, key);
+    match DEDUP.with(|m| m.borrow().get(&id)) {
+        Some(entry) if entry.lock_days != lock_days || entry.amount != amount => {
+            Err(DepositError::InvalidArgument(
+                "idempotency key was used for a different deposit".to_string(),
+            ))
+        }
+        Some(DedupEntry {
+            status: DedupStatus::Completed(deposit),
+            ..
+        }) => Ok(Begin::Replay(deposit)),
+        Some(_) => Err(DepositError::OperationInProgress),
+        None => {
+            let entry = DedupEntry {
+                lock_days,
+                amount,
+                recorded_at: now,
+                status: DedupStatus::Pending,
+            };
+            DEDUP.with(|m| m.borrow_mut().insert(id, entry));
+            Ok(Begin::Started(id))
+        }
+    }
+}
+
+pub fn complete(id: [u8; 32], deposit: &Deposit) {
+    DEDUP.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(mut entry) = m.get(&id) {
+            entry.status = DedupStatus::Completed(deposit.clone());
+            m.insert(id, entry);
+        }
+    });
+}
+
+/// Releases a key whose deposit failed before any tokens moved.
+pub fn abandon(id: [u8; 32]) {
+    DEDUP.with(|m| m.borrow_mut().remove(&id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_replays_original_deposit() {
+        let caller = Principal::anonymous();
+        let sub = Subaccount([0; 32]);
+        let Ok(Begin::Started(id)) = begin(caller, sub, b"k1", 90, 500, 0) else {
+            panic!("first use must start");
+        };
+        assert_eq!(
+            begin(caller, sub, b"k1", 90, 500, 1).err(),
+            Some(DepositError::OperationInProgress)
+        );
+
+        let deposit = Deposit {
+            id: 7,
+            amount: 500,
+            timestamp: 0,
+            lock_period_days: 90,
+        };
+        complete(id, &deposit);
+        assert!(
+            matches!(begin(caller, sub, b"k1", 90, 500, 2), Ok(Begin::Replay(d)) if d == deposit)
+        );
+        assert!(begin(caller, sub, b"k1", 180, 500, 2).is_err());
+        assert!(matches!(
+            begin(caller, Subaccount([1; 32]), b"k1", 90, 500, 2),
+            Ok(Begin::Started(_))
+        ));
+        assert!(matches!(
+            begin(caller, sub, b"k1", 90, 500, DEDUP_WINDOW_SECS),
+            Ok(Begin::Started(_))
+        ));
+    }
+}
@@ -0,0 +1,48 @@
+// src/denylist.rs
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, DENYLIST_MEMORY_ID};
+use crate::state_hash;
+use crate::PrincipalKey;
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+thread_local! {
+    static DENYLIST: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DENYLIST_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "denylist",
+        DENYLIST.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn add(principal: Principal) {
+    DENYLIST.with(|d| d.borrow_mut().insert(PrincipalKey(principal), ()));
+}
+
+pub fn remove(principal: Principal) {
+    DENYLIST.with(|d| d.borrow_mut().remove(&PrincipalKey(principal)));
+}
+
+pub fn contains(principal: Principal) -> bool {
+    DENYLIST.with(|d| d.borrow().contains_key(&PrincipalKey(principal)))
+}
+
+/// Fails if `principal` is barred from making new deposits.
+pub fn ensure_not_denied(principal: Principal) -> Result<(), DepositError> {
+    if contains(principal) {
+        Err(DepositError::Denied)
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the principals barred from making new deposits.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_denylist() -> Vec<Principal> {
+    DENYLIST.with(|d| d.borrow().iter().map(|(k, _)| k.0).collect())
+}
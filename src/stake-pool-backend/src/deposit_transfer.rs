@@ -0,0 +1,141 @@
+// src/deposit_transfer.rs
+//! Handing a deposit over to another account, for example a new wallet,
+//! without unlocking it. The deposit keeps its id, lock and dissolve state;
+//! rewards it earned so far stay with the sender.
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::maintenance::{self, Operation};
+use crate::transactions::{self, TransactionKind};
+use crate::{
+    allowlist, analytics, config, denylist, distribution, locks, receipts, sharding, status,
+    validators, withdrawal_queue, Deposit, UserKey,
+};
+use candid::Principal;
+use ic_ledger_types::Subaccount;
+
+pub fn transfer_internal(
+    from: &UserKey,
+    to: &UserKey,
+    deposit_id: u64,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    if to == from || to.principal == Principal::anonymous() {
+        return Err(DepositError::InvalidArgument(
+            "recipient must be another account and not anonymous".to_string(),
+        ));
+    }
+    denylist::ensure_not_denied(to.principal)?;
+    allowlist::ensure_allowed(to.principal)?;
+    sharding::ensure_local(to.principal)?;
+    let deposit = crate::find_deposit(from, deposit_id)?;
+    if withdrawal_queue::deposit_position(deposit_id).is_some() {
+        return Err(DepositError::InvalidArgument(
+            "deposit is queued for withdrawal".to_string(),
+        ));
+    }
+    if let Some(cap) = config::get().max_deposit_per_user {
+        if crate::principal_stake(to.principal).saturating_add(deposit.amount) > cap {
+            return Err(DepositError::UserCapReached { cap });
+        }
+    }
+    // Payouts of a running distribution still target the sender's key.
+    distribution::ensure_idle()?;
+    receipts::ensure_redeemable(from, deposit_id, deposit.amount)?;
+
+    let deposit = crate::remove_deposit(from, deposit_id, now)?;
+    crate::insert_deposit(to, deposit.clone(), now);
+    receipts::move_with_deposit(from, to, deposit_id);
+    validators::rekey_deposit(deposit_id, to.clone());
+    analytics::record_depositor(to.principal, now);
+
+    events::record(
+        now,
+        EventKind::DepositTransferred {
+            from: from.clone(),
+            to: to.clone(),
+            deposit_id,
+            amount: deposit.amount,
+        },
+    );
+    transactions::record(
+        now,
+        from.principal,
+        TransactionKind::DepositTransfer {
+            from: from.clone(),
+            deposit_id,
+        },
+        Some(to.clone()),
+        deposit.amount,
+        None,
+    );
+    Ok(deposit)
+}
+
+/// Hands one of the caller's deposits over to `to_principal`'s
+/// `to_subaccount`, lock and all. Rewards the deposit earned so far stay
+/// with the caller, and its deposit receipts move with it.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::InvalidArgument`: If the recipient is the same account or
+///   anonymous, or the deposit is queued for withdrawal.
+/// * `DepositError::InsufficientBalance`: If the caller no longer holds the deposit's receipts.
+/// * `DepositError::UserCapReached`: If the recipient would exceed the per-user cap.
+/// * `DepositError::Denied`: If either principal is on the denylist.
+/// * `DepositError::NotAllowlisted`: If the pool is gated and the recipient is not allowed.
+/// * `DepositError::DistributionInProgress`: If a reward distribution is running.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn transfer_deposit(
+    deposit_id: u64,
+    to_principal: Principal,
+    to_subaccount: Subaccount,
+) -> Result<Deposit, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    let from = locks::owner_key(caller, deposit_id).ok_or(DepositError::NoDepositFound)?;
+    let to = UserKey {
+        principal: to_principal,
+        subaccount: to_subaccount,
+    };
+    transfer_internal(&from, &to, deposit_id, crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_moves_deposit_and_receipts() {
+        let key = |b: u8| UserKey {
+            principal: Principal::from_slice(&[b]),
+            subaccount: Subaccount([b; 32]),
+        };
+        let (alice, bob) = (key(41), key(42));
+        let deposit =
+            crate::deposit_internal(alice.principal, alice.subaccount, 180, 700, 0).unwrap();
+        assert!(transfer_internal(&alice, &alice, deposit.id, 10).is_err());
+
+        assert_eq!(
+            transfer_internal(&alice, &bob, deposit.id, 10),
+            Ok(deposit.clone())
+        );
+        assert_eq!(
+            crate::find_deposit(&alice, deposit.id),
+            Err(DepositError::NoDepositFound)
+        );
+        assert_eq!(crate::find_deposit(&bob, deposit.id), Ok(deposit.clone()));
+        let stake = |k: &UserKey| crate::STAKE_BALANCE_MAP.with(|m| m.borrow().get(k));
+        assert_eq!((stake(&alice), stake(&bob)), (Some(0), Some(700)));
+        assert_eq!(receipts::balance_of(&bob), 700);
+
+        // Still locked for the recipient.
+        assert_eq!(
+            crate::withdraw_internal(bob.principal, bob.subaccount, deposit.id, 10),
+            Err(DepositError::LockPeriodNotExpired)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// src/direct_deposit.rs
+//! Deposits for wallets without ICRC-2 approvals. The staker transfers to a
+//! pool subaccount derived from their account, then calls `notify_deposit`,
+//! which sweeps that subaccount into the pool's main account and records the
+//! deposit.
+use crate::circuit_breaker;
+use crate::error::DepositError;
+use crate::ledger;
+use crate::maintenance::{self, Operation};
+use crate::migration;
+use crate::status::PoolStatus;
+use crate::transactions::{self, TransactionKind};
+use crate::{
+    allowlist, config, cycles, denylist, memory_guard, neurons, status, tiers, Deposit, UserKey,
+};
+use candid::Nat;
+use ic_cdk::call;
+use ic_ledger_types::Subaccount;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+const DEPOSIT_TAG: &[u8] = b"direct-deposit";
+
+thread_local! {
+    // Deposit subaccounts being swept, so concurrent notifications of the
+    // same transfer are refused instead of racing on the balance.
+    static SWEEPING: RefCell<BTreeSet<[u8; 32]>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Pool subaccount that collects direct deposits for `key`.
+pub fn deposit_subaccount(key: &UserKey) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(DEPOSIT_TAG);
+    hasher.update([key.principal.as_slice().len() as u8]);
+    hasher.update(key.principal.as_slice());
+    hasher.update(key.subaccount.0);
+    hasher.finalize().into()
+}
+
+pub fn deposit_address(key: &UserKey) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(deposit_subaccount(key)),
+    }
+}
+
+// Releases the sweep guard of a deposit subaccount when dropped.
+struct SweepGuard([u8; 32]);
+
+impl SweepGuard {
+    fn acquire(subaccount: [u8; 32]) -> Result<Self, DepositError> {
+        if SWEEPING.with(|s| s.borrow_mut().insert(subaccount)) {
+            Ok(Self(subaccount))
+        } else {
+            Err(DepositError::OperationInProgress)
+        }
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEPING.with(|s| s.borrow_mut().remove(&self.0));
+    }
+}
+
+// Moves `amount` from the deposit subaccount to the pool's main account.
+async fn sweep(from: [u8; 32], amount: u64, fee: u64) -> Result<u64, DepositError> {
+    let ledger = ledger::ledger_id();
+    let transfer_arg = TransferArg {
+        from_subaccount: Some(from),
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+    let (res,): (Result<Nat, TransferError>,) =
+        circuit_breaker::observe(call(ledger, "icrc1_transfer", (transfer_arg,)).await)
+            .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    if let Err(TransferError::BadFee { expected_fee }) = &res {
+        ledger::note_fee(ledger, expected_fee, crate::now_secs());
+    }
+    res.map(crate::block_index)
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))
+}
+
+/// Returns the account to transfer to before calling `notify_deposit` for
+/// the caller's `subaccount`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_deposit_address(subaccount: Subaccount) -> Account {
+    deposit_address(&UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+/// Stakes everything transferred to the caller's deposit address, less the
+/// fee of moving it into the pool, as a deposit locked for `lock_days`.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidLockPeriod`: If `lock_days` is not 90, 180, or 360 days.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+/// * `DepositError::BelowLedgerFee`: If the deposit address holds no more than the ledger fee.
+/// * `DepositError::AmountTooLow` / `DepositError::UserCapReached` / `DepositError::PoolCapReached`:
+///   If the amount is outside the deposit limits. The funds stay at the deposit address.
+/// * `DepositError::OperationInProgress`: If a notification for the same address is still running.
+/// * `DepositError::LedgerTransferFailed`: If the balance lookup or the sweep failed.
+/// * `DepositError::Migrated`: If the pool migrated. The balance of the deposit
+///   address is forwarded to the same address at the successor first.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn notify_deposit(
+    subaccount: Subaccount,
+    lock_days: u16,
+) -> Result<Deposit, DepositError> {
+    cycles::check_headroom();
+    if let PoolStatus::Migrated { successor } = status::get() {
+        // `migrate_to` only sweeps the addresses of accounts that staked.
+        let key = UserKey {
+            principal: ic_cdk::caller(),
+            subaccount,
+        };
+        let _guard = SweepGuard::acquire(deposit_subaccount(&key))?;
+        migration::sweep(
+            ledger::ledger_id(),
+            Some(deposit_subaccount(&key)),
+            successor,
+        )
+        .await?;
+        return Err(DepositError::Migrated { successor });
+    }
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    allowlist::ensure_allowed(caller)?;
+    let now = crate::now_secs();
+    memory_guard::ensure_deposit_capacity(now)?;
+    tiers::ensure_open(lock_days)?;
+    let key = UserKey {
+        principal: caller,
+        subaccount,
+    };
+    let address = deposit_address(&key);
+    let _guard = SweepGuard::acquire(deposit_subaccount(&key))?;
+
+    let balance: u64 = ledger::balance_of(address)
+        .await?
+        .0
+        .try_into()
+        .unwrap_or(u64::MAX);
+    let fee = ledger::fee(ledger::ledger_id()).await?;
+    if balance <= fee {
+        return Err(DepositError::BelowLedgerFee { fee });
+    }
+    let amount = balance - fee;
+    crate::ensure_deposit_allowed(caller, amount)?;
+    let block = sweep(deposit_subaccount(&key), amount, fee).await?;
+    let deposit = crate::deposit_internal(caller, subaccount, lock_days, amount, now)?;
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::Deposit,
+        Some(key),
+        amount,
+        Some(block),
+    );
+    if config::get().neuron_staking_enabled {
+        neurons::stake_deposit(lock_days, amount).await;
+    }
+    Ok(deposit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_deposit_subaccount_is_unique_per_account() {
+        let key = |principal: &[u8], byte| UserKey {
+            principal: Principal::from_slice(principal),
+            subaccount: Subaccount([byte; 32]),
+        };
+        let a = deposit_subaccount(&key(&[1], 0));
+        assert_eq!(a, deposit_subaccount(&key(&[1], 0)));
+        assert_ne!(a, deposit_subaccount(&key(&[1], 1)));
+        assert_ne!(a, deposit_subaccount(&key(&[2], 0)));
+        // The length prefix keeps principal and subaccount bytes apart.
+        assert_ne!(
+            deposit_subaccount(&key(&[1, 0], 0)),
+            deposit_subaccount(&key(&[1], 0))
+        );
+    }
+}
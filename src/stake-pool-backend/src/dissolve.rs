@@ -0,0 +1,250 @@
+// src/dissolve.rs
+//! Dissolve delay mode, an alternative to fixed locks modelled on NNS
+//! neurons. A deposit in this mode stays locked until its owner starts
+//! dissolving it; the delay then runs down in real time and the deposit can
+//! be withdrawn once it reaches zero.
+use crate::accrual;
+use crate::denylist;
+use crate::error::DepositError;
+use crate::locks;
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, DISSOLVE_STATES_MEMORY_ID};
+use crate::state_hash;
+use crate::{status, Deposit, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DissolveState {
+    /// Locked indefinitely. Dissolving takes `delay_secs` once started.
+    NotDissolving { delay_secs: u64 },
+    /// Withdrawable from `dissolves_at` on.
+    Dissolving { dissolves_at: u64 },
+}
+
+impl DissolveState {
+    pub fn unlock_time(&self) -> u64 {
+        match self {
+            DissolveState::NotDissolving { .. } => u64::MAX,
+            DissolveState::Dissolving { dissolves_at } => *dissolves_at,
+        }
+    }
+}
+
+impl Storable for DissolveState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DissolveState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DissolveState")
+    }
+}
+
+impl BoundedStorable for DissolveState {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by deposit id. Entries are kept after a withdrawal so that a
+    // deposit put back after a refused transfer keeps its state; ids are
+    // never reused.
+    static DISSOLVE_STATES: RefCell<StableBTreeMap<u64, DissolveState, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DISSOLVE_STATES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "dissolve_states",
+        DISSOLVE_STATES.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Dissolve state of a deposit, `None` for deposits with a fixed lock.
+pub fn state_of(deposit_id: u64) -> Option<DissolveState> {
+    DISSOLVE_STATES.with(|m| m.borrow().get(&deposit_id))
+}
+
+/// Puts a deposit back on its fixed lock.
+pub fn clear(deposit_id: u64) {
+    DISSOLVE_STATES.with(|m| m.borrow_mut().remove(&deposit_id));
+}
+
+fn not_in_dissolve_mode() -> DepositError {
+    DepositError::InvalidArgument("deposit is not in dissolve mode".to_string())
+}
+
+// Stores `state` for a deposit of `key` and re-weighs its reward shares.
+fn transition(
+    key: &UserKey,
+    deposit_id: u64,
+    now: u64,
+    next: impl FnOnce(&Deposit, Option<DissolveState>) -> Result<DissolveState, DepositError>,
+) -> Result<DissolveState, DepositError> {
+    let deposit = crate::find_deposit(key, deposit_id)?;
+    let state = next(&deposit, state_of(deposit_id))?;
+    DISSOLVE_STATES.with(|m| m.borrow_mut().insert(deposit_id, state.clone()));
+    accrual::resize(key, &deposit, now)?;
+    locks::record_lock_change(key.clone(), &deposit, now);
+    Ok(state)
+}
+
+/// Moves a fixed-lock deposit to dissolve mode, with its lock period as the
+/// dissolve delay. The delay only starts running with `start_dissolving`.
+pub fn enter_internal(
+    key: &UserKey,
+    deposit_id: u64,
+    now: u64,
+) -> Result<DissolveState, DepositError> {
+    transition(key, deposit_id, now, |deposit, state| match state {
+        Some(_) => Err(DepositError::InvalidArgument(
+            "deposit is already in dissolve mode".to_string(),
+        )),
+        None => Ok(DissolveState::NotDissolving {
+            delay_secs: deposit.lock_period_days as u64 * 86400,
+        }),
+    })
+}
+
+pub fn start_internal(
+    key: &UserKey,
+    deposit_id: u64,
+    now: u64,
+) -> Result<DissolveState, DepositError> {
+    transition(key, deposit_id, now, |_, state| match state {
+        Some(DissolveState::NotDissolving { delay_secs }) => Ok(DissolveState::Dissolving {
+            dissolves_at: now + delay_secs,
+        }),
+        Some(DissolveState::Dissolving { .. }) => Err(DepositError::InvalidArgument(
+            "deposit is already dissolving".to_string(),
+        )),
+        None => Err(not_in_dissolve_mode()),
+    })
+}
+
+pub fn stop_internal(
+    key: &UserKey,
+    deposit_id: u64,
+    now: u64,
+) -> Result<DissolveState, DepositError> {
+    transition(key, deposit_id, now, |_, state| match state {
+        Some(DissolveState::Dissolving { dissolves_at }) if dissolves_at > now => {
+            Ok(DissolveState::NotDissolving {
+                delay_secs: dissolves_at - now,
+            })
+        }
+        Some(DissolveState::Dissolving { .. }) => Err(DepositError::InvalidArgument(
+            "deposit has already dissolved".to_string(),
+        )),
+        Some(DissolveState::NotDissolving { .. }) => Err(DepositError::InvalidArgument(
+            "deposit is not dissolving".to_string(),
+        )),
+        None => Err(not_in_dissolve_mode()),
+    })
+}
+
+fn caller_key(caller: Principal, deposit_id: u64) -> Result<UserKey, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    denylist::ensure_not_denied(caller)?;
+    locks::owner_key(caller, deposit_id).ok_or(DepositError::NoDepositFound)
+}
+
+/// Moves a deposit from its fixed lock to dissolve mode. The deposit stays
+/// locked until `start_dissolving` is called, and then for as long as its
+/// lock period.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::InvalidArgument`: If the deposit is already in dissolve mode.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn enter_dissolve_mode(deposit_id: u64) -> Result<DissolveState, DepositError> {
+    let key = caller_key(ic_cdk::caller(), deposit_id)?;
+    enter_internal(&key, deposit_id, crate::now_secs())
+}
+
+/// Starts running down the dissolve delay of a deposit. It can be withdrawn
+/// once the delay reaches zero.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::InvalidArgument`: If the deposit is not in dissolve mode or already dissolving.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn start_dissolving(deposit_id: u64) -> Result<DissolveState, DepositError> {
+    let key = caller_key(ic_cdk::caller(), deposit_id)?;
+    start_internal(&key, deposit_id, crate::now_secs())
+}
+
+/// Stops a dissolving deposit, keeping the delay it had left.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::InvalidArgument`: If the deposit is not dissolving or has dissolved.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn stop_dissolving(deposit_id: u64) -> Result<DissolveState, DepositError> {
+    let key = caller_key(ic_cdk::caller(), deposit_id)?;
+    stop_internal(&key, deposit_id, crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_dissolve_lifecycle() {
+        let principal = Principal::from_slice(&[7]);
+        let subaccount = Subaccount([7u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount,
+        };
+        let day = 86400;
+        let deposit = crate::deposit_internal(principal, subaccount, 90, 1_000, 0).unwrap();
+        assert_eq!(
+            start_internal(&key, deposit.id, 0),
+            Err(not_in_dissolve_mode())
+        );
+
+        enter_internal(&key, deposit.id, 10 * day).unwrap();
+        let find = || crate::find_deposit(&key, deposit.id).unwrap();
+        // Locked past its original 90 days until dissolving starts.
+        assert_eq!(find().unlock_time(), u64::MAX);
+
+        start_internal(&key, deposit.id, 100 * day).unwrap();
+        assert_eq!(find().unlock_time(), 190 * day);
+        assert_eq!(
+            stop_internal(&key, deposit.id, 150 * day),
+            Ok(DissolveState::NotDissolving {
+                delay_secs: 40 * day
+            })
+        );
+        assert_eq!(find().unlock_time(), u64::MAX);
+
+        start_internal(&key, deposit.id, 200 * day).unwrap();
+        assert_eq!(
+            crate::withdraw_internal(principal, subaccount, deposit.id, 239 * day),
+            Err(DepositError::LockPeriodNotExpired)
+        );
+        assert!(stop_internal(&key, deposit.id, 240 * day).is_err());
+        assert_eq!(
+            crate::withdraw_internal(principal, subaccount, deposit.id, 240 * day),
+            Ok(1_000)
+        );
+    }
+}
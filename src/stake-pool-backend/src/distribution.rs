@@ -0,0 +1,499 @@
+// src/distribution.rs
+use crate::accrual;
+use crate::canister_stakers;
+use crate::compounding;
+use crate::config;
+use crate::error::DepositError;
+use crate::liquid;
+use crate::memory::{get_memory, Memory, DISTRIBUTION_STATE_MEMORY_ID, REWARD_RESIDUAL_MEMORY_ID};
+use crate::metrics;
+use crate::reward_history;
+use crate::state_hash;
+use crate::stats;
+use crate::{DepositKey, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// Instructions a distribution may use in one message before it leaves the
+/// rest to a later one, well under the per-message limit.
+const STEP_INSTRUCTIONS: u64 = 5_000_000_000;
+/// Deposits or accounts handled between checks of the instruction budget.
+const CHUNK_SIZE: usize = 100;
+
+/// Reward distribution bookkeeping. Kept in stable memory so that a round
+/// interrupted by an upgrade is still visible as in progress afterwards.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct DistributionState {
+    pub active_round: Option<u64>,
+    pub last_round_id: u64,
+    /// Work left in the active round.
+    pub job: Option<DistributionJob>,
+}
+
+/// Where a distribution job resumes.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DistributionPhase {
+    /// Bringing the reward weight of deposits following `after` up to date.
+    Reweigh { after: Option<DepositKey> },
+    /// Splitting the rewards between stakers and the liquid pool.
+    Allocate,
+    /// Settling compounding accounts following `after`.
+    SettleCompounding { after: Option<UserKey> },
+    /// Settling canister stakers with a claim callback following `after`.
+    SettleCanisters { after: Option<Principal> },
+}
+
+/// A distribution round, worked through in chunks so that it never exceeds
+/// the instruction limit of a message however many stakers there are. What
+/// does not fit in the message that started the round continues on a timer.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DistributionJob {
+    pub round_id: u64,
+    pub amount: u64,
+    pub phase: DistributionPhase,
+    /// Outcome of the chunks run so far.
+    pub report: RewardDistributionReport,
+}
+
+impl DistributionJob {
+    pub fn new(round_id: u64, amount: u64) -> Self {
+        Self {
+            round_id,
+            amount,
+            phase: DistributionPhase::Reweigh { after: None },
+            report: RewardDistributionReport::default(),
+        }
+    }
+
+    // Rewards have not been allocated yet, so dropping the job loses none.
+    fn before_allocation(&self) -> bool {
+        matches!(
+            self.phase,
+            DistributionPhase::Reweigh { .. } | DistributionPhase::Allocate
+        )
+    }
+}
+
+impl Storable for DistributionState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DistributionState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DistributionState")
+    }
+}
+
+/// Rewards credited to an account settled as part of a distribution.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserRewardResult {
+    pub key: UserKey,
+    pub amount: u64,
+    /// Why settling failed. The rewards stay accrued and are settled by the
+    /// account's next claim or distribution.
+    pub error: Option<DepositError>,
+}
+
+/// Outcome of a distribution round.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct RewardDistributionReport {
+    /// Rewards allocated to stakers and the liquid pool.
+    pub total_distributed: u64,
+    pub liquid_share: u64,
+    /// Accounts settled right away: those compounding their rewards and
+    /// registered canister stakers. Everyone else accrues and claims later.
+    pub per_user_results: Vec<UserRewardResult>,
+    /// Rounding remainder carried into the next distribution.
+    pub skipped_dust: u64,
+    pub failed: u64,
+    /// Whether the round has finished. An unfinished round continues in
+    /// later messages; `get_distribution_state` shows its progress.
+    pub complete: bool,
+}
+
+impl RewardDistributionReport {
+    /// Records the settlement of `key`.
+    pub fn push(&mut self, key: UserKey, result: Result<u64, DepositError>) {
+        let (amount, error) = match result {
+            Ok(amount) => (amount, None),
+            Err(e) => {
+                self.failed += 1;
+                (0, Some(e))
+            }
+        };
+        self.per_user_results
+            .push(UserRewardResult { key, amount, error });
+    }
+}
+
+thread_local! {
+    static DISTRIBUTION_STATE: RefCell<StableCell<DistributionState, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(DISTRIBUTION_STATE_MEMORY_ID),
+            DistributionState::default(),
+        )
+        .expect("Failed to init distribution state cell"),
+    );
+
+    // Rewards left over by rounding, added to the next distribution.
+    static REWARD_RESIDUAL: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(REWARD_RESIDUAL_MEMORY_ID), 0)
+            .expect("Failed to init reward residual cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "distribution_state",
+            DISTRIBUTION_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "reward_residual",
+            REWARD_RESIDUAL.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+fn get() -> DistributionState {
+    DISTRIBUTION_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn set(state: DistributionState) {
+    DISTRIBUTION_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist distribution state");
+    });
+}
+
+/// Starts a new distribution round and returns its id. Must be called before
+/// the first await of a distribution and paired with `end_round`.
+pub fn begin_round() -> Result<u64, DepositError> {
+    let mut state = get();
+    if let Some(round_id) = state.active_round {
+        return Err(DepositError::DistributionInProgress { round_id });
+    }
+    state.last_round_id += 1;
+    state.active_round = Some(state.last_round_id);
+    let round_id = state.last_round_id;
+    set(state);
+    Ok(round_id)
+}
+
+/// Fails while a distribution round is running.
+pub fn ensure_idle() -> Result<(), DepositError> {
+    match get().active_round {
+        Some(round_id) => Err(DepositError::DistributionInProgress { round_id }),
+        None => Ok(()),
+    }
+}
+
+pub fn end_round() {
+    let mut state = get();
+    state.active_round = None;
+    state.job = None;
+    set(state);
+}
+
+pub fn job() -> Option<DistributionJob> {
+    get().job
+}
+
+fn set_job(job: DistributionJob) {
+    let mut state = get();
+    state.job = Some(job);
+    set(state);
+}
+
+/// Fails when nobody holds stake that rewards could go to, so that callers
+/// can check before taking rewards in.
+pub(crate) fn ensure_stakers() -> Result<(), DepositError> {
+    if accrual::state().total_shares == 0 && liquid::state().total_underlying == 0 {
+        return Err(DepositError::NoStakerFound);
+    }
+    Ok(())
+}
+
+// Splits the rewards of `job`, together with the remainder the previous
+// distribution left over, between the liquid pool and stakers by weighted
+// stake. Stakers' shares only raise the accrual index; each account collects
+// its rewards with `claim_rewards`.
+fn allocate(job: &mut DistributionJob, now: u64) -> Result<(), DepositError> {
+    let staker_stake = accrual::state().total_shares;
+    let liquid_stake = liquid::state().total_underlying as u128;
+    let total_stake = staker_stake + liquid_stake;
+
+    if total_stake == 0 {
+        return Err(DepositError::NoStakerFound);
+    }
+    let total = job.amount + residual();
+
+    // The liquid pool's share stays in the pool and raises the stToken rate.
+    let liquid_reward = ((liquid_stake * total as u128) / total_stake) as u64;
+    if liquid_reward > 0 {
+        liquid::accrue_rewards(liquid_reward, now);
+    }
+
+    let staker_reward = ((staker_stake * total as u128) / total_stake) as u64;
+    let dust = if staker_reward > 0 {
+        accrual::distribute(staker_reward, now)?
+    } else {
+        0
+    };
+    let skipped_dust = total - liquid_reward - staker_reward + dust;
+    set_residual(skipped_dust);
+    job.report.total_distributed = total - skipped_dust;
+    job.report.liquid_share = liquid_reward;
+    job.report.skipped_dust = skipped_dust;
+    Ok(())
+}
+
+// Runs one chunk of `job`.
+fn step(job: &mut DistributionJob, now: u64) -> Result<(), DepositError> {
+    match job.phase.clone() {
+        DistributionPhase::Reweigh { after } => {
+            let config = config::get();
+            let next = if config.inactivity_decay.is_some() || !config.loyalty_tiers.is_empty() {
+                accrual::reweigh_batch(after.as_ref(), CHUNK_SIZE, now)?
+            } else {
+                None
+            };
+            job.phase = match next {
+                Some(after) => DistributionPhase::Reweigh { after: Some(after) },
+                None => DistributionPhase::Allocate,
+            };
+        }
+        DistributionPhase::Allocate => {
+            allocate(job, now)?;
+            job.phase = DistributionPhase::SettleCompounding { after: None };
+        }
+        // Compounding accounts settle right away so their rewards are
+        // restaked on schedule; canisters with a claim callback learn what
+        // they can claim. A failed settlement does not stop the others.
+        DistributionPhase::SettleCompounding { after } => {
+            let (keys, next) = compounding::enabled_keys_after(after.as_ref(), CHUNK_SIZE);
+            for key in keys {
+                let settled = accrual::settle_account(&key, now);
+                job.report.push(key, settled);
+            }
+            job.phase = match next {
+                Some(after) => DistributionPhase::SettleCompounding { after: Some(after) },
+                None => DistributionPhase::SettleCanisters { after: None },
+            };
+        }
+        DistributionPhase::SettleCanisters { after } => {
+            let principals = canister_stakers::registered_after(after, CHUNK_SIZE);
+            for &principal in &principals {
+                for key in accrual::keys_of(principal) {
+                    let settled = accrual::settle_account(&key, now);
+                    if settled.is_ok() {
+                        canister_stakers::notify_claimable(
+                            &key,
+                            compounding::pending_of(&key).amount,
+                        );
+                    }
+                    job.report.push(key, settled);
+                }
+            }
+            match principals.last() {
+                Some(&last) if principals.len() == CHUNK_SIZE => {
+                    job.phase = DistributionPhase::SettleCanisters { after: Some(last) };
+                }
+                _ => job.report.complete = true,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Works through `job` until it completes or `has_budget` says to stop,
+/// keeping it for a later call in the latter case. The round ends once the
+/// job completes or fails.
+pub(crate) fn run(
+    mut job: DistributionJob,
+    now: u64,
+    mut has_budget: impl FnMut() -> bool,
+) -> Result<DistributionJob, DepositError> {
+    while !job.report.complete && has_budget() {
+        if let Err(e) = step(&mut job, now) {
+            end_round();
+            return Err(e);
+        }
+    }
+    if job.report.complete {
+        reward_history::record(now, job.amount, stats::total_value_locked());
+        end_round();
+    } else {
+        set_job(job.clone());
+    }
+    Ok(job)
+}
+
+/// Runs `job` for as long as the current message allows and leaves the rest
+/// to a timer. Returns the report of the work done so far.
+pub(crate) fn drive(job: DistributionJob) -> Result<RewardDistributionReport, DepositError> {
+    let job = run(job, crate::now_secs(), || {
+        metrics::instructions() < STEP_INSTRUCTIONS
+    })?;
+    if !job.report.complete {
+        ic_cdk_timers::set_timer(Duration::ZERO, resume);
+    }
+    Ok(job.report)
+}
+
+/// Continues the job of the active round, if any. Rewards of a job that
+/// fails before they were allocated are carried into the next distribution.
+pub(crate) fn resume() {
+    let Some(job) = job() else {
+        return;
+    };
+    let (amount, unallocated) = (job.amount, job.before_allocation());
+    if let Err(e) = drive(job) {
+        ic_cdk::println!("reward distribution failed: {:?}", e);
+        if unallocated {
+            carry_over(amount);
+        }
+    }
+}
+
+/// Adds rewards the pool holds but could not distribute to the next
+/// distribution.
+pub(crate) fn carry_over(amount: u64) {
+    set_residual(residual() + amount);
+}
+
+pub fn residual() -> u64 {
+    REWARD_RESIDUAL.with(|r| *r.borrow().get())
+}
+
+pub(crate) fn set_residual(amount: u64) {
+    REWARD_RESIDUAL.with(|r| {
+        r.borrow_mut()
+            .set(amount)
+            .expect("Failed to persist reward residual");
+    });
+}
+
+/// Returns the rewards left undistributed by rounding. They are added to the
+/// next distribution.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_reward_residual() -> u64 {
+    residual()
+}
+
+/// Returns the distribution round state.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_distribution_state() -> DistributionState {
+    get()
+}
+
+/// Clears the in-progress flag left behind by a distribution that trapped
+/// midway, dropping the rest of its work. Only canister controllers may call
+/// this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn force_release_distribution_lock() -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    end_round();
+    crate::transactions::admin("force_release_distribution_lock", Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_flight_round() {
+        assert_eq!(begin_round(), Ok(1));
+        assert_eq!(
+            begin_round(),
+            Err(DepositError::DistributionInProgress { round_id: 1 })
+        );
+
+        end_round();
+        assert_eq!(begin_round(), Ok(2));
+        assert_eq!(get().active_round, Some(2));
+    }
+
+    #[test]
+    fn test_rewards_without_stakers_are_kept() {
+        assert_eq!(ensure_stakers(), Err(DepositError::NoStakerFound));
+        let round_id = begin_round().unwrap();
+        assert_eq!(
+            run(DistributionJob::new(round_id, 700), 10, || true),
+            Err(DepositError::NoStakerFound)
+        );
+        assert_eq!(ensure_idle(), Ok(()));
+        carry_over(700);
+        assert_eq!(residual(), 700);
+    }
+
+    #[test]
+    fn test_report_counts_failed_settlements() {
+        let key = |byte| UserKey {
+            principal: candid::Principal::anonymous(),
+            subaccount: ic_ledger_types::Subaccount([byte; 32]),
+        };
+        let mut report = RewardDistributionReport::default();
+        report.push(key(1), Ok(40));
+        report.push(key(2), Err(DepositError::NoDepositFound));
+        report.push(key(3), Ok(0));
+
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.per_user_results[0].amount, 40);
+        assert_eq!(
+            report.per_user_results[1].error,
+            Some(DepositError::NoDepositFound)
+        );
+    }
+
+    #[test]
+    fn test_job_resumes_where_it_stopped() {
+        let key = UserKey {
+            principal: Principal::from_slice(&[180]),
+            subaccount: ic_ledger_types::Subaccount([0; 32]),
+        };
+        crate::deposit_internal(key.principal, key.subaccount, 90, 1_000, 10).unwrap();
+        compounding::set_prefs(
+            key.clone(),
+            compounding::CompoundingPrefs {
+                enabled: true,
+                ..compounding::prefs_of(&key)
+            },
+        )
+        .unwrap();
+
+        // Two chunks fit: weights are current and the rewards are allocated.
+        let round_id = begin_round().unwrap();
+        let mut budget = 2;
+        let job = run(DistributionJob::new(round_id, 500), 20, || {
+            budget -= 1;
+            budget >= 0
+        })
+        .unwrap();
+        assert!(!job.report.complete);
+        assert_eq!(job.report.total_distributed, 500);
+        assert_eq!(
+            get().job.map(|j| j.phase),
+            Some(DistributionPhase::SettleCompounding { after: None })
+        );
+        assert!(ensure_idle().is_err());
+
+        let job = run(get().job.unwrap(), 30, || true).unwrap();
+        assert!(job.report.complete);
+        assert_eq!(job.report.per_user_results[0].key, key);
+        assert_eq!(job.report.per_user_results[0].amount, 500);
+        assert_eq!(get().job, None);
+        assert_eq!(ensure_idle(), Ok(()));
+    }
+}
@@ -0,0 +1,209 @@
+// src/epochs.rs
+//! Numbered weekly staking periods. Each epoch keeps the deposits opened and
+//! rewards distributed during it, and is closed with the stake it ended at.
+//! Epoch `n` covers `[n * EPOCH_LENGTH_SECS, (n + 1) * EPOCH_LENGTH_SECS)`.
+use crate::events::MAX_EVENTS_PER_PAGE;
+use crate::memory::{get_memory, Memory, DEPOSIT_ENTRY_EPOCHS_MEMORY_ID, EPOCH_HISTORY_MEMORY_ID};
+use crate::state_hash;
+use crate::stats;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const EPOCH_LENGTH_SECS: u64 = 7 * 86400;
+const TICK_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EpochRecord {
+    pub epoch: u64,
+    pub start: u64,
+    pub end: u64,
+    pub deposits_opened: u64,
+    pub amount_deposited: u64,
+    pub distributions: u64,
+    pub rewards_distributed: u64,
+    /// Stake of all accounts when the epoch was closed, within an hour of
+    /// its end. `None` while it is open.
+    pub closing_stake: Option<u64>,
+}
+
+impl EpochRecord {
+    fn open(epoch: u64) -> Self {
+        Self {
+            epoch,
+            start: epoch * EPOCH_LENGTH_SECS,
+            end: (epoch + 1) * EPOCH_LENGTH_SECS,
+            deposits_opened: 0,
+            amount_deposited: 0,
+            distributions: 0,
+            rewards_distributed: 0,
+            closing_stake: None,
+        }
+    }
+}
+
+impl Storable for EpochRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode EpochRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode EpochRecord")
+    }
+}
+
+impl BoundedStorable for EpochRecord {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Epochs in which something happened, keyed by number.
+    static EPOCH_HISTORY: RefCell<StableBTreeMap<u64, EpochRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EPOCH_HISTORY_MEMORY_ID)));
+
+    // Keyed by deposit id. Entries outlive their deposits.
+    static DEPOSIT_ENTRY_EPOCHS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_ENTRY_EPOCHS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "epoch_history",
+            EPOCH_HISTORY.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "deposit_entry_epochs",
+            DEPOSIT_ENTRY_EPOCHS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn epoch_at(now: u64) -> u64 {
+    now / EPOCH_LENGTH_SECS
+}
+
+/// Closes the open epoch if `now` is past its end and opens the current one.
+pub fn advance(now: u64) {
+    let current = epoch_at(now);
+    EPOCH_HISTORY.with(|m| {
+        let mut m = m.borrow_mut();
+        match m.last_key_value() {
+            Some((epoch, _)) if epoch >= current => return,
+            Some((epoch, mut record)) if record.closing_stake.is_none() => {
+                record.closing_stake = Some(stats::total_staked());
+                m.insert(epoch, record);
+            }
+            _ => {}
+        }
+        m.insert(current, EpochRecord::open(current));
+    });
+}
+
+fn update_current(now: u64, f: impl FnOnce(&mut EpochRecord)) {
+    advance(now);
+    let epoch = epoch_at(now);
+    EPOCH_HISTORY.with(|m| {
+        let mut m = m.borrow_mut();
+        let mut record = m.get(&epoch).unwrap_or_else(|| EpochRecord::open(epoch));
+        f(&mut record);
+        m.insert(epoch, record);
+    });
+}
+
+/// Books a new deposit into the epoch it was opened in.
+pub fn record_deposit(deposit_id: u64, amount: u64, now: u64) {
+    DEPOSIT_ENTRY_EPOCHS.with(|m| m.borrow_mut().insert(deposit_id, epoch_at(now)));
+    update_current(now, |r| {
+        r.deposits_opened += 1;
+        r.amount_deposited += amount;
+    });
+}
+
+/// Books a reward distribution into the current epoch.
+pub fn record_distribution(amount: u64, now: u64) {
+    update_current(now, |r| {
+        r.distributions += 1;
+        r.rewards_distributed += amount;
+    });
+}
+
+pub fn entry_epoch(deposit_id: u64) -> Option<u64> {
+    DEPOSIT_ENTRY_EPOCHS.with(|m| m.borrow().get(&deposit_id))
+}
+
+/// Starts the hourly check for epoch changes. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_epochs() {
+    ic_cdk_timers::set_timer_interval(TICK_INTERVAL, || advance(crate::now_secs()));
+}
+
+/// Returns the epoch in progress, with what it recorded so far.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_current_epoch() -> EpochRecord {
+    let epoch = epoch_at(crate::now_secs());
+    EPOCH_HISTORY
+        .with(|m| m.borrow().get(&epoch))
+        .unwrap_or_else(|| EpochRecord::open(epoch))
+}
+
+/// Returns up to `limit` (capped at 100) recorded epochs from epoch `start`
+/// on, oldest first. Epochs in which nothing happened are skipped.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_epoch_history(start: u64, limit: u64) -> Vec<EpochRecord> {
+    EPOCH_HISTORY.with(|m| {
+        m.borrow()
+            .range(start..)
+            .take(limit.min(MAX_EVENTS_PER_PAGE) as usize)
+            .map(|(_, r)| r)
+            .collect()
+    })
+}
+
+/// Returns the epoch a deposit was opened in.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_deposit_entry_epoch(deposit_id: u64) -> Option<u64> {
+    entry_epoch(deposit_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_activity_is_booked_per_epoch() {
+        let principal = Principal::from_slice(&[110]);
+        let week = EPOCH_LENGTH_SECS;
+        let deposit =
+            crate::deposit_internal(principal, Subaccount([0; 32]), 90, 500, week + 10).unwrap();
+        record_distribution(40, week + 20);
+        record_distribution(60, 3 * week + 5);
+
+        assert_eq!(entry_epoch(deposit.id), Some(1));
+        let history = get_epoch_history(0, 10);
+        assert_eq!(
+            history.iter().map(|r| r.epoch).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            (history[0].amount_deposited, history[0].rewards_distributed),
+            (500, 40)
+        );
+        assert_eq!(history[0].closing_stake, Some(500));
+        assert_eq!(
+            (history[1].distributions, history[1].closing_stake),
+            (1, None)
+        );
+    }
+}
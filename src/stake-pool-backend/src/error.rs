@@ -1,10 +1,234 @@
-use candid::CandidType;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 
-#[derive(CandidType, Debug, PartialEq)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum DepositError {
     InvalidLockPeriod,
     LockPeriodNotExpired,
     NoDepositFound,
     LedgerTransferFailed(String),
     NoStakerFound,
+    Unauthorized,
+    WithdrawalsOnly,
+    MemoryLimitReached,
+    DistributionInProgress {
+        round_id: u64,
+    },
+    Migrated {
+        successor: Principal,
+    },
+    Denied,
+    InvalidConfig(String),
+    InvalidArgument(String),
+    LockTierClosed,
+    GovernanceCallFailed(String),
+    InsufficientBalance,
+    /// The circuit breaker halted deposits and reward distributions.
+    Halted,
+    /// A scheduled maintenance window suspends the operation until `until`.
+    UnderMaintenance {
+        until: u64,
+    },
+    /// A partial withdrawal would leave less than `minimum` in the deposit.
+    BelowMinimumStake {
+        minimum: u64,
+    },
+    /// A controller paused the pool.
+    Paused,
+    /// An earlier call with the same idempotency key has not finished.
+    OperationInProgress,
+    /// The token's ledger is neither the primary ledger nor registered.
+    UnsupportedToken,
+    /// The amount to pay out does not cover the ledger transfer fee.
+    BelowLedgerFee {
+        fee: u64,
+    },
+    /// The deposit is smaller than the configured minimum.
+    AmountTooLow {
+        minimum: u64,
+    },
+    /// The deposit would take the depositor's stake over the per-user cap.
+    UserCapReached {
+        cap: u64,
+    },
+    /// The deposit would take the pool's total value locked over its cap.
+    PoolCapReached {
+        cap: u64,
+    },
+    /// The pool is private and the caller is not on its allowlist.
+    NotAllowlisted,
+    /// No usable USD price of the token is cached.
+    PriceUnavailable(String),
+    /// Another call is operating on the same deposit or account.
+    ConcurrentOperation,
+    /// The referrer is the caller, was referred by the caller, or is not the
+    /// referrer the caller named before.
+    InvalidReferrer,
+    /// The withdrawal destination is the pool, the anonymous principal, or a
+    /// denylisted principal.
+    InvalidDestination,
+    /// The caller's accounts live on `shard`, which takes its deposits.
+    WrongShard {
+        shard: Principal,
+    },
+    /// A shard did not answer.
+    ShardUnavailable {
+        shard: Principal,
+        reason: String,
+    },
+}
+
+impl DepositError {
+    /// Stable numeric code of the error, for logs and client-side mapping.
+    pub fn code(&self) -> u32 {
+        match self {
+            DepositError::InvalidLockPeriod => 1001,
+            DepositError::LockPeriodNotExpired => 1002,
+            DepositError::NoDepositFound => 1003,
+            DepositError::LedgerTransferFailed(_) => 1004,
+            DepositError::NoStakerFound => 1005,
+            DepositError::Unauthorized => 1006,
+            DepositError::WithdrawalsOnly => 1007,
+            DepositError::MemoryLimitReached => 1008,
+            DepositError::DistributionInProgress { .. } => 1009,
+            DepositError::Migrated { .. } => 1010,
+            DepositError::Denied => 1011,
+            DepositError::InvalidConfig(_) => 1012,
+            DepositError::InvalidArgument(_) => 1013,
+            DepositError::LockTierClosed => 1014,
+            DepositError::GovernanceCallFailed(_) => 1015,
+            DepositError::InsufficientBalance => 1016,
+            DepositError::Halted => 1017,
+            DepositError::UnderMaintenance { .. } => 1018,
+            DepositError::BelowMinimumStake { .. } => 1019,
+            DepositError::Paused => 1020,
+            DepositError::OperationInProgress => 1021,
+            DepositError::UnsupportedToken => 1022,
+            DepositError::BelowLedgerFee { .. } => 1023,
+            DepositError::AmountTooLow { .. } => 1024,
+            DepositError::UserCapReached { .. } => 1025,
+            DepositError::PoolCapReached { .. } => 1026,
+            DepositError::NotAllowlisted => 1027,
+            DepositError::PriceUnavailable(_) => 1028,
+            DepositError::ConcurrentOperation => 1029,
+            DepositError::InvalidReferrer => 1030,
+            DepositError::InvalidDestination => 1031,
+            DepositError::WrongShard { .. } => 1032,
+            DepositError::ShardUnavailable { .. } => 1033,
+        }
+    }
+}
+
+/// A ledger call that failed, with the ledger's own error where it replied.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum LedgerError {
+    TransferFrom(TransferFromError),
+    Transfer(TransferError),
+    /// The ledger could not be called or its reply not decoded.
+    Unreachable(String),
+}
+
+/// Version 2 of the pool's errors, returned by the `_v2` endpoints. It keeps
+/// the failures `DepositError` folds into strings typed; the version 1
+/// endpoints return the same errors converted to `DepositError`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PoolError {
+    /// The caller approved the pool for less than the transfer needs.
+    InsufficientAllowance {
+        required: u64,
+        allowance: u64,
+    },
+    /// The caller's account holds less than the transfer needs, fee included.
+    InsufficientFunds {
+        required: u64,
+        available: u64,
+    },
+    Paused,
+    Unauthorized,
+    LedgerError(LedgerError),
+    /// Any other failure, as version 1 reports it.
+    Deposit(DepositError),
+}
+
+impl PoolError {
+    /// Stable numeric code of the error. Failures version 1 also reports
+    /// keep its code.
+    pub fn code(&self) -> u32 {
+        match self {
+            PoolError::InsufficientAllowance { .. } => 2001,
+            PoolError::InsufficientFunds { .. } => 2002,
+            PoolError::LedgerError(LedgerError::TransferFrom(_)) => 2003,
+            PoolError::LedgerError(LedgerError::Transfer(_)) => 2004,
+            PoolError::LedgerError(LedgerError::Unreachable(_)) => 2005,
+            PoolError::Paused => DepositError::Paused.code(),
+            PoolError::Unauthorized => DepositError::Unauthorized.code(),
+            PoolError::Deposit(e) => e.code(),
+        }
+    }
+}
+
+impl From<DepositError> for PoolError {
+    fn from(e: DepositError) -> Self {
+        match e {
+            DepositError::Paused => PoolError::Paused,
+            DepositError::Unauthorized => PoolError::Unauthorized,
+            e => PoolError::Deposit(e),
+        }
+    }
+}
+
+// Ledger failures read the same as before the typed errors existed.
+impl From<PoolError> for DepositError {
+    fn from(e: PoolError) -> Self {
+        let ledger_failure =
+            |e: &dyn std::fmt::Debug| DepositError::LedgerTransferFailed(format!("{:?}", e));
+        match e {
+            PoolError::InsufficientAllowance { allowance, .. } => {
+                ledger_failure(&TransferFromError::InsufficientAllowance {
+                    allowance: Nat::from(allowance),
+                })
+            }
+            PoolError::InsufficientFunds { available, .. } => {
+                ledger_failure(&TransferFromError::InsufficientFunds {
+                    balance: Nat::from(available),
+                })
+            }
+            PoolError::LedgerError(LedgerError::TransferFrom(e)) => ledger_failure(&e),
+            PoolError::LedgerError(LedgerError::Transfer(e)) => ledger_failure(&e),
+            PoolError::LedgerError(LedgerError::Unreachable(message)) => {
+                DepositError::LedgerTransferFailed(message)
+            }
+            PoolError::Paused => DepositError::Paused,
+            PoolError::Unauthorized => DepositError::Unauthorized,
+            PoolError::Deposit(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_ledger_errors_downgrade_to_version_1() {
+        let e = PoolError::InsufficientFunds {
+            required: 110,
+            available: 50,
+        };
+        assert_eq!(e.code(), 2002);
+        assert_eq!(
+            DepositError::from(e),
+            DepositError::LedgerTransferFailed(
+                "InsufficientFunds { balance: Nat(50) }".to_string()
+            )
+        );
+        let paused = PoolError::from(DepositError::Paused);
+        assert_eq!(paused, PoolError::Paused);
+        assert_eq!(paused.code(), DepositError::Paused.code());
+        assert_eq!(
+            DepositError::from(PoolError::from(DepositError::NoDepositFound)),
+            DepositError::NoDepositFound
+        );
+    }
 }
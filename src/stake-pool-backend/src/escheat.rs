@@ -0,0 +1,242 @@
+// src/escheat.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, UNCLAIMED_MEMORY_ID};
+use crate::state_hash;
+use crate::{config, status, Deposit, UserKey, DEPOSITS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(86400);
+/// Shortest escheat period the config accepts.
+pub const MIN_ESCHEAT_DAYS: u32 = 365;
+
+/// A matured deposit moved out of the active pool after going unclaimed for
+/// the configured period. It earns no rewards but stays reclaimable.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct UnclaimedDeposit {
+    pub key: UserKey,
+    pub deposit: Deposit,
+    pub escheated_at: u64,
+}
+
+impl Storable for UnclaimedDeposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode UnclaimedDeposit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode UnclaimedDeposit")
+    }
+}
+
+impl BoundedStorable for UnclaimedDeposit {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by deposit id.
+    static UNCLAIMED: RefCell<StableBTreeMap<u64, UnclaimedDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(UNCLAIMED_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "unclaimed",
+        UNCLAIMED.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Moves every deposit that matured more than `after_days` ago to the
+/// unclaimed-funds bucket and returns the ids moved.
+pub fn sweep(now: u64, after_days: u32) -> Vec<u64> {
+    let cutoff = after_days as u64 * 86400;
+    let stale: Vec<(UserKey, u64)> = DEPOSITS.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, d)| now >= d.unlock_time().saturating_add(cutoff))
+            .map(|(key, d)| (key.user, d.id))
+            .collect()
+    });
+
+    let mut moved = Vec::with_capacity(stale.len());
+    for (key, deposit_id) in stale {
+        let Ok(deposit) = crate::remove_deposit(&key, deposit_id, now) else {
+            continue;
+        };
+        events::record(
+            now,
+            EventKind::Escheated {
+                key: key.clone(),
+                deposit_id,
+                amount: deposit.amount,
+            },
+        );
+        UNCLAIMED.with(|m| {
+            m.borrow_mut().insert(
+                deposit_id,
+                UnclaimedDeposit {
+                    key,
+                    deposit,
+                    escheated_at: now,
+                },
+            )
+        });
+        moved.push(deposit_id);
+    }
+    moved
+}
+
+pub fn total_unclaimed() -> u64 {
+    UNCLAIMED.with(|m| m.borrow().iter().map(|(_, u)| u.deposit.amount).sum())
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    UNCLAIMED.with(|m| {
+        let mut m = m.borrow_mut();
+        let moved: Vec<UnclaimedDeposit> = m
+            .iter()
+            .map(|(_, u)| u)
+            .filter(|u| u.key.principal == from)
+            .collect();
+        for mut entry in moved {
+            entry.key.principal = to;
+            m.insert(entry.deposit.id, entry);
+        }
+    });
+}
+
+pub fn unclaimed_of(key: &UserKey) -> Vec<UnclaimedDeposit> {
+    UNCLAIMED.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, u)| u)
+            .filter(|u| u.key == *key)
+            .collect()
+    })
+}
+
+fn take_unclaimed(key: &UserKey) -> Vec<UnclaimedDeposit> {
+    let entries = unclaimed_of(key);
+    UNCLAIMED.with(|m| {
+        let mut m = m.borrow_mut();
+        for entry in &entries {
+            m.remove(&entry.deposit.id);
+        }
+    });
+    entries
+}
+
+fn restore_unclaimed(entries: Vec<UnclaimedDeposit>) {
+    UNCLAIMED.with(|m| {
+        let mut m = m.borrow_mut();
+        for entry in entries {
+            m.insert(entry.deposit.id, entry);
+        }
+    });
+}
+
+/// Starts the daily escheat sweep. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_sweeps() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || {
+        if let Some(after_days) = config::get().escheat_after_days {
+            sweep(crate::now_secs(), after_days);
+        }
+    });
+}
+
+/// Returns the caller's deposits that were moved to unclaimed funds.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_unclaimed(subaccount: Subaccount) -> Vec<UnclaimedDeposit> {
+    unclaimed_of(&UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+/// Pays out all of the caller's unclaimed funds for `subaccount`.
+///
+/// # Returns
+///
+/// * The amount received, net of the ledger fee.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the subaccount has no unclaimed funds.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * `DepositError::BelowLedgerFee`: If the funds do not cover the ledger fee. They stay unclaimed.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed. The funds stay unclaimed.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn reclaim_unclaimed(subaccount: Subaccount) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    let entries = take_unclaimed(&key);
+    if entries.is_empty() {
+        return Err(DepositError::NoDepositFound);
+    }
+    let amount: u64 = entries.iter().map(|e| e.deposit.amount).sum();
+    let transfer = crate::transfer_net(
+        crate::ledger::ledger_id(),
+        key.principal,
+        subaccount,
+        amount,
+    )
+    .await;
+    let received = match transfer {
+        Ok((_, received)) => received,
+        Err(e) => {
+            restore_unclaimed(entries);
+            return Err(e);
+        }
+    };
+    events::record(crate::now_secs(), EventKind::Reclaimed { key, amount });
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_sweep_moves_only_long_matured_deposits() {
+        let principal = Principal::anonymous();
+        let sub = Subaccount([9u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+        let day = 86400;
+        crate::deposit_internal(principal, sub, 90, 100, 0).unwrap();
+        crate::deposit_internal(principal, sub, 360, 200, 0).unwrap();
+
+        assert!(sweep(90 * day + 1_459 * day, 1_460).is_empty());
+        assert_eq!(sweep(90 * day + 1_460 * day, 1_460), vec![1]);
+        assert_eq!(unclaimed_of(&key)[0].deposit.amount, 100);
+        assert_eq!(
+            crate::STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key)),
+            Some(200)
+        );
+
+        let taken = take_unclaimed(&key);
+        assert!(unclaimed_of(&key).is_empty());
+        restore_unclaimed(taken);
+        assert_eq!(unclaimed_of(&key).len(), 1);
+    }
+}
@@ -0,0 +1,244 @@
+// src/events.rs
+use crate::circuit_breaker::HaltReason;
+use crate::governance::Vote;
+use crate::memory::{get_memory, Memory, EVENT_LOG_DATA_MEMORY_ID, EVENT_LOG_INDEX_MEMORY_ID};
+use crate::notifications::Notification;
+use crate::state_hash;
+use crate::status::PoolStatus;
+use crate::UserKey;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::{storable::Storable, StableLog};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const MAX_EVENTS_PER_PAGE: u64 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum EventKind {
+    PoolStatusChanged {
+        from: PoolStatus,
+        to: PoolStatus,
+    },
+    CircuitBreakerTripped {
+        reason: HaltReason,
+    },
+    MemoryAlert {
+        heap_bytes: u64,
+        stable_bytes: u64,
+    },
+    Deposited {
+        key: UserKey,
+        deposit_id: u64,
+        amount: u64,
+    },
+    Withdrawn {
+        key: UserKey,
+        deposit_id: u64,
+        amount: u64,
+    },
+    EarlyWithdrawn {
+        key: UserKey,
+        deposit_id: u64,
+        amount: u64,
+        penalty: u64,
+    },
+    /// A long-matured deposit moved to unclaimed funds.
+    Escheated {
+        key: UserKey,
+        deposit_id: u64,
+        amount: u64,
+    },
+    Reclaimed {
+        key: UserKey,
+        amount: u64,
+    },
+    /// The ledger refused the transfer of a withdrawal and the deposit went
+    /// back to its owner.
+    WithdrawalReverted {
+        key: UserKey,
+        deposit_id: u64,
+        amount: u64,
+    },
+    /// A deposit's lock was extended or restarted.
+    LockChanged {
+        key: UserKey,
+        deposit_id: u64,
+        lock_days: u16,
+        unlock_time: u64,
+    },
+    /// A reward paid out to, or credited for compounding to, an account.
+    Rewarded {
+        key: UserKey,
+        amount: u64,
+    },
+    /// Touches the stake balance of every account.
+    PoolSlashed {
+        amount: u64,
+    },
+    Notified {
+        principal: Principal,
+        notification: Notification,
+    },
+    MigratedTo {
+        successor: Principal,
+        amount: Nat,
+    },
+    /// Every position of `from` was moved to `to`.
+    AccountMigrated {
+        from: Principal,
+        to: Principal,
+    },
+    TreasuryDisbursed {
+        disbursement_id: u64,
+        amount: u64,
+    },
+    /// Deposits of a migrated pool were recreated in this one.
+    PositionsImported {
+        source: Principal,
+        accounts: u64,
+        amount: u64,
+    },
+    NeuronVoted {
+        neuron_id: u64,
+        proposal_id: u64,
+        vote: Vote,
+    },
+    MaturityHarvested {
+        neuron_id: u64,
+        amount: u64,
+    },
+    NeuronSplit {
+        parent_id: u64,
+        neuron_id: u64,
+        amount: u64,
+    },
+    /// A deposit changed hands without being unlocked.
+    DepositTransferred {
+        from: UserKey,
+        to: UserKey,
+        deposit_id: u64,
+        amount: u64,
+    },
+    /// A withdrawal paid, or queued to be paid, to an account other than the
+    /// deposit's own.
+    WithdrawnTo {
+        key: UserKey,
+        deposit_id: u64,
+        destination: Account,
+    },
+    /// The cycles balance fell below `low_cycles_threshold`.
+    LowCycles {
+        balance: u128,
+        threshold: u128,
+    },
+    /// Cycles sent to `wallet_receive`.
+    CyclesReceived {
+        from: Principal,
+        amount: u128,
+    },
+    /// Treasury funds converted to cycles by the cycles minting canister.
+    CyclesToppedUp {
+        amount: u64,
+        block_index: u64,
+        cycles: u128,
+    },
+    /// A custody account other than the primary main account was emptied
+    /// into the same subaccount of the successor by `migrate_to`.
+    CustodySwept {
+        successor: Principal,
+        ledger: Principal,
+        subaccount: Option<[u8; 32]>,
+        amount: Nat,
+    },
+}
+
+impl EventKind {
+    /// The accounts whose deposits or stake balance this event changed, when
+    /// it names them.
+    pub fn accounts(&self) -> Vec<&UserKey> {
+        match self {
+            EventKind::Deposited { key, .. }
+            | EventKind::Withdrawn { key, .. }
+            | EventKind::EarlyWithdrawn { key, .. }
+            | EventKind::Escheated { key, .. }
+            | EventKind::LockChanged { key, .. }
+            | EventKind::WithdrawalReverted { key, .. } => vec![key],
+            EventKind::DepositTransferred { from, to, .. } => vec![from, to],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// An entry in the append-only pool event log. `seq` is the position of the
+/// event in the log and increases by one with every recorded event.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PoolEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub kind: EventKind,
+}
+
+impl Storable for PoolEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PoolEvent"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PoolEvent")
+    }
+}
+
+thread_local! {
+    static EVENT_LOG: RefCell<StableLog<PoolEvent, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            get_memory(EVENT_LOG_INDEX_MEMORY_ID),
+            get_memory(EVENT_LOG_DATA_MEMORY_ID),
+        )
+        .expect("Failed to init event log"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "event_log",
+        EVENT_LOG.with(|s| state_hash::log_digest(&s.borrow())),
+    )]
+}
+
+/// Appends an event to the log and returns its sequence number.
+pub fn record(timestamp: u64, kind: EventKind) -> u64 {
+    crate::stats::invalidate();
+    EVENT_LOG.with(|log| {
+        let log = log.borrow();
+        let event = PoolEvent {
+            seq: log.len(),
+            timestamp,
+            kind,
+        };
+        log.append(&event).expect("Failed to append event")
+    })
+}
+
+pub fn len() -> u64 {
+    EVENT_LOG.with(|log| log.borrow().len())
+}
+
+pub fn get(seq: u64) -> Option<PoolEvent> {
+    EVENT_LOG.with(|log| log.borrow().get(seq))
+}
+
+pub fn range(start: u64, limit: u64) -> Vec<PoolEvent> {
+    EVENT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = start.saturating_add(limit).min(log.len());
+        (start..end).filter_map(|i| log.get(i)).collect()
+    })
+}
+
+/// Returns up to `limit` events (capped at 100) starting at sequence number `start`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_events(start: u64, limit: u64) -> Vec<PoolEvent> {
+    range(start, limit.min(MAX_EVENTS_PER_PAGE))
+}
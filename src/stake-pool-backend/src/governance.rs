@@ -0,0 +1,437 @@
+// src/governance.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, NEURON_FOLLOWING_MEMORY_ID};
+use crate::state_hash;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NNS_GOVERNANCE_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+/// NNS limit on the number of followees per topic.
+pub const MAX_FOLLOWEES: usize = 15;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Vote {
+    Yes,
+    No,
+}
+
+impl Vote {
+    fn as_nns(self) -> i32 {
+        match self {
+            Vote::Yes => 1,
+            Vote::No => 2,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct NeuronFollowing {
+    pub neuron_id: u64,
+    pub topic: i32,
+    pub followees: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Followees(Vec<u64>);
+
+impl Storable for Followees {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Followees"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Followees")
+    }
+}
+
+impl BoundedStorable for Followees {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Subset of the NNS governance `manage_neuron` interface used by the pool.
+#[derive(CandidType, Deserialize, Debug)]
+pub(crate) struct NeuronId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct ProposalId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct GovernanceAccount {
+    pub owner: Option<Principal>,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+pub(crate) enum Command {
+    Follow {
+        topic: i32,
+        followees: Vec<NeuronId>,
+    },
+    RegisterVote {
+        vote: i32,
+        proposal: Option<ProposalId>,
+    },
+    DisburseMaturity {
+        to_account: Option<GovernanceAccount>,
+        percentage_to_disburse: u32,
+    },
+    ClaimOrRefresh {
+        by: Option<ClaimBy>,
+    },
+    Split {
+        amount_e8s: u64,
+    },
+    /// Moves the maturity of a neuron into a new neuron that turns it into
+    /// ICP after a week.
+    Spawn {
+        new_controller: Option<Principal>,
+        nonce: Option<u64>,
+        percentage_to_spawn: Option<u32>,
+    },
+    Configure {
+        operation: Option<Operation>,
+    },
+    /// Disburses the full stake to the neuron controller, the pool canister.
+    Disburse {},
+}
+
+#[derive(CandidType)]
+pub(crate) enum Operation {
+    StartDissolving {},
+    IncreaseDissolveDelay {
+        additional_dissolve_delay_seconds: u32,
+    },
+}
+
+#[derive(CandidType)]
+pub(crate) struct ClaimOrRefreshNeuronFromAccount {
+    pub memo: u64,
+    pub controller: Option<Principal>,
+}
+
+#[derive(CandidType)]
+pub(crate) enum ClaimBy {
+    NeuronIdOrSubaccount {},
+    /// Claims a new neuron from a transfer to the staking subaccount of
+    /// `controller` and `memo`.
+    MemoAndController(ClaimOrRefreshNeuronFromAccount),
+}
+
+#[derive(CandidType)]
+struct ManageNeuron {
+    id: Option<NeuronId>,
+    command: Option<Command>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub(crate) struct GovernanceError {
+    pub error_message: String,
+    pub error_type: i32,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub(crate) enum CommandResponse {
+    Error(GovernanceError),
+    Follow {},
+    RegisterVote {},
+    DisburseMaturity {
+        amount_disbursed_e8s: Option<u64>,
+    },
+    ClaimOrRefresh {
+        refreshed_neuron_id: Option<NeuronId>,
+    },
+    Split {
+        created_neuron_id: Option<NeuronId>,
+    },
+    Spawn {
+        created_neuron_id: Option<NeuronId>,
+    },
+    Configure {},
+    Disburse {
+        transfer_block_height: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub(crate) enum DissolveState {
+    DissolveDelaySeconds(u64),
+    WhenDissolvedTimestampSeconds(u64),
+}
+
+/// The fields of an NNS neuron the pool reads.
+#[derive(CandidType, Deserialize, Debug)]
+pub(crate) struct FullNeuron {
+    pub maturity_e8s_equivalent: u64,
+    pub cached_neuron_stake_e8s: u64,
+    pub account: Vec<u8>,
+    pub dissolve_state: Option<DissolveState>,
+}
+
+impl FullNeuron {
+    /// Seconds until the neuron is dissolved, whether or not it is dissolving.
+    pub fn dissolve_delay_secs(&self, now: u64) -> u64 {
+        match self.dissolve_state {
+            Some(DissolveState::DissolveDelaySeconds(d)) => d,
+            Some(DissolveState::WhenDissolvedTimestampSeconds(t)) => t.saturating_sub(now),
+            None => 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct ManageNeuronResponse {
+    command: Option<CommandResponse>,
+}
+
+thread_local! {
+    // Keyed by (neuron id, topic).
+    static NEURON_FOLLOWING: RefCell<StableBTreeMap<(u64, u64), Followees, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NEURON_FOLLOWING_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "neuron_following",
+        NEURON_FOLLOWING.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn set_following(neuron_id: u64, topic: i32, followees: Vec<u64>) {
+    NEURON_FOLLOWING.with(|m| {
+        let mut m = m.borrow_mut();
+        let key = (neuron_id, topic as u64);
+        if followees.is_empty() {
+            m.remove(&key);
+        } else {
+            m.insert(key, Followees(followees));
+        }
+    });
+}
+
+pub fn following() -> Vec<NeuronFollowing> {
+    NEURON_FOLLOWING.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|((neuron_id, topic), followees)| NeuronFollowing {
+                neuron_id,
+                topic: topic as i32,
+                followees: followees.0,
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn governance_id() -> Principal {
+    Principal::from_text(NNS_GOVERNANCE_ID).unwrap()
+}
+
+fn governance_error(e: GovernanceError) -> DepositError {
+    DepositError::GovernanceCallFailed(format!("{} ({})", e.error_message, e.error_type))
+}
+
+/// Subaccount of the governance canister a neuron of `controller` is staked
+/// from, as derived by NNS governance.
+pub(crate) fn neuron_subaccount(controller: Principal, nonce: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x0c]);
+    hasher.update(b"neuron-stake");
+    hasher.update(controller.as_slice());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Issues `command` for `neuron_id` and returns the governance response.
+pub(crate) async fn manage_neuron(
+    neuron_id: u64,
+    command: Command,
+) -> Result<CommandResponse, DepositError> {
+    manage(Some(NeuronId { id: neuron_id }), command).await
+}
+
+/// Claims the neuron staked from the subaccount of the pool canister and
+/// `nonce`, and returns its id.
+pub(crate) async fn claim_neuron(nonce: u64) -> Result<u64, DepositError> {
+    let command = Command::ClaimOrRefresh {
+        by: Some(ClaimBy::MemoAndController(
+            ClaimOrRefreshNeuronFromAccount {
+                memo: nonce,
+                controller: Some(ic_cdk::id()),
+            },
+        )),
+    };
+    match manage(None, command).await? {
+        CommandResponse::ClaimOrRefresh {
+            refreshed_neuron_id: Some(neuron),
+        } => Ok(neuron.id),
+        other => Err(DepositError::GovernanceCallFailed(format!(
+            "unexpected claim response: {:?}",
+            other
+        ))),
+    }
+}
+
+async fn manage(id: Option<NeuronId>, command: Command) -> Result<CommandResponse, DepositError> {
+    let request = ManageNeuron {
+        id,
+        command: Some(command),
+    };
+    let (response,): (ManageNeuronResponse,) = call(governance_id(), "manage_neuron", (request,))
+        .await
+        .map_err(|e| DepositError::GovernanceCallFailed(format!("{:?}", e)))?;
+    match response.command {
+        Some(CommandResponse::Error(e)) => Err(governance_error(e)),
+        Some(response) => Ok(response),
+        None => Err(DepositError::GovernanceCallFailed(
+            "empty manage_neuron response".to_string(),
+        )),
+    }
+}
+
+pub(crate) async fn get_full_neuron(neuron_id: u64) -> Result<FullNeuron, DepositError> {
+    let (response,): (Result<FullNeuron, GovernanceError>,) =
+        call(governance_id(), "get_full_neuron", (neuron_id,))
+            .await
+            .map_err(|e| DepositError::GovernanceCallFailed(format!("{:?}", e)))?;
+    response.map_err(governance_error)
+}
+
+/// Sets the followees of one of the pool's neurons on `topic`. An empty list
+/// removes following on that topic. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If more than 15 followees are given.
+/// * `DepositError::GovernanceCallFailed`: If NNS governance rejected the change.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn set_neuron_followees(
+    neuron_id: u64,
+    topic: i32,
+    followees: Vec<u64>,
+) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if topic < 0 || followees.len() > MAX_FOLLOWEES {
+        return Err(DepositError::InvalidArgument(format!(
+            "invalid topic or more than {} followees",
+            MAX_FOLLOWEES
+        )));
+    }
+    let command = Command::Follow {
+        topic,
+        followees: followees.iter().map(|&id| NeuronId { id }).collect(),
+    };
+    manage_neuron(neuron_id, command).await?;
+    set_following(neuron_id, topic, followees);
+    Ok(())
+}
+
+/// Casts a vote on a proposal with one of the pool's neurons. Only canister
+/// controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::GovernanceCallFailed`: If NNS governance rejected the vote.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn cast_neuron_vote(
+    neuron_id: u64,
+    proposal_id: u64,
+    vote: Vote,
+) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let command = Command::RegisterVote {
+        vote: vote.as_nns(),
+        proposal: Some(ProposalId { id: proposal_id }),
+    };
+    manage_neuron(neuron_id, command).await?;
+    events::record(
+        crate::now_secs(),
+        EventKind::NeuronVoted {
+            neuron_id,
+            proposal_id,
+            vote,
+        },
+    );
+    Ok(())
+}
+
+/// Lengthens the dissolve delay of one of the pool's neurons. Only canister
+/// controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::GovernanceCallFailed`: If NNS governance rejected the change.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn increase_neuron_dissolve_delay(
+    neuron_id: u64,
+    additional_secs: u32,
+) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    increase_dissolve_delay(neuron_id, additional_secs).await
+}
+
+pub(crate) async fn increase_dissolve_delay(
+    neuron_id: u64,
+    additional_secs: u32,
+) -> Result<(), DepositError> {
+    let command = Command::Configure {
+        operation: Some(Operation::IncreaseDissolveDelay {
+            additional_dissolve_delay_seconds: additional_secs,
+        }),
+    };
+    manage_neuron(neuron_id, command).await?;
+    Ok(())
+}
+
+/// Returns the followees configured for the pool's neurons, per topic.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_neuron_following() -> Vec<NeuronFollowing> {
+    following()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_following_is_stored_per_neuron_and_topic() {
+        set_following(7, 4, vec![27, 28]);
+        set_following(7, 0, vec![27]);
+        set_following(9, 4, vec![1]);
+        set_following(9, 4, vec![]);
+
+        assert_eq!(
+            following(),
+            vec![
+                NeuronFollowing {
+                    neuron_id: 7,
+                    topic: 0,
+                    followees: vec![27],
+                },
+                NeuronFollowing {
+                    neuron_id: 7,
+                    topic: 4,
+                    followees: vec![27, 28],
+                },
+            ]
+        );
+    }
+}
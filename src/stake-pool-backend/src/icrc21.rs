@@ -0,0 +1,372 @@
+// src/icrc21.rs
+//! ICRC-21 consent messages, so wallets can show what a deposit or
+//! withdrawal does before the user signs it. Messages describe the call as
+//! the caller would make it right now: amounts, the lock period and the
+//! early-exit penalty come from the caller's deposits and the current config.
+use crate::ledger;
+use crate::penalty;
+use crate::receipts::SupportedStandard;
+use crate::tokens;
+use crate::{config, find_deposit, Deposit, UserKey};
+use candid::Principal;
+use ic_ledger_types::Subaccount;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc21::errors::{ErrorInfo, Icrc21Error};
+use icrc_ledger_types::icrc21::requests::{
+    ConsentMessageMetadata, ConsentMessageRequest, DisplayMessageType,
+};
+use icrc_ledger_types::icrc21::responses::{ConsentInfo, ConsentMessage, FieldsDisplay, Value};
+
+const SECS_PER_DAY: u64 = 86_400;
+const DEFAULT_SUBACCOUNT: Subaccount = Subaccount([0; 32]);
+
+fn unsupported(description: String) -> Icrc21Error {
+    Icrc21Error::UnsupportedCanisterCall(ErrorInfo { description })
+}
+
+fn unavailable(description: String) -> Icrc21Error {
+    Icrc21Error::ConsentMessageUnavailable(ErrorInfo { description })
+}
+
+// Amount in `token`, with the ledger's symbol and decimals once known.
+fn token_amount(amount: u64, token: Option<Principal>) -> Value {
+    match (
+        token.filter(|t| !tokens::is_primary(*t)),
+        ledger::token_metadata(),
+    ) {
+        (None, Some(metadata)) => Value::TokenAmount {
+            decimals: metadata.decimals,
+            amount,
+            symbol: metadata.symbol,
+        },
+        (None, None) => text(format!("{} base units", amount)),
+        (Some(token), _) => text(format!("{} base units of token {}", amount, token)),
+    }
+}
+
+fn text(content: impl Into<String>) -> Value {
+    Value::Text {
+        content: content.into(),
+    }
+}
+
+// Names the caller's account `subaccount` unless it is the default one.
+fn push_subaccount(fields: &mut Vec<(String, Value)>, label: &str, subaccount: Subaccount) {
+    if subaccount != DEFAULT_SUBACCOUNT {
+        fields.push((
+            label.to_string(),
+            text(crate::statements::hex(&subaccount.0)),
+        ));
+    }
+}
+
+fn deposit_of(
+    caller: Principal,
+    subaccount: Subaccount,
+    deposit_id: u64,
+    token: Option<Principal>,
+) -> Result<Deposit, Icrc21Error> {
+    let found = match token.filter(|t| !tokens::is_primary(*t)) {
+        None => find_deposit(
+            &UserKey {
+                principal: caller,
+                subaccount,
+            },
+            deposit_id,
+        )
+        .ok(),
+        Some(token) => tokens::deposits_of(&tokens::TokenKey {
+            token,
+            principal: caller,
+            subaccount,
+        })
+        .into_iter()
+        .find(|d| d.id == deposit_id),
+    };
+    found.ok_or_else(|| unavailable(format!("no deposit {} in this account", deposit_id)))
+}
+
+fn deposit_consent(
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+    token: Option<Principal>,
+    now: u64,
+) -> FieldsDisplay {
+    let lock = lock_days as u64 * SECS_PER_DAY;
+    let curve = config::get().early_exit_penalty;
+    let mut fields = vec![
+        ("Amount".to_string(), token_amount(amount, token)),
+        (
+            "Lock period".to_string(),
+            Value::DurationSeconds { amount: lock },
+        ),
+        (
+            "Unlocks at".to_string(),
+            Value::TimestampSeconds { amount: now + lock },
+        ),
+        (
+            "Early-exit penalty".to_string(),
+            text(format!(
+                "{}% of the deposit, decreasing to {}% at unlock",
+                percent(curve.start_bps as u64),
+                percent(curve.end_bps as u64)
+            )),
+        ),
+    ];
+    push_subaccount(&mut fields, "From subaccount", subaccount);
+    FieldsDisplay {
+        intent: "Stake tokens".to_string(),
+        fields,
+    }
+}
+
+fn withdraw_consent(
+    caller: Principal,
+    subaccount: Subaccount,
+    deposit_id: u64,
+    token: Option<Principal>,
+    destination: Option<Account>,
+) -> Result<FieldsDisplay, Icrc21Error> {
+    let deposit = deposit_of(caller, subaccount, deposit_id, token)?;
+    let mut fields = vec![
+        ("Deposit".to_string(), text(format!("#{}", deposit_id))),
+        ("Amount".to_string(), token_amount(deposit.amount, token)),
+    ];
+    push_subaccount(&mut fields, "From subaccount", subaccount);
+    let destination = destination.map_or_else(|| "Your account".to_string(), |d| d.to_string());
+    fields.push(("Send to".to_string(), text(destination)));
+    Ok(FieldsDisplay {
+        intent: "Withdraw stake".to_string(),
+        fields,
+    })
+}
+
+fn early_withdraw_consent(
+    caller: Principal,
+    subaccount: Subaccount,
+    deposit_id: u64,
+    now: u64,
+) -> Result<FieldsDisplay, Icrc21Error> {
+    let deposit = deposit_of(caller, subaccount, deposit_id, None)?;
+    let preview = penalty::preview(&deposit, now, &config::get().early_exit_penalty);
+    let mut fields = vec![
+        ("Deposit".to_string(), text(format!("#{}", deposit_id))),
+        ("Amount".to_string(), token_amount(preview.amount, None)),
+        (
+            "Unlocks at".to_string(),
+            Value::TimestampSeconds {
+                amount: preview.unlock_timestamp,
+            },
+        ),
+        (
+            format!("Penalty ({}%)", percent(preview.penalty_bps)),
+            token_amount(preview.penalty, None),
+        ),
+        (
+            "You receive".to_string(),
+            token_amount(preview.payout, None),
+        ),
+    ];
+    push_subaccount(&mut fields, "From subaccount", subaccount);
+    Ok(FieldsDisplay {
+        intent: "Withdraw stake early".to_string(),
+        fields,
+    })
+}
+
+fn percent(bps: u64) -> String {
+    ledger::format_decimal(bps, 2)
+}
+
+fn decode<'a, T: candid::utils::ArgumentDecoder<'a>>(
+    method: &str,
+    arg: &'a [u8],
+) -> Result<T, Icrc21Error> {
+    candid::decode_args(arg).map_err(|e| unsupported(format!("bad arguments to {}: {}", method, e)))
+}
+
+/// What calling `method` with the Candid-encoded `arg` does for `caller`.
+pub fn consent_of(
+    caller: Principal,
+    method: &str,
+    arg: &[u8],
+    now: u64,
+) -> Result<FieldsDisplay, Icrc21Error> {
+    match method {
+        "deposit_funds" | "deposit_funds_v2" => {
+            let (subaccount, lock_days, amount, _, token): (
+                Subaccount,
+                u16,
+                u64,
+                Option<Vec<u8>>,
+                Option<Principal>,
+            ) = decode(method, arg)?;
+            Ok(deposit_consent(subaccount, lock_days, amount, token, now))
+        }
+        "withdraw_funds" => {
+            let (subaccount, deposit_id, token, destination) = decode(method, arg)?;
+            withdraw_consent(caller, subaccount, deposit_id, token, destination)
+        }
+        "early_withdraw" => {
+            let (subaccount, deposit_id) = decode(method, arg)?;
+            early_withdraw_consent(caller, subaccount, deposit_id, now)
+        }
+        _ => Err(unsupported(format!("no consent message for {}", method))),
+    }
+}
+
+// "2024-03-01 12:00 UTC", shifted by `offset_minutes`.
+fn format_timestamp(secs: u64, offset_minutes: i16) -> String {
+    let local = secs as i64 + offset_minutes as i64 * 60;
+    let (days, secs_of_day) = (local.div_euclid(86_400), local.rem_euclid(86_400));
+    // Inverse of `statements::days_from_civil`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let zone = match offset_minutes {
+        0 => "UTC".to_string(),
+        m => format!(
+            "UTC{}{:02}:{:02}",
+            if m < 0 { '-' } else { '+' },
+            m.unsigned_abs() / 60,
+            m.unsigned_abs() % 60
+        ),
+    };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} {}",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        zone
+    )
+}
+
+fn format_value(value: &Value, offset_minutes: i16) -> String {
+    match value {
+        Value::TokenAmount {
+            decimals,
+            amount,
+            symbol,
+        } => format!("{} {}", ledger::format_decimal(*amount, *decimals), symbol),
+        Value::TimestampSeconds { amount } => format_timestamp(*amount, offset_minutes),
+        Value::DurationSeconds { amount } => match amount / SECS_PER_DAY {
+            1 => "1 day".to_string(),
+            days => format!("{} days", days),
+        },
+        Value::Text { content } => content.clone(),
+    }
+}
+
+/// Renders `display` as Markdown for wallets that cannot show fields.
+pub fn to_markdown(display: &FieldsDisplay, offset_minutes: i16) -> String {
+    display.fields.iter().fold(
+        format!("# {}", display.intent),
+        |mut message, (name, value)| {
+            message.push_str(&format!(
+                "\n\n**{}:** {}",
+                name,
+                format_value(value, offset_minutes)
+            ));
+            message
+        },
+    )
+}
+
+/// Returns a human-readable description of a call to `deposit_funds`,
+/// `deposit_funds_v2`, `withdraw_funds` or `early_withdraw` by the caller,
+/// for the wallet to show before signing it. Messages are in English.
+///
+/// # Errors
+///
+/// * `Icrc21Error::UnsupportedCanisterCall`: If the method has no consent
+///   message or its arguments do not decode.
+/// * `Icrc21Error::ConsentMessageUnavailable`: If the deposit to withdraw is
+///   not in the caller's account.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc21_canister_call_consent_message(
+    request: ConsentMessageRequest,
+) -> Result<ConsentInfo, Icrc21Error> {
+    let display = consent_of(
+        ic_cdk::caller(),
+        &request.method,
+        &request.arg,
+        crate::now_secs(),
+    )?;
+    let utc_offset_minutes = request.user_preferences.metadata.utc_offset_minutes;
+    let consent_message = match request.user_preferences.device_spec {
+        Some(DisplayMessageType::FieldsDisplay) => ConsentMessage::FieldsDisplayMessage(display),
+        _ => ConsentMessage::GenericDisplayMessage(to_markdown(
+            &display,
+            utc_offset_minutes.unwrap_or(0),
+        )),
+    };
+    Ok(ConsentInfo {
+        consent_message,
+        metadata: ConsentMessageMetadata {
+            language: "en".to_string(),
+            utc_offset_minutes,
+        },
+    })
+}
+
+/// Lists the ICRC standards the pool canister implements.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    [
+        ("ICRC-10", "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md"),
+        ("ICRC-21", "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/ICRC-21/icrc_21_consent_msg.md"),
+        ("ICRC-28", "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/icrc_28_trusted_origins.md"),
+    ]
+    .into_iter()
+    .map(|(name, url)| SupportedStandard {
+        name: name.to_string(),
+        url: url.to_string(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_withdraw_consent_shows_the_penalty() {
+        let key = UserKey {
+            principal: Principal::from_slice(&[200]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let deposit = crate::deposit_internal(key.principal, key.subaccount, 90, 1_000, 0).unwrap();
+        let arg = candid::encode_args((key.subaccount, deposit.id)).unwrap();
+        let display = consent_of(key.principal, "early_withdraw", &arg, 0).unwrap();
+        assert_eq!(
+            to_markdown(&display, 60),
+            "# Withdraw stake early\n\n**Deposit:** #".to_string()
+                + &deposit.id.to_string()
+                + "\n\n**Amount:** 1000 base units\
+                   \n\n**Unlocks at:** 1970-04-01 01:00 UTC+01:00\
+                   \n\n**Penalty (30%):** 300 base units\
+                   \n\n**You receive:** 700 base units"
+        );
+
+        let other = Principal::from_slice(&[201]);
+        assert!(matches!(
+            consent_of(other, "early_withdraw", &arg, 0),
+            Err(Icrc21Error::ConsentMessageUnavailable(_))
+        ));
+        assert!(matches!(
+            consent_of(key.principal, "transfer_deposit", &arg, 0),
+            Err(Icrc21Error::UnsupportedCanisterCall(_))
+        ));
+    }
+}
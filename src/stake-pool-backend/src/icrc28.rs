@@ -0,0 +1,94 @@
+// src/icrc28.rs
+//! ICRC-28 trusted origins. Signers only let a relying party call the pool
+//! on a user's behalf when its origin is listed here, so controllers keep
+//! the list to the pool's frontend and trusted integrations through the
+//! config's `trusted_origins`.
+use crate::config;
+use candid::{CandidType, Deserialize};
+
+/// Most origins the config may list.
+pub const MAX_TRUSTED_ORIGINS: usize = 32;
+/// Longest origin the config may list, in bytes.
+pub const MAX_ORIGIN_LEN: usize = 256;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Icrc28TrustedOriginsResponse {
+    pub trusted_origins: Vec<String>,
+}
+
+/// Whether `origin` is a bare `https://host[:port]` origin, or an
+/// `http://` one on localhost for local development.
+pub fn is_valid_origin(origin: &str) -> bool {
+    let host = match (
+        origin.strip_prefix("https://"),
+        origin.strip_prefix("http://"),
+    ) {
+        (Some(host), _) => host,
+        (None, Some(host)) => {
+            let name = host.split(':').next().unwrap_or_default();
+            if name != "localhost" && name != "127.0.0.1" && !name.ends_with(".localhost") {
+                return false;
+            }
+            host
+        }
+        (None, None) => return false,
+    };
+    origin.len() <= MAX_ORIGIN_LEN
+        && !host.is_empty()
+        && !host.starts_with(':')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'))
+}
+
+/// Whether `origins` are few enough, valid and listed once each.
+pub fn is_valid(origins: &[String]) -> bool {
+    origins.len() <= MAX_TRUSTED_ORIGINS
+        && origins.iter().all(|o| is_valid_origin(o))
+        && origins
+            .iter()
+            .enumerate()
+            .all(|(i, o)| !origins[..i].contains(o))
+}
+
+/// Returns the origins signers may let call the pool on a user's behalf. An
+/// update, as ICRC-28 requires, so that the reply is certified.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn icrc28_trusted_origins() -> Icrc28TrustedOriginsResponse {
+    Icrc28TrustedOriginsResponse {
+        trusted_origins: config::get().trusted_origins,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_bare_origins_are_trusted() {
+        assert!(is_valid_origin("https://pool.example.com"));
+        assert!(is_valid_origin("https://pool.example.com:8443"));
+        assert!(is_valid_origin("http://localhost:4943"));
+        assert!(is_valid_origin(
+            "http://bkyz2-fmaaa-aaaaa-qaaaq-cai.localhost:4943"
+        ));
+        assert!(!is_valid_origin("http://pool.example.com"));
+        assert!(!is_valid_origin("https://pool.example.com/"));
+        assert!(!is_valid_origin("https://pool.example.com/app?x=1"));
+        assert!(!is_valid_origin("https://"));
+        assert!(!is_valid_origin("pool.example.com"));
+
+        let origins = vec!["https://a.example.com".to_string()];
+        assert!(is_valid(&origins));
+        assert!(!is_valid(&[origins.clone(), origins].concat()));
+
+        let mut config = config::get();
+        config.trusted_origins = vec!["https://pool.example.com".to_string()];
+        config::set(config);
+        assert_eq!(
+            icrc28_trusted_origins().trusted_origins,
+            vec!["https://pool.example.com"]
+        );
+    }
+}
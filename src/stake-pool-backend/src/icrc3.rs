@@ -0,0 +1,311 @@
+// src/icrc3.rs
+//! ICRC-3 view of the transaction log, so indexers and explorers ingest pool
+//! operations like ledger blocks. Block `i` is transaction `i`, chained to
+//! block `i - 1` by its hash. Blocks of archived transactions are served by
+//! their archive canister; see `archive`.
+use crate::archive;
+use crate::events::MAX_EVENTS_PER_PAGE;
+use crate::memory::{get_memory, Memory, BLOCK_HASHES_MEMORY_ID};
+use crate::state_hash;
+use crate::transactions::{self, Transaction, TransactionKind};
+use crate::UserKey;
+use candid::{Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
+use icrc_ledger_types::icrc::generic_value::{ICRC3Map, ICRC3Value};
+use icrc_ledger_types::icrc3::archive::{GetArchivesArgs, ICRC3ArchiveInfo, QueryArchiveFn};
+use icrc_ledger_types::icrc3::blocks::{
+    ArchivedBlocks, BlockWithId, GetBlocksRequest, GetBlocksResult, SupportedBlockType,
+};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+const BLOCK_TYPES_URL: &str =
+    "https://github.com/akasharora963/icp-stake-pool/blob/main/src/stake-pool-backend/src/icrc3.rs";
+
+thread_local! {
+    // Hash of each block, keyed by index. Trails the log while backfilling.
+    static BLOCK_HASHES: RefCell<StableBTreeMap<u64, [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BLOCK_HASHES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "block_hashes",
+        BLOCK_HASHES.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+fn nat(n: u64) -> ICRC3Value {
+    ICRC3Value::Nat(Nat::from(n))
+}
+
+fn blob(bytes: &[u8]) -> ICRC3Value {
+    ICRC3Value::Blob(ByteBuf::from(bytes))
+}
+
+// Accounts are encoded as in ICRC-3 transfer blocks.
+fn account(key: &UserKey) -> ICRC3Value {
+    ICRC3Value::Array(vec![
+        blob(key.principal.as_slice()),
+        blob(&key.subaccount.0),
+    ])
+}
+
+fn btype(kind: &TransactionKind) -> &'static str {
+    match kind {
+        TransactionKind::Deposit => "pool_deposit",
+        TransactionKind::Withdrawal => "pool_withdraw",
+        TransactionKind::EarlyWithdrawal => "pool_early_withdraw",
+        TransactionKind::Penalty => "pool_penalty",
+        TransactionKind::RewardDistribution => "pool_reward",
+        TransactionKind::RewardClaim => "pool_claim",
+        TransactionKind::Compound => "pool_compound",
+        TransactionKind::Donation => "pool_donate",
+        TransactionKind::DepositTransfer { .. } => "pool_deposit_transfer",
+        TransactionKind::PriceSnapshot { .. } => "pool_price",
+        TransactionKind::AdminChange { .. } => "pool_admin",
+        TransactionKind::ReferralBonus { .. } => "pool_referral_bonus",
+    }
+}
+
+/// Encodes a transaction as an ICRC-3 block following the block hashed `phash`.
+pub fn block(tx: &Transaction, phash: Option<[u8; 32]>) -> ICRC3Value {
+    let mut op = ICRC3Map::new();
+    op.insert("caller".to_string(), blob(tx.caller.as_slice()));
+    op.insert("amt".to_string(), nat(tx.amount));
+    if let Some(key) = &tx.account {
+        op.insert("acc".to_string(), account(key));
+    }
+    if let Some(index) = tx.block_index {
+        op.insert("ledger_block".to_string(), nat(index));
+    }
+    if let Some(token) = tx.token {
+        op.insert("token".to_string(), blob(token.as_slice()));
+    }
+    match &tx.kind {
+        TransactionKind::DepositTransfer { from, deposit_id } => {
+            op.insert("from".to_string(), account(from));
+            op.insert("deposit_id".to_string(), nat(*deposit_id));
+        }
+        TransactionKind::PriceSnapshot {
+            usd_e8s,
+            fetched_at,
+        } => {
+            op.insert("usd_e8s".to_string(), nat(*usd_e8s));
+            op.insert("fetched_at".to_string(), nat(*fetched_at));
+        }
+        TransactionKind::AdminChange { action } => {
+            op.insert("action".to_string(), ICRC3Value::Text(action.clone()));
+        }
+        TransactionKind::ReferralBonus { referrer } => {
+            op.insert("referrer".to_string(), blob(referrer.as_slice()));
+        }
+        _ => {}
+    }
+
+    let mut fields = ICRC3Map::new();
+    fields.insert(
+        "btype".to_string(),
+        ICRC3Value::Text(btype(&tx.kind).to_string()),
+    );
+    fields.insert(
+        "ts".to_string(),
+        ICRC3Value::Nat(Nat::from(tx.timestamp as u128 * 1_000_000_000)),
+    );
+    fields.insert("tx".to_string(), ICRC3Value::Map(op));
+    if let Some(phash) = phash {
+        fields.insert("phash".to_string(), blob(&phash));
+    }
+    ICRC3Value::Map(fields)
+}
+
+/// Blocks whose hash is known, which are the ones served.
+pub fn log_length() -> u64 {
+    BLOCK_HASHES.with(|m| m.borrow().len())
+}
+
+fn hash_of(index: u64) -> Option<[u8; 32]> {
+    BLOCK_HASHES.with(|m| m.borrow().get(&index))
+}
+
+pub(crate) fn block_at(index: u64) -> Option<ICRC3Value> {
+    let tx = transactions::get(index)?;
+    let phash = index.checked_sub(1).and_then(hash_of);
+    Some(block(&tx, phash))
+}
+
+/// Hashes up to `limit` transactions the chain does not cover yet.
+pub fn sync_hashes(limit: u64) {
+    let end = transactions::len().min(log_length().saturating_add(limit));
+    for index in log_length()..end {
+        let Some(block) = block_at(index) else {
+            return;
+        };
+        BLOCK_HASHES.with(|m| m.borrow_mut().insert(index, block.hash()));
+    }
+}
+
+/// Serves up to 100 blocks across `requests`, pointing to the archive of
+/// each archived range.
+pub fn get_blocks(requests: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    let log_length = log_length();
+    let mut budget = MAX_EVENTS_PER_PAGE;
+    let mut blocks = Vec::new();
+    let mut archived: Vec<(Principal, Vec<GetBlocksRequest>)> = Vec::new();
+    for request in requests {
+        let Ok((start, length)) = request.as_start_and_length() else {
+            continue;
+        };
+        let end = start.saturating_add(length.min(budget)).min(log_length);
+        let mut index = start;
+        while index < end {
+            if let Some(archive) = archive::holding(index) {
+                let upto = end.min(archive.served().end);
+                let range = GetBlocksRequest {
+                    start: Nat::from(index),
+                    length: Nat::from(upto - index),
+                };
+                match archived
+                    .iter_mut()
+                    .find(|(id, _)| *id == archive.canister_id)
+                {
+                    Some((_, args)) => args.push(range),
+                    None => archived.push((archive.canister_id, vec![range])),
+                }
+                index = upto;
+            } else {
+                if let Some(block) = block_at(index) {
+                    blocks.push(BlockWithId {
+                        id: Nat::from(index),
+                        block,
+                    });
+                }
+                index += 1;
+            }
+        }
+        budget -= end.saturating_sub(start);
+        if budget == 0 {
+            break;
+        }
+    }
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: archived
+            .into_iter()
+            .map(|(canister_id, args)| ArchivedBlocks {
+                args,
+                callback: QueryArchiveFn::new(canister_id, "icrc3_get_blocks"),
+            })
+            .collect(),
+    }
+}
+
+/// Returns the requested blocks of the pool's operation log, up to 100 per
+/// call, and where to fetch the archived ones.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    get_blocks(args)
+}
+
+/// Returns the archives holding old blocks, starting after `from` if set.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ICRC3ArchiveInfo> {
+    archive::records()
+        .into_iter()
+        .skip_while(|a| args.from.is_some_and(|from| a.canister_id != from))
+        .skip(usize::from(args.from.is_some()))
+        .map(|a| ICRC3ArchiveInfo {
+            canister_id: a.canister_id,
+            start: Nat::from(a.start),
+            end: Nat::from(a.served().end - 1),
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    [
+        TransactionKind::Deposit,
+        TransactionKind::Withdrawal,
+        TransactionKind::EarlyWithdrawal,
+        TransactionKind::Penalty,
+        TransactionKind::RewardDistribution,
+        TransactionKind::RewardClaim,
+        TransactionKind::Compound,
+        TransactionKind::Donation,
+        TransactionKind::DepositTransfer {
+            from: UserKey {
+                principal: Principal::anonymous(),
+                subaccount: ic_ledger_types::Subaccount([0; 32]),
+            },
+            deposit_id: 0,
+        },
+        TransactionKind::PriceSnapshot {
+            usd_e8s: 0,
+            fetched_at: 0,
+        },
+        TransactionKind::AdminChange {
+            action: String::new(),
+        },
+        TransactionKind::ReferralBonus {
+            referrer: Principal::anonymous(),
+        },
+    ]
+    .iter()
+    .map(|kind| SupportedBlockType {
+        block_type: btype(kind).to_string(),
+        url: BLOCK_TYPES_URL.to_string(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_chain_and_point_to_archives() {
+        let caller = Principal::from_slice(&[150]);
+        for amount in [10, 20, 30, 40] {
+            transactions::record(1_000, caller, TransactionKind::Donation, None, amount, None);
+        }
+        let all = vec![GetBlocksRequest {
+            start: Nat::from(0u64),
+            length: Nat::from(10u64),
+        }];
+        let result = get_blocks(all.clone());
+        assert_eq!(result.log_length, Nat::from(4u64));
+        for pair in result.blocks.windows(2) {
+            let ICRC3Value::Map(fields) = &pair[1].block else {
+                panic!("block is not a map");
+            };
+            assert_eq!(
+                fields.get("phash"),
+                Some(&blob(&pair[0].block.clone().hash()))
+            );
+        }
+
+        let canister = Principal::from_slice(&[151]);
+        archive::put(&archive::ArchiveRecord {
+            canister_id: canister,
+            start: 0,
+            end: 1,
+            appended: 2,
+        });
+        let result = get_blocks(all);
+        assert_eq!(result.blocks.len(), 2);
+        assert_eq!(result.blocks[0].id, Nat::from(2u64));
+        assert_eq!(result.archived_blocks[0].callback.canister_id, canister);
+        assert_eq!(result.archived_blocks[0].args[0].length, Nat::from(2u64));
+        let archives = icrc3_get_archives(GetArchivesArgs { from: None });
+        assert_eq!(archives[0].end, Nat::from(1u64));
+        assert!(icrc3_get_archives(GetArchivesArgs {
+            from: Some(canister)
+        })
+        .is_empty());
+    }
+}
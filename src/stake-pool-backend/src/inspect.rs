@@ -0,0 +1,105 @@
+// src/inspect.rs
+//! Ingress filter run by the replica before an update call executes. Calls it
+//! rejects are dropped without being charged to the canister, so spam from
+//! anonymous callers, unknown methods and oversized arguments is cheap.
+use candid::Principal;
+
+/// Argument size accepted for most methods.
+pub const MAX_ARG_BYTES: usize = 4 * 1024;
+/// Argument size accepted for methods taking a list of items.
+pub const MAX_BULK_ARG_BYTES: usize = 32 * 1024;
+const BULK_METHODS: &[&str] = &["create_team", "deposit_funds_batch", "withdraw_funds_batch"];
+
+// Served by the canister but not part of the interface file.
+const UNLISTED_QUERIES: &[&str] = &["__get_candid_interface_tmp_hack"];
+// Updates signers call before any user has signed in.
+const ANONYMOUS_UPDATES: &[&str] = &["icrc28_trusted_origins"];
+// Updates only controllers may call.
+const CONTROLLER_UPDATES: &[&str] = &["slash_pool"];
+
+// Method name and whether it is a query, for every method of the service in
+// the interface file, sorted by name. Generated by `build.rs`.
+const METHODS: &[(&str, bool)] = include!(concat!(env!("OUT_DIR"), "/methods.rs"));
+
+fn is_query(method: &str) -> Option<bool> {
+    if UNLISTED_QUERIES.contains(&method) {
+        return Some(true);
+    }
+    METHODS
+        .binary_search_by(|(name, _)| (*name).cmp(method))
+        .ok()
+        .map(|i| METHODS[i].1)
+}
+
+/// Decides whether an ingress call may run. Query methods are only inspected
+/// when called as updates, and stay open to the anonymous principal.
+pub fn check(
+    method: &str,
+    caller: Principal,
+    arg_bytes: usize,
+    is_controller: bool,
+) -> Result<(), String> {
+    let Some(is_query) = is_query(method) else {
+        return Err(format!("unknown method {}", method));
+    };
+    if !is_query && caller == Principal::anonymous() && !ANONYMOUS_UPDATES.contains(&method) {
+        return Err(format!("anonymous caller may not call {}", method));
+    }
+    if !is_controller && CONTROLLER_UPDATES.contains(&method) {
+        return Err(format!("only controllers may call {}", method));
+    }
+    let limit = if BULK_METHODS.contains(&method) {
+        MAX_BULK_ARG_BYTES
+    } else {
+        MAX_ARG_BYTES
+    };
+    if !is_controller && arg_bytes > limit {
+        return Err(format!(
+            "argument of {} bytes exceeds the {} byte limit of {}",
+            arg_bytes, limit, method
+        ));
+    }
+    Ok(())
+}
+
+/// Accepts the ingress message only if `check` allows it. Controllers are not
+/// held to the argument limits, since admin batches can be large.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let caller = ic_cdk::caller();
+    let result = check(
+        &ic_cdk::api::call::method_name(),
+        caller,
+        ic_cdk::api::call::arg_data_raw_size(),
+        ic_cdk::api::is_controller(&caller),
+    );
+    if result.is_ok() {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spam_is_rejected_before_execution() {
+        let user = Principal::from_slice(&[120]);
+        let anonymous = Principal::anonymous();
+
+        assert!(check("deposit_funds", user, 200, false).is_ok());
+        assert!(check("deposit_funds_v2", user, 200, false).is_ok());
+        assert!(check("no_such_method", user, 10, true).is_err());
+        assert!(check("deposit_funds", anonymous, 200, false).is_err());
+        assert!(check("get_pool_stats", anonymous, 10, false).is_ok());
+        assert!(check("get_deposits_by_user_formatted", anonymous, 10, false).is_ok());
+        assert!(check("__get_candid_interface_tmp_hack", anonymous, 10, false).is_ok());
+        assert!(check("icrc28_trusted_origins", anonymous, 10, false).is_ok());
+        assert!(check("slash_pool", user, 200, false).is_err());
+        assert!(check("slash_pool", user, 200, true).is_ok());
+
+        assert!(check("deposit_funds", user, MAX_ARG_BYTES + 1, false).is_err());
+        assert!(check("deposit_funds_batch", user, MAX_ARG_BYTES + 1, false).is_ok());
+        assert!(check("admin_batch", user, MAX_BULK_ARG_BYTES, true).is_ok());
+    }
+}
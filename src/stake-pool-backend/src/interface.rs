@@ -0,0 +1,82 @@
+// src/interface.rs
+//! Candid interface of the canister, generated from the `candid_method`
+//! endpoints. `stake-pool-backend.did` is checked against it by a test.
+use crate::dissolve::DissolveState;
+use crate::error::{DepositError, PoolError};
+use crate::maintenance::Operation;
+#[allow(unused_imports)]
+use crate::{
+    account_migration::*, admin::*, allowlist::*, analytics::*, archive::*, backup::*, batch::*,
+    canister_stakers::*, certification::*, circuit_breaker::*, compounding::*, config::*,
+    cycles::*, denylist::*, deposit_transfer::*, direct_deposit::*, dissolve::*, distribution::*,
+    epochs::*, escheat::*, events::*, governance::*, icrc21::*, icrc28::*, icrc3::*, ledger::*,
+    liquid::*, locks::*, loyalty::*, maintenance::*, maturity::*, metrics::*, migration::*,
+    multipliers::*, neurons::*, notifications::*, pending_withdrawals::*, position_import::*,
+    positions::*, price_oracle::*, proposals::*, rate_model::*, receipts::*, referrals::*,
+    reward_streams::*, scheduler::*, sharding::*, snapshot::*, state_hash::*, statements::*,
+    stats::*, status::*, storage::*, teams::*, tiers::*, tokens::*, transactions::*, treasury::*,
+    unlocks::*, unstaking::*, validators::*, withdrawal_queue::*,
+};
+use crate::{ledger, penalty, Deposit, DepositView, FullBalance, UserKey};
+use candid::{Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_ledger_types::Subaccount;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc21::errors::Icrc21Error;
+use icrc_ledger_types::icrc21::requests::ConsentMessageRequest;
+use icrc_ledger_types::icrc21::responses::ConsentInfo;
+use icrc_ledger_types::icrc3::archive::{GetArchivesArgs, ICRC3ArchiveInfo};
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult, SupportedBlockType};
+
+candid::export_service!();
+
+/// Returns the Candid interface generated from the endpoints, for tooling
+/// that reads it from the deployed canister.
+#[ic_cdk::query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    __export_service()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run with `UPDATE_CANDID=1` to rewrite the .did file after changing an
+    // endpoint.
+    #[test]
+    fn test_did_file_matches_endpoints() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/stake-pool-backend.did");
+        let generated = __export_service();
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(path, &generated).expect("Failed to write the .did file");
+        }
+        let checked_in = std::fs::read_to_string(path).expect("Failed to read the .did file");
+        assert!(
+            checked_in == generated,
+            "stake-pool-backend.did is out of date; regenerate it with `UPDATE_CANDID=1 cargo test`"
+        );
+    }
+
+    #[test]
+    fn test_inspect_accepts_every_exported_method() {
+        let did = __export_service();
+        let (env, actor) = candid_parser::utils::CandidSource::Text(&did)
+            .load()
+            .expect("the exported interface parses");
+        let service = env
+            .as_service(&actor.expect("the interface declares a service"))
+            .expect("the service is a service type")
+            .to_vec();
+        assert!(!service.is_empty());
+        let user = candid::Principal::from_slice(&[121]);
+        // As a controller, since some updates are reserved to them.
+        for (method, _) in service {
+            assert!(
+                crate::inspect::check(&method, user, 10, true).is_ok(),
+                "inspect rejects the exported method {method}"
+            );
+        }
+    }
+}
@@ -0,0 +1,519 @@
+// src/ledger.rs
+use crate::error::{DepositError, PoolError};
+use crate::memory::{
+    get_memory, Memory, LEDGER_CANISTER_MEMORY_ID, LEDGER_FEES_MEMORY_ID, TOKEN_METADATA_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::PrincipalKey;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Age after which a cached transfer fee is read from the ledger again.
+const FEE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Init and upgrade argument of the pool canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct InitArgs {
+    /// ICRC-2 ledger of the staked token. Keeps the current ledger when `None`.
+    pub ledger_canister: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct TokenMetadataCache(Option<TokenMetadata>);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct LedgerCanister(Option<Principal>);
+
+impl Storable for LedgerCanister {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode LedgerCanister"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode LedgerCanister")
+    }
+}
+
+impl Storable for TokenMetadataCache {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode TokenMetadata"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode TokenMetadata")
+    }
+}
+
+/// Transfer fee of a ledger and when it was read.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CachedFee {
+    pub fee: u64,
+    pub fetched_at: u64,
+}
+
+impl Storable for CachedFee {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode CachedFee"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode CachedFee")
+    }
+}
+
+impl BoundedStorable for CachedFee {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LEDGER_FEES: RefCell<StableBTreeMap<PrincipalKey, CachedFee, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LEDGER_FEES_MEMORY_ID)));
+
+    static TOKEN_METADATA: RefCell<StableCell<TokenMetadataCache, Memory>> = RefCell::new(
+        StableCell::init(get_memory(TOKEN_METADATA_MEMORY_ID), TokenMetadataCache::default())
+            .expect("Failed to init token metadata cell"),
+    );
+
+    static LEDGER_CANISTER: RefCell<StableCell<LedgerCanister, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LEDGER_CANISTER_MEMORY_ID), LedgerCanister::default())
+            .expect("Failed to init ledger canister cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "token_metadata",
+            TOKEN_METADATA.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "ledger_canister",
+            LEDGER_CANISTER.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "ledger_fees",
+            LEDGER_FEES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn ledger_canister() -> Option<Principal> {
+    LEDGER_CANISTER.with(|c| c.borrow().get().0)
+}
+
+/// Principal of the ICRC-2 ledger holding the staked token. Traps if none was
+/// configured, since no ledger call can succeed without it.
+pub fn ledger_id() -> Principal {
+    ledger_canister().expect("ledger canister is not configured")
+}
+
+/// Switches the pool to `ledger`. Refused while the pool holds stake, whose
+/// funds sit on the current ledger.
+pub fn set_ledger_canister_internal(ledger: Principal) -> Result<(), DepositError> {
+    if ledger_canister() == Some(ledger) {
+        return Ok(());
+    }
+    let holds_stake = crate::STAKE_BALANCE_MAP.with(|m| m.borrow().iter().any(|(_, s)| s > 0))
+        || crate::liquid::state().total_underlying > 0;
+    if holds_stake {
+        return Err(DepositError::InvalidArgument(
+            "the ledger cannot change while the pool holds stake".to_string(),
+        ));
+    }
+    LEDGER_CANISTER.with(|c| {
+        c.borrow_mut()
+            .set(LedgerCanister(Some(ledger)))
+            .expect("Failed to persist ledger canister");
+    });
+    // Symbol and decimals belong to the previous ledger.
+    TOKEN_METADATA.with(|c| {
+        c.borrow_mut()
+            .set(TokenMetadataCache::default())
+            .expect("Failed to persist token metadata");
+    });
+    Ok(())
+}
+
+/// Applies the init or upgrade argument.
+pub fn apply_init_args(args: Option<InitArgs>) {
+    if let Some(ledger) = args.and_then(|a| a.ledger_canister) {
+        set_ledger_canister_internal(ledger).expect("Failed to set the ledger canister");
+    }
+}
+
+pub fn token_metadata() -> Option<TokenMetadata> {
+    TOKEN_METADATA.with(|c| c.borrow().get().0.clone())
+}
+
+fn set_token_metadata(metadata: TokenMetadata) {
+    TOKEN_METADATA.with(|c| {
+        c.borrow_mut()
+            .set(TokenMetadataCache(Some(metadata)))
+            .expect("Failed to persist token metadata");
+    });
+}
+
+/// A raw token amount together with its human-readable rendering. The display
+/// fields are `None` until the token metadata has been fetched from the ledger.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FormattedAmount {
+    pub amount: u64,
+    pub display: Option<String>,
+    pub symbol: Option<String>,
+}
+
+/// Renders `amount` base units as a decimal string, e.g. `123_450_000` with
+/// 8 decimals becomes `"1.2345"`.
+pub fn format_decimal(amount: u64, decimals: u8) -> String {
+    let scale = 10u128.pow(decimals as u32);
+    let whole = amount as u128 / scale;
+    let fraction = amount as u128 % scale;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+pub fn format_amount(amount: u64) -> FormattedAmount {
+    let metadata = token_metadata();
+    FormattedAmount {
+        amount,
+        display: metadata
+            .as_ref()
+            .map(|m| format_decimal(amount, m.decimals)),
+        symbol: metadata.map(|m| m.symbol),
+    }
+}
+
+async fn fetch_token_metadata() -> Result<TokenMetadata, DepositError> {
+    let (decimals,): (u8,) = call(ledger_id(), "icrc1_decimals", ())
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    let (symbol,): (String,) = call(ledger_id(), "icrc1_symbol", ())
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(TokenMetadata { symbol, decimals })
+}
+
+/// Reads the ledger balance of `account`.
+pub async fn balance_of(account: Account) -> Result<Nat, DepositError> {
+    balance_in(ledger_id(), account).await
+}
+
+/// Reads the balance of `account` on `ledger`.
+pub async fn balance_in(ledger: Principal, account: Account) -> Result<Nat, DepositError> {
+    let (balance,): (Nat,) = call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(balance)
+}
+
+fn pool_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    }
+}
+
+/// Reads what `account` approved the pool to pull from it on `ledger`.
+pub async fn allowance(ledger: Principal, account: Account) -> Result<Allowance, DepositError> {
+    let args = AllowanceArgs {
+        account,
+        spender: pool_account(),
+    };
+    let (allowance,): (Allowance,) = call(ledger, "icrc2_allowance", (args,))
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(allowance)
+}
+
+fn to_u64(n: &Nat) -> u64 {
+    u64::try_from(n.0.clone()).unwrap_or(u64::MAX)
+}
+
+/// Fails unless `allowance`, unexpired at `now_nanos`, and `balance` both
+/// cover `required`.
+pub fn check_pullable(
+    allowance: &Allowance,
+    balance: &Nat,
+    required: u64,
+    now_nanos: u64,
+) -> Result<(), PoolError> {
+    let current = match allowance.expires_at {
+        Some(expiry) if expiry <= now_nanos => 0,
+        _ => to_u64(&allowance.allowance),
+    };
+    if current < required {
+        return Err(PoolError::InsufficientAllowance {
+            required,
+            allowance: current,
+        });
+    }
+    let available = to_u64(balance);
+    if available < required {
+        return Err(PoolError::InsufficientFunds {
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the allowance and balance of `from` on `ledger` and fails unless
+/// both cover `required`, so a transfer that cannot succeed is not tried.
+pub async fn ensure_pullable(
+    ledger: Principal,
+    from: Account,
+    required: u64,
+) -> Result<(), PoolError> {
+    let allowance = allowance(ledger, from).await?;
+    let balance = balance_in(ledger, from).await?;
+    check_pullable(&allowance, &balance, required, ic_cdk::api::time())
+}
+
+/// Cached fee of `ledger`, unless it is older than the TTL at `now`.
+pub fn cached_fee(ledger: Principal, now: u64) -> Option<u64> {
+    LEDGER_FEES
+        .with(|m| m.borrow().get(&PrincipalKey(ledger)))
+        .filter(|c| now.saturating_sub(c.fetched_at) < FEE_TTL_SECS)
+        .map(|c| c.fee)
+}
+
+/// Caches `fee` for `ledger`, e.g. after a transfer was refused with the
+/// fee the ledger expects.
+pub fn note_fee(ledger: Principal, fee: &Nat, now: u64) {
+    let fee = u64::try_from(fee.0.clone()).unwrap_or(u64::MAX);
+    LEDGER_FEES.with(|m| {
+        m.borrow_mut().insert(
+            PrincipalKey(ledger),
+            CachedFee {
+                fee,
+                fetched_at: now,
+            },
+        )
+    });
+}
+
+/// Transfer fee of `ledger`, read from the ledger when the cache is stale.
+pub async fn fee(ledger: Principal) -> Result<u64, DepositError> {
+    let now = crate::now_secs();
+    if let Some(fee) = cached_fee(ledger, now) {
+        return Ok(fee);
+    }
+    let (fee,): (Nat,) = call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    note_fee(ledger, &fee, now);
+    Ok(u64::try_from(fee.0).unwrap_or(u64::MAX))
+}
+
+/// Fee the pool pays to send `amount` out of its account on `ledger`. Fails
+/// if the fee would take all of it.
+pub async fn payout_fee(ledger: Principal, amount: u64) -> Result<u64, DepositError> {
+    let fee = fee(ledger).await?;
+    if amount <= fee {
+        return Err(DepositError::BelowLedgerFee { fee });
+    }
+    Ok(fee)
+}
+
+/// Re-reads the token symbol and decimals from the ledger.
+///
+/// # Errors
+///
+/// * `DepositError::LedgerTransferFailed`: If the ledger could not be queried.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn refresh_token_metadata() -> Result<TokenMetadata, DepositError> {
+    let metadata = fetch_token_metadata().await?;
+    set_token_metadata(metadata.clone());
+    Ok(metadata)
+}
+
+/// Sets the ICRC-2 ledger of the staked token. Only canister controllers may
+/// call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the pool holds stake on the current ledger.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_ledger_canister(ledger: Principal) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("set_ledger_canister", set_ledger_canister_internal(ledger))
+}
+
+/// Returns the ICRC-2 ledger of the staked token, if configured.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_ledger_canister() -> Option<Principal> {
+    ledger_canister()
+}
+
+/// Returns the cached transfer fee of the primary ledger, if known. Payouts
+/// are sent net of this fee.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_ledger_fee() -> Option<u64> {
+    ledger_canister().and_then(|l| {
+        LEDGER_FEES
+            .with(|m| m.borrow().get(&PrincipalKey(l)))
+            .map(|c| c.fee)
+    })
+}
+
+/// What a frontend needs to approve before a deposit.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequiredApproval {
+    /// Account to name as the spender of `icrc2_approve`.
+    pub spender: Account,
+    /// Allowance to approve: the amount plus the fee of the transfer that
+    /// pulls it.
+    pub allowance: u64,
+    pub fee: u64,
+    /// Balance needed to approve and deposit, both ledger fees included.
+    pub balance: u64,
+}
+
+pub fn required_approval(spender: Account, amount: u64, fee: u64) -> RequiredApproval {
+    let allowance = amount.saturating_add(fee);
+    RequiredApproval {
+        spender,
+        allowance,
+        fee,
+        balance: allowance.saturating_add(fee),
+    }
+}
+
+/// Returns the approval a deposit of `amount` of the primary token needs,
+/// with the ledger fee included. `None` until the pool has read the fee.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_required_approval(amount: u64) -> Option<RequiredApproval> {
+    get_ledger_fee().map(|fee| required_approval(pool_account(), amount, fee))
+}
+
+/// Returns the cached token symbol and decimals, if known.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_token_metadata() -> Option<TokenMetadata> {
+    token_metadata()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_cache_expires() {
+        let ledger = Principal::from_slice(&[9]);
+        assert_eq!(cached_fee(ledger, 0), None);
+        note_fee(ledger, &Nat::from(10_000u64), 100);
+        assert_eq!(cached_fee(ledger, 100 + FEE_TTL_SECS - 1), Some(10_000));
+        assert_eq!(cached_fee(ledger, 100 + FEE_TTL_SECS), None);
+    }
+
+    #[test]
+    fn test_pull_is_checked_against_allowance_and_balance() {
+        let allowance = |amount: u64, expires_at| Allowance {
+            allowance: Nat::from(amount),
+            expires_at,
+        };
+        let balance = Nat::from(1_010u64);
+        assert_eq!(
+            check_pullable(&allowance(1_010, None), &balance, 1_010, 0),
+            Ok(())
+        );
+        assert_eq!(
+            check_pullable(&allowance(1_000, None), &balance, 1_010, 0),
+            Err(PoolError::InsufficientAllowance {
+                required: 1_010,
+                allowance: 1_000,
+            })
+        );
+        // An expired approval allows nothing.
+        assert_eq!(
+            check_pullable(&allowance(5_000, Some(10)), &balance, 1_010, 10),
+            Err(PoolError::InsufficientAllowance {
+                required: 1_010,
+                allowance: 0,
+            })
+        );
+        assert_eq!(
+            check_pullable(&allowance(5_000, Some(11)), &balance, 2_000, 10),
+            Err(PoolError::InsufficientFunds {
+                required: 2_000,
+                available: 1_010,
+            })
+        );
+
+        let spender = Account {
+            owner: Principal::from_slice(&[8]),
+            subaccount: None,
+        };
+        let approval = required_approval(spender, 1_000, 10);
+        assert_eq!((approval.allowance, approval.balance), (1_010, 1_020));
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(format_decimal(123_450_000, 8), "1.2345");
+        assert_eq!(format_decimal(100_000_000, 8), "1");
+        assert_eq!(format_decimal(1, 8), "0.00000001");
+        assert_eq!(format_decimal(42, 0), "42");
+    }
+
+    #[test]
+    fn test_format_amount_uses_cached_metadata() {
+        assert_eq!(format_amount(5).display, None);
+
+        set_token_metadata(TokenMetadata {
+            symbol: "ckBTC".to_string(),
+            decimals: 8,
+        });
+        let formatted = format_amount(250_000_000);
+        assert_eq!(formatted.amount, 250_000_000);
+        assert_eq!(formatted.display.as_deref(), Some("2.5"));
+        assert_eq!(formatted.symbol.as_deref(), Some("ckBTC"));
+    }
+
+    #[test]
+    fn test_ledger_cannot_change_under_stake() {
+        let ckbtc = Principal::from_slice(&[1]);
+        let cketh = Principal::from_slice(&[2]);
+        assert_eq!(ledger_canister(), None);
+        apply_init_args(Some(InitArgs {
+            ledger_canister: Some(ckbtc),
+        }));
+        assert_eq!(ledger_id(), ckbtc);
+
+        crate::deposit_internal(
+            Principal::anonymous(),
+            ic_ledger_types::Subaccount([0; 32]),
+            90,
+            10,
+            0,
+        )
+        .unwrap();
+        assert!(set_ledger_canister_internal(cketh).is_err());
+        assert_eq!(set_ledger_canister_internal(ckbtc), Ok(()));
+        assert_eq!(ledger_id(), ckbtc);
+    }
+}
@@ -1,20 +1,102 @@
 // src/lib.rs
+mod account_migration;
+mod accrual;
+mod admin;
+mod allowlist;
+mod analytics;
+mod archive;
+mod backup;
+mod batch;
+mod canister_stakers;
+mod certification;
+mod circuit_breaker;
+mod compounding;
+mod config;
+mod cycles;
+mod dedup;
+mod denylist;
+mod deposit_transfer;
+mod direct_deposit;
+mod dissolve;
+mod distribution;
+mod epochs;
 mod error;
-use candid::{CandidType, Deserialize, Principal};
-use error::DepositError;
+mod escheat;
+mod events;
+mod governance;
+mod icrc21;
+mod icrc28;
+mod icrc3;
+mod inspect;
+mod ledger;
+mod liquid;
+mod locks;
+mod loyalty;
+mod maintenance;
+mod maturity;
+mod memory;
+mod memory_guard;
+mod metrics;
+mod migration;
+mod multipliers;
+mod neurons;
+mod notifications;
+mod op_locks;
+mod penalty;
+mod pending_withdrawals;
+mod position_import;
+mod positions;
+mod price_oracle;
+mod proposals;
+mod rate_model;
+mod receipts;
+mod referrals;
+mod reward_history;
+mod reward_streams;
+mod rewards;
+mod scheduler;
+mod sharding;
+mod snapshot;
+mod state_hash;
+mod statements;
+mod stats;
+mod status;
+mod storage;
+mod teams;
+mod tiers;
+mod tokens;
+mod transactions;
+mod treasury;
+mod unlocks;
+mod unstaking;
+mod upgrade;
+mod validators;
+mod withdrawal_queue;
+mod xrc;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use certification::CertifiedBalance;
+use distribution::{DistributionJob, RewardDistributionReport};
+use error::{DepositError, LedgerError, PoolError};
 use ic_cdk::api::time;
 use ic_cdk::call;
 use ic_ledger_types::Subaccount;
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::{BoundedStorable, Storable},
-    DefaultMemoryImpl, StableBTreeMap,
+    StableBTreeMap, StableCell,
 };
 use icrc_ledger_types::icrc1::transfer::TransferArg;
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferError};
-use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use maintenance::Operation;
+use memory::{
+    get_memory, Memory, DEPOSITS_MEMORY_ID, DEPOSIT_ID_COUNTER_MEMORY_ID, STAKE_BALANCE_MEMORY_ID,
+};
+use pending_withdrawals::Part;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use withdrawal_queue::{WithdrawalOutcome, WithdrawalSource};
 
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct UserKey {
@@ -22,8 +104,20 @@ pub struct UserKey {
     pub subaccount: Subaccount,
 }
 
+impl UserKey {
+    /// Keys of every subaccount of `principal`. Maps keyed by `UserKey` sort
+    /// by principal first, so this range holds exactly its accounts.
+    pub fn range_of_principal(principal: Principal) -> RangeInclusive<UserKey> {
+        let key = |fill| UserKey {
+            principal,
+            subaccount: Subaccount([fill; 32]),
+        };
+        key(0)..=key(u8::MAX)
+    }
+}
+
 impl Storable for UserKey {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).expect("Failed to encode UserKey"))
     }
 
@@ -37,6 +131,25 @@ impl BoundedStorable for UserKey {
     const IS_FIXED_SIZE: bool = false;
 }
 
+/// Stable-memory key for data kept per principal rather than per subaccount.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PrincipalKey(pub Principal);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub struct Deposit {
     pub id: u64,
@@ -45,11 +158,8 @@ pub struct Deposit {
     pub lock_period_days: u16,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct DepositList(pub Vec<Deposit>);
-
-impl Storable for DepositList {
-    fn to_bytes(&self) -> Cow<[u8]> {
+impl Storable for Deposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(candid::encode_one(self).expect("Failed to encode Deposit"))
     }
 
@@ -58,26 +168,265 @@ impl Storable for DepositList {
     }
 }
 
-impl BoundedStorable for DepositList {
-    const MAX_SIZE: u32 = 100;
+impl BoundedStorable for Deposit {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Stable-memory key of a deposit. Keys sort by account and then by id, so
+/// the deposits of an account form one range, oldest first.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DepositKey {
+    pub user: UserKey,
+    pub deposit_id: u64,
+}
+
+impl DepositKey {
+    pub fn new(user: &UserKey, deposit_id: u64) -> Self {
+        Self {
+            user: user.clone(),
+            deposit_id,
+        }
+    }
+
+    /// Keys of every deposit of `user`.
+    pub fn range_of(user: &UserKey) -> RangeInclusive<DepositKey> {
+        Self::new(user, 0)..=Self::new(user, u64::MAX)
+    }
+
+    /// Keys of every deposit of `principal`, in any subaccount.
+    pub fn range_of_principal(principal: Principal) -> RangeInclusive<DepositKey> {
+        let (first, last) = UserKey::range_of_principal(principal).into_inner();
+        Self::new(&first, 0)..=Self::new(&last, u64::MAX)
+    }
+}
+
+impl Storable for DepositKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DepositKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DepositKey")
+    }
+}
+
+impl BoundedStorable for DepositKey {
+    const MAX_SIZE: u32 = 160;
     const IS_FIXED_SIZE: bool = false;
 }
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+impl Deposit {
+    /// Time in seconds at which the lock period of this deposit ends. A
+    /// deposit in dissolve mode unlocks when its delay has run down, never
+    /// while it is not dissolving.
+    pub fn unlock_time(&self) -> u64 {
+        dissolve::state_of(self.id).map_or(
+            self.timestamp + (self.lock_period_days as u64 * 86400),
+            |state| state.unlock_time(),
+        )
+    }
+}
+
+/// A deposit together with its lock status at the time of the query.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DepositView {
+    pub deposit: Deposit,
+    pub unlock_timestamp: u64,
+    pub is_unlocked: bool,
+    /// Rewards earned since the account was last settled. Rewards already
+    /// settled show up in the account's pending rewards instead.
+    pub accrued_rewards: u64,
+    /// Whole days until unlock, rounded up. The full dissolve delay for a
+    /// deposit in dissolve mode that is not dissolving.
+    pub days_remaining: u64,
+    /// `None` for deposits with a fixed lock.
+    pub dissolve_state: Option<dissolve::DissolveState>,
+    /// Validator the deposit is delegated to.
+    pub validator: Option<Principal>,
+}
+
+impl DepositView {
+    pub fn new(deposit: Deposit, accrued_rewards: u64, now: u64) -> Self {
+        let unlock_timestamp = deposit.unlock_time();
+        let dissolve_state = dissolve::state_of(deposit.id);
+        let validator = validators::delegation_of(deposit.id);
+        let remaining_secs = match dissolve_state {
+            Some(dissolve::DissolveState::NotDissolving { delay_secs }) => delay_secs,
+            _ => unlock_timestamp.saturating_sub(now),
+        };
+        Self {
+            deposit,
+            unlock_timestamp,
+            is_unlocked: now >= unlock_timestamp,
+            accrued_rewards,
+            days_remaining: remaining_secs.div_ceil(86400),
+            dissolve_state,
+            validator,
+        }
+    }
+}
 
 thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    static DEPOSIT_MAP: RefCell<StableBTreeMap<UserKey, DepositList, Memory>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))));
+    static DEPOSITS: RefCell<StableBTreeMap<DepositKey, Deposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSITS_MEMORY_ID)));
 
     static STAKE_BALANCE_MAP: RefCell<StableBTreeMap<UserKey, u64, Memory>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))));
+        RefCell::new(StableBTreeMap::init(get_memory(STAKE_BALANCE_MEMORY_ID)));
+
+    static DEPOSIT_ID_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(DEPOSIT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Failed to init deposit id counter"),
+    );
+}
 
-    static DEPOSIT_ID_COUNTER: RefCell<u64> = RefCell::new(0);
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "deposits",
+            DEPOSITS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "stake_balances",
+            STAKE_BALANCE_MAP.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "deposit_id_counter",
+            DEPOSIT_ID_COUNTER.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+/// Id of the most recently created deposit, 0 before the first one.
+pub(crate) fn deposit_id_counter() -> u64 {
+    DEPOSIT_ID_COUNTER.with(|c| *c.borrow().get())
+}
+
+pub(crate) fn set_deposit_id_counter(value: u64) {
+    DEPOSIT_ID_COUNTER.with(|c| {
+        c.borrow_mut()
+            .set(value)
+            .expect("Failed to persist deposit id counter");
+    });
 }
 
 const VALID_LOCKS: [u16; 3] = [90, 180, 360];
 
+/// Current IC time in seconds, the unit used for all stored timestamps.
+pub(crate) fn now_secs() -> u64 {
+    time() / 1_000_000_000
+}
+
+/// Fails unless `caller` is a controller of this canister.
+pub(crate) fn ensure_controller(caller: Principal) -> Result<(), DepositError> {
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(DepositError::Unauthorized)
+    }
+}
+
+#[ic_cdk::init]
+#[candid::candid_method(init)]
+fn init(args: Option<ledger::InitArgs>) {
+    storage::init_version();
+    ledger::apply_init_args(args);
+    cycles::start_monitoring();
+    rate_model::start_epochs();
+    epochs::start_epochs();
+    maturity::start_harvesting();
+    unstaking::start_liquidity_checks();
+    escheat::start_sweeps();
+    unlocks::start_scans();
+    scheduler::start_sweeps();
+    reward_streams::start_drips();
+    proposals::start_tallies();
+    withdrawal_queue::start_processing();
+    stats::start_refresh();
+    price_oracle::start_refresh();
+    circuit_breaker::start_checks();
+    state_hash::start_refresh();
+    archive::start_archiving();
+    certification::start_refresh();
+    certification::refresh();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    upgrade::save_runtime_state();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<ledger::InitArgs>) {
+    metrics::measure("post_upgrade", || {
+        upgrade::restore_runtime_state();
+        storage::migrate(now_secs());
+        ledger::apply_init_args(args);
+        cycles::start_monitoring();
+        rate_model::start_epochs();
+        epochs::start_epochs();
+        maturity::start_harvesting();
+        unstaking::start_liquidity_checks();
+        escheat::start_sweeps();
+        unlocks::start_scans();
+        scheduler::start_sweeps();
+        reward_streams::start_drips();
+        proposals::start_tallies();
+        withdrawal_queue::start_processing();
+        stats::start_refresh();
+        price_oracle::start_refresh();
+        circuit_breaker::start_checks();
+        certification::start_refresh();
+        certification::refresh();
+        state_hash::start_refresh();
+        archive::start_archiving();
+        ic_cdk_timers::set_timer(Duration::ZERO, distribution::resume);
+        state_hash::refresh_all(now_secs());
+    });
+}
+
+// Stake held across all subaccounts of `principal`.
+fn principal_stake(principal: Principal) -> u64 {
+    STAKE_BALANCE_MAP.with(|m| {
+        m.borrow()
+            .range(UserKey::range_of_principal(principal))
+            .map(|(_, s)| s)
+            .sum()
+    })
+}
+
+/// Fails if a deposit of `amount` is below the configured minimum, would
+/// take a principal already holding `staked` over the per-user cap, or would
+/// take the pool over its total value locked cap.
+pub(crate) fn check_deposit_limits(staked: u64, amount: u64) -> Result<(), DepositError> {
+    let config = config::get();
+    if amount < config.min_deposit {
+        return Err(DepositError::AmountTooLow {
+            minimum: config.min_deposit,
+        });
+    }
+    if let Some(cap) = config.max_deposit_per_user {
+        if staked.saturating_add(amount) > cap {
+            return Err(DepositError::UserCapReached { cap });
+        }
+    }
+    // Summing the pool's stake is linear, so only done when a cap is set.
+    if let Some(cap) = config.max_total_value_locked {
+        if stats::total_value_locked().saturating_add(amount) > cap {
+            return Err(DepositError::PoolCapReached { cap });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn ensure_deposit_allowed(
+    principal: Principal,
+    amount: u64,
+) -> Result<(), DepositError> {
+    sharding::ensure_local(principal)?;
+    check_deposit_limits(principal_stake(principal), amount)
+}
+
 // Internal reusable logic for testing or canister
 fn deposit_internal(
     principal: Principal,
@@ -85,6 +434,19 @@ fn deposit_internal(
     lock_days: u16,
     amount: u64,
     timestamp: u64,
+) -> Result<Deposit, DepositError> {
+    ensure_deposit_allowed(principal, amount)?;
+    record_deposit(principal, subaccount, lock_days, amount, timestamp)
+}
+
+// Creates a deposit without the amount limits, for stake that is not new to
+// the pool: restaked rewards and imported positions.
+pub(crate) fn record_deposit(
+    principal: Principal,
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+    timestamp: u64,
 ) -> Result<Deposit, DepositError> {
     if !VALID_LOCKS.contains(&lock_days) {
         return Err(DepositError::InvalidLockPeriod);
@@ -95,11 +457,8 @@ fn deposit_internal(
         subaccount,
     };
 
-    let id = DEPOSIT_ID_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        *c
-    });
+    let id = deposit_id_counter() + 1;
+    set_deposit_id_counter(id);
 
     let deposit = Deposit {
         id,
@@ -108,23 +467,143 @@ fn deposit_internal(
         lock_period_days: lock_days,
     };
 
-    DEPOSIT_MAP.with(|map| {
-        let mut m = map.borrow_mut();
-        let mut deposits = m.get(&key).unwrap_or(DepositList(vec![]));
-        deposits.0.push(deposit.clone());
-        m.insert(key.clone(), deposits);
+    DEPOSITS.with(|m| {
+        m.borrow_mut()
+            .insert(DepositKey::new(&key, id), deposit.clone())
     });
 
+    analytics::record_depositor(principal, timestamp);
+    accrual::register(principal, &deposit, timestamp);
+    epochs::record_deposit(id, amount, timestamp);
+    receipts::issue(&key, id, amount);
+
     // Update cumulative stake per user subaccount
     STAKE_BALANCE_MAP.with(|map| {
         let mut store = map.borrow_mut();
         let current = store.get(&key).unwrap_or(0);
         store.insert(key.clone(), current + amount);
     });
+    certification::touch(&key);
+    loyalty::touch(principal, timestamp);
+
+    events::record(
+        timestamp,
+        events::EventKind::Deposited {
+            key,
+            deposit_id: id,
+            amount,
+        },
+    );
+
+    Ok(deposit)
+}
 
+/// Deposits of `user`, oldest first.
+pub(crate) fn deposits_of(user: &UserKey) -> Vec<Deposit> {
+    DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of(user))
+            .map(|(_, d)| d)
+            .collect()
+    })
+}
+
+fn find_deposit(user_key: &UserKey, deposit_id: u64) -> Result<Deposit, DepositError> {
+    DEPOSITS
+        .with(|m| m.borrow().get(&DepositKey::new(user_key, deposit_id)))
+        .ok_or(DepositError::NoDepositFound)
+}
+
+// Applies `change` to a deposit in place and re-weighs its reward shares.
+// Nothing is written if `change` fails.
+pub(crate) fn modify_deposit(
+    user_key: &UserKey,
+    deposit_id: u64,
+    now: u64,
+    change: impl FnOnce(&mut Deposit) -> Result<(), DepositError>,
+) -> Result<Deposit, DepositError> {
+    let mut deposit = find_deposit(user_key, deposit_id)?;
+    change(&mut deposit)?;
+    DEPOSITS.with(|m| {
+        m.borrow_mut()
+            .insert(DepositKey::new(user_key, deposit_id), deposit.clone())
+    });
+    accrual::resize(user_key, &deposit, now)?;
+    loyalty::touch(user_key.principal, now);
     Ok(deposit)
 }
 
+// Puts a deposit whose withdrawal was refused by the ledger back into the
+// user's list and stake balance.
+fn restore_deposit(user_key: &UserKey, deposit: Deposit, now: u64) {
+    receipts::issue(user_key, deposit.id, deposit.amount);
+    insert_deposit(user_key, deposit, now);
+}
+
+// Puts `amount` withdrawn from `deposit` back after the ledger refused the
+// transfer. A deposit withdrawn in full since is reopened with `amount`.
+fn restore_withdrawn_part(user_key: &UserKey, deposit: &Deposit, amount: u64, now: u64) {
+    if find_deposit(user_key, deposit.id).is_err() {
+        let reopened = Deposit {
+            amount,
+            ..deposit.clone()
+        };
+        restore_deposit(user_key, reopened, now);
+        return;
+    }
+    modify_deposit(user_key, deposit.id, now, |d| {
+        d.amount += amount;
+        Ok(())
+    })
+    .expect("Failed to restore withdrawn amount");
+    receipts::issue(user_key, deposit.id, amount);
+    STAKE_BALANCE_MAP.with(|map| {
+        let mut m = map.borrow_mut();
+        let current = m.get(user_key).unwrap_or(0);
+        m.insert(user_key.clone(), current + amount);
+    });
+    certification::touch(user_key);
+}
+
+// Adds an existing deposit to the user's list and stake balance, accruing
+// rewards from `now`.
+fn insert_deposit(user_key: &UserKey, deposit: Deposit, now: u64) {
+    DEPOSITS.with(|m| {
+        m.borrow_mut()
+            .insert(DepositKey::new(user_key, deposit.id), deposit.clone())
+    });
+    STAKE_BALANCE_MAP.with(|map| {
+        let mut m = map.borrow_mut();
+        let current = m.get(user_key).unwrap_or(0);
+        m.insert(user_key.clone(), current + deposit.amount);
+    });
+    certification::touch(user_key);
+    accrual::register(user_key.principal, &deposit, now);
+    loyalty::touch(user_key.principal, now);
+}
+
+// Removes the deposit from the user's list and deducts it from their stake
+// balance. Rewards the deposit accrued move to the user's pending rewards.
+fn remove_deposit(user_key: &UserKey, deposit_id: u64, now: u64) -> Result<Deposit, DepositError> {
+    let removed = DEPOSITS
+        .with(|m| {
+            m.borrow_mut()
+                .remove(&DepositKey::new(user_key, deposit_id))
+        })
+        .ok_or(DepositError::NoDepositFound)?;
+
+    STAKE_BALANCE_MAP.with(|map| {
+        let mut m = map.borrow_mut();
+        let current = m.get(user_key).unwrap_or(0);
+        m.insert(user_key.clone(), current.saturating_sub(removed.amount));
+    });
+    certification::touch(user_key);
+    accrual::release(user_key, deposit_id, now)?;
+    loyalty::touch(user_key.principal, now);
+
+    Ok(removed)
+}
+
 fn withdraw_internal(
     principal: Principal,
     subaccount: Subaccount,
@@ -136,119 +615,282 @@ fn withdraw_internal(
         subaccount,
     };
 
-    // Get deposits list
-    let deposit_list_opt = DEPOSIT_MAP.with(|map| map.borrow().get(&user_key));
-
-    // Validate deposit exists
-    let mut deposit_list = match deposit_list_opt {
-        Some(list) => list,
-        None => return Err(DepositError::NoDepositFound),
-    };
-
-    // Find the deposit by ID
-    let position = deposit_list.0.iter().position(|d| d.id == deposit_id);
-    let deposit = match position {
-        Some(pos) => &deposit_list.0[pos],
-        None => return Err(DepositError::NoDepositFound),
-    };
-
     // Check lock expiry
-    let unlock_time = deposit.timestamp + (deposit.lock_period_days as u64 * 86400);
-    if now < unlock_time {
+    let deposit = find_deposit(&user_key, deposit_id)?;
+    if now < deposit.unlock_time() {
         return Err(DepositError::LockPeriodNotExpired);
     }
+    receipts::ensure_redeemable(&user_key, deposit_id, deposit.amount)?;
 
-    // Remove deposit and update state
-    let withdrawn = deposit_list.0.remove(position.unwrap());
+    let withdrawn = remove_deposit(&user_key, deposit_id, now)?;
+    receipts::redeem(&user_key, deposit_id, withdrawn.amount);
 
-    DEPOSIT_MAP.with(|map| {
-        map.borrow_mut().insert(user_key.clone(), deposit_list);
-    });
+    events::record(
+        now,
+        events::EventKind::Withdrawn {
+            key: user_key,
+            deposit_id,
+            amount: withdrawn.amount,
+        },
+    );
 
+    Ok(withdrawn.amount)
+}
+
+// Takes `amount` out of a matured deposit, leaving at least the configured
+// minimum stake in it. Returns the amount left in the deposit.
+fn withdraw_partial_internal(
+    principal: Principal,
+    subaccount: Subaccount,
+    deposit_id: u64,
+    amount: u64,
+    now: u64,
+) -> Result<u64, DepositError> {
+    let user_key = UserKey {
+        principal,
+        subaccount,
+    };
+
+    receipts::ensure_redeemable(&user_key, deposit_id, amount)?;
+    let remaining = modify_deposit(&user_key, deposit_id, now, |deposit| {
+        if now < deposit.unlock_time() {
+            return Err(DepositError::LockPeriodNotExpired);
+        }
+        if amount == 0 || amount >= deposit.amount {
+            return Err(DepositError::InvalidArgument(
+                "amount must be positive and less than the deposit; use withdraw_funds to withdraw it in full"
+                    .to_string(),
+            ));
+        }
+        let minimum = config::get().min_stake;
+        if deposit.amount - amount < minimum {
+            return Err(DepositError::BelowMinimumStake { minimum });
+        }
+        deposit.amount -= amount;
+        Ok(())
+    })?;
     STAKE_BALANCE_MAP.with(|map| {
         let mut m = map.borrow_mut();
         let current = m.get(&user_key).unwrap_or(0);
-        m.insert(user_key.clone(), current.saturating_sub(withdrawn.amount));
+        m.insert(user_key.clone(), current.saturating_sub(amount));
     });
+    certification::touch(&user_key);
+    receipts::redeem(&user_key, deposit_id, amount);
+
+    events::record(
+        now,
+        events::EventKind::Withdrawn {
+            key: user_key,
+            deposit_id,
+            amount,
+        },
+    );
+
+    Ok(remaining.amount)
+}
 
-    Ok(withdrawn.amount)
+// Withdraws a deposit before its lock ends, keeping the penalty given by the
+// configured curve in the pool. Returns (payout, penalty).
+fn early_withdraw_internal(
+    principal: Principal,
+    subaccount: Subaccount,
+    deposit_id: u64,
+    now: u64,
+) -> Result<(u64, u64), DepositError> {
+    let user_key = UserKey {
+        principal,
+        subaccount,
+    };
+
+    let deposit = find_deposit(&user_key, deposit_id)?;
+    let preview = penalty::preview(&deposit, now, &config::get().early_exit_penalty);
+    receipts::ensure_redeemable(&user_key, deposit_id, deposit.amount)?;
+    remove_deposit(&user_key, deposit_id, now)?;
+    receipts::redeem(&user_key, deposit_id, deposit.amount);
+
+    events::record(
+        now,
+        events::EventKind::EarlyWithdrawn {
+            key: user_key,
+            deposit_id,
+            amount: deposit.amount,
+            penalty: preview.penalty,
+        },
+    );
+
+    Ok((preview.payout, preview.penalty))
+}
+
+// Pays `amount` out of the pool's main account, of which `fee` (from
+// `ledger::payout_fee`) goes to the ledger, so the pool parts with exactly
+// `amount`. Returns the ledger block of the transfer.
+async fn transfer_to_user(
+    ledger: Principal,
+    principal: Principal,
+    subaccount: Subaccount,
+    amount: u64,
+    fee: u64,
+) -> Result<u64, DepositError> {
+    let to_account = Account {
+        owner: principal,
+        subaccount: Some(subaccount.0),
+    };
+    transfer_to_account(ledger, to_account, amount, fee).await
+}
+
+async fn transfer_to_account(
+    ledger: Principal,
+    to: Account,
+    amount: u64,
+    fee: u64,
+) -> Result<u64, DepositError> {
+    let transfer_arg = TransferArg {
+        to,
+        amount: (amount - fee).into(),
+        fee: Some(fee.into()),
+        memo: None,
+        created_at_time: None,
+        from_subaccount: None,
+    };
+
+    let (transfer_res,): (Result<Nat, TransferError>,) =
+        circuit_breaker::observe(call(ledger, "icrc1_transfer", (transfer_arg,)).await)
+            .map_err(|e| PoolError::LedgerError(LedgerError::Unreachable(format!("{:?}", e))))?;
+
+    if let Err(TransferError::BadFee { expected_fee }) = &transfer_res {
+        ledger::note_fee(ledger, expected_fee, now_secs());
+    }
+    Ok(transfer_res
+        .map(block_index)
+        .map_err(|e| PoolError::LedgerError(LedgerError::Transfer(e)))?)
+}
+
+// Looks up the fee and pays `amount` out net of it, for callers that undo
+// their bookkeeping on any error. Returns the ledger block and the amount
+// received.
+async fn transfer_net(
+    ledger: Principal,
+    principal: Principal,
+    subaccount: Subaccount,
+    amount: u64,
+) -> Result<(u64, u64), DepositError> {
+    let to = Account {
+        owner: principal,
+        subaccount: Some(subaccount.0),
+    };
+    transfer_net_to(ledger, to, amount).await
 }
 
-async fn reward_pool_internal(caller: Principal, amount: u64) -> Result<bool, DepositError> {
+async fn transfer_net_to(
+    ledger: Principal,
+    to: Account,
+    amount: u64,
+) -> Result<(u64, u64), DepositError> {
+    let fee = ledger::payout_fee(ledger, amount).await?;
+    let block = transfer_to_account(ledger, to, amount, fee).await?;
+    Ok((block, amount - fee))
+}
+
+/// Fails unless `destination` may receive a withdrawal from the pool
+/// `pool`: not the pool itself, the anonymous principal or a denylisted
+/// principal.
+pub(crate) fn check_destination(
+    destination: &Account,
+    pool: Principal,
+) -> Result<(), DepositError> {
+    if destination.owner == pool
+        || destination.owner == Principal::anonymous()
+        || denylist::contains(destination.owner)
+    {
+        return Err(DepositError::InvalidDestination);
+    }
+    Ok(())
+}
+
+async fn reward_pool_internal(
+    caller: Principal,
+    round_id: u64,
+    amount: u64,
+) -> Result<RewardDistributionReport, DepositError> {
+    // Rewards with no one to go to are not taken in at all
+    distribution::ensure_stakers()?;
     // 1. Transfer full reward from caller to canister
     let from = Account {
         owner: caller,
         subaccount: None,
     };
-    let to = Account {
-        owner: ic_cdk::id(), // need to check ledger id and replace it
+    let block = pull_funds(ledger::ledger_id(), from, amount).await?;
+    transactions::record(
+        now_secs(),
+        caller,
+        transactions::TransactionKind::RewardDistribution,
+        None,
+        amount,
+        Some(block),
+    );
+    price_oracle::record_snapshot(now_secs(), caller, amount);
+
+    // A job only fails before it allocates anything, such as when the last
+    // staker left while the funds were pulled. The pool holds the funds by
+    // now, so they go to the next distribution.
+    distribution::drive(DistributionJob::new(round_id, amount))
+        .inspect_err(|_| distribution::carry_over(amount))
+}
+
+// ICRC ledgers return block indexes as `nat`.
+pub(crate) fn block_index(index: Nat) -> u64 {
+    index.0.try_into().unwrap_or(u64::MAX)
+}
+
+// Moves `amount` from `from` to the pool's main account under the pool's
+// ICRC-2 allowance. The fee is charged to `from` on top of `amount`, so the
+// pool receives all of it. The allowance and balance are checked first, so a
+// short one is reported precisely. Returns the ledger block of the transfer.
+pub(crate) async fn pull_funds(
+    ledger: Principal,
+    from: Account,
+    amount: u64,
+) -> Result<u64, PoolError> {
+    let fee = ledger::fee(ledger).await?;
+    let required = amount.saturating_add(fee);
+    ledger::ensure_pullable(ledger, from, required).await?;
+    let to_account = Account {
+        owner: ic_cdk::id(),
         subaccount: None,
     };
 
     let transfer_args = TransferFromArgs {
         from,
-        to,
+        to: to_account,
         amount: amount.into(),
         spender_subaccount: None,
-        fee: None,
+        fee: Some(fee.into()),
         memo: None,
         created_at_time: None,
     };
 
-    let (res,): (Result<u64, String>,) = call(
-        Principal::from_text("icrc2_ledger").unwrap(),
-        "icrc2_transfer_from",
-        (transfer_args,),
-    )
-    .await
-    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-
-    res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-
-    // 2. Total stake amount
-    let total_stake: u128 =
-        STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s as u128).sum());
-
-    if total_stake == 0 {
-        return Err(DepositError::NoStakerFound);
-    }
-
-    // 3. Sequentially transfer proportional reward to each staker
-    let stake_data: Vec<(UserKey, u64)> =
-        STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(k, v)| (k.clone(), v)).collect());
+    let (res,): (Result<Nat, TransferFromError>,) =
+        circuit_breaker::observe(call(ledger, "icrc2_transfer_from", (transfer_args,)).await)
+            .map_err(|e| PoolError::LedgerError(LedgerError::Unreachable(format!("{:?}", e))))?;
 
-    for (key, stake) in stake_data {
-        let reward = (stake as u128 * amount as u128) / total_stake;
-        if reward == 0 {
-            continue;
+    res.map(block_index).map_err(|e| match e {
+        TransferFromError::InsufficientAllowance { allowance } => {
+            PoolError::InsufficientAllowance {
+                required,
+                allowance: u64::try_from(allowance.0).unwrap_or(u64::MAX),
+            }
         }
-
-        let to_account = Account {
-            owner: key.principal,
-            subaccount: Some(key.subaccount.0),
-        };
-
-        let transfer_arg = TransferArg {
-            to: to_account,
-            amount: (reward as u64).into(),
-            fee: None,
-            memo: None,
-            from_subaccount: None,
-            created_at_time: None,
-        };
-
-        let (res,): (Result<u64, String>,) = call(
-            Principal::from_text("icrc2_ledger").unwrap(),
-            "icrc1_transfer",
-            (transfer_arg,),
-        )
-        .await
-        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-
-        res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-    }
-
-    Ok(true)
+        TransferFromError::InsufficientFunds { balance } => PoolError::InsufficientFunds {
+            required,
+            available: u64::try_from(balance.0).unwrap_or(u64::MAX),
+        },
+        e => {
+            if let TransferFromError::BadFee { expected_fee } = &e {
+                ledger::note_fee(ledger, expected_fee, now_secs());
+            }
+            PoolError::LedgerError(LedgerError::TransferFrom(e))
+        }
+    })
 }
 
 /// Deposit funds into the stake pool and lock them for a given period of time. The funds are transferred from the user's subaccount to the stake pool's main account.
@@ -258,10 +900,29 @@ async fn reward_pool_internal(caller: Principal, amount: u64) -> Result<bool, De
 /// * `subaccount`: The subaccount from which the funds should be transferred.
 /// * `lock_days`: The number of days the funds should be locked.
 /// * `amount`: The amount of tokens to transfer.
+/// * `idempotency_key`: Optional key of up to 32 bytes. A retry with the same
+///   key within 24 hours returns the original deposit without pulling the
+///   tokens again.
+/// * `token`: Ledger of the tokens to deposit. `None` is the pool's primary
+///   ledger; other ledgers must be registered with `add_token`.
 ///
 /// # Errors
 ///
 /// * `DepositError::InvalidLockPeriod`: If the lock period is not 90, 180, or 360 days.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+/// * `DepositError::WithdrawalsOnly`: If the pool is low on cycles and only accepts withdrawals.
+/// * `DepositError::MemoryLimitReached`: If heap or stable memory usage reached the configured limits.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+/// * `DepositError::NotAllowlisted`: If the pool is private and the caller is not on its allowlist.
+/// * `DepositError::WrongShard`: If the caller's accounts live on a shard; deposit there instead.
+/// * `DepositError::InvalidArgument`: If the idempotency key is empty, too long or was used
+///   for a deposit with other terms.
+/// * `DepositError::OperationInProgress`: If a call with the same idempotency key is still running.
+/// * `DepositError::ConcurrentOperation`: If another deposit into the same subaccount is running.
+/// * `DepositError::AmountTooLow`: If `amount` is below the configured minimum deposit.
+/// * `DepositError::UserCapReached`: If the caller's stake would exceed the per-user cap.
+/// * `DepositError::PoolCapReached`: If the pool's total value locked would exceed its cap.
+/// * `DepositError::UnsupportedToken`: If `token` is not a registered ledger.
 /// * `DepositError::LedgerTransferFailed`: If the transfer failed for any reason.
 #[candid::candid_method(update)]
 #[ic_cdk::update]
@@ -269,91 +930,359 @@ pub async fn deposit_funds(
     subaccount: Subaccount,
     lock_days: u16,
     amount: u64,
+    idempotency_key: Option<Vec<u8>>,
+    token: Option<Principal>,
 ) -> Result<Deposit, DepositError> {
+    deposit_funds_v2(subaccount, lock_days, amount, idempotency_key, token)
+        .await
+        .map_err(DepositError::from)
+}
+
+/// Same as `deposit_funds`, with the failures of the transfer from the
+/// caller reported as typed errors.
+///
+/// # Errors
+///
+/// * `PoolError::InsufficientAllowance`: If the caller approved the pool for less than
+///   `amount` plus the ledger fee.
+/// * `PoolError::InsufficientFunds`: If the caller's subaccount holds less than that.
+/// * `PoolError::LedgerError`: If the ledger refused the transfer for another reason or
+///   could not be called.
+/// * `PoolError::Paused`: If a controller paused the pool.
+/// * `PoolError::Deposit`: Any other error of `deposit_funds`.
+#[candid::candid_method(update)]
+#[ic_cdk::update]
+pub async fn deposit_funds_v2(
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+    idempotency_key: Option<Vec<u8>>,
+    token: Option<Principal>,
+) -> Result<Deposit, PoolError> {
+    cycles::check_headroom();
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
     let caller = ic_cdk::caller();
-    let now = time() / 1_000_000_000;
-    // Step 1: Pull tokens from user's subaccount
-    let from_account = Account {
-        owner: caller,
-        subaccount: Some(subaccount.0),
+    denylist::ensure_not_denied(caller)?;
+    allowlist::ensure_allowed(caller)?;
+    let now = now_secs();
+    memory_guard::ensure_deposit_capacity(now)?;
+    tiers::ensure_open(lock_days)?;
+    if let Some(token) = token.filter(|t| !tokens::is_primary(*t)) {
+        if idempotency_key.is_some() {
+            return Err(DepositError::InvalidArgument(
+                "idempotency keys are only supported for the primary token".to_string(),
+            )
+            .into());
+        }
+        sharding::ensure_local(caller)?;
+        let key = tokens::TokenKey {
+            token,
+            principal: caller,
+            subaccount,
+        };
+        return Ok(tokens::deposit(key, lock_days, amount, now).await?);
+    }
+    let _guard = op_locks::OperationGuard::account(
+        &UserKey {
+            principal: caller,
+            subaccount,
+        },
+        now,
+    )?;
+    ensure_deposit_allowed(caller, amount)?;
+    let operation = match idempotency_key {
+        Some(key) => match dedup::begin(caller, subaccount, &key, lock_days, amount, now)? {
+            dedup::Begin::Replay(deposit) => return Ok(deposit),
+            dedup::Begin::Started(id) => Some(id),
+        },
+        None => None,
     };
-
-    let to_account = Account {
-        owner: ic_cdk::id(),
-        subaccount: None,
+    let deposit = match pull_deposit(caller, subaccount, lock_days, amount, now).await {
+        Ok(deposit) => deposit,
+        Err(e) => {
+            if let Some(id) = operation {
+                dedup::abandon(id);
+            }
+            return Err(e);
+        }
     };
+    if let Some(id) = operation {
+        dedup::complete(id, &deposit);
+    }
+    if config::get().neuron_staking_enabled {
+        neurons::stake_deposit(lock_days, amount).await;
+    }
+    Ok(deposit)
+}
 
-    let transfer_args = TransferFromArgs {
-        from: from_account,
-        to: to_account,
-        amount: amount.into(),
-        spender_subaccount: None,
-        fee: None,
-        memo: None,
-        created_at_time: None,
+// Pulls `amount` of the primary token from the caller's subaccount and
+// records it as a new deposit.
+pub(crate) async fn pull_deposit(
+    caller: Principal,
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+    now: u64,
+) -> Result<Deposit, PoolError> {
+    let from_account = Account {
+        owner: caller,
+        subaccount: Some(subaccount.0),
     };
-
-    let (res,): (Result<u64, String>,) = call(
-        Principal::from_text("icrc2_ledger").unwrap(), // need to check ledger id and replace it
-        "icrc2_transfer_from",
-        (transfer_args,),
-    )
-    .await
-    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-
-    res.map_err(DepositError::LedgerTransferFailed)?;
-    deposit_internal(caller, subaccount, lock_days, amount, now)
+    let block = pull_funds(ledger::ledger_id(), from_account, amount).await?;
+    let deposit = deposit_internal(caller, subaccount, lock_days, amount, now)?;
+    transactions::record(
+        now,
+        caller,
+        transactions::TransactionKind::Deposit,
+        Some(UserKey {
+            principal: caller,
+            subaccount,
+        }),
+        amount,
+        Some(block),
+    );
+    Ok(deposit)
 }
 
 /// Withdraw the deposit with the given ID. The deposit must have been created with `deposit_funds` and the lock period must have expired.
+/// Once the configured hourly outflow limit is reached, the withdrawal is
+/// queued instead and paid out by a timer; see `get_withdrawal_queue_position`.
 ///
 /// # Arguments
 ///
 /// * `subaccount`: The subaccount from which the deposit was created.
 /// * `deposit_id`: The ID of the deposit to withdraw.
+/// * `token`: Ledger the deposit was made in. `None` is the pool's primary ledger.
+/// * `destination`: Account to pay to, such as an exchange deposit address.
+///   `None` pays the subaccount the deposit was made from.
+///
+/// # Returns
+///
+/// * The amount received, net of the ledger fee, or the ID of the queued withdrawal request.
 ///
 /// # Errors
 ///
 /// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+/// * `DepositError::InvalidDestination`: If `destination` is the pool, the anonymous principal or a denylisted principal.
+/// * `DepositError::ConcurrentOperation`: If another withdrawal of the deposit is running.
+/// * `DepositError::OperationInProgress`: If a partial withdrawal from the deposit is pending.
 /// * `DepositError::LockPeriodNotExpired`: If the lock period has not expired.
+/// * `DepositError::InsufficientBalance`: If the deposit's receipt tokens are no longer in the account.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * `DepositError::BelowLedgerFee`: If the deposit does not cover the ledger fee.
 /// * `DepositError::LedgerTransferFailed`: If the transfer failed for any reason.
 #[ic_cdk::update]
 #[candid::candid_method(update)]
-pub async fn withdraw_funds(subaccount: Subaccount, deposit_id: u64) -> Result<u64, DepositError> {
+pub async fn withdraw_funds(
+    subaccount: Subaccount,
+    deposit_id: u64,
+    token: Option<Principal>,
+    destination: Option<Account>,
+) -> Result<WithdrawalOutcome, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    if let Some(destination) = &destination {
+        check_destination(destination, ic_cdk::id())?;
+    }
     let principal = ic_cdk::caller();
-    let now = time() / 1_000_000_000;
-    let withdrawn_amount = withdraw_internal(principal, subaccount, deposit_id, now)?;
-    // Transfer funds back to user
-    let to_account = Account {
-        owner: principal,
-        subaccount: Some(subaccount.0),
+    if let Some(token) = token.filter(|t| !tokens::is_primary(*t)) {
+        let key = tokens::TokenKey {
+            token,
+            principal,
+            subaccount,
+        };
+        let amount = tokens::withdraw(key, deposit_id, now_secs(), destination).await?;
+        return Ok(WithdrawalOutcome::Paid { amount });
+    }
+    let key = UserKey {
+        principal,
+        subaccount,
     };
+    withdraw_deposit(key, deposit_id, destination).await
+}
 
-    let transfer_arg = TransferArg {
-        to: to_account,
-        amount: withdrawn_amount.into(),
-        fee: None,
-        memo: None,
-        created_at_time: None,
-        from_subaccount: None,
+// Records that the withdrawal of a deposit goes to another account.
+fn record_destination(key: &UserKey, deposit_id: u64, destination: Option<Account>, now: u64) {
+    if let Some(destination) = destination {
+        events::record(
+            now,
+            events::EventKind::WithdrawnTo {
+                key: key.clone(),
+                deposit_id,
+                destination,
+            },
+        );
+    }
+}
+
+// Withdraws a matured primary-token deposit of `key` to `destination`, or
+// to `key` when `None`, or queues the withdrawal once the hourly outflow
+// limit is reached.
+pub(crate) async fn withdraw_deposit(
+    key: UserKey,
+    deposit_id: u64,
+    destination: Option<Account>,
+) -> Result<WithdrawalOutcome, DepositError> {
+    let UserKey {
+        principal,
+        subaccount,
+    } = key.clone();
+    let deposit = find_deposit(&key, deposit_id)?;
+    let now = now_secs();
+    let _guard = op_locks::OperationGuard::deposit(deposit_id, now)?;
+    pending_withdrawals::ensure_none(deposit_id)?;
+    if withdrawal_queue::must_queue(deposit.amount, now) {
+        let amount = withdraw_internal(principal, subaccount, deposit_id, now)?;
+        record_destination(&key, deposit_id, destination, now);
+        let source = WithdrawalSource::Deposit { deposit_id };
+        let request_id = withdrawal_queue::enqueue(key, source, amount, now, destination);
+        return Ok(WithdrawalOutcome::Queued { request_id });
+    }
+    let fee = ledger::payout_fee(ledger::ledger_id(), deposit.amount).await?;
+    withdraw_internal(principal, subaccount, deposit_id, now_secs())?;
+    record_destination(&key, deposit_id, destination, now_secs());
+    withdrawal_queue::record_outflow(deposit.amount, now_secs());
+    // Transfer funds to the user; the deposit stays pending until it settles
+    let pending = pending_withdrawals::mark(key, deposit, Part::Whole, fee, time(), destination);
+    let amount = pending_withdrawals::execute(pending, principal).await?;
+    Ok(WithdrawalOutcome::Paid { amount })
+}
+
+/// Withdraw part of a matured deposit. The rest stays in the deposit and
+/// keeps earning rewards. The ledger fee is deducted from `amount`.
+///
+/// # Arguments
+///
+/// * `subaccount`: The subaccount from which the deposit was created.
+/// * `deposit_id`: The ID of the deposit to withdraw from.
+/// * `amount`: The amount to withdraw.
+///
+/// # Returns
+///
+/// * The amount left in the deposit.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+/// * `DepositError::ConcurrentOperation`: If another withdrawal of the deposit is running.
+/// * `DepositError::OperationInProgress`: If an earlier withdrawal from the deposit is pending.
+/// * `DepositError::LockPeriodNotExpired`: If the lock period has not expired.
+/// * `DepositError::InsufficientBalance`: If the deposit's receipt tokens are no longer in the account.
+/// * `DepositError::InvalidArgument`: If `amount` is zero or not less than the deposit.
+/// * `DepositError::BelowMinimumStake`: If less than the configured minimum stake would remain.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * `DepositError::BelowLedgerFee`: If `amount` does not cover the ledger fee.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed. A refused transfer
+///   puts `amount` back in the deposit; otherwise it stays pending for `retry_withdrawal`.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn withdraw_partial(
+    subaccount: Subaccount,
+    deposit_id: u64,
+    amount: u64,
+) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let principal = ic_cdk::caller();
+    let key = UserKey {
+        principal,
+        subaccount,
     };
+    let _guard = op_locks::OperationGuard::deposit(deposit_id, now_secs())?;
+    pending_withdrawals::ensure_none(deposit_id)?;
+    let fee = ledger::payout_fee(ledger::ledger_id(), amount).await?;
+    let deposit = find_deposit(&key, deposit_id)?;
+    let remaining =
+        withdraw_partial_internal(principal, subaccount, deposit_id, amount, now_secs())?;
+    withdrawal_queue::record_outflow(amount, now_secs());
+    // The withdrawn part stays pending until the transfer settles
+    let part = Part::Partial(amount);
+    let pending = pending_withdrawals::mark(key, deposit, part, fee, time(), None);
+    pending_withdrawals::execute(pending, principal).await?;
+    Ok(remaining)
+}
 
-    let (transfer_res,): (Result<u64, TransferError>,) = call(
-        Principal::from_text("icrc2_ledger").unwrap(), // need to check ledger id and replace it
-        "icrc1_transfer",
-        (transfer_arg,),
-    )
-    .await
-    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+/// Withdraw a deposit before its lock period has expired. A penalty that
+/// decays linearly with the time already served (see `preview_withdraw`) is
+/// kept by the pool; a matured deposit is withdrawn without penalty.
+///
+/// # Arguments
+///
+/// * `subaccount`: The subaccount from which the deposit was created.
+/// * `deposit_id`: The ID of the deposit to withdraw.
+///
+/// # Returns
+///
+/// * The amount received after the penalty and the ledger fee.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+/// * `DepositError::ConcurrentOperation`: If another withdrawal of the deposit is running.
+/// * `DepositError::OperationInProgress`: If an earlier withdrawal from the deposit is pending.
+/// * `DepositError::InsufficientBalance`: If the deposit's receipt tokens are no longer in the account.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+/// * `DepositError::BelowLedgerFee`: If the payout does not cover the ledger fee.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed. A refused transfer
+///   returns the deposit; otherwise it stays pending for `retry_withdrawal`.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn early_withdraw(subaccount: Subaccount, deposit_id: u64) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let principal = ic_cdk::caller();
+    let now = now_secs();
+    let key = UserKey {
+        principal,
+        subaccount,
+    };
+    let deposit = find_deposit(&key, deposit_id)?;
+    let _guard = op_locks::OperationGuard::deposit(deposit_id, now)?;
+    pending_withdrawals::ensure_none(deposit_id)?;
+    let preview = penalty::preview(&deposit, now, &config::get().early_exit_penalty);
+    let fee = ledger::payout_fee(ledger::ledger_id(), preview.payout).await?;
+    let deposit = find_deposit(&key, deposit_id)?;
+    let (_, penalty) = early_withdraw_internal(principal, subaccount, deposit_id, now)?;
+    // The deposit stays pending, and the penalty unbooked, until the
+    // transfer settles
+    let part = Part::Early { penalty };
+    let pending = pending_withdrawals::mark(key, deposit, part, fee, time(), None);
+    pending_withdrawals::execute(pending, principal).await
+}
 
-    transfer_res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-    Ok(withdrawn_amount)
+/// Shows what withdrawing a deposit right now would pay out, including the
+/// early-exit penalty if the lock period has not expired yet.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn preview_withdraw(
+    subaccount: Subaccount,
+    deposit_id: u64,
+) -> Result<penalty::WithdrawPreview, DepositError> {
+    let user_key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    let deposit = find_deposit(&user_key, deposit_id)?;
+    Ok(penalty::preview(
+        &deposit,
+        now_secs(),
+        &config::get().early_exit_penalty,
+    ))
 }
 
 /// Distributes a specified reward amount proportionally among all stakers
 /// in the stake pool. The reward is transferred from the caller's account
 /// to the canister's account and then distributed based on each staker's
-/// stake proportion.
+/// stake proportion, weighted by how long it was staked since the previous
+/// distribution. The rounding remainder is carried into the next
+/// distribution; see `get_reward_residual`. A round with more stakers than
+/// one message can handle finishes in later messages, and reports
+/// `complete = false` until then.
 ///
 /// # Arguments
 ///
@@ -361,25 +1290,88 @@ pub async fn withdraw_funds(subaccount: Subaccount, deposit_id: u64) -> Result<u
 ///
 /// # Returns
 ///
-/// * `Ok(true)`: If the reward distribution was successful.
-/// * `Err(DepositError)`: If there was an error during the reward transfer or distribution.
+/// * A report of the amounts allocated and of the accounts settled right
+///   away, including any whose settlement failed.
 ///
 /// # Errors
 ///
 /// * `DepositError::LedgerTransferFailed`: If the transfer of the reward
 ///   from the caller's account to the canister fails.
 /// * `DepositError::NoStakerFound`: If there are no stakers in the pool to distribute the reward.
+/// * `DepositError::WithdrawalsOnly`: If the pool is too low on cycles to safely run a distribution.
+/// * `DepositError::DistributionInProgress`: If another distribution round has not finished yet.
 
 #[ic_cdk::update]
 #[candid::candid_method(update)]
-pub async fn reward_pool(amount: u64) -> Result<bool, DepositError> {
+pub async fn reward_pool(amount: u64) -> Result<RewardDistributionReport, DepositError> {
+    cycles::check_headroom();
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::RewardDistribution)?;
+    let round_id = distribution::begin_round()?;
     let caller = ic_cdk::caller();
-    let result = reward_pool_internal(caller, amount).await;
+    let start = metrics::instructions();
+    let result = reward_pool_internal(caller, round_id, amount).await;
+    metrics::record("reward_pool", metrics::instructions().saturating_sub(start));
+    if result.is_err() {
+        distribution::end_round();
+    }
     result
 }
 
+/// Runs a distribution round for `amount` tokens the pool already holds, such
+/// as harvested neuron maturity.
+pub(crate) fn distribute_held_funds(amount: u64) -> Result<RewardDistributionReport, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::RewardDistribution)?;
+    let round_id = distribution::begin_round()?;
+    let result = distribution::drive(DistributionJob::new(round_id, amount));
+    if result.is_ok() {
+        transactions::record(
+            now_secs(),
+            ic_cdk::id(),
+            transactions::TransactionKind::RewardDistribution,
+            None,
+            amount,
+            None,
+        );
+        price_oracle::record_snapshot(now_secs(), ic_cdk::id(), amount);
+    }
+    result
+}
+
+// Cuts `amount` from every deposit in proportion to its size, along with the
+// stake balances and reward shares of their accounts.
+fn slash_deposits(amount: u64, now: u64) {
+    let deposits: Vec<(DepositKey, u64)> =
+        DEPOSITS.with(|m| m.borrow().iter().map(|(k, d)| (k, d.amount)).collect());
+    let total_stake = deposits
+        .iter()
+        .map(|(_, stake)| *stake as u128)
+        .sum::<u128>()
+        .max(1);
+    for (key, stake) in deposits {
+        let cut = (stake as u128 * amount as u128 / total_stake) as u64;
+        if cut == 0 {
+            continue;
+        }
+        modify_deposit(&key.user, key.deposit_id, now, |d| {
+            d.amount -= cut;
+            Ok(())
+        })
+        .expect("Failed to slash deposit");
+        STAKE_BALANCE_MAP.with(|map| {
+            let mut m = map.borrow_mut();
+            let current = m.get(&key.user).unwrap_or(0);
+            m.insert(key.user.clone(), current.saturating_sub(cut));
+        });
+        certification::touch(&key.user);
+    }
+}
+
 /// Slash a specified amount of tokens from all stakers in the stake pool.
-/// The slashed tokens are transferred to the given receiver.
+/// The slashed tokens are transferred to the given receiver, net of the
+/// ledger fee. Deposits are only cut once the transfer succeeded. Only
+/// controllers may call this.
 ///
 /// # Arguments
 ///
@@ -393,54 +1385,30 @@ pub async fn reward_pool(amount: u64) -> Result<bool, DepositError> {
 ///
 /// # Errors
 ///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
 /// * `DepositError::NoDepositFound`: If there are no stakers in the pool to slash.
+/// * `DepositError::WithdrawalsOnly` / `DepositError::Migrated`: If the pool is not active.
+/// * `DepositError::BelowLedgerFee`: If `amount` does not cover the ledger fee.
 /// * `DepositError::LedgerTransferFailed`: If the transfer of the slashed tokens fails.
 #[ic_cdk::update]
 #[candid::candid_method(update)]
 pub async fn slash_pool(amount: u64, receiver: UserKey) -> Result<bool, DepositError> {
-    let total_stake: u128 =
-        STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s as u128).sum());
-
-    if total_stake == 0 {
+    crate::ensure_controller(ic_cdk::caller())?;
+    status::ensure_active()?;
+    let total_stake = || -> u128 {
+        STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s as u128).sum())
+    };
+    if total_stake() == 0 {
         return Err(DepositError::NoDepositFound);
     }
 
-    let stake_data: Vec<(UserKey, u64)> =
-        STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(k, v)| (k.clone(), v)).collect());
-
-    STAKE_BALANCE_MAP.with(|map| {
-        let mut store = map.borrow_mut();
-        for (key, stake) in &stake_data {
-            let slash_amt = (*stake as u128 * amount as u128) / total_stake;
-            let current = *stake;
-            let updated = current.saturating_sub(slash_amt as u64);
-            store.insert(key.clone(), updated);
-        }
-    });
-
-    let receiver_account = Account {
-        owner: receiver.principal,
-        subaccount: Some(receiver.subaccount.0),
-    };
+    let ledger = ledger::ledger_id();
+    let fee = ledger::payout_fee(ledger, amount).await?;
+    transfer_to_user(ledger, receiver.principal, receiver.subaccount, amount, fee).await?;
 
-    let transfer_arg = TransferArg {
-        to: receiver_account,
-        amount: amount.into(),
-        fee: None,
-        memo: None,
-        from_subaccount: None,
-        created_at_time: None,
-    };
-
-    let (res,): (Result<u64, String>,) = call(
-        Principal::from_text("icrc2_ledger").unwrap(),
-        "icrc1_transfer",
-        (transfer_arg,),
-    )
-    .await
-    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
-
-    res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    // Stakes may have changed while the transfer was in flight, even to none.
+    slash_deposits(amount, now_secs());
+    events::record(now_secs(), events::EventKind::PoolSlashed { amount });
 
     Ok(true)
 }
@@ -456,11 +1424,10 @@ pub async fn slash_pool(amount: u64, receiver: UserKey) -> Result<bool, DepositE
 pub fn get_deposits_by_user() -> Vec<(Subaccount, Deposit)> {
     let caller = ic_cdk::caller();
 
-    DEPOSIT_MAP.with(|map| {
-        map.borrow()
-            .iter()
-            .filter(|(key, _)| key.principal == caller)
-            .flat_map(|(key, list)| list.clone().0.into_iter().map(move |d| (key.subaccount, d)))
+    DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(caller))
+            .map(|(key, d)| (key.user.subaccount, d))
             .collect()
     })
 }
@@ -473,25 +1440,133 @@ pub fn get_deposits_by_user() -> Vec<(Subaccount, Deposit)> {
 ///
 /// # Returns
 ///
-/// * `u64`: The stake balance associated with the specified subaccount and caller principal.
-///   Returns 0 if no balance is found.
+/// * `CertifiedBalance`: The stake balance associated with the specified subaccount and caller
+///   principal as of the last certification, which may trail the live balance by a few seconds,
+///   with its path to the certified data and the certificate. The balance is 0 if none is found.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_stake_balance(subaccount: Subaccount) -> CertifiedBalance {
+    certification::certified_balance(UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
 
+/// Same as `get_stake_balance`, with the amount also rendered using the
+/// ledger's decimals and symbol.
 #[ic_cdk::query]
 #[candid::candid_method(query)]
-pub fn get_stake_balance(subaccount: Subaccount) -> u64 {
-    let principal = ic_cdk::caller();
+pub fn get_stake_balance_formatted(subaccount: Subaccount) -> ledger::FormattedAmount {
+    ledger::format_amount(get_stake_balance(subaccount).balance)
+}
+
+/// Same as `get_deposits_by_user`, with each deposit's unlock time, lock
+/// status and accrued rewards.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_deposit_views() -> Vec<(Subaccount, DepositView)> {
+    let now = now_secs();
+    get_deposits_by_user()
+        .into_iter()
+        .map(|(subaccount, deposit)| {
+            let accrued = accrual::accrued_of_deposit(deposit.id);
+            (subaccount, DepositView::new(deposit, accrued, now))
+        })
+        .collect()
+}
+
+/// Same as `get_deposits_by_user`, with each deposit amount also rendered
+/// using the ledger's decimals and symbol.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_deposits_by_user_formatted() -> Vec<(Subaccount, Deposit, ledger::FormattedAmount)> {
+    get_deposits_by_user()
+        .into_iter()
+        .map(|(subaccount, deposit)| {
+            let formatted = ledger::format_amount(deposit.amount);
+            (subaccount, deposit, formatted)
+        })
+        .collect()
+}
+
+/// The caller's wallet balance on the ledger together with their position in
+/// the pool for one subaccount.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FullBalance {
+    pub ledger_balance: candid::Nat,
+    pub stake: u64,
+    pub pending_rewards: u64,
+}
+
+/// Returns the caller's ledger balance, stake and pending compounding rewards
+/// for `subaccount` in a single composite query.
+///
+/// # Errors
+///
+/// * `DepositError::LedgerTransferFailed`: If the ledger balance could not be read.
+#[ic_cdk::query(composite = true)]
+#[candid::candid_method(composite_query)]
+pub async fn get_full_balance(subaccount: Subaccount) -> Result<FullBalance, DepositError> {
     let key = UserKey {
-        principal,
+        principal: ic_cdk::caller(),
         subaccount,
     };
-    STAKE_BALANCE_MAP.with(|map| map.borrow().get(&key).unwrap_or(0))
+    let stake = STAKE_BALANCE_MAP.with(|map| map.borrow().get(&key).unwrap_or(0));
+    let pending_rewards = accrual::claimable(&key);
+    let ledger_balance = ledger::balance_of(Account {
+        owner: key.principal,
+        subaccount: Some(key.subaccount.0),
+    })
+    .await?;
+    Ok(FullBalance {
+        ledger_balance,
+        stake,
+        pending_rewards,
+    })
 }
 
+// Collects every `candid_method` endpoint, so it must be declared after all
+// of them.
+mod interface;
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_ledger_error_replies_decode() {
+        // Replies as an ICRC ledger encodes them.
+        let reply = candid::encode_one(Result::<Nat, TransferError>::Err(
+            TransferError::InsufficientFunds {
+                balance: Nat::from(40u64),
+            },
+        ))
+        .unwrap();
+        assert!(candid::decode_args::<(Result<u64, String>,)>(&reply).is_err());
+        let (res,): (Result<Nat, TransferError>,) = candid::decode_args(&reply).unwrap();
+        assert_eq!(
+            DepositError::from(PoolError::LedgerError(LedgerError::Transfer(
+                res.unwrap_err()
+            ))),
+            DepositError::LedgerTransferFailed(
+                "InsufficientFunds { balance: Nat(40) }".to_string()
+            )
+        );
+
+        let reply = candid::encode_one(Result::<Nat, TransferFromError>::Err(
+            TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(5u64),
+            },
+        ))
+        .unwrap();
+        let (res,): (Result<Nat, TransferFromError>,) = candid::decode_args(&reply).unwrap();
+        assert!(matches!(
+            res,
+            Err(TransferFromError::InsufficientAllowance { .. })
+        ));
+    }
+
     #[test]
     fn test_deposit_validation() {
         let caller = Principal::anonymous();
@@ -512,6 +1587,31 @@ mod tests {
         assert_eq!(deposit2.id, 2);
     }
 
+    #[test]
+    fn test_deposit_view_lock_status() {
+        let deposit = Deposit {
+            id: 1,
+            amount: 500,
+            timestamp: 1_000,
+            lock_period_days: 90,
+        };
+        let unlock = 1_000 + 90 * 86400;
+
+        let view = DepositView::new(deposit.clone(), 7, 1_000 + 86400 + 1);
+        assert_eq!(view.unlock_timestamp, unlock);
+        assert!(!view.is_unlocked);
+        assert_eq!(view.days_remaining, 89);
+        assert_eq!(view.accrued_rewards, 7);
+
+        assert_eq!(
+            DepositView::new(deposit.clone(), 0, unlock - 1).days_remaining,
+            1
+        );
+        let matured = DepositView::new(deposit, 0, unlock + 86400);
+        assert!(matured.is_unlocked);
+        assert_eq!(matured.days_remaining, 0);
+    }
+
     #[test]
     fn test_withdraw_funds_success() {
         let principal = Principal::anonymous();
@@ -520,7 +1620,7 @@ mod tests {
         let current_time = 1_000_000_000;
         let timestamp = current_time - (100 * 86400); // 100 days ago
 
-        let deposit = deposit_internal(principal, sub.clone(), 90, 1_000_000, timestamp).unwrap();
+        let deposit = deposit_internal(principal, sub, 90, 1_000_000, timestamp).unwrap();
         assert_eq!(deposit.id, 1);
 
         let result = withdraw_internal(principal, sub, deposit.id, current_time);
@@ -536,8 +1636,7 @@ mod tests {
         let current_time = 1_000_000_000; // Mocked current time (in seconds)
 
         // Deposit just now, lock not expired
-        let deposit =
-            deposit_internal(principal, sub.clone(), 90, 2_000_000, current_time).unwrap();
+        let deposit = deposit_internal(principal, sub, 90, 2_000_000, current_time).unwrap();
 
         assert_eq!(deposit.id, 1);
 
@@ -546,6 +1645,95 @@ mod tests {
         assert_eq!(result, Err(DepositError::LockPeriodNotExpired));
     }
 
+    #[test]
+    fn test_early_withdraw_applies_penalty() {
+        let principal = Principal::anonymous();
+        let sub = Subaccount([5u8; 32]);
+
+        let current_time = 1_000_000_000;
+        let timestamp = current_time - (45 * 86400); // halfway through a 90 day lock
+
+        let deposit = deposit_internal(principal, sub, 90, 1_000_000, timestamp).unwrap();
+
+        let result = early_withdraw_internal(principal, sub, deposit.id, current_time);
+
+        assert_eq!(result, Ok((850_000, 150_000)));
+        assert_eq!(
+            withdraw_internal(principal, sub, deposit.id, current_time),
+            Err(DepositError::NoDepositFound)
+        );
+    }
+
+    #[test]
+    fn test_withdraw_partial_keeps_minimum_stake() {
+        let principal = Principal::anonymous();
+        let sub = Subaccount([6u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+
+        let current_time = 1_000_000_000;
+        let timestamp = current_time - (100 * 86400);
+        let deposit = deposit_internal(principal, sub, 90, 1_000_000, timestamp).unwrap();
+        config::set(config::PoolConfig {
+            min_stake: 300_000,
+            ..config::PoolConfig::default()
+        });
+
+        assert_eq!(
+            withdraw_partial_internal(principal, sub, deposit.id, 800_000, current_time),
+            Err(DepositError::BelowMinimumStake { minimum: 300_000 })
+        );
+        assert!(
+            withdraw_partial_internal(principal, sub, deposit.id, 1_000_000, current_time).is_err()
+        );
+        assert_eq!(
+            withdraw_partial_internal(principal, sub, deposit.id, 600_000, current_time),
+            Ok(400_000)
+        );
+        assert_eq!(find_deposit(&key, deposit.id).unwrap().amount, 400_000);
+        assert_eq!(
+            STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key)),
+            Some(400_000)
+        );
+        assert_eq!(accrual::state().total_shares, 400_000);
+    }
+
+    #[test]
+    fn test_deposit_limits_apply_per_principal() {
+        let principal = Principal::from_slice(&[42]);
+        config::set(config::PoolConfig {
+            min_deposit: 1_000,
+            max_deposit_per_user: Some(5_000),
+            ..config::PoolConfig::default()
+        });
+
+        assert_eq!(
+            deposit_internal(principal, Subaccount([1u8; 32]), 90, 999, 0),
+            Err(DepositError::AmountTooLow { minimum: 1_000 })
+        );
+        deposit_internal(principal, Subaccount([1u8; 32]), 90, 3_000, 0).unwrap();
+        assert_eq!(
+            deposit_internal(principal, Subaccount([2u8; 32]), 90, 2_001, 0),
+            Err(DepositError::UserCapReached { cap: 5_000 })
+        );
+        deposit_internal(principal, Subaccount([2u8; 32]), 90, 2_000, 0).unwrap();
+
+        config::set(config::PoolConfig {
+            max_total_value_locked: Some(6_000),
+            ..config::get()
+        });
+        let other = Principal::from_slice(&[43]);
+        assert_eq!(
+            deposit_internal(other, Subaccount([1u8; 32]), 90, 1_001, 0),
+            Err(DepositError::PoolCapReached { cap: 6_000 })
+        );
+        deposit_internal(other, Subaccount([1u8; 32]), 90, 1_000, 0).unwrap();
+        // Restaked rewards are not new stake and skip the limits.
+        assert!(record_deposit(principal, Subaccount([3u8; 32]), 90, 10, 0).is_ok());
+    }
+
     #[test]
     fn test_withdraw_funds_invalid_deposit_id() {
         let principal = Principal::anonymous();
@@ -555,7 +1743,7 @@ mod tests {
         let timestamp = current_time - (100 * 86400); // 100 days ago
         let invalid_id = 999;
 
-        let deposit = deposit_internal(principal, sub.clone(), 90, 3_000_000, timestamp).unwrap();
+        let deposit = deposit_internal(principal, sub, 90, 3_000_000, timestamp).unwrap();
 
         assert_eq!(deposit.id, 1);
 
@@ -564,6 +1752,79 @@ mod tests {
         assert_eq!(result, Err(DepositError::NoDepositFound));
     }
 
+    #[test]
+    fn test_hundreds_of_deposits_per_account() {
+        // The longest principal and the largest values a deposit can hold.
+        let principal = Principal::from_slice(&[0xff; 29]);
+        let sub = Subaccount([0xff; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+        let widest = Deposit {
+            id: u64::MAX,
+            amount: u64::MAX,
+            timestamp: u64::MAX,
+            lock_period_days: u16::MAX,
+        };
+        assert!(widest.to_bytes().len() <= Deposit::MAX_SIZE as usize);
+        assert!(DepositKey::new(&key, u64::MAX).to_bytes().len() <= DepositKey::MAX_SIZE as usize);
+
+        let ids: Vec<u64> = (0..300)
+            .map(|_| deposit_internal(principal, sub, 90, 10, 0).unwrap().id)
+            .collect();
+        assert_eq!(deposits_of(&key).len(), 300);
+        assert_eq!(principal_stake(principal), 3_000);
+        assert_eq!(
+            withdraw_internal(principal, sub, ids[150], 90 * 86400),
+            Ok(10)
+        );
+        assert_eq!(deposits_of(&key).len(), 299);
+        assert_eq!(find_deposit(&key, ids[299]).unwrap().id, ids[299]);
+    }
+
+    #[test]
+    fn test_slash_cuts_deposits_and_reward_shares() {
+        let alice = Principal::from_slice(&[150]);
+        let bob = Principal::from_slice(&[151]);
+        let sub = Subaccount([1; 32]);
+        let a = deposit_internal(alice, sub, 90, 300, 0).unwrap();
+        let b = deposit_internal(bob, sub, 90, 100, 0).unwrap();
+        let shares = accrual::state().total_shares;
+
+        slash_deposits(100, 10);
+        let key = |principal| UserKey {
+            principal,
+            subaccount: sub,
+        };
+        assert_eq!(find_deposit(&key(alice), a.id).unwrap().amount, 225);
+        assert_eq!(find_deposit(&key(bob), b.id).unwrap().amount, 75);
+        assert_eq!(principal_stake(alice), 225);
+        assert_eq!(principal_stake(bob), 75);
+        assert_eq!(accrual::state().total_shares, shares * 3 / 4);
+    }
+
+    #[test]
+    fn test_principal_ranges_hold_only_its_accounts() {
+        let principals = [&[140][..], &[140, 0], &[141]].map(Principal::from_slice);
+        for (i, principal) in principals.iter().enumerate() {
+            for sub in [0, 0xff] {
+                deposit_internal(*principal, Subaccount([sub; 32]), 90, 10 + i as u64, 0).unwrap();
+            }
+        }
+        for (i, principal) in principals.iter().enumerate() {
+            let deposits: Vec<Deposit> = DEPOSITS.with(|m| {
+                m.borrow()
+                    .range(DepositKey::range_of_principal(*principal))
+                    .map(|(_, d)| d)
+                    .collect()
+            });
+            assert_eq!(deposits.len(), 2);
+            assert!(deposits.iter().all(|d| d.amount == 10 + i as u64));
+            assert_eq!(principal_stake(*principal), 2 * (10 + i as u64));
+        }
+    }
+
     // #[tokio::test]
     // async fn test_reward_pool_distributes_proportionally() {
     //     // Setup: 2 stakers with 100 and 300 stake
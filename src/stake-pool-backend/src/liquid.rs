@@ -0,0 +1,382 @@
+// src/liquid.rs
+use crate::account_migration::rekey;
+use crate::circuit_breaker;
+use crate::error::DepositError;
+use crate::maintenance::{self, Operation};
+use crate::memory::{
+    get_memory, Memory, EXCHANGE_RATE_HISTORY_MEMORY_ID, LIQUID_BALANCES_MEMORY_ID,
+    LIQUID_STATE_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::withdrawal_queue::{self, WithdrawalRequestStatus, WithdrawalSource};
+use crate::{allowlist, denylist, ledger, sharding, status, UserKey};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call;
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Totals of the liquid staking pool. The stToken exchange rate is
+/// `total_underlying / total_supply`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct LiquidState {
+    pub total_underlying: u64,
+    pub total_supply: u64,
+}
+
+impl LiquidState {
+    /// stTokens minted for `amount` underlying tokens.
+    pub fn to_st(&self, amount: u64) -> u64 {
+        if self.total_supply == 0 || self.total_underlying == 0 {
+            return amount;
+        }
+        (amount as u128 * self.total_supply as u128 / self.total_underlying as u128) as u64
+    }
+
+    /// Underlying tokens owed for `st_amount` stTokens.
+    pub fn to_underlying(&self, st_amount: u64) -> u64 {
+        if self.total_supply == 0 {
+            return 0;
+        }
+        (st_amount as u128 * self.total_underlying as u128 / self.total_supply as u128) as u64
+    }
+}
+
+impl Storable for LiquidState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode LiquidState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode LiquidState")
+    }
+}
+
+/// Fixed-point scale of `ExchangeRate::rate_e8s`.
+const RATE_SCALE: u128 = 100_000_000;
+pub const MAX_RATE_HISTORY_PER_PAGE: u64 = 100;
+
+/// stToken exchange rate at a point in time.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExchangeRate {
+    pub timestamp: u64,
+    pub total_underlying: u64,
+    pub total_supply: u64,
+    /// Underlying tokens per stToken, scaled by 1e8.
+    pub rate_e8s: u64,
+}
+
+impl Storable for ExchangeRate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ExchangeRate"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ExchangeRate")
+    }
+}
+
+impl BoundedStorable for ExchangeRate {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl LiquidState {
+    pub fn exchange_rate(&self, timestamp: u64) -> ExchangeRate {
+        let rate_e8s = if self.total_supply == 0 {
+            RATE_SCALE as u64
+        } else {
+            (self.total_underlying as u128 * RATE_SCALE / self.total_supply as u128) as u64
+        };
+        ExchangeRate {
+            timestamp,
+            total_underlying: self.total_underlying,
+            total_supply: self.total_supply,
+            rate_e8s,
+        }
+    }
+}
+
+thread_local! {
+    // Keyed by timestamp; one entry per change of the rate.
+    static RATE_HISTORY: RefCell<StableBTreeMap<u64, ExchangeRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EXCHANGE_RATE_HISTORY_MEMORY_ID)));
+
+    static LIQUID_STATE: RefCell<StableCell<LiquidState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LIQUID_STATE_MEMORY_ID), LiquidState::default())
+            .expect("Failed to init liquid state cell"),
+    );
+
+    static ST_BALANCES: RefCell<StableBTreeMap<UserKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LIQUID_BALANCES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "liquid_state",
+            LIQUID_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "st_balances",
+            ST_BALANCES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "exchange_rate_history",
+            RATE_HISTORY.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn state() -> LiquidState {
+    LIQUID_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn set_state(state: LiquidState) {
+    crate::stats::invalidate();
+    LIQUID_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist liquid state");
+    });
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    ST_BALANCES.with(|m| rekey(&mut m.borrow_mut(), from, to, |a, b| a + b));
+}
+
+pub fn balances() -> Vec<(UserKey, u64)> {
+    ST_BALANCES.with(|m| m.borrow().iter().collect())
+}
+
+pub fn balance_of(key: &UserKey) -> u64 {
+    ST_BALANCES.with(|m| m.borrow().get(key).unwrap_or(0))
+}
+
+/// Credits `amount` underlying tokens to the liquid pool and mints the
+/// corresponding stTokens to `key`.
+pub fn mint(key: &UserKey, amount: u64) -> u64 {
+    let mut state = state();
+    let minted = state.to_st(amount);
+    state.total_underlying += amount;
+    state.total_supply += minted;
+    set_state(state);
+    ST_BALANCES.with(|m| {
+        let mut m = m.borrow_mut();
+        let balance = m.get(key).unwrap_or(0);
+        m.insert(key.clone(), balance + minted);
+    });
+    minted
+}
+
+/// Adds rewards earned by the liquid pool to its underlying total, raising the
+/// exchange rate, and records the new rate in the history.
+pub fn accrue_rewards(amount: u64, now: u64) {
+    let mut state = state();
+    state.total_underlying += amount;
+    let rate = state.exchange_rate(now);
+    set_state(state);
+    RATE_HISTORY.with(|h| h.borrow_mut().insert(now, rate));
+}
+
+pub fn rate_history(from: u64, limit: u64) -> Vec<ExchangeRate> {
+    RATE_HISTORY.with(|h| {
+        h.borrow()
+            .range(from..)
+            .take(limit as usize)
+            .map(|(_, r)| r)
+            .collect()
+    })
+}
+
+/// Burns `st_amount` of `key`'s stTokens and returns the underlying amount owed.
+pub fn burn(key: &UserKey, st_amount: u64) -> Result<u64, DepositError> {
+    let balance = balance_of(key);
+    if st_amount == 0 || st_amount > balance {
+        return Err(DepositError::InsufficientBalance);
+    }
+    let mut state = state();
+    let underlying = state.to_underlying(st_amount);
+    state.total_underlying -= underlying;
+    state.total_supply -= st_amount;
+    set_state(state);
+    ST_BALANCES.with(|m| m.borrow_mut().insert(key.clone(), balance - st_amount));
+    Ok(underlying)
+}
+
+/// Stake tokens in the liquid pool and receive stTokens at the current
+/// exchange rate.
+///
+/// # Returns
+///
+/// * The amount of stTokens minted.
+///
+/// # Errors
+///
+/// * `DepositError::WithdrawalsOnly` / `DepositError::Migrated`: If the pool is not active.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+/// * `DepositError::NotAllowlisted`: If the pool is private and the caller is not on its allowlist.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed for any reason.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn stake_liquid(subaccount: Subaccount, amount: u64) -> Result<u64, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    allowlist::ensure_allowed(caller)?;
+    sharding::ensure_local(caller)?;
+
+    let transfer_args = TransferFromArgs {
+        from: Account {
+            owner: caller,
+            subaccount: Some(subaccount.0),
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: amount.into(),
+        spender_subaccount: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (res,): (Result<Nat, TransferFromError>,) = circuit_breaker::observe(
+        call(ledger::ledger_id(), "icrc2_transfer_from", (transfer_args,)).await,
+    )
+    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+
+    let key = UserKey {
+        principal: caller,
+        subaccount,
+    };
+    Ok(mint(&key, amount))
+}
+
+/// Burn stTokens and queue the payout of the underlying tokens. The payout is
+/// made from liquid funds or dissolving neurons through the withdrawal queue.
+///
+/// # Returns
+///
+/// * The ID of the withdrawal request tracking the redemption.
+///
+/// # Errors
+///
+/// * `DepositError::InsufficientBalance`: If the caller holds fewer stTokens than `amount`.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn request_redeem(subaccount: Subaccount, amount: u64) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    let underlying = burn(&key, amount)?;
+    Ok(withdrawal_queue::enqueue(
+        key,
+        WithdrawalSource::Redemption { st_amount: amount },
+        underlying,
+        crate::now_secs(),
+        None,
+    ))
+}
+
+/// Returns the current stToken exchange rate.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_exchange_rate() -> ExchangeRate {
+    state().exchange_rate(crate::now_secs())
+}
+
+/// Returns up to `limit` (capped at 100) past exchange rates recorded at or
+/// after timestamp `from`, oldest first. A new entry is recorded whenever
+/// rewards accrue to the liquid pool.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_exchange_rate_history(from: u64, limit: u64) -> Vec<ExchangeRate> {
+    rate_history(from, limit.min(MAX_RATE_HISTORY_PER_PAGE))
+}
+
+/// Returns the stToken balance of the caller's subaccount.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_liquid_balance(subaccount: Subaccount) -> u64 {
+    balance_of(&UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+/// Returns the status of every redemption of the caller's subaccount.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_redemptions(subaccount: Subaccount) -> Vec<WithdrawalRequestStatus> {
+    let key = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount,
+    };
+    withdrawal_queue::requests_of(&key)
+        .into_iter()
+        .filter(|r| matches!(r.source, WithdrawalSource::Redemption { .. }))
+        .filter_map(|r| withdrawal_queue::request_status(r.id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_rewards_raise_rate_and_extend_history() {
+        let key = UserKey {
+            principal: Principal::anonymous(),
+            subaccount: Subaccount([4u8; 32]),
+        };
+        mint(&key, 1_000);
+        accrue_rewards(100, 10);
+        accrue_rewards(110, 20);
+
+        let rates: Vec<u64> = rate_history(0, 10).iter().map(|r| r.rate_e8s).collect();
+        assert_eq!(rates, vec![110_000_000, 121_000_000]);
+        assert_eq!(rate_history(15, 10).len(), 1);
+        // Minting at the current rate leaves it unchanged.
+        assert_eq!(mint(&key, 1_210), 1_000);
+        assert_eq!(state().exchange_rate(30).rate_e8s, 121_000_000);
+    }
+
+    #[test]
+    fn test_redeem_burns_at_exchange_rate_and_queues_payout() {
+        let key = UserKey {
+            principal: Principal::anonymous(),
+            subaccount: Subaccount([3u8; 32]),
+        };
+        assert_eq!(mint(&key, 1_000), 1_000);
+        // Rewards accrued to the pool raise the rate to 1.5 underlying per stToken.
+        set_state(LiquidState {
+            total_underlying: 1_500,
+            total_supply: 1_000,
+        });
+        assert_eq!(state().exchange_rate(0).rate_e8s, 150_000_000);
+        assert_eq!(burn(&key, 2_000), Err(DepositError::InsufficientBalance));
+        assert_eq!(burn(&key, 400), Ok(600));
+        assert_eq!(balance_of(&key), 600);
+        assert_eq!(
+            state(),
+            LiquidState {
+                total_underlying: 900,
+                total_supply: 600,
+            }
+        );
+    }
+}
@@ -0,0 +1,176 @@
+// src/locks.rs
+//! Changing the lock of an existing deposit without moving its tokens.
+use crate::denylist;
+use crate::dissolve;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::maintenance::{self, Operation};
+use crate::{status, tiers, Deposit, DepositKey, UserKey, DEPOSITS};
+use candid::Principal;
+use ic_ledger_types::Subaccount;
+
+pub(crate) fn record_lock_change(key: UserKey, deposit: &Deposit, now: u64) {
+    events::record(
+        now,
+        EventKind::LockChanged {
+            key,
+            deposit_id: deposit.id,
+            lock_days: deposit.lock_period_days,
+            unlock_time: deposit.unlock_time(),
+        },
+    );
+}
+
+/// Lengthens the lock of a deposit that has not matured yet. The lock keeps
+/// its original start, so only the unlock time moves.
+pub fn extend_lock_internal(
+    key: &UserKey,
+    deposit_id: u64,
+    new_lock_days: u16,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    tiers::ensure_open(new_lock_days)?;
+    let deposit = crate::modify_deposit(key, deposit_id, now, |deposit| {
+        if dissolve::state_of(deposit.id).is_some() {
+            return Err(DepositError::InvalidArgument(
+                "deposit is in dissolve mode".to_string(),
+            ));
+        }
+        if now >= deposit.unlock_time() {
+            return Err(DepositError::InvalidArgument(
+                "deposit has matured; use relock".to_string(),
+            ));
+        }
+        if new_lock_days <= deposit.lock_period_days {
+            return Err(DepositError::InvalidLockPeriod);
+        }
+        deposit.lock_period_days = new_lock_days;
+        Ok(())
+    })?;
+    record_lock_change(key.clone(), &deposit, now);
+    Ok(deposit)
+}
+
+/// Starts a new lock of `lock_days` from `now` on a matured deposit of
+/// `principal`, in whichever of its subaccounts holds the deposit. A
+/// dissolved deposit goes back to a fixed lock.
+pub fn relock_internal(
+    principal: Principal,
+    deposit_id: u64,
+    lock_days: u16,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    tiers::ensure_open(lock_days)?;
+    let key = owner_key(principal, deposit_id).ok_or(DepositError::NoDepositFound)?;
+    let deposit = crate::modify_deposit(&key, deposit_id, now, |deposit| {
+        if now < deposit.unlock_time() {
+            return Err(DepositError::LockPeriodNotExpired);
+        }
+        deposit.timestamp = now;
+        deposit.lock_period_days = lock_days;
+        dissolve::clear(deposit.id);
+        Ok(())
+    })?;
+    record_lock_change(key, &deposit, now);
+    Ok(deposit)
+}
+
+pub(crate) fn owner_key(principal: Principal, deposit_id: u64) -> Option<UserKey> {
+    DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(principal))
+            .find(|(key, _)| key.deposit_id == deposit_id)
+            .map(|(key, _)| key.user)
+    })
+}
+
+/// Extends the lock of an active deposit to a longer lock period.
+///
+/// # Arguments
+///
+/// * `subaccount`: The subaccount from which the deposit was created.
+/// * `deposit_id`: The ID of the deposit.
+/// * `new_lock_days`: The new lock period, 180 or 360 days and longer than the current one.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+/// * `DepositError::InvalidLockPeriod`: If the new period is not valid or not longer.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+/// * `DepositError::InvalidArgument`: If the deposit has already matured or is in dissolve mode.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn extend_lock(
+    subaccount: Subaccount,
+    deposit_id: u64,
+    new_lock_days: u16,
+) -> Result<Deposit, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    let key = UserKey {
+        principal: caller,
+        subaccount,
+    };
+    extend_lock_internal(&key, deposit_id, new_lock_days, crate::now_secs())
+}
+
+/// Locks a matured deposit again for `lock_days` starting now, instead of
+/// withdrawing it and depositing the tokens anew. A dissolved deposit goes
+/// back to a fixed lock.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::LockPeriodNotExpired`: If the deposit has not matured yet.
+/// * `DepositError::InvalidLockPeriod`: If the lock period is not 90, 180, or 360 days.
+/// * `DepositError::LockTierClosed`: If the lock tier was closed to new deposits.
+/// * `DepositError::Denied`: If the caller is on the denylist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn relock(deposit_id: u64, lock_days: u16) -> Result<Deposit, DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    denylist::ensure_not_denied(caller)?;
+    relock_internal(caller, deposit_id, lock_days, crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extend_and_relock() {
+        let principal = Principal::anonymous();
+        let sub = Subaccount([3u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+        let day = 86400;
+        let deposit = crate::deposit_internal(principal, sub, 180, 1_000, 0).unwrap();
+
+        assert_eq!(
+            extend_lock_internal(&key, deposit.id, 90, day),
+            Err(DepositError::InvalidLockPeriod)
+        );
+        let extended = extend_lock_internal(&key, deposit.id, 360, day).unwrap();
+        assert_eq!((extended.timestamp, extended.unlock_time()), (0, 360 * day));
+        assert_eq!(
+            relock_internal(principal, deposit.id, 90, 200 * day),
+            Err(DepositError::LockPeriodNotExpired)
+        );
+        assert!(extend_lock_internal(&key, deposit.id, 360, 360 * day).is_err());
+
+        let relocked = relock_internal(principal, deposit.id, 90, 400 * day).unwrap();
+        assert_eq!(relocked.unlock_time(), 490 * day);
+        assert_eq!(relocked.amount, 1_000);
+        assert_eq!(
+            relock_internal(Principal::from_slice(&[9]), deposit.id, 90, 400 * day),
+            Err(DepositError::NoDepositFound)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// src/loyalty.rs
+//! Loyalty boosts. The pool tracks how long each principal has staked
+//! without interruption and its lifetime stake-days, and the config's
+//! `loyalty_tiers` raise the reward weight of principals that have staked
+//! continuously for long enough. Withdrawing every deposit ends the streak.
+use crate::config;
+use crate::memory::{get_memory, Memory, LOYALTY_MEMORY_ID};
+use crate::state_hash;
+use crate::{DepositKey, PrincipalKey, DEPOSITS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const SECS_PER_DAY: u64 = 86_400;
+/// Largest bonus a tier may grant, +100%.
+pub const MAX_LOYALTY_BONUS_BPS: u32 = 10_000;
+
+/// Reward bonus of principals that staked continuously for `min_days`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoyaltyTier {
+    pub min_days: u32,
+    /// Added to the reward weight of the principal's deposits, in basis
+    /// points. 500 is +5%.
+    pub bonus_bps: u32,
+}
+
+/// Whether `tiers` are ordered by strictly increasing `min_days` and grant
+/// bonuses between 1 and `MAX_LOYALTY_BONUS_BPS`.
+pub fn is_valid(tiers: &[LoyaltyTier]) -> bool {
+    tiers
+        .iter()
+        .all(|t| t.bonus_bps > 0 && t.bonus_bps <= MAX_LOYALTY_BONUS_BPS)
+        && tiers.windows(2).all(|w| w[0].min_days < w[1].min_days)
+}
+
+/// Staking history of a principal as of `updated_at`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct LoyaltyRecord {
+    staked: u64,
+    /// Start of the current streak; `None` while nothing is staked.
+    staked_since: Option<u64>,
+    /// Amount staked times seconds staked, over the principal's lifetime.
+    stake_secs: u128,
+    updated_at: u64,
+}
+
+impl Storable for LoyaltyRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode LoyaltyRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode LoyaltyRecord")
+    }
+}
+
+impl BoundedStorable for LoyaltyRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoyaltyStatus {
+    pub staked_since: Option<u64>,
+    /// Whole days of the current streak.
+    pub continuous_days: u64,
+    /// Amount staked times days staked, over the principal's lifetime.
+    pub stake_days: u128,
+    pub tier: Option<LoyaltyTier>,
+    pub next_tier: Option<LoyaltyTier>,
+}
+
+thread_local! {
+    static LOYALTY: RefCell<StableBTreeMap<PrincipalKey, LoyaltyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOYALTY_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "loyalty",
+        LOYALTY.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+// History of a principal staking before loyalty was tracked, taken from the
+// deposits it holds.
+fn seed(principal: Principal, now: u64) -> LoyaltyRecord {
+    DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(principal))
+            .fold(
+                LoyaltyRecord {
+                    staked: 0,
+                    staked_since: None,
+                    stake_secs: 0,
+                    updated_at: now,
+                },
+                |mut record, (_, d)| {
+                    record.staked += d.amount;
+                    record.staked_since = Some(
+                        record
+                            .staked_since
+                            .map_or(d.timestamp, |s| s.min(d.timestamp)),
+                    );
+                    record.stake_secs += d.amount as u128 * now.saturating_sub(d.timestamp) as u128;
+                    record
+                },
+            )
+    })
+}
+
+fn record_of(principal: Principal, now: u64) -> LoyaltyRecord {
+    let Some(mut record) = LOYALTY.with(|m| m.borrow().get(&PrincipalKey(principal))) else {
+        return seed(principal, now);
+    };
+    record.stake_secs += record.staked as u128 * now.saturating_sub(record.updated_at) as u128;
+    record.updated_at = record.updated_at.max(now);
+    record
+}
+
+fn staked(principal: Principal) -> u64 {
+    DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(principal))
+            .map(|(_, d)| d.amount)
+            .sum()
+    })
+}
+
+/// Brings the history of `principal` up to `now` after its deposits changed.
+pub fn touch(principal: Principal, now: u64) {
+    let mut record = record_of(principal, now);
+    record.staked = staked(principal);
+    record.staked_since = match record.staked {
+        0 => None,
+        _ => record.staked_since.or(Some(now)),
+    };
+    LOYALTY.with(|m| m.borrow_mut().insert(PrincipalKey(principal), record));
+}
+
+fn continuous_days(record: &LoyaltyRecord, now: u64) -> u64 {
+    record
+        .staked_since
+        .map_or(0, |since| now.saturating_sub(since) / SECS_PER_DAY)
+}
+
+fn reached(tiers: &[LoyaltyTier], days: u64) -> Option<LoyaltyTier> {
+    tiers
+        .iter()
+        .rev()
+        .find(|t| days >= t.min_days as u64)
+        .cloned()
+}
+
+/// Tier `principal` has reached by `now`, if any.
+pub fn tier_of(principal: Principal, now: u64) -> Option<LoyaltyTier> {
+    let tiers = config::get().loyalty_tiers;
+    if tiers.is_empty() {
+        return None;
+    }
+    reached(&tiers, continuous_days(&record_of(principal, now), now))
+}
+
+/// Scales a weighted stake of `principal` by its loyalty bonus.
+pub fn apply(principal: Principal, weighted: u128, now: u64) -> u128 {
+    let bonus = tier_of(principal, now).map_or(0, |t| t.bonus_bps);
+    weighted * (10_000 + bonus as u128) / 10_000
+}
+
+pub fn status_of(principal: Principal, now: u64) -> LoyaltyStatus {
+    let record = record_of(principal, now);
+    let days = continuous_days(&record, now);
+    let tiers = config::get().loyalty_tiers;
+    LoyaltyStatus {
+        staked_since: record.staked_since,
+        continuous_days: days,
+        stake_days: record.stake_secs / SECS_PER_DAY as u128,
+        tier: reached(&tiers, days),
+        next_tier: tiers.into_iter().find(|t| days < t.min_days as u64),
+    }
+}
+
+/// Returns the caller's staking streak, lifetime stake-days and the loyalty
+/// tier they earn.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_loyalty_tier() -> LoyaltyStatus {
+    status_of(ic_cdk::caller(), crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accrual, UserKey};
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_continuous_staking_earns_a_bonus() {
+        let key = UserKey {
+            principal: Principal::from_slice(&[170]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let year = 365 * SECS_PER_DAY;
+        let mut config = config::get();
+        config.loyalty_tiers = vec![LoyaltyTier {
+            min_days: 365,
+            bonus_bps: 500,
+        }];
+        config::set(config);
+
+        let first = crate::deposit_internal(key.principal, key.subaccount, 90, 1_000, 0).unwrap();
+        crate::deposit_internal(key.principal, key.subaccount, 90, 1_000, year / 2).unwrap();
+        crate::remove_deposit(&key, first.id, year / 2).unwrap();
+        let status = status_of(key.principal, year - 1);
+        assert_eq!(status.tier, None);
+        assert_eq!(status.next_tier.map(|t| t.bonus_bps), Some(500));
+
+        // A year in, the remaining deposit weighs 5% more.
+        let shares = accrual::state().total_shares;
+        accrual::reweigh(year).unwrap();
+        assert_eq!(accrual::state().total_shares, shares + 50);
+        let status = status_of(key.principal, year);
+        assert_eq!(status.continuous_days, 365);
+        assert_eq!(status.stake_days, 1_000 * 365);
+        assert_eq!(status.tier.map(|t| t.bonus_bps), Some(500));
+
+        // Withdrawing everything ends the streak.
+        let second = crate::deposits_of(&key)[0].id;
+        crate::remove_deposit(&key, second, year).unwrap();
+        crate::deposit_internal(key.principal, key.subaccount, 90, 1_000, year + 1).unwrap();
+        let status = status_of(key.principal, year + 1);
+        assert_eq!(status.staked_since, Some(year + 1));
+        assert_eq!(status.tier, None);
+    }
+}
@@ -0,0 +1,223 @@
+// src/maintenance.rs
+use crate::config;
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, MAINTENANCE_WINDOWS_MEMORY_ID};
+use crate::state_hash;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const MAX_REASON_LEN: usize = 128;
+
+/// Groups of operations a maintenance window can suspend.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Deposits, team contributions and liquid staking.
+    Deposits,
+    /// Withdrawals, queued withdrawals, redemptions and reclaims.
+    Withdrawals,
+    RewardDistribution,
+    /// Claims of pending and team rewards.
+    Claims,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceWindow {
+    pub id: u64,
+    pub start: u64,
+    pub end: u64,
+    pub operations: Vec<Operation>,
+    pub reason: String,
+    pub scheduled_at: u64,
+}
+
+impl Storable for MaintenanceWindow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode MaintenanceWindow"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode MaintenanceWindow")
+    }
+}
+
+impl BoundedStorable for MaintenanceWindow {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MAINTENANCE_WINDOWS: RefCell<StableBTreeMap<u64, MaintenanceWindow, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MAINTENANCE_WINDOWS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "maintenance_windows",
+        MAINTENANCE_WINDOWS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn schedule(
+    start: u64,
+    end: u64,
+    operations: Vec<Operation>,
+    reason: String,
+    now: u64,
+) -> Result<u64, DepositError> {
+    let notice = config::get().maintenance_notice_secs;
+    if start < now.saturating_add(notice) {
+        return Err(DepositError::InvalidArgument(format!(
+            "maintenance must be announced at least {} seconds ahead",
+            notice
+        )));
+    }
+    if end <= start || operations.is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(DepositError::InvalidArgument(format!(
+            "window must end after it starts, suspend an operation and have a reason of at most {} bytes",
+            MAX_REASON_LEN
+        )));
+    }
+    let id = MAINTENANCE_WINDOWS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    MAINTENANCE_WINDOWS.with(|m| {
+        m.borrow_mut().insert(
+            id,
+            MaintenanceWindow {
+                id,
+                start,
+                end,
+                operations,
+                reason,
+                scheduled_at: now,
+            },
+        )
+    });
+    Ok(id)
+}
+
+/// Windows that have not ended yet, by start time.
+pub fn upcoming(now: u64) -> Vec<MaintenanceWindow> {
+    let mut windows: Vec<_> = MAINTENANCE_WINDOWS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, w)| w)
+            .filter(|w| w.end > now)
+            .collect()
+    });
+    windows.sort_by_key(|w| w.start);
+    windows
+}
+
+/// Fails while a window suspending `operation` is open. Operations resume by
+/// themselves once every such window has ended.
+pub fn ensure_available_at(operation: Operation, now: u64) -> Result<(), DepositError> {
+    let until = MAINTENANCE_WINDOWS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, w)| w)
+            .filter(|w| w.start <= now && now < w.end && w.operations.contains(&operation))
+            .map(|w| w.end)
+            .max()
+    });
+    match until {
+        Some(until) => Err(DepositError::UnderMaintenance { until }),
+        None => Ok(()),
+    }
+}
+
+pub fn ensure_available(operation: Operation) -> Result<(), DepositError> {
+    ensure_available_at(operation, crate::now_secs())
+}
+
+/// Schedules a window from `start` to `end` (seconds since the epoch) during
+/// which `operations` are rejected. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the window starts before the configured
+///   notice period has passed, does not end after it starts, suspends no
+///   operation, or its reason is longer than 128 bytes.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn schedule_maintenance(
+    start: u64,
+    end: u64,
+    operations: Vec<Operation>,
+    reason: String,
+) -> Result<u64, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin(
+        "schedule_maintenance",
+        schedule(start, end, operations, reason, crate::now_secs()),
+    )
+}
+
+/// Cancels a window, ending it immediately if it is already open. Only
+/// canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the window does not exist.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn cancel_maintenance(id: u64) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let removed = MAINTENANCE_WINDOWS
+        .with(|m| m.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or_else(|| DepositError::InvalidArgument(format!("unknown maintenance window {}", id)));
+    crate::transactions::admin("cancel_maintenance", removed)
+}
+
+/// Returns the open and upcoming maintenance windows, by start time.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_maintenance_schedule() -> Vec<MaintenanceWindow> {
+    upcoming(crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_requires_notice_and_suspends_only_its_operations() {
+        let now = 1_000_000;
+        let notice = config::get().maintenance_notice_secs;
+        assert!(schedule(
+            now + 60,
+            now + 3600,
+            vec![Operation::Deposits],
+            String::new(),
+            now
+        )
+        .is_err());
+
+        let start = now + notice;
+        let end = start + 3600;
+        schedule(
+            start,
+            end,
+            vec![Operation::Deposits],
+            "upgrade".to_string(),
+            now,
+        )
+        .unwrap();
+        assert_eq!(upcoming(now).len(), 1);
+
+        assert_eq!(ensure_available_at(Operation::Deposits, start - 1), Ok(()));
+        assert_eq!(
+            ensure_available_at(Operation::Deposits, start),
+            Err(DepositError::UnderMaintenance { until: end })
+        );
+        assert_eq!(ensure_available_at(Operation::Withdrawals, start), Ok(()));
+        assert_eq!(ensure_available_at(Operation::Deposits, end), Ok(()));
+        assert!(upcoming(end).is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+// src/maturity.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::governance::{self, Command, CommandResponse, GovernanceAccount};
+use crate::memory::{get_memory, Memory, MATURITY_HARVEST_MEMORY_ID, SPAWNED_NEURONS_MEMORY_ID};
+use crate::neurons;
+use crate::state_hash;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const HARVEST_INTERVAL: Duration = Duration::from_secs(86400);
+/// Neurons with less maturity than this are left alone until the next run.
+const MIN_HARVEST_E8S: u64 = 100_000_000;
+/// Time NNS governance takes to pay out disbursed maturity, and to turn the
+/// maturity of a spawned neuron into stake.
+const DISBURSE_MATURITY_DELAY_SECS: u64 = 7 * 86400;
+/// Ledger fee NNS governance deducts when disbursing a neuron.
+const DISBURSE_FEE_E8S: u64 = 10_000;
+
+/// Disbursed maturity on its way to the pool account, distributed to stakers
+/// once it has arrived.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaturityHarvest {
+    pub neuron_id: u64,
+    pub amount: u64,
+    pub available_at: u64,
+}
+
+impl Storable for MaturityHarvest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode MaturityHarvest"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode MaturityHarvest")
+    }
+}
+
+impl BoundedStorable for MaturityHarvest {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A neuron spawned from the maturity of a pool neuron. Once its maturity
+/// has turned into stake, it is disbursed to the pool and distributed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpawnedNeuron {
+    pub neuron_id: u64,
+    pub parent_id: u64,
+    pub ready_at: u64,
+}
+
+impl Storable for SpawnedNeuron {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode SpawnedNeuron"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode SpawnedNeuron")
+    }
+}
+
+impl BoundedStorable for SpawnedNeuron {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by (available_at, neuron_id).
+    static PENDING_HARVESTS: RefCell<StableBTreeMap<(u64, u64), MaturityHarvest, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MATURITY_HARVEST_MEMORY_ID)));
+
+    static SPAWNED_NEURONS: RefCell<StableBTreeMap<u64, SpawnedNeuron, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SPAWNED_NEURONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "maturity_harvests",
+            PENDING_HARVESTS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "spawned_neurons",
+            SPAWNED_NEURONS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+fn record_spawn(spawned: SpawnedNeuron) {
+    SPAWNED_NEURONS.with(|m| m.borrow_mut().insert(spawned.neuron_id, spawned));
+}
+
+/// Spawned neurons whose maturity has turned into stake by `now`.
+fn spawned_ready(now: u64) -> Vec<SpawnedNeuron> {
+    SPAWNED_NEURONS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| s.ready_at <= now)
+            .collect()
+    })
+}
+
+pub fn enqueue(harvest: MaturityHarvest) {
+    PENDING_HARVESTS.with(|m| {
+        let mut m = m.borrow_mut();
+        let key = (harvest.available_at, harvest.neuron_id);
+        let amount = m.get(&key).map_or(0, |h| h.amount) + harvest.amount;
+        m.insert(key, MaturityHarvest { amount, ..harvest });
+    });
+}
+
+/// Removes the harvests that have arrived by `now` and returns their total.
+pub fn take_available(now: u64) -> u64 {
+    PENDING_HARVESTS.with(|m| {
+        let mut m = m.borrow_mut();
+        let due: Vec<((u64, u64), MaturityHarvest)> = m.range(..=(now, u64::MAX)).collect();
+        due.into_iter()
+            .map(|(key, harvest)| {
+                m.remove(&key);
+                harvest.amount
+            })
+            .sum()
+    })
+}
+
+async fn harvest_neuron(neuron_id: u64, now: u64) -> Result<u64, DepositError> {
+    let neuron = governance::get_full_neuron(neuron_id).await?;
+    if neuron.maturity_e8s_equivalent < MIN_HARVEST_E8S {
+        return Ok(0);
+    }
+    let command = Command::DisburseMaturity {
+        to_account: Some(GovernanceAccount {
+            owner: Some(ic_cdk::id()),
+            subaccount: None,
+        }),
+        percentage_to_disburse: 100,
+    };
+    let amount = match governance::manage_neuron(neuron_id, command).await? {
+        CommandResponse::DisburseMaturity {
+            amount_disbursed_e8s,
+        } => amount_disbursed_e8s.unwrap_or(0),
+        _ => 0,
+    };
+    if amount > 0 {
+        events::record(now, EventKind::MaturityHarvested { neuron_id, amount });
+        enqueue(MaturityHarvest {
+            neuron_id,
+            amount,
+            available_at: now + DISBURSE_MATURITY_DELAY_SECS,
+        });
+    }
+    Ok(amount)
+}
+
+// Disburses a spawned neuron to the pool account and queues the proceeds for
+// distribution.
+async fn disburse_spawned(spawned: &SpawnedNeuron, now: u64) -> Result<u64, DepositError> {
+    let neuron = governance::get_full_neuron(spawned.neuron_id).await?;
+    governance::manage_neuron(spawned.neuron_id, Command::Disburse {}).await?;
+    SPAWNED_NEURONS.with(|m| m.borrow_mut().remove(&spawned.neuron_id));
+    let amount = neuron
+        .cached_neuron_stake_e8s
+        .saturating_sub(DISBURSE_FEE_E8S);
+    events::record(
+        now,
+        EventKind::MaturityHarvested {
+            neuron_id: spawned.parent_id,
+            amount,
+        },
+    );
+    enqueue(MaturityHarvest {
+        neuron_id: spawned.parent_id,
+        amount,
+        available_at: now,
+    });
+    Ok(amount)
+}
+
+/// Disburses maturity of every pool neuron and spawned neuron, and
+/// distributes the proceeds that have arrived since the last run.
+async fn run_harvest() {
+    let now = crate::now_secs();
+    for spawned in spawned_ready(now) {
+        if let Err(e) = disburse_spawned(&spawned, now).await {
+            ic_cdk::println!(
+                "disbursing spawned neuron {} failed: {:?}",
+                spawned.neuron_id,
+                e
+            );
+        }
+    }
+    for neuron in neurons::all() {
+        if let Err(e) = harvest_neuron(neuron.neuron_id, now).await {
+            ic_cdk::println!(
+                "maturity harvest of neuron {} failed: {:?}",
+                neuron.neuron_id,
+                e
+            );
+        }
+    }
+
+    let amount = take_available(now);
+    if amount == 0 {
+        return;
+    }
+    if let Err(e) = crate::distribute_held_funds(amount) {
+        ic_cdk::println!("distributing harvested maturity failed: {:?}", e);
+        // Retry with the next run.
+        enqueue(MaturityHarvest {
+            neuron_id: 0,
+            amount,
+            available_at: now,
+        });
+    }
+}
+
+/// Starts the daily maturity harvest. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_harvesting() {
+    ic_cdk_timers::set_timer_interval(HARVEST_INTERVAL, || ic_cdk::spawn(run_harvest()));
+}
+
+/// Spawns the maturity of one of the pool's neurons into a new neuron. A week
+/// later the harvest disburses it to the pool and distributes the proceeds to
+/// stakers. Only canister controllers may call this.
+///
+/// # Returns
+///
+/// * The ID of the spawned neuron.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::GovernanceCallFailed`: If NNS governance refused to spawn.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn spawn_neuron_maturity(neuron_id: u64) -> Result<u64, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let command = Command::Spawn {
+        new_controller: None,
+        nonce: None,
+        percentage_to_spawn: Some(100),
+    };
+    let spawned_id = match governance::manage_neuron(neuron_id, command).await? {
+        CommandResponse::Spawn {
+            created_neuron_id: Some(created),
+        } => created.id,
+        other => {
+            return Err(DepositError::GovernanceCallFailed(format!(
+                "unexpected spawn response: {:?}",
+                other
+            )))
+        }
+    };
+    record_spawn(SpawnedNeuron {
+        neuron_id: spawned_id,
+        parent_id: neuron_id,
+        ready_at: crate::now_secs() + DISBURSE_MATURITY_DELAY_SECS,
+    });
+    Ok(spawned_id)
+}
+
+/// Returns spawned neurons that have not been disbursed to the pool yet.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_spawned_neurons() -> Vec<SpawnedNeuron> {
+    SPAWNED_NEURONS.with(|m| m.borrow().iter().map(|(_, s)| s).collect())
+}
+
+/// Returns disbursed maturity that has not arrived or not been distributed yet.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pending_maturity() -> Vec<MaturityHarvest> {
+    PENDING_HARVESTS.with(|m| m.borrow().iter().map(|(_, h)| h).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_harvests_are_released_when_they_arrive() {
+        enqueue(MaturityHarvest {
+            neuron_id: 1,
+            amount: 300,
+            available_at: 100,
+        });
+        enqueue(MaturityHarvest {
+            neuron_id: 2,
+            amount: 200,
+            available_at: 200,
+        });
+        enqueue(MaturityHarvest {
+            neuron_id: 1,
+            amount: 50,
+            available_at: 100,
+        });
+
+        assert_eq!(take_available(99), 0);
+        assert_eq!(take_available(100), 350);
+        assert_eq!(get_pending_maturity().len(), 1);
+        assert_eq!(take_available(1_000), 200);
+        assert!(get_pending_maturity().is_empty());
+    }
+
+    #[test]
+    fn test_spawned_neurons_are_disbursed_once_ready() {
+        record_spawn(SpawnedNeuron {
+            neuron_id: 11,
+            parent_id: 1,
+            ready_at: 7 * 86400,
+        });
+        assert!(spawned_ready(7 * 86400 - 1).is_empty());
+        assert_eq!(spawned_ready(7 * 86400)[0].neuron_id, 11);
+    }
+}
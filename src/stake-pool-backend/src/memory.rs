@@ -0,0 +1,112 @@
+// src/memory.rs
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl,
+};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Every stable structure gets its own virtual memory. Ids must never be reused
+// or reordered, otherwise existing state is read back as garbage after an upgrade.
+pub const DEPOSIT_MAP_MEMORY_ID: u8 = 0;
+pub const STAKE_BALANCE_MEMORY_ID: u8 = 1;
+pub const CONFIG_MEMORY_ID: u8 = 2;
+pub const POOL_STATUS_MEMORY_ID: u8 = 3;
+pub const EVENT_LOG_INDEX_MEMORY_ID: u8 = 4;
+pub const EVENT_LOG_DATA_MEMORY_ID: u8 = 5;
+pub const NOTIFICATION_PREFS_MEMORY_ID: u8 = 6;
+pub const DISTRIBUTION_STATE_MEMORY_ID: u8 = 7;
+pub const TOKEN_METADATA_MEMORY_ID: u8 = 8;
+// 9 held the journal of reward transfers, which rewards accruing per deposit
+// left unused.
+pub const DENYLIST_MEMORY_ID: u8 = 10;
+pub const WITHDRAWAL_QUEUE_MEMORY_ID: u8 = 11;
+pub const RATE_STATE_MEMORY_ID: u8 = 12;
+pub const COMPOUNDING_PREFS_MEMORY_ID: u8 = 13;
+pub const PENDING_REWARDS_MEMORY_ID: u8 = 14;
+pub const FIRST_SEEN_MEMORY_ID: u8 = 15;
+pub const NEURON_FOLLOWING_MEMORY_ID: u8 = 16;
+pub const POOL_NEURONS_MEMORY_ID: u8 = 17;
+pub const MATURITY_HARVEST_MEMORY_ID: u8 = 18;
+pub const DISSOLVING_NEURONS_MEMORY_ID: u8 = 19;
+pub const LIQUID_STATE_MEMORY_ID: u8 = 20;
+pub const LIQUID_BALANCES_MEMORY_ID: u8 = 21;
+pub const EXCHANGE_RATE_HISTORY_MEMORY_ID: u8 = 22;
+pub const UNCLAIMED_MEMORY_ID: u8 = 23;
+pub const ACCOUNT_MIGRATIONS_MEMORY_ID: u8 = 24;
+pub const PUBLIC_POSITIONS_MEMORY_ID: u8 = 25;
+pub const SNAPSHOTS_MEMORY_ID: u8 = 26;
+pub const SNAPSHOT_LEAVES_MEMORY_ID: u8 = 27;
+pub const TREASURY_STATE_MEMORY_ID: u8 = 28;
+pub const TREASURY_HISTORY_MEMORY_ID: u8 = 29;
+pub const DISBURSEMENTS_MEMORY_ID: u8 = 30;
+pub const CLAIM_CALLBACKS_MEMORY_ID: u8 = 31;
+pub const TEAMS_MEMORY_ID: u8 = 32;
+pub const SHARDS_MEMORY_ID: u8 = 33;
+pub const POSITION_IMPORTS_MEMORY_ID: u8 = 34;
+pub const MAINTENANCE_WINDOWS_MEMORY_ID: u8 = 35;
+pub const LEDGER_CANISTER_MEMORY_ID: u8 = 36;
+pub const DEPOSIT_ID_COUNTER_MEMORY_ID: u8 = 37;
+pub const UPGRADE_STATE_MEMORY_ID: u8 = 38;
+pub const ACCRUAL_STATE_MEMORY_ID: u8 = 39;
+pub const DEPOSIT_ACCRUALS_MEMORY_ID: u8 = 40;
+pub const REWARD_MULTIPLIERS_MEMORY_ID: u8 = 41;
+pub const DEPOSIT_DEDUP_MEMORY_ID: u8 = 42;
+pub const PENDING_WITHDRAWALS_MEMORY_ID: u8 = 43;
+pub const TRANSACTION_LOG_INDEX_MEMORY_ID: u8 = 44;
+pub const TRANSACTION_LOG_DATA_MEMORY_ID: u8 = 45;
+pub const USER_TRANSACTIONS_MEMORY_ID: u8 = 46;
+pub const REWARD_HISTORY_INDEX_MEMORY_ID: u8 = 47;
+pub const REWARD_HISTORY_DATA_MEMORY_ID: u8 = 48;
+pub const REWARD_SWEEP_MEMORY_ID: u8 = 49;
+pub const TOKEN_REGISTRY_MEMORY_ID: u8 = 50;
+pub const TOKEN_DEPOSITS_MEMORY_ID: u8 = 51;
+pub const TOKEN_STAKES_MEMORY_ID: u8 = 52;
+pub const LEDGER_FEES_MEMORY_ID: u8 = 53;
+pub const REWARD_RESIDUAL_MEMORY_ID: u8 = 54;
+pub const PROPOSALS_MEMORY_ID: u8 = 55;
+pub const PROPOSAL_BALLOTS_MEMORY_ID: u8 = 56;
+pub const ALLOWLIST_MEMORY_ID: u8 = 57;
+pub const ALLOWLIST_MODE_MEMORY_ID: u8 = 58;
+pub const WITHDRAWAL_OUTFLOW_MEMORY_ID: u8 = 59;
+pub const DISSOLVE_STATES_MEMORY_ID: u8 = 60;
+pub const VALIDATORS_MEMORY_ID: u8 = 61;
+pub const DELEGATIONS_MEMORY_ID: u8 = 62;
+pub const SPAWNED_NEURONS_MEMORY_ID: u8 = 63;
+pub const RECEIPT_BALANCES_MEMORY_ID: u8 = 64;
+pub const RECEIPT_OUTSTANDING_MEMORY_ID: u8 = 65;
+pub const RECEIPT_LEDGER_MEMORY_ID: u8 = 66;
+pub const MATURED_DEPOSITS_MEMORY_ID: u8 = 67;
+pub const PRICE_ORACLE_MEMORY_ID: u8 = 68;
+pub const REWARD_STREAMS_MEMORY_ID: u8 = 69;
+pub const EPOCH_STATE_MEMORY_ID: u8 = 70;
+pub const EPOCH_RATES_MEMORY_ID: u8 = 71;
+pub const DEPOSIT_EPOCHS_MEMORY_ID: u8 = 72;
+pub const EPOCH_HISTORY_MEMORY_ID: u8 = 73;
+pub const DEPOSIT_ENTRY_EPOCHS_MEMORY_ID: u8 = 74;
+pub const OPERATION_LOCKS_MEMORY_ID: u8 = 75;
+pub const SCHEMA_VERSION_MEMORY_ID: u8 = 76;
+pub const DEPOSITS_MEMORY_ID: u8 = 77;
+pub const TOKEN_DEPOSIT_ENTRIES_MEMORY_ID: u8 = 78;
+pub const BLOCK_HASHES_MEMORY_ID: u8 = 79;
+pub const ARCHIVES_MEMORY_ID: u8 = 80;
+pub const ARCHIVE_WASM_MEMORY_ID: u8 = 81;
+pub const TRANSACTIONS_MEMORY_ID: u8 = 82;
+pub const REFERRER_OF_MEMORY_ID: u8 = 83;
+pub const REFERRED_DEPOSITS_MEMORY_ID: u8 = 84;
+pub const REFERRAL_TOTALS_MEMORY_ID: u8 = 85;
+pub const LOYALTY_MEMORY_ID: u8 = 86;
+pub const WITHDRAWAL_DESTINATIONS_MEMORY_ID: u8 = 87;
+pub const SHARD_ASSIGNMENTS_MEMORY_ID: u8 = 88;
+pub const VESTING_BONUSES_MEMORY_ID: u8 = 89;
+pub const LEGACY_TRANSACTIONS_COPIED_MEMORY_ID: u8 = 90;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get_memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
@@ -0,0 +1,109 @@
+// src/memory_guard.rs
+use crate::config::{self, PoolConfig};
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use std::cell::RefCell;
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+thread_local! {
+    // Set once an alert has been raised so that repeated rejected deposits do
+    // not flood the event log. Cleared when usage drops back under the limits.
+    static ALERT_RAISED: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn heap_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn heap_bytes() -> u64 {
+    0
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn stable_bytes() -> u64 {
+    ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn stable_bytes() -> u64 {
+    0
+}
+
+pub fn alert_raised() -> bool {
+    ALERT_RAISED.with(|a| *a.borrow())
+}
+
+pub fn restore_alert_raised(raised: bool) {
+    ALERT_RAISED.with(|a| *a.borrow_mut() = raised);
+}
+
+pub fn within_limits(heap_bytes: u64, stable_bytes: u64, config: &PoolConfig) -> bool {
+    heap_bytes < config.max_heap_bytes && stable_bytes < config.max_stable_memory_bytes
+}
+
+/// Rejects new deposits once heap or stable memory usage reaches the
+/// configured limits, raising a single `MemoryAlert` event per excursion.
+pub fn check_deposit_capacity(
+    heap_bytes: u64,
+    stable_bytes: u64,
+    now: u64,
+) -> Result<(), DepositError> {
+    if within_limits(heap_bytes, stable_bytes, &config::get()) {
+        ALERT_RAISED.with(|a| *a.borrow_mut() = false);
+        return Ok(());
+    }
+
+    let already_raised = ALERT_RAISED.with(|a| a.replace(true));
+    if !already_raised {
+        events::record(
+            now,
+            EventKind::MemoryAlert {
+                heap_bytes,
+                stable_bytes,
+            },
+        );
+    }
+    Err(DepositError::MemoryLimitReached)
+}
+
+pub fn ensure_deposit_capacity(now: u64) -> Result<(), DepositError> {
+    check_deposit_capacity(heap_bytes(), stable_bytes(), now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_capacity_raises_single_alert() {
+        config::set(PoolConfig {
+            max_heap_bytes: 1_000,
+            max_stable_memory_bytes: 10_000,
+            ..PoolConfig::default()
+        });
+
+        assert_eq!(check_deposit_capacity(999, 9_999, 1), Ok(()));
+        assert_eq!(
+            check_deposit_capacity(1_000, 0, 2),
+            Err(DepositError::MemoryLimitReached)
+        );
+        assert_eq!(
+            check_deposit_capacity(0, 10_000, 3),
+            Err(DepositError::MemoryLimitReached)
+        );
+
+        let alerts = events::range(0, 10);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(
+            alerts[0].kind,
+            EventKind::MemoryAlert {
+                heap_bytes: 1_000,
+                stable_bytes: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// src/metrics.rs
+use crate::error::DepositError;
+use crate::{memory_guard, reward_history, stats};
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Instruction usage of one kind of operation since the last upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct InstructionStats {
+    pub calls: u64,
+    pub last: u64,
+    pub max: u64,
+    pub total: u128,
+}
+
+/// Health of the canister, readable by anyone so monitoring services can
+/// watch a pool whose controllers were removed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CanisterMetrics {
+    pub cycles_balance: u128,
+    pub stable_memory_bytes: u64,
+    pub heap_bytes: u64,
+    pub staker_count: u64,
+    pub last_distribution_at: Option<u64>,
+}
+
+thread_local! {
+    // Heap only: the numbers describe the running Wasm module and are
+    // expected to start over after an upgrade.
+    static INSTRUCTION_STATS: RefCell<BTreeMap<String, InstructionStats>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Instructions executed so far in the current call context, including
+/// executions before earlier awaits.
+#[cfg(target_arch = "wasm32")]
+pub fn instructions() -> u64 {
+    ic_cdk::api::call_context_instruction_counter()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn instructions() -> u64 {
+    0
+}
+
+pub fn record(operation: &str, instructions: u64) {
+    INSTRUCTION_STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        let entry = stats.entry(operation.to_string()).or_default();
+        entry.calls += 1;
+        entry.last = instructions;
+        entry.max = entry.max.max(instructions);
+        entry.total += instructions as u128;
+    });
+}
+
+/// Records the instructions `f` spends under `operation`.
+pub fn measure<T>(operation: &str, f: impl FnOnce() -> T) -> T {
+    let start = instructions();
+    let result = f();
+    record(operation, instructions().saturating_sub(start));
+    result
+}
+
+/// Returns instruction usage per operation, for tuning batch sizes against
+/// the per-message instruction limits.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_instruction_metrics() -> Vec<(String, InstructionStats)> {
+    INSTRUCTION_STATS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    })
+}
+
+/// Returns cycles, memory usage, staker count and the time of the last reward
+/// distribution. Needs no controller access.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_canister_metrics() -> CanisterMetrics {
+    CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_balance128(),
+        stable_memory_bytes: memory_guard::stable_bytes(),
+        heap_bytes: memory_guard::heap_bytes(),
+        staker_count: stats::pool_stats().staker_count,
+        last_distribution_at: reward_history::last_distribution_at(),
+    }
+}
+
+/// Runs the heaviest queries inside an update call and records their
+/// instruction cost. Metrics recorded during query calls are discarded along
+/// with every other state change, so this is the only way to sample them.
+/// Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn profile_queries() -> Result<Vec<(String, InstructionStats)>, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    measure("get_deposits_by_user", crate::get_deposits_by_user);
+    measure("get_events", || {
+        crate::events::range(0, crate::events::MAX_EVENTS_PER_PAGE)
+    });
+    measure("export_changes", || {
+        crate::backup::collect_changes(0, crate::backup::MAX_EVENTS_PER_EXPORT)
+    });
+    Ok(get_instruction_metrics())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_tracks_last_max_and_total() {
+        record("reward_pool", 100);
+        record("reward_pool", 300);
+        record("reward_pool", 200);
+
+        let stats = get_instruction_metrics();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats[0].1,
+            InstructionStats {
+                calls: 3,
+                last: 200,
+                max: 300,
+                total: 600,
+            }
+        );
+    }
+}
@@ -0,0 +1,270 @@
+// src/migration.rs
+use crate::direct_deposit;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::ledger;
+use crate::proposals;
+use crate::scheduler::REWARDS_SUBACCOUNT;
+use crate::status::{self, PoolStatus};
+use crate::tokens;
+use crate::treasury::TREASURY_SUBACCOUNT;
+use crate::{DEPOSITS, STAKE_BALANCE_MAP};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::collections::BTreeSet;
+
+/// Balance moved from one of the pool's accounts to the successor.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SweptAccount {
+    pub ledger: Principal,
+    /// Subaccount of the pool that was swept, and of the successor that was
+    /// credited. `None` is the main account.
+    pub subaccount: Option<[u8; 32]>,
+    pub amount: Nat,
+    pub block_index: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigrationReport {
+    pub successor: Principal,
+    /// Moved from the main account on the primary ledger, which backs the
+    /// deposits.
+    pub transferred: Nat,
+    pub block_index: Nat,
+    /// Every other custody account that held more than the ledger fee.
+    pub swept: Vec<SweptAccount>,
+}
+
+/// Switches the pool into redirect-only mode pointing at `successor`.
+/// Repeating the call for the same successor is allowed so a failed balance
+/// transfer can be retried.
+pub fn begin_migration(successor: Principal, now: u64) -> Result<(), DepositError> {
+    let current = status::get();
+    match current {
+        PoolStatus::Migrated {
+            successor: existing,
+        } if existing != successor => Err(DepositError::Migrated {
+            successor: existing,
+        }),
+        PoolStatus::Migrated { .. } => Ok(()),
+        _ => {
+            let next = PoolStatus::Migrated { successor };
+            status::set(next);
+            events::record(
+                now,
+                EventKind::PoolStatusChanged {
+                    from: current,
+                    to: next,
+                },
+            );
+            Ok(())
+        }
+    }
+}
+
+fn ledger_error<E: std::fmt::Debug>(e: E) -> DepositError {
+    DepositError::LedgerTransferFailed(format!("{:?}", e))
+}
+
+/// Accounts other than the primary main account that the pool holds funds
+/// in: the treasury and rewards subaccounts and the deposit addresses of
+/// every known account on the primary ledger, and the main account on each
+/// registered token ledger.
+pub fn custody_accounts(primary: Principal) -> Vec<(Principal, Option<[u8; 32]>)> {
+    let mut accounts = vec![
+        (primary, Some(TREASURY_SUBACCOUNT)),
+        (primary, Some(REWARDS_SUBACCOUNT)),
+    ];
+    let mut keys: BTreeSet<_> =
+        STAKE_BALANCE_MAP.with(|m| m.borrow().iter().map(|(k, _)| k).collect());
+    DEPOSITS.with(|m| keys.extend(m.borrow().iter().map(|(k, _)| k.user)));
+    accounts.extend(
+        keys.iter()
+            .map(|key| (primary, Some(direct_deposit::deposit_subaccount(key)))),
+    );
+    accounts.extend(
+        tokens::registered()
+            .into_iter()
+            .filter(|t| t.ledger != primary)
+            .map(|t| (t.ledger, None)),
+    );
+    accounts
+}
+
+/// Moves the balance of the pool's `subaccount` on `ledger`, less the fee,
+/// to the same subaccount of `successor`. `None` if it holds no more than
+/// the fee.
+pub(crate) async fn sweep(
+    ledger: Principal,
+    subaccount: Option<[u8; 32]>,
+    successor: Principal,
+) -> Result<Option<SweptAccount>, DepositError> {
+    let balance = ledger::balance_in(
+        ledger,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount,
+        },
+    )
+    .await?;
+    let fee = Nat::from(ledger::fee(ledger).await?);
+    if balance <= fee {
+        return Ok(None);
+    }
+
+    let amount = balance - fee.clone();
+    let transfer_arg = TransferArg {
+        to: Account {
+            owner: successor,
+            subaccount,
+        },
+        amount: amount.clone(),
+        fee: Some(fee),
+        memo: None,
+        from_subaccount: subaccount,
+        created_at_time: None,
+    };
+    let (res,): (Result<Nat, TransferError>,) = call(ledger, "icrc1_transfer", (transfer_arg,))
+        .await
+        .map_err(ledger_error)?;
+    let block_index = res.map_err(ledger_error)?;
+    Ok(Some(SweptAccount {
+        ledger,
+        subaccount,
+        amount,
+        block_index,
+    }))
+}
+
+/// Migration to a successor canister approved by a staker proposal. Only
+/// canister controllers may call this.
+///
+/// The pool stops serving every update except further `migrate_to` calls for
+/// the same successor, and its state stays readable through
+/// `export_changes`, paging from sequence number 0. Every account the pool
+/// holds funds in is emptied, less the transfer fee, into the same
+/// subaccount of the successor: the main account, the treasury and rewards
+/// subaccounts and stakers' deposit addresses on the primary ledger, and the
+/// main account on each registered token ledger.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller, or no
+///   executed proposal approved migrating to `successor`.
+/// * `DepositError::Migrated`: If the pool was already migrated to a different successor.
+/// * `DepositError::LedgerTransferFailed`: If a transfer failed. The pool stays in
+///   redirect-only mode and the call can be retried; accounts already swept are skipped.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn migrate_to(successor: Principal) -> Result<MigrationReport, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if !proposals::migration_approved(successor) {
+        return Err(DepositError::Unauthorized);
+    }
+    begin_migration(successor, crate::now_secs())?;
+
+    let primary = ledger::ledger_id();
+    let main = sweep(primary, None, successor).await?;
+    let (transferred, block_index) = main.map_or_else(
+        || (Nat::from(0u64), Nat::from(0u64)),
+        |s| (s.amount, s.block_index),
+    );
+    events::record(
+        crate::now_secs(),
+        EventKind::MigratedTo {
+            successor,
+            amount: transferred.clone(),
+        },
+    );
+    let mut swept = Vec::new();
+    for (ledger, subaccount) in custody_accounts(primary) {
+        if let Some(account) = sweep(ledger, subaccount, successor).await? {
+            events::record(
+                crate::now_secs(),
+                EventKind::CustodySwept {
+                    successor,
+                    ledger,
+                    subaccount,
+                    amount: account.amount.clone(),
+                },
+            );
+            swept.push(account);
+        }
+    }
+    Ok(MigrationReport {
+        successor,
+        transferred,
+        block_index,
+        swept,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proposals::ProposalAction;
+    use crate::{config, cycles, governance::Vote, UserKey};
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_migration_is_redirect_only_and_sticky() {
+        let successor = Principal::from_slice(&[1, 2, 3]);
+        let other = Principal::from_slice(&[4, 5, 6]);
+
+        assert_eq!(begin_migration(successor, 1), Ok(()));
+        assert_eq!(
+            status::ensure_active(),
+            Err(DepositError::Migrated { successor })
+        );
+        assert_eq!(
+            status::ensure_withdrawals_allowed(),
+            Err(DepositError::Migrated { successor })
+        );
+
+        // Retrying for the same successor is fine, switching successors is not.
+        assert_eq!(begin_migration(successor, 2), Ok(()));
+        assert_eq!(
+            begin_migration(other, 3),
+            Err(DepositError::Migrated { successor })
+        );
+
+        // Cycles recovering must not bring a migrated pool back to life.
+        assert_eq!(
+            cycles::apply_headroom_check(u128::MAX, 4),
+            PoolStatus::Migrated { successor }
+        );
+    }
+
+    #[test]
+    fn test_migration_needs_a_proposal_and_sweeps_every_account() {
+        let primary = Principal::from_slice(&[7]);
+        let token = Principal::from_slice(&[9]);
+        let successor = Principal::from_slice(&[10]);
+        let key = UserKey {
+            principal: Principal::from_slice(&[8]),
+            subaccount: Subaccount([1; 32]),
+        };
+        crate::deposit_internal(key.principal, key.subaccount, 90, 100, 0).unwrap();
+        tokens::add(token, 0).unwrap();
+
+        let accounts = custody_accounts(primary);
+        assert!(accounts.contains(&(primary, Some(TREASURY_SUBACCOUNT))));
+        assert!(accounts.contains(&(primary, Some(REWARDS_SUBACCOUNT))));
+        assert!(accounts.contains(&(primary, Some(direct_deposit::deposit_subaccount(&key)))));
+        assert!(accounts.contains(&(token, None)));
+
+        assert!(!proposals::migration_approved(successor));
+        config::set(config::PoolConfig {
+            proposal_voting_secs: Some(86_400),
+            ..config::get()
+        });
+        let proposal =
+            proposals::create(key.principal, ProposalAction::MigrateTo { successor }, 0).unwrap();
+        proposals::vote(key.principal, proposal.id, Vote::Yes, 10).unwrap();
+        proposals::tally(86_400);
+        assert!(proposals::migration_approved(successor));
+        assert!(!proposals::migration_approved(primary));
+    }
+}
@@ -0,0 +1,180 @@
+// src/multipliers.rs
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, REWARD_MULTIPLIERS_MEMORY_ID};
+use crate::state_hash;
+use crate::{accrual, VALID_LOCKS};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const ONE_X_BPS: u32 = 10_000;
+/// Largest multiplier the schedule accepts, 10x.
+pub const MAX_MULTIPLIER_BPS: u32 = 100_000;
+
+/// Reward weight of deposits in one lock tier, in basis points of their
+/// stake. 10000 is 1x.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LockMultiplier {
+    pub lock_days: u16,
+    pub multiplier_bps: u32,
+}
+
+/// Lock tiers missing from the schedule earn 1x.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct MultiplierSchedule(pub Vec<LockMultiplier>);
+
+impl Storable for MultiplierSchedule {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode MultiplierSchedule"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode MultiplierSchedule")
+    }
+}
+
+impl MultiplierSchedule {
+    pub fn validate(&self) -> Result<(), DepositError> {
+        for (i, entry) in self.0.iter().enumerate() {
+            if !VALID_LOCKS.contains(&entry.lock_days) {
+                return Err(DepositError::InvalidConfig(format!(
+                    "unknown lock tier {}",
+                    entry.lock_days
+                )));
+            }
+            if self.0[..i].iter().any(|e| e.lock_days == entry.lock_days) {
+                return Err(DepositError::InvalidConfig(format!(
+                    "lock tier {} listed twice",
+                    entry.lock_days
+                )));
+            }
+            if entry.multiplier_bps == 0 || entry.multiplier_bps > MAX_MULTIPLIER_BPS {
+                return Err(DepositError::InvalidConfig(format!(
+                    "multipliers must be between 1 and {} bps",
+                    MAX_MULTIPLIER_BPS
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn multiplier_bps(&self, lock_days: u16) -> u32 {
+        self.0
+            .iter()
+            .find(|e| e.lock_days == lock_days)
+            .map_or(ONE_X_BPS, |e| e.multiplier_bps)
+    }
+}
+
+thread_local! {
+    static SCHEDULE: RefCell<StableCell<MultiplierSchedule, Memory>> = RefCell::new(
+        StableCell::init(
+            get_memory(REWARD_MULTIPLIERS_MEMORY_ID),
+            MultiplierSchedule::default(),
+        )
+        .expect("Failed to init reward multiplier cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "reward_multipliers",
+        SCHEDULE.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+pub fn schedule() -> MultiplierSchedule {
+    SCHEDULE.with(|s| s.borrow().get().clone())
+}
+
+/// Scales a weighted stake in the `lock_days` tier by the tier's multiplier.
+pub fn apply(lock_days: u16, weighted: u128) -> u128 {
+    weighted * schedule().multiplier_bps(lock_days) as u128 / ONE_X_BPS as u128
+}
+
+/// Replaces the schedule. Rewards accrued so far are settled at the old
+/// multipliers before every deposit moves to the new ones.
+pub fn set_schedule(schedule: MultiplierSchedule, now: u64) -> Result<(), DepositError> {
+    schedule.validate()?;
+    SCHEDULE.with(|s| {
+        s.borrow_mut()
+            .set(schedule)
+            .expect("Failed to persist reward multipliers");
+    });
+    accrual::reweigh(now)
+}
+
+/// Sets the reward multiplier of each lock tier. Only canister controllers
+/// may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidConfig`: If a tier is unknown or listed twice, or a
+///   multiplier is 0 or above 10x.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_reward_multipliers(multipliers: Vec<LockMultiplier>) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin(
+        "set_reward_multipliers",
+        set_schedule(MultiplierSchedule(multipliers), crate::now_secs()),
+    )
+}
+
+/// Returns the reward multiplier of every lock tier.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_reward_multipliers() -> Vec<LockMultiplier> {
+    let schedule = schedule();
+    VALID_LOCKS
+        .iter()
+        .map(|&lock_days| LockMultiplier {
+            lock_days,
+            multiplier_bps: schedule.multiplier_bps(lock_days),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_longer_locks_earn_more() {
+        let short = crate::UserKey {
+            principal: Principal::from_slice(&[1]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let long = crate::UserKey {
+            principal: Principal::from_slice(&[2]),
+            subaccount: Subaccount([0; 32]),
+        };
+        crate::deposit_internal(short.principal, short.subaccount, 90, 1_000, 0).unwrap();
+        crate::deposit_internal(long.principal, long.subaccount, 360, 1_000, 0).unwrap();
+
+        let invalid = MultiplierSchedule(vec![LockMultiplier {
+            lock_days: 30,
+            multiplier_bps: 15_000,
+        }]);
+        assert!(set_schedule(invalid, 0).is_err());
+
+        accrual::distribute(2_000, 0).unwrap();
+        set_schedule(
+            MultiplierSchedule(vec![LockMultiplier {
+                lock_days: 360,
+                multiplier_bps: 20_000,
+            }]),
+            0,
+        )
+        .unwrap();
+        assert_eq!(accrual::state().total_shares, 3_000);
+        accrual::distribute(3_000, 0).unwrap();
+
+        assert_eq!(accrual::claimable(&short), 2_000);
+        assert_eq!(accrual::claimable(&long), 3_000);
+    }
+}
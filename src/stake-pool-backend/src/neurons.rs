@@ -0,0 +1,282 @@
+// src/neurons.rs
+use crate::circuit_breaker;
+use crate::error::DepositError;
+use crate::governance::{self, ClaimBy, Command};
+use crate::ledger;
+use crate::memory::{get_memory, Memory, POOL_NEURONS_MEMORY_ID};
+use crate::state_hash;
+use crate::VALID_LOCKS;
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Smallest stake NNS governance accepts for a neuron, including the one left
+/// behind by a split.
+pub const MIN_NEURON_STAKE_E8S: u64 = 100_000_000;
+
+/// An NNS neuron controlled by the pool canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PoolNeuron {
+    pub neuron_id: u64,
+    pub registered_at: u64,
+    /// Lock tier whose deposits are staked in this neuron, if any.
+    pub lock_days: Option<u16>,
+    /// Subaccount of the governance canister holding the neuron's stake.
+    pub account: Vec<u8>,
+    /// Deposits staked into the neuron by the pool.
+    pub staked: u64,
+}
+
+impl Storable for PoolNeuron {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PoolNeuron"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PoolNeuron")
+    }
+}
+
+impl BoundedStorable for PoolNeuron {
+    const MAX_SIZE: u32 = 192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static POOL_NEURONS: RefCell<StableBTreeMap<u64, PoolNeuron, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(POOL_NEURONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "pool_neurons",
+        POOL_NEURONS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn insert(neuron: PoolNeuron) {
+    crate::stats::invalidate();
+    POOL_NEURONS.with(|m| m.borrow_mut().insert(neuron.neuron_id, neuron));
+}
+
+pub fn all() -> Vec<PoolNeuron> {
+    POOL_NEURONS.with(|m| m.borrow().iter().map(|(_, n)| n).collect())
+}
+
+/// The neuron new deposits of `lock_days` are staked in: the least-staked
+/// neuron backing that tier.
+pub fn neuron_for_tier(lock_days: u16) -> Option<PoolNeuron> {
+    all()
+        .into_iter()
+        .filter(|n| n.lock_days == Some(lock_days))
+        .min_by_key(|n| n.staked)
+}
+
+pub fn add_staked(neuron_id: u64, amount: u64) {
+    update_staked(neuron_id, |staked| staked + amount);
+}
+
+pub fn remove_staked(neuron_id: u64, amount: u64) {
+    update_staked(neuron_id, |staked| staked.saturating_sub(amount));
+}
+
+fn update_staked(neuron_id: u64, f: impl FnOnce(u64) -> u64) {
+    crate::stats::invalidate();
+    POOL_NEURONS.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(mut neuron) = m.get(&neuron_id) {
+            neuron.staked = f(neuron.staked);
+            m.insert(neuron_id, neuron);
+        }
+    });
+}
+
+// Moves `amount` of pooled tokens to the governance subaccount `account`.
+async fn transfer_to_governance(account: [u8; 32], amount: u64) -> Result<(), DepositError> {
+    let transfer_arg = TransferArg {
+        to: Account {
+            owner: governance::governance_id(),
+            subaccount: Some(account),
+        },
+        amount: amount.into(),
+        fee: None,
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+    };
+    let (res,): (Result<Nat, TransferError>,) = circuit_breaker::observe(
+        call(ledger::ledger_id(), "icrc1_transfer", (transfer_arg,)).await,
+    )
+    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(())
+}
+
+async fn top_up(neuron: &PoolNeuron, amount: u64) -> Result<(), DepositError> {
+    let account = neuron.account.clone().try_into().map_err(|_| {
+        DepositError::InvalidArgument(format!("neuron {} has no valid account", neuron.neuron_id))
+    })?;
+    transfer_to_governance(account, amount).await?;
+
+    let command = Command::ClaimOrRefresh {
+        by: Some(ClaimBy::NeuronIdOrSubaccount {}),
+    };
+    governance::manage_neuron(neuron.neuron_id, command).await?;
+    Ok(())
+}
+
+/// Stakes a new deposit in the neuron backing its lock tier. Deposits of a
+/// tier without a backing neuron, or whose staking fails, stay liquid in the
+/// pool account.
+pub async fn stake_deposit(lock_days: u16, amount: u64) {
+    let Some(neuron) = neuron_for_tier(lock_days) else {
+        return;
+    };
+    match top_up(&neuron, amount).await {
+        Ok(()) => add_staked(neuron.neuron_id, amount),
+        Err(e) => ic_cdk::println!("staking into neuron {} failed: {:?}", neuron.neuron_id, e),
+    }
+}
+
+/// Adds a neuron controlled by the pool canister to the set the pool manages.
+/// When `lock_days` is given, the neuron backs that lock tier and its dissolve
+/// delay must cover the full lock period. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidLockPeriod`: If `lock_days` is not 90, 180, or 360 days.
+/// * `DepositError::InvalidArgument`: If the neuron's dissolve delay is shorter than the lock period.
+/// * `DepositError::GovernanceCallFailed`: If the neuron could not be read.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn register_pool_neuron(
+    neuron_id: u64,
+    lock_days: Option<u16>,
+) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if lock_days.is_some_and(|d| !VALID_LOCKS.contains(&d)) {
+        return Err(DepositError::InvalidLockPeriod);
+    }
+    let full = governance::get_full_neuron(neuron_id).await?;
+    let now = crate::now_secs();
+    if let Some(days) = lock_days {
+        if full.dissolve_delay_secs(now) < days as u64 * 86400 {
+            return Err(DepositError::InvalidArgument(format!(
+                "dissolve delay of neuron {} is shorter than {} days",
+                neuron_id, days
+            )));
+        }
+    }
+    let staked = POOL_NEURONS.with(|m| m.borrow().get(&neuron_id).map_or(0, |n| n.staked));
+    insert(PoolNeuron {
+        neuron_id,
+        registered_at: now,
+        lock_days,
+        account: full.account,
+        staked,
+    });
+    Ok(())
+}
+
+/// Stakes `amount` of pooled tokens in a new NNS neuron and adds it to the
+/// neurons the pool manages. `nonce` must not have been used for another
+/// neuron of the pool. When `lock_days` is given, the neuron backs that lock
+/// tier and gets a dissolve delay of the full lock period. Only canister
+/// controllers may call this.
+///
+/// # Returns
+///
+/// * The ID of the new neuron.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidLockPeriod`: If `lock_days` is not 90, 180, or 360 days.
+/// * `DepositError::InvalidArgument`: If `amount` is below the 1 ICP neuron minimum.
+/// * `DepositError::LedgerTransferFailed`: If the stake could not be transferred.
+/// * `DepositError::GovernanceCallFailed`: If the neuron could not be claimed or configured.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn create_pool_neuron(
+    lock_days: Option<u16>,
+    amount: u64,
+    nonce: u64,
+) -> Result<u64, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if lock_days.is_some_and(|d| !VALID_LOCKS.contains(&d)) {
+        return Err(DepositError::InvalidLockPeriod);
+    }
+    if amount < MIN_NEURON_STAKE_E8S {
+        return Err(DepositError::InvalidArgument(format!(
+            "a neuron needs at least {} e8s",
+            MIN_NEURON_STAKE_E8S
+        )));
+    }
+    let account = governance::neuron_subaccount(ic_cdk::id(), nonce);
+    transfer_to_governance(account, amount).await?;
+    let neuron_id = governance::claim_neuron(nonce).await?;
+    // Recorded before configuring so a failed dissolve delay change leaves a
+    // neuron the pool still knows about.
+    insert(PoolNeuron {
+        neuron_id,
+        registered_at: crate::now_secs(),
+        lock_days: None,
+        account: account.to_vec(),
+        staked: amount,
+    });
+    if let Some(days) = lock_days {
+        governance::increase_dissolve_delay(neuron_id, days as u32 * 86400).await?;
+        POOL_NEURONS.with(|m| {
+            let mut m = m.borrow_mut();
+            if let Some(mut neuron) = m.get(&neuron_id) {
+                neuron.lock_days = lock_days;
+                m.insert(neuron_id, neuron);
+            }
+        });
+    }
+    Ok(neuron_id)
+}
+
+/// Returns the neurons managed by the pool.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pool_neurons() -> Vec<PoolNeuron> {
+    all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neuron(neuron_id: u64, lock_days: Option<u16>, staked: u64) -> PoolNeuron {
+        PoolNeuron {
+            neuron_id,
+            registered_at: 0,
+            lock_days,
+            account: vec![0u8; 32],
+            staked,
+        }
+    }
+
+    #[test]
+    fn test_deposits_go_to_least_staked_neuron_of_tier() {
+        insert(neuron(1, Some(90), 500));
+        insert(neuron(2, Some(90), 100));
+        insert(neuron(3, Some(180), 0));
+        insert(neuron(4, None, 0));
+
+        assert_eq!(neuron_for_tier(90).map(|n| n.neuron_id), Some(2));
+        add_staked(2, 1_000);
+        assert_eq!(neuron_for_tier(90).map(|n| n.neuron_id), Some(1));
+        assert_eq!(neuron_for_tier(180).map(|n| n.neuron_id), Some(3));
+        assert_eq!(neuron_for_tier(360), None);
+    }
+}
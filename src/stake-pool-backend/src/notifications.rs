@@ -0,0 +1,177 @@
+// src/notifications.rs
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, NOTIFICATION_PREFS_MEMORY_ID};
+use crate::state_hash;
+use crate::PrincipalKey;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Which notifications a principal wants to receive.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotificationPrefs {
+    pub notify_on_maturity: bool,
+    /// Notify about reward payouts of at least this amount. `None` disables
+    /// reward notifications.
+    pub notify_on_reward_above: Option<u64>,
+    pub notify_on_governance_proposals: bool,
+}
+
+impl Default for NotificationPrefs {
+    fn default() -> Self {
+        Self {
+            notify_on_maturity: true,
+            notify_on_reward_above: None,
+            notify_on_governance_proposals: false,
+        }
+    }
+}
+
+impl Storable for NotificationPrefs {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode NotificationPrefs"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode NotificationPrefs")
+    }
+}
+
+impl BoundedStorable for NotificationPrefs {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A notification for a single principal. Delivered through the event log,
+/// from which frontends and off-chain relays pick them up.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Notification {
+    Maturity { deposit_id: u64 },
+    Reward { amount: u64 },
+    GovernanceProposal { proposal_id: u64 },
+}
+
+thread_local! {
+    static NOTIFICATION_PREFS: RefCell<StableBTreeMap<PrincipalKey, NotificationPrefs, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(NOTIFICATION_PREFS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "notification_prefs",
+        NOTIFICATION_PREFS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn prefs_of(principal: Principal) -> NotificationPrefs {
+    NOTIFICATION_PREFS.with(|m| m.borrow().get(&PrincipalKey(principal)).unwrap_or_default())
+}
+
+/// Moves the preferences of `from` to `to`, unless `to` already set its own.
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    NOTIFICATION_PREFS.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(prefs) = m.remove(&PrincipalKey(from)) {
+            if !m.contains_key(&PrincipalKey(to)) {
+                m.insert(PrincipalKey(to), prefs);
+            }
+        }
+    });
+}
+
+/// Dispatchers must call this before delivering `notification` to `principal`.
+pub fn should_notify(principal: Principal, notification: &Notification) -> bool {
+    let prefs = prefs_of(principal);
+    match notification {
+        Notification::Maturity { .. } => prefs.notify_on_maturity,
+        Notification::Reward { amount } => prefs
+            .notify_on_reward_above
+            .is_some_and(|threshold| *amount >= threshold),
+        Notification::GovernanceProposal { .. } => prefs.notify_on_governance_proposals,
+    }
+}
+
+/// Records `notification` for `principal` in the event log, unless their
+/// preferences opt out of it.
+pub fn dispatch(principal: Principal, notification: Notification, now: u64) {
+    if should_notify(principal, &notification) {
+        events::record(
+            now,
+            EventKind::Notified {
+                principal,
+                notification,
+            },
+        );
+    }
+}
+
+/// Stores the caller's notification preferences.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_notification_prefs(prefs: NotificationPrefs) {
+    let caller = ic_cdk::caller();
+    NOTIFICATION_PREFS.with(|m| m.borrow_mut().insert(PrincipalKey(caller), prefs));
+}
+
+/// Returns the caller's notification preferences, or the defaults if none were set.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_notification_prefs() -> NotificationPrefs {
+    prefs_of(ic_cdk::caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_notify_respects_prefs() {
+        let user = Principal::anonymous();
+        assert!(should_notify(
+            user,
+            &Notification::Maturity { deposit_id: 1 }
+        ));
+        assert!(!should_notify(
+            user,
+            &Notification::Reward { amount: 1_000 }
+        ));
+
+        NOTIFICATION_PREFS.with(|m| {
+            m.borrow_mut().insert(
+                PrincipalKey(user),
+                NotificationPrefs {
+                    notify_on_maturity: false,
+                    notify_on_reward_above: Some(500),
+                    notify_on_governance_proposals: true,
+                },
+            )
+        });
+
+        assert!(!should_notify(
+            user,
+            &Notification::Maturity { deposit_id: 1 }
+        ));
+        assert!(!should_notify(user, &Notification::Reward { amount: 499 }));
+        assert!(should_notify(user, &Notification::Reward { amount: 500 }));
+        assert!(should_notify(
+            user,
+            &Notification::GovernanceProposal { proposal_id: 7 }
+        ));
+
+        dispatch(user, Notification::Reward { amount: 10 }, 1);
+        dispatch(user, Notification::Reward { amount: 600 }, 2);
+        let delivered = events::range(0, 10);
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(
+            delivered[0].kind,
+            EventKind::Notified {
+                principal: user,
+                notification: Notification::Reward { amount: 600 }
+            }
+        );
+    }
+}
@@ -0,0 +1,126 @@
+// src/op_locks.rs
+//! Locks held across the ledger calls of deposits and withdrawals, so a
+//! second message cannot act on the same deposit or account while the first
+//! one awaits the ledger. Kept in stable memory; a lock older than
+//! `LOCK_TIMEOUT_SECS` is treated as leaked and may be taken over.
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, OPERATION_LOCKS_MEMORY_ID};
+use crate::state_hash;
+use crate::UserKey;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const LOCK_TIMEOUT_SECS: u64 = 600;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OperationKey {
+    Deposit(u64),
+    Account(UserKey),
+}
+
+impl Storable for OperationKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode OperationKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode OperationKey")
+    }
+}
+
+impl BoundedStorable for OperationKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Held locks, with the time each was acquired.
+    static OPERATION_LOCKS: RefCell<StableBTreeMap<OperationKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(OPERATION_LOCKS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "operation_locks",
+        OPERATION_LOCKS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Releases its lock when dropped, including when the message traps after an
+/// await and the replica cleans up the call.
+pub struct OperationGuard {
+    key: OperationKey,
+    acquired_at: u64,
+}
+
+impl OperationGuard {
+    pub fn acquire(key: OperationKey, now: u64) -> Result<Self, DepositError> {
+        OPERATION_LOCKS.with(|m| {
+            let mut m = m.borrow_mut();
+            if let Some(held_since) = m.get(&key) {
+                if now < held_since + LOCK_TIMEOUT_SECS {
+                    return Err(DepositError::ConcurrentOperation);
+                }
+            }
+            m.insert(key.clone(), now);
+            Ok(Self {
+                key,
+                acquired_at: now,
+            })
+        })
+    }
+
+    pub fn deposit(deposit_id: u64, now: u64) -> Result<Self, DepositError> {
+        Self::acquire(OperationKey::Deposit(deposit_id), now)
+    }
+
+    pub fn account(key: &UserKey, now: u64) -> Result<Self, DepositError> {
+        Self::acquire(OperationKey::Account(key.clone()), now)
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        // A lock taken over after a timeout belongs to its new holder.
+        OPERATION_LOCKS.with(|m| {
+            let mut m = m.borrow_mut();
+            if m.get(&self.key) == Some(self.acquired_at) {
+                m.remove(&self.key);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_held(deposit_id: u64) -> bool {
+        OPERATION_LOCKS.with(|m| m.borrow().contains_key(&OperationKey::Deposit(deposit_id)))
+    }
+
+    #[test]
+    fn test_second_operation_on_a_deposit_is_refused() {
+        let guard = OperationGuard::deposit(7, 100).unwrap();
+        assert_eq!(
+            OperationGuard::deposit(7, 101).err(),
+            Some(DepositError::ConcurrentOperation)
+        );
+        assert!(OperationGuard::deposit(8, 101).is_ok());
+        drop(guard);
+        assert!(!is_held(7));
+
+        // A leaked lock expires, and its old guard does not free the new one.
+        let leaked = OperationGuard::deposit(9, 100).unwrap();
+        let taken_over = OperationGuard::deposit(9, 100 + LOCK_TIMEOUT_SECS).unwrap();
+        drop(leaked);
+        assert!(is_held(9));
+        drop(taken_over);
+        assert!(!is_held(9));
+    }
+}
@@ -0,0 +1,104 @@
+// src/penalty.rs
+use crate::Deposit;
+use candid::{CandidType, Deserialize};
+
+const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Early-exit penalty that decays linearly from `start_bps` at deposit time to
+/// `end_bps` at maturity. Setting both to the same value gives a flat penalty.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PenaltyCurve {
+    pub start_bps: u16,
+    pub end_bps: u16,
+}
+
+impl Default for PenaltyCurve {
+    fn default() -> Self {
+        Self {
+            start_bps: 3_000,
+            end_bps: 0,
+        }
+    }
+}
+
+impl PenaltyCurve {
+    pub fn is_valid(&self) -> bool {
+        (self.start_bps as u64) <= BPS_DENOMINATOR && (self.end_bps as u64) <= BPS_DENOMINATOR
+    }
+
+    /// Penalty rate in basis points for a deposit at time `now`.
+    pub fn rate_bps(&self, deposit: &Deposit, now: u64) -> u64 {
+        let unlock_time = deposit.unlock_time();
+        if now >= unlock_time {
+            return 0;
+        }
+        let lock = unlock_time - deposit.timestamp;
+        let served = now.saturating_sub(deposit.timestamp) as i128;
+        let start = self.start_bps as i128;
+        let end = self.end_bps as i128;
+        (start + (end - start) * served / lock as i128) as u64
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawPreview {
+    pub amount: u64,
+    pub matured: bool,
+    pub unlock_timestamp: u64,
+    pub penalty_bps: u64,
+    pub penalty: u64,
+    pub payout: u64,
+}
+
+pub fn preview(deposit: &Deposit, now: u64, curve: &PenaltyCurve) -> WithdrawPreview {
+    let penalty_bps = curve.rate_bps(deposit, now);
+    let penalty = (deposit.amount as u128 * penalty_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+    WithdrawPreview {
+        amount: deposit.amount,
+        matured: now >= deposit.unlock_time(),
+        unlock_timestamp: deposit.unlock_time(),
+        penalty_bps,
+        penalty,
+        payout: deposit.amount - penalty,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit() -> Deposit {
+        Deposit {
+            id: 1,
+            amount: 1_000_000,
+            timestamp: 0,
+            lock_period_days: 90,
+        }
+    }
+
+    #[test]
+    fn test_penalty_decays_linearly_to_maturity() {
+        let curve = PenaltyCurve::default();
+        let d = deposit();
+
+        assert_eq!(preview(&d, 0, &curve).penalty, 300_000);
+        let halfway = preview(&d, 45 * 86400, &curve);
+        assert_eq!(halfway.penalty_bps, 1_500);
+        assert_eq!(halfway.payout, 850_000);
+        assert!(!halfway.matured);
+
+        let matured = preview(&d, 90 * 86400, &curve);
+        assert!(matured.matured);
+        assert_eq!(matured.penalty, 0);
+        assert_eq!(matured.payout, 1_000_000);
+    }
+
+    #[test]
+    fn test_flat_penalty() {
+        let curve = PenaltyCurve {
+            start_bps: 1_000,
+            end_bps: 1_000,
+        };
+        assert_eq!(preview(&deposit(), 80 * 86400, &curve).penalty, 100_000);
+    }
+}
@@ -0,0 +1,535 @@
+// src/pending_withdrawals.rs
+//! Two-phase withdrawals. A withdrawn deposit, or the withdrawn part of one,
+//! is parked here until its ledger transfer settles: it is dropped on
+//! success, restored to the owner's deposits when the ledger refused the
+//! transfer, and kept for `retry_withdrawal` when the outcome of the call is
+//! unknown. Controllers settle withdrawals the ledger can no longer
+//! deduplicate with `resolve_withdrawal`.
+use crate::circuit_breaker;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::ledger;
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, PENDING_WITHDRAWALS_MEMORY_ID};
+use crate::op_locks::OperationGuard;
+use crate::state_hash;
+use crate::status;
+use crate::transactions::{self, TransactionKind};
+use crate::treasury::{self, InflowSource};
+use crate::{Deposit, UserKey};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Leaves room for the destination within `PendingWithdrawal::MAX_SIZE`.
+const MAX_ERROR_LEN: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingWithdrawal {
+    pub key: UserKey,
+    /// The deposit as it was before the withdrawal.
+    pub deposit: Deposit,
+    /// Ledger `created_at_time` of the transfer, in nanoseconds. Reused on
+    /// every attempt so the ledger deduplicates retries.
+    pub created_at_time: u64,
+    /// Ledger fee deducted from the payout, fixed when the withdrawal was
+    /// parked so every attempt sends the same transfer. `None` for
+    /// withdrawals parked before fees were set explicitly.
+    pub fee: Option<u64>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Account the withdrawal is paid to. `None` pays the deposit's own
+    /// account.
+    pub destination: Option<Account>,
+    /// Part of the deposit withdrawn, the rest staying staked. `None`
+    /// withdraws the whole deposit.
+    pub amount: Option<u64>,
+    /// Early-exit penalty the pool keeps out of the deposit, booked to the
+    /// treasury once the transfer lands.
+    pub penalty: Option<u64>,
+}
+
+impl PendingWithdrawal {
+    /// What the pool owes the account until the transfer settles.
+    pub fn owed(&self) -> u64 {
+        self.amount.unwrap_or(self.deposit.amount)
+    }
+
+    /// What the transfer takes out of the pool, the ledger fee included.
+    fn gross(&self) -> u64 {
+        self.owed() - self.penalty.unwrap_or(0)
+    }
+}
+
+/// Which part of a deposit a withdrawal pays out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Part {
+    Whole,
+    /// An amount out of a deposit that stays open.
+    Partial(u64),
+    /// The whole deposit less an early-exit penalty.
+    Early {
+        penalty: u64,
+    },
+}
+
+/// How a controller settles a withdrawal whose transfer the ledger can no
+/// longer deduplicate, after looking the transfer up on the ledger.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WithdrawalResolution {
+    /// The transfer landed in this ledger block.
+    Paid { block_index: u64 },
+    /// No transfer landed; the deposit goes back to its owner.
+    NotPaid,
+}
+
+impl Storable for PendingWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PendingWithdrawal"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PendingWithdrawal")
+    }
+}
+
+impl BoundedStorable for PendingWithdrawal {
+    const MAX_SIZE: u32 = 400;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// How a withdrawal transfer attempt ended.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// Carries the ledger block of the transfer.
+    Transferred(u64),
+    /// The ledger refused the transfer; no tokens moved.
+    Refused(String),
+    /// The call failed without a reply; the transfer may or may not exist.
+    Unknown(String),
+}
+
+thread_local! {
+    // Keyed by deposit id.
+    static PENDING: RefCell<StableBTreeMap<u64, PendingWithdrawal, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PENDING_WITHDRAWALS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "pending_withdrawals",
+        PENDING.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn get(deposit_id: u64) -> Option<PendingWithdrawal> {
+    PENDING.with(|m| m.borrow().get(&deposit_id))
+}
+
+pub fn total_pending() -> u64 {
+    PENDING.with(|m| m.borrow().iter().map(|(_, p)| p.owed()).sum())
+}
+
+/// Fails while a withdrawal of the deposit waits for its transfer, since a
+/// deposit has room for one pending withdrawal only.
+pub fn ensure_none(deposit_id: u64) -> Result<(), DepositError> {
+    match get(deposit_id) {
+        Some(_) => Err(DepositError::OperationInProgress),
+        None => Ok(()),
+    }
+}
+
+/// Parks `part` of a deposit already taken out of the owner's deposits, to
+/// be paid to `destination` or else to the owner.
+pub fn mark(
+    key: UserKey,
+    deposit: Deposit,
+    part: Part,
+    fee: u64,
+    now_nanos: u64,
+    destination: Option<Account>,
+) -> PendingWithdrawal {
+    let (amount, penalty) = match part {
+        Part::Whole => (None, None),
+        Part::Partial(amount) => (Some(amount), None),
+        Part::Early { penalty } => (None, Some(penalty)),
+    };
+    let pending = PendingWithdrawal {
+        key,
+        deposit,
+        created_at_time: now_nanos,
+        fee: Some(fee),
+        attempts: 0,
+        last_error: None,
+        destination,
+        amount,
+        penalty,
+    };
+    PENDING.with(|m| m.borrow_mut().insert(pending.deposit.id, pending.clone()));
+    pending
+}
+
+fn classify(reply: Result<(Result<Nat, TransferError>,), String>) -> Outcome {
+    match reply {
+        Ok((Ok(block),))
+        | Ok((Err(TransferError::Duplicate {
+            duplicate_of: block,
+        }),)) => Outcome::Transferred(crate::block_index(block)),
+        // Outside the dedup window the ledger cannot tell whether an earlier
+        // attempt landed, so the withdrawal stays pending.
+        Ok((Err(e @ TransferError::TooOld),)) => Outcome::Unknown(format!("{:?}", e)),
+        Ok((Err(e),)) => Outcome::Refused(format!("{:?}", e)),
+        Err(e) => Outcome::Unknown(e),
+    }
+}
+
+/// Applies the outcome of an attempt. Returns the amount received, net of
+/// the ledger fee, on success.
+fn settle(
+    mut pending: PendingWithdrawal,
+    outcome: Outcome,
+    caller: Principal,
+    now: u64,
+) -> Result<u64, DepositError> {
+    let deposit_id = pending.deposit.id;
+    match outcome {
+        Outcome::Transferred(block) => {
+            PENDING.with(|m| m.borrow_mut().remove(&deposit_id));
+            let kind = match pending.penalty {
+                Some(penalty) => {
+                    if penalty > 0 {
+                        transactions::record(
+                            now,
+                            caller,
+                            TransactionKind::Penalty,
+                            Some(pending.key.clone()),
+                            penalty,
+                            None,
+                        );
+                        treasury::credit(InflowSource::Penalty, penalty, now);
+                    }
+                    TransactionKind::EarlyWithdrawal
+                }
+                None => TransactionKind::Withdrawal,
+            };
+            let gross = pending.gross();
+            transactions::record(now, caller, kind, Some(pending.key), gross, Some(block));
+            Ok(gross - pending.fee.unwrap_or(0))
+        }
+        Outcome::Refused(reason) => {
+            PENDING.with(|m| m.borrow_mut().remove(&deposit_id));
+            match pending.amount {
+                Some(amount) => {
+                    crate::restore_withdrawn_part(&pending.key, &pending.deposit, amount, now)
+                }
+                None => crate::restore_deposit(&pending.key, pending.deposit.clone(), now),
+            }
+            events::record(
+                now,
+                EventKind::WithdrawalReverted {
+                    key: pending.key.clone(),
+                    deposit_id,
+                    amount: pending.owed(),
+                },
+            );
+            Err(DepositError::LedgerTransferFailed(reason))
+        }
+        Outcome::Unknown(mut reason) => {
+            reason.truncate(MAX_ERROR_LEN);
+            pending.last_error = Some(reason.clone());
+            PENDING.with(|m| m.borrow_mut().insert(deposit_id, pending));
+            Err(DepositError::LedgerTransferFailed(reason))
+        }
+    }
+}
+
+/// Issues the transfer of a parked withdrawal on behalf of `caller` and
+/// settles it.
+pub async fn execute(
+    mut pending: PendingWithdrawal,
+    caller: Principal,
+) -> Result<u64, DepositError> {
+    pending.attempts += 1;
+    PENDING.with(|m| m.borrow_mut().insert(pending.deposit.id, pending.clone()));
+    let arg = TransferArg {
+        to: pending.destination.unwrap_or(Account {
+            owner: pending.key.principal,
+            subaccount: Some(pending.key.subaccount.0),
+        }),
+        amount: (pending.gross() - pending.fee.unwrap_or(0)).into(),
+        fee: pending.fee.map(Nat::from),
+        memo: Some(Memo::from(pending.deposit.id)),
+        created_at_time: Some(pending.created_at_time),
+        from_subaccount: None,
+    };
+    let reply = circuit_breaker::observe(call(ledger::ledger_id(), "icrc1_transfer", (arg,)).await)
+        .map_err(|e| format!("{:?}", e));
+    if let Ok((Err(TransferError::BadFee { expected_fee }),)) = &reply {
+        ledger::note_fee(ledger::ledger_id(), expected_fee, crate::now_secs());
+    }
+    settle(pending, classify(reply), caller, crate::now_secs())
+}
+
+/// Retries the ledger transfer of a withdrawal whose outcome was unknown.
+/// The owner of the deposit or a controller may call this.
+///
+/// # Returns
+///
+/// * The amount received, net of the ledger fee.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If no withdrawal of this deposit is pending.
+/// * `DepositError::Unauthorized`: If the caller neither owns the deposit nor controls the pool.
+/// * `DepositError::ConcurrentOperation`: If another attempt at the withdrawal is running.
+/// * `DepositError::LedgerTransferFailed`: If the transfer failed again. A refused
+///   transfer returns the deposit to its owner; otherwise it stays pending.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn retry_withdrawal(deposit_id: u64) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let pending = get(deposit_id).ok_or(DepositError::NoDepositFound)?;
+    let caller = ic_cdk::caller();
+    if caller != pending.key.principal {
+        crate::ensure_controller(caller)?;
+    }
+    let _guard = OperationGuard::deposit(deposit_id, crate::now_secs())?;
+    execute(pending, caller).await
+}
+
+/// Settles a pending withdrawal by hand. Meant for withdrawals the ledger
+/// answered with `TooOld`, or that failed without a reply for longer than
+/// its deduplication window, once a controller has looked the transfer up
+/// on the ledger. Only controllers may call this.
+///
+/// # Returns
+///
+/// * The amount the owner received, net of the ledger fee, or 0 for `NotPaid`.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If no withdrawal of this deposit is pending.
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::ConcurrentOperation`: If a retry of the withdrawal is running.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn resolve_withdrawal(
+    deposit_id: u64,
+    resolution: WithdrawalResolution,
+) -> Result<u64, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    transactions::admin(
+        "resolve_withdrawal",
+        resolve(deposit_id, resolution, caller, crate::now_secs()),
+    )
+}
+
+fn resolve(
+    deposit_id: u64,
+    resolution: WithdrawalResolution,
+    caller: Principal,
+    now: u64,
+) -> Result<u64, DepositError> {
+    let pending = get(deposit_id).ok_or(DepositError::NoDepositFound)?;
+    let _guard = OperationGuard::deposit(deposit_id, now)?;
+    match resolution {
+        WithdrawalResolution::Paid { block_index } => {
+            settle(pending, Outcome::Transferred(block_index), caller, now)
+        }
+        WithdrawalResolution::NotPaid => {
+            // Settling a refusal only gives the deposit back, so its error
+            // says nothing here.
+            let refused = Outcome::Refused("resolved as not paid".to_string());
+            let _ = settle(pending, refused, caller, now);
+            Ok(0)
+        }
+    }
+}
+
+/// Returns the caller's withdrawals waiting for their ledger transfer.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pending_withdrawals() -> Vec<PendingWithdrawal> {
+    let caller = ic_cdk::caller();
+    PENDING.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.key.principal == caller)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_refused_transfer_restores_deposit_and_unknown_stays_pending() {
+        let principal = Principal::anonymous();
+        let sub = Subaccount([4u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+        let deposit = crate::deposit_internal(principal, sub, 90, 1_000, 0).unwrap();
+        let day = 86400;
+        crate::withdraw_internal(principal, sub, deposit.id, 90 * day).unwrap();
+        let pending = mark(key.clone(), deposit.clone(), Part::Whole, 10, 1, None);
+        assert_eq!(total_pending(), 1_000);
+
+        let unknown = settle(
+            pending.clone(),
+            classify(Err("SysTransient".to_string())),
+            principal,
+            90 * day,
+        );
+        assert!(unknown.is_err());
+        assert_eq!(
+            get(deposit.id).unwrap().last_error.as_deref(),
+            Some("SysTransient")
+        );
+
+        let refused = classify(Ok((Err(TransferError::InsufficientFunds {
+            balance: Nat::from(0u64),
+        }),)));
+        assert!(settle(pending, refused, principal, 90 * day).is_err());
+        assert_eq!(get(deposit.id), None);
+        assert_eq!(crate::find_deposit(&key, deposit.id), Ok(deposit.clone()));
+        assert_eq!(
+            crate::STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key)),
+            Some(1_000)
+        );
+
+        let duplicate = classify(Ok((Err(TransferError::Duplicate {
+            duplicate_of: Nat::from(3u64),
+        }),)));
+        assert_eq!(duplicate, Outcome::Transferred(3));
+        let pending = mark(key.clone(), deposit.clone(), Part::Whole, 10, 1, None);
+        assert_eq!(settle(pending, duplicate, principal, 90 * day), Ok(990));
+    }
+
+    #[test]
+    fn test_partial_and_early_withdrawals_settle_their_part() {
+        let principal = Principal::from_slice(&[181]);
+        let sub = Subaccount([5u8; 32]);
+        let key = UserKey {
+            principal,
+            subaccount: sub,
+        };
+        let day = 86400;
+        let deposit = crate::deposit_internal(principal, sub, 90, 1_000, 0).unwrap();
+        crate::withdraw_partial_internal(principal, sub, deposit.id, 300, 90 * day).unwrap();
+        let pending = mark(
+            key.clone(),
+            deposit.clone(),
+            Part::Partial(300),
+            10,
+            1,
+            None,
+        );
+        assert_eq!(
+            ensure_none(deposit.id),
+            Err(DepositError::OperationInProgress)
+        );
+        assert_eq!(total_pending(), 300);
+        let refused = classify(Ok((Err(TransferError::InsufficientFunds {
+            balance: Nat::from(0u64),
+        }),)));
+        assert!(settle(pending, refused, principal, 90 * day).is_err());
+        assert_eq!(crate::find_deposit(&key, deposit.id).unwrap().amount, 1_000);
+        assert_eq!(
+            crate::STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key)),
+            Some(1_000)
+        );
+
+        // The penalty is booked only once the transfer is known to have landed.
+        let (payout, penalty) =
+            crate::early_withdraw_internal(principal, sub, deposit.id, 45 * day).unwrap();
+        let pending = mark(
+            key.clone(),
+            deposit.clone(),
+            Part::Early { penalty },
+            10,
+            1,
+            None,
+        );
+        let treasury_before = treasury::state().balance;
+        let unknown = classify(Ok((Err(TransferError::TooOld),)));
+        assert!(settle(pending, unknown, principal, 45 * day).is_err());
+        assert_eq!(treasury::state().balance, treasury_before);
+        assert_eq!(
+            resolve(
+                deposit.id,
+                WithdrawalResolution::Paid { block_index: 7 },
+                principal,
+                45 * day
+            ),
+            Ok(payout - 10)
+        );
+        assert_eq!(treasury::state().balance, treasury_before + penalty);
+        assert_eq!(get(deposit.id), None);
+        assert_eq!(
+            resolve(
+                deposit.id,
+                WithdrawalResolution::NotPaid,
+                principal,
+                45 * day
+            ),
+            Err(DepositError::NoDepositFound)
+        );
+    }
+
+    #[test]
+    fn test_destination_is_checked_and_fits_storage() {
+        let pool = Principal::from_slice(&[180]);
+        let long = Principal::from_slice(&[7; 29]);
+        let destination = Account {
+            owner: long,
+            subaccount: Some([9; 32]),
+        };
+        assert_eq!(crate::check_destination(&destination, pool), Ok(()));
+        for owner in [pool, Principal::anonymous()] {
+            let account = Account {
+                owner,
+                subaccount: None,
+            };
+            assert_eq!(
+                crate::check_destination(&account, pool),
+                Err(DepositError::InvalidDestination)
+            );
+        }
+        crate::denylist::add(long);
+        assert_eq!(
+            crate::check_destination(&destination, pool),
+            Err(DepositError::InvalidDestination)
+        );
+
+        let key = UserKey {
+            principal: long,
+            subaccount: Subaccount([u8::MAX; 32]),
+        };
+        let deposit = Deposit {
+            id: u64::MAX,
+            amount: u64::MAX,
+            timestamp: u64::MAX,
+            lock_period_days: u16::MAX,
+        };
+        let part = Part::Early { penalty: u64::MAX };
+        let mut pending = mark(key, deposit, part, u64::MAX, u64::MAX, Some(destination));
+        pending.attempts = u32::MAX;
+        pending.amount = Some(u64::MAX);
+        pending.last_error = Some("x".repeat(MAX_ERROR_LEN));
+        assert!(pending.to_bytes().len() <= PendingWithdrawal::MAX_SIZE as usize);
+    }
+}
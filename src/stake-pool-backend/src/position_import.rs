@@ -0,0 +1,374 @@
+// src/position_import.rs
+use crate::backup::{AccountSnapshot, StateChanges};
+use crate::config;
+use crate::error::DepositError;
+use crate::events::{self, EventKind, PoolEvent, MAX_EVENTS_PER_PAGE};
+use crate::memory::{get_memory, Memory, POSITION_IMPORTS_MEMORY_ID};
+use crate::state_hash;
+use crate::status::PoolStatus;
+use crate::{UserKey, VALID_LOCKS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ImportStatus {
+    Pending,
+    /// Approved and fetching the export from the source pool.
+    Importing,
+    Imported {
+        accounts: u64,
+        deposits: u64,
+        amount: u64,
+    },
+    Failed(String),
+    Cancelled,
+}
+
+/// Import of every position of a migrated pool into this one.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PositionImport {
+    pub id: u64,
+    pub source: Principal,
+    pub proposed_at: u64,
+    /// Controllers that approved, the proposer first.
+    pub approvals: Vec<Principal>,
+    pub status: ImportStatus,
+}
+
+impl Storable for PositionImport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PositionImport"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PositionImport")
+    }
+}
+
+impl BoundedStorable for PositionImport {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static POSITION_IMPORTS: RefCell<StableBTreeMap<u64, PositionImport, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(POSITION_IMPORTS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "position_imports",
+        POSITION_IMPORTS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+fn get_import(id: u64) -> Result<PositionImport, DepositError> {
+    POSITION_IMPORTS
+        .with(|m| m.borrow().get(&id))
+        .ok_or_else(|| DepositError::InvalidArgument(format!("unknown position import {}", id)))
+}
+
+fn put_import(import: PositionImport) {
+    POSITION_IMPORTS.with(|m| m.borrow_mut().insert(import.id, import));
+}
+
+pub fn propose(proposer: Principal, source: Principal, now: u64) -> Result<u64, DepositError> {
+    let taken = POSITION_IMPORTS.with(|m| {
+        m.borrow().iter().any(|(_, i)| {
+            i.source == source
+                && matches!(
+                    i.status,
+                    ImportStatus::Pending | ImportStatus::Importing | ImportStatus::Imported { .. }
+                )
+        })
+    });
+    if taken {
+        return Err(DepositError::InvalidArgument(format!(
+            "positions of {} are already being imported",
+            source
+        )));
+    }
+    let id = POSITION_IMPORTS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    put_import(PositionImport {
+        id,
+        source,
+        proposed_at: now,
+        approvals: vec![proposer],
+        status: ImportStatus::Pending,
+    });
+    Ok(id)
+}
+
+/// Adds `approver` to a pending import. Returns the import, marked as
+/// importing, once it has as many approvals as a treasury disbursement needs.
+pub fn approve(approver: Principal, id: u64) -> Result<Option<PositionImport>, DepositError> {
+    let mut import = get_import(id)?;
+    if import.status != ImportStatus::Pending {
+        return Err(DepositError::InvalidArgument(
+            "position import is not pending".to_string(),
+        ));
+    }
+    if !import.approvals.contains(&approver) {
+        import.approvals.push(approver);
+    }
+    let ready = import.approvals.len() >= config::get().treasury_approvals_required as usize;
+    if ready {
+        import.status = ImportStatus::Importing;
+    }
+    put_import(import.clone());
+    Ok(ready.then_some(import))
+}
+
+/// Recreates the deposits of `accounts` with their original timestamps and
+/// lock terms, provided `transferred` covers them. Either every deposit is
+/// imported or none is.
+pub fn import_accounts(
+    source: Principal,
+    accounts: &[AccountSnapshot],
+    transferred: u64,
+    now: u64,
+) -> Result<ImportStatus, DepositError> {
+    let deposits = accounts.iter().flat_map(|a| &a.deposits);
+    if let Some(d) = deposits
+        .clone()
+        .find(|d| !VALID_LOCKS.contains(&d.lock_period_days))
+    {
+        return Err(DepositError::InvalidArgument(format!(
+            "deposit {} has an unsupported lock of {} days",
+            d.id, d.lock_period_days
+        )));
+    }
+    let amount = deposits
+        .clone()
+        .try_fold(0u64, |sum, d| sum.checked_add(d.amount))
+        .ok_or(DepositError::InsufficientBalance)?;
+    if transferred < amount {
+        return Err(DepositError::InsufficientBalance);
+    }
+
+    for account in accounts {
+        for d in &account.deposits {
+            crate::record_deposit(
+                account.key.principal,
+                account.key.subaccount,
+                d.lock_period_days,
+                d.amount,
+                d.timestamp,
+            )?;
+        }
+    }
+    events::record(
+        now,
+        EventKind::PositionsImported {
+            source,
+            accounts: accounts.len() as u64,
+            amount,
+        },
+    );
+    Ok(ImportStatus::Imported {
+        accounts: accounts.len() as u64,
+        deposits: deposits.count() as u64,
+        amount,
+    })
+}
+
+fn call_error<E: std::fmt::Debug>(e: E) -> String {
+    format!("{:?}", e)
+}
+
+/// Reads every account of `source` through its change feed, together with
+/// what its migration transferred to this pool.
+async fn fetch_export(source: Principal) -> Result<(Vec<AccountSnapshot>, u64), String> {
+    let this = ic_cdk::id();
+    let (status,): (PoolStatus,) = call(source, "get_pool_status", ())
+        .await
+        .map_err(call_error)?;
+    if status != (PoolStatus::Migrated { successor: this }) {
+        return Err(format!("{} has not migrated to this pool", source));
+    }
+
+    let mut accounts = BTreeMap::<UserKey, AccountSnapshot>::new();
+    let mut since = 0;
+    loop {
+        let (res,): (Result<StateChanges, DepositError>,) =
+            call(source, "export_changes", (since,))
+                .await
+                .map_err(call_error)?;
+        let changes = res.map_err(call_error)?;
+        for account in changes.accounts {
+            accounts.insert(account.key.clone(), account);
+        }
+        if changes.next_seq == since {
+            break;
+        }
+        since = changes.next_seq;
+    }
+
+    // A migrated pool accepts no other updates, so its migration transfers
+    // are among the last events.
+    let (tail,): (Vec<PoolEvent>,) = call(
+        source,
+        "get_events",
+        (
+            since.saturating_sub(MAX_EVENTS_PER_PAGE),
+            MAX_EVENTS_PER_PAGE,
+        ),
+    )
+    .await
+    .map_err(call_error)?;
+    let transferred = tail
+        .into_iter()
+        .filter_map(|e| match e.kind {
+            EventKind::MigratedTo { successor, amount } if successor == this => {
+                u64::try_from(amount.0).ok()
+            }
+            _ => None,
+        })
+        .sum();
+    Ok((accounts.into_values().collect(), transferred))
+}
+
+/// Proposes importing the positions of `source`, a pool running this
+/// interface that migrated its balance here with `migrate_to`. The proposal
+/// counts as the proposer's approval. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If an import of `source` is pending or done.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn propose_position_import(source: Principal) -> Result<u64, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    propose(caller, source, crate::now_secs())
+}
+
+/// Approves a pending import and runs it once it has the same number of
+/// approvals as a treasury disbursement. Only canister controllers may call
+/// this.
+///
+/// The export is read directly from the source canister, and only imported
+/// if the source reports being migrated to this pool and its migration
+/// transfers cover every imported deposit. Any surplus stays in the pool.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the import does not exist or is not pending.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn approve_position_import(id: u64) -> Result<PositionImport, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    let Some(mut import) = approve(caller, id)? else {
+        return get_import(id);
+    };
+    import.status = match fetch_export(import.source).await {
+        Ok((accounts, transferred)) => {
+            import_accounts(import.source, &accounts, transferred, crate::now_secs())
+                .unwrap_or_else(|e| ImportStatus::Failed(call_error(e)))
+        }
+        Err(e) => ImportStatus::Failed(e),
+    };
+    put_import(import.clone());
+    Ok(import)
+}
+
+/// Cancels a pending import. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the import does not exist or is not pending.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn cancel_position_import(id: u64) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let mut import = get_import(id)?;
+    if import.status != ImportStatus::Pending {
+        return Err(DepositError::InvalidArgument(
+            "position import is not pending".to_string(),
+        ));
+    }
+    import.status = ImportStatus::Cancelled;
+    put_import(import);
+    Ok(())
+}
+
+/// Returns all position imports, oldest first.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_position_imports() -> Vec<PositionImport> {
+    POSITION_IMPORTS.with(|m| m.borrow().iter().map(|(_, i)| i).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Deposit, STAKE_BALANCE_MAP};
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_import_keeps_terms_and_requires_funds() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let source = Principal::from_slice(&[9]);
+
+        let id = propose(alice, source, 0).unwrap();
+        assert!(propose(bob, source, 0).is_err());
+        assert_eq!(approve(alice, id), Ok(None));
+        let import = approve(bob, id).unwrap().unwrap();
+        assert_eq!(import.status, ImportStatus::Importing);
+
+        let key = UserKey {
+            principal: alice,
+            subaccount: Subaccount([3u8; 32]),
+        };
+        let accounts = vec![AccountSnapshot {
+            key: key.clone(),
+            deposits: vec![
+                Deposit {
+                    id: 7,
+                    amount: 400,
+                    timestamp: 1_000,
+                    lock_period_days: 360,
+                },
+                Deposit {
+                    id: 8,
+                    amount: 100,
+                    timestamp: 2_000,
+                    lock_period_days: 90,
+                },
+            ],
+            stake_balance: 500,
+        }];
+
+        assert_eq!(
+            import_accounts(source, &accounts, 499, 5_000),
+            Err(DepositError::InsufficientBalance)
+        );
+        let stake = |key: &UserKey| STAKE_BALANCE_MAP.with(|m| m.borrow().get(key)).unwrap_or(0);
+        assert_eq!(stake(&key), 0);
+
+        assert_eq!(
+            import_accounts(source, &accounts, 600, 5_000),
+            Ok(ImportStatus::Imported {
+                accounts: 1,
+                deposits: 2,
+                amount: 500
+            })
+        );
+        assert_eq!(stake(&key), 500);
+        let imported = crate::deposits_of(&key);
+        assert_eq!(imported[0].timestamp, 1_000);
+        assert_eq!(imported[0].lock_period_days, 360);
+    }
+}
@@ -0,0 +1,138 @@
+// src/positions.rs
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, PUBLIC_POSITIONS_MEMORY_ID};
+use crate::state_hash;
+use crate::{accrual, liquid, Deposit, PrincipalKey, UserKey, STAKE_BALANCE_MAP};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// Everything one subaccount holds in the pool.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Position {
+    pub subaccount: Subaccount,
+    pub deposits: Vec<Deposit>,
+    pub stake: u64,
+    pub st_balance: u64,
+    pub pending_rewards: u64,
+}
+
+thread_local! {
+    // Principals that let anyone read their positions.
+    static PUBLIC_POSITIONS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PUBLIC_POSITIONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "public_positions",
+        PUBLIC_POSITIONS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn is_public(principal: Principal) -> bool {
+    PUBLIC_POSITIONS.with(|m| m.borrow().contains_key(&PrincipalKey(principal)))
+}
+
+pub fn set_public(principal: Principal, public: bool) {
+    PUBLIC_POSITIONS.with(|m| {
+        let mut m = m.borrow_mut();
+        if public {
+            m.insert(PrincipalKey(principal), ());
+        } else {
+            m.remove(&PrincipalKey(principal));
+        }
+    });
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    if is_public(from) {
+        set_public(from, false);
+        set_public(to, true);
+    }
+}
+
+pub fn positions_of(principal: Principal) -> Vec<Position> {
+    let staked: Vec<UserKey> = STAKE_BALANCE_MAP.with(|m| {
+        m.borrow()
+            .range(UserKey::range_of_principal(principal))
+            .map(|(k, _)| k)
+            .collect()
+    });
+    let subaccounts: BTreeSet<Subaccount> = accrual::keys_of(principal)
+        .into_iter()
+        .chain(staked)
+        .map(|key| key.subaccount)
+        .collect();
+    subaccounts
+        .into_iter()
+        .map(|subaccount| {
+            let key = UserKey {
+                principal,
+                subaccount,
+            };
+            Position {
+                subaccount,
+                deposits: crate::deposits_of(&key),
+                stake: STAKE_BALANCE_MAP.with(|m| m.borrow().get(&key).unwrap_or(0)),
+                st_balance: liquid::balance_of(&key),
+                pending_rewards: accrual::claimable(&key),
+            }
+        })
+        .collect()
+}
+
+/// Makes the caller's positions readable by anyone through `get_positions_of`,
+/// or private again. Positions are private by default.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_positions_public(public: bool) {
+    set_public(ic_cdk::caller(), public);
+}
+
+/// Returns whether the caller's positions are publicly readable.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_positions_public() -> bool {
+    is_public(ic_cdk::caller())
+}
+
+/// Returns the positions of `principal`, per subaccount.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If `principal` keeps its positions private
+///   and is not the caller.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_positions_of(principal: Principal) -> Result<Vec<Position>, DepositError> {
+    if principal != ic_cdk::caller() && !is_public(principal) {
+        return Err(DepositError::Unauthorized);
+    }
+    Ok(positions_of(principal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_positions_are_private_by_default() {
+        let alice = Principal::from_slice(&[1]);
+        assert!(!is_public(alice));
+        set_public(alice, true);
+        assert!(is_public(alice));
+
+        crate::deposit_internal(alice, Subaccount([1u8; 32]), 90, 100, 0).unwrap();
+        crate::deposit_internal(alice, Subaccount([2u8; 32]), 90, 50, 0).unwrap();
+        let positions = positions_of(alice);
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[1].stake, 50);
+        assert_eq!(positions[1].deposits.len(), 1);
+
+        set_public(alice, false);
+        assert!(!is_public(alice));
+    }
+}
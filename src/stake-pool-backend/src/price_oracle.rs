@@ -0,0 +1,436 @@
+// src/price_oracle.rs
+//! USD price of the staked token, fetched from the exchange rate canister or
+//! an exchange API over HTTPS outcalls, and cached for the USD-denominated
+//! pool stats and the price snapshots of reward distributions.
+use crate::error::DepositError;
+use crate::ledger;
+use crate::memory::{get_memory, Memory, PRICE_ORACLE_MEMORY_ID};
+use crate::state_hash;
+use crate::stats::{self, PoolStats};
+use crate::transactions::{self, TransactionKind};
+use crate::xrc;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(900);
+const MAX_RESPONSE_BYTES: u64 = 4096;
+// Covers a request to a 34-node subnet; cycles the call does not use are
+// refunded.
+const REQUEST_CYCLES: u128 = 2_000_000_000;
+const USD_DECIMALS: u32 = 8;
+
+/// Where the price is fetched from.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceSource {
+    /// HTTPS endpoint answering a GET with a JSON document.
+    pub url: String,
+    /// JSON pointer to the price in the response, e.g. `/data/amount`. The
+    /// price may be a number or a decimal string.
+    pub json_pointer: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct UsdPrice {
+    /// USD per whole token, with 8 decimals.
+    pub usd_e8s: u64,
+    pub fetched_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OracleState {
+    pub source: Option<PriceSource>,
+    /// Symbol of the token at the exchange rate canister, e.g. `ICP`. When
+    /// set, the price is fetched from there instead of `source`.
+    pub xrc_symbol: Option<String>,
+    /// Age after which a cached price is no longer used.
+    pub max_age_secs: u64,
+    pub price: Option<UsdPrice>,
+}
+
+impl Default for OracleState {
+    fn default() -> Self {
+        Self {
+            source: None,
+            xrc_symbol: None,
+            max_age_secs: 3600,
+            price: None,
+        }
+    }
+}
+
+impl Storable for OracleState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode OracleState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode OracleState")
+    }
+}
+
+/// Pool totals in USD, with 8 decimals.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PoolStatsUsd {
+    pub price: UsdPrice,
+    pub total_staked_usd_e8s: u64,
+    pub total_value_locked_usd_e8s: u64,
+    pub total_rewards_distributed_usd_e8s: u64,
+}
+
+thread_local! {
+    static ORACLE_STATE: RefCell<StableCell<OracleState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(PRICE_ORACLE_MEMORY_ID), OracleState::default())
+            .expect("Failed to init price oracle cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "price_oracle",
+        ORACLE_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+pub fn state() -> OracleState {
+    ORACLE_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update(f: impl FnOnce(&mut OracleState)) {
+    let mut state = state();
+    f(&mut state);
+    ORACLE_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist price oracle state");
+    });
+}
+
+/// Parses a decimal such as `"7.125"` into units of 10^-8, dropping digits
+/// past the eighth decimal.
+pub fn parse_e8s(decimal: &str) -> Option<u64> {
+    let (whole, fraction) = decimal.split_once('.').unwrap_or((decimal, ""));
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() || !digits(whole) || !digits(fraction) {
+        return None;
+    }
+    let fraction = &fraction[..fraction.len().min(USD_DECIMALS as usize)];
+    let scale = 10u64.pow(USD_DECIMALS - fraction.len() as u32);
+    let fraction = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u64>().ok()?
+    };
+    whole
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(10u64.pow(USD_DECIMALS))?
+        .checked_add(fraction * scale)
+}
+
+/// Reads the price at `json_pointer` out of an API response body.
+pub fn parse_price(body: &[u8], json_pointer: &str) -> Result<u64, String> {
+    let json: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let value = json
+        .pointer(json_pointer)
+        .ok_or_else(|| format!("no value at {}", json_pointer))?;
+    let decimal = match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => return Err(format!("value at {} is not a number", json_pointer)),
+    };
+    match parse_e8s(&decimal) {
+        Some(price) if price > 0 => Ok(price),
+        _ => Err(format!("unusable price {:?}", decimal)),
+    }
+}
+
+/// Converts `amount` base units of a token with `decimals` decimals to USD.
+fn to_usd_e8s(amount: u64, usd_e8s: u64, decimals: u8) -> u64 {
+    let usd = amount as u128 * usd_e8s as u128 / 10u128.pow(decimals as u32);
+    usd.min(u64::MAX as u128) as u64
+}
+
+/// Prices `stats` in USD, unless the price is missing or older than the
+/// state's staleness window.
+pub fn stats_usd(
+    stats: &PoolStats,
+    state: &OracleState,
+    decimals: u8,
+    now: u64,
+) -> Result<PoolStatsUsd, DepositError> {
+    let price = state
+        .price
+        .clone()
+        .ok_or_else(|| DepositError::PriceUnavailable("no price fetched yet".to_string()))?;
+    if now.saturating_sub(price.fetched_at) > state.max_age_secs {
+        return Err(DepositError::PriceUnavailable(format!(
+            "price fetched at {} is stale",
+            price.fetched_at
+        )));
+    }
+    let usd = |amount| to_usd_e8s(amount, price.usd_e8s, decimals);
+    Ok(PoolStatsUsd {
+        total_staked_usd_e8s: usd(stats.total_staked),
+        total_value_locked_usd_e8s: usd(stats.total_value_locked),
+        total_rewards_distributed_usd_e8s: usd(stats.total_rewards_distributed),
+        price,
+    })
+}
+
+async fn fetch(source: &PriceSource) -> Result<u64, String> {
+    let request = CanisterHttpRequestArgument {
+        url: source.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: Vec::new(),
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_price_response".to_string(),
+            Vec::new(),
+        )),
+    };
+    let (response,) = http_request(request, REQUEST_CYCLES)
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+    if response.status != 200u64 {
+        return Err(format!("HTTP status {}", response.status));
+    }
+    parse_price(&response.body, &source.json_pointer)
+}
+
+/// Fetches the price from the configured source and caches it. The cached
+/// price is kept when the fetch fails.
+pub async fn refresh(now: u64) -> Result<UsdPrice, DepositError> {
+    let state = state();
+    let usd_e8s = match (state.xrc_symbol, state.source) {
+        (Some(symbol), _) => xrc::fetch_usd_e8s(&symbol).await,
+        (None, Some(source)) => fetch(&source).await,
+        (None, None) => Err("no price source set".to_string()),
+    }
+    .map_err(DepositError::PriceUnavailable)?;
+    let price = UsdPrice {
+        usd_e8s,
+        fetched_at: now,
+    };
+    update(|s| s.price = Some(price.clone()));
+    Ok(price)
+}
+
+/// Starts refreshing the price every 15 minutes. Must be called from `init`
+/// and `post_upgrade`, since timers do not survive upgrades.
+pub fn start_refresh() {
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, || {
+        let state = state();
+        if state.xrc_symbol.is_some() || state.source.is_some() {
+            ic_cdk::spawn(async {
+                let _ = refresh(crate::now_secs()).await;
+            });
+        }
+    });
+}
+
+/// Records the cached price in the transaction log next to a distribution of
+/// `amount` rewards, so that its value can be recomputed later. Nothing is
+/// recorded without a price inside the staleness window.
+pub(crate) fn record_snapshot(now: u64, caller: Principal, amount: u64) {
+    let state = state();
+    let Some(price) = state
+        .price
+        .filter(|p| now.saturating_sub(p.fetched_at) <= state.max_age_secs)
+    else {
+        return;
+    };
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::PriceSnapshot {
+            usd_e8s: price.usd_e8s,
+            fetched_at: price.fetched_at,
+        },
+        None,
+        amount,
+        None,
+    );
+}
+
+/// Strips the headers of a price response, so that every replica of the
+/// subnet agrees on it.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn transform_price_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: args.response.body,
+    }
+}
+
+/// Sets where the USD price is fetched from and how long a fetched price is
+/// used. `None` stops fetching and drops the cached price. Only canister
+/// controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidConfig`: If the URL is not HTTPS or the staleness window is zero.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_price_source(
+    source: Option<PriceSource>,
+    max_age_secs: u64,
+) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if source
+        .as_ref()
+        .is_some_and(|s| !s.url.starts_with("https://"))
+    {
+        return Err(DepositError::InvalidConfig(
+            "price source must be an HTTPS URL".to_string(),
+        ));
+    }
+    if max_age_secs == 0 {
+        return Err(DepositError::InvalidConfig(
+            "price staleness window must be positive".to_string(),
+        ));
+    }
+    update(|s| {
+        if s.source != source {
+            s.price = None;
+        }
+        s.source = source;
+        s.max_age_secs = max_age_secs;
+    });
+    transactions::admin("set_price_source", Ok(()))
+}
+
+/// Prices the token at the IC exchange rate canister, as `symbol`/USD,
+/// instead of the HTTPS source. `None` goes back to the HTTPS source. Only
+/// canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidConfig`: If the symbol is empty.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_xrc_price_symbol(symbol: Option<String>) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    if symbol.as_ref().is_some_and(|s| s.trim().is_empty()) {
+        return Err(DepositError::InvalidConfig(
+            "exchange rate symbol must not be empty".to_string(),
+        ));
+    }
+    update(|s| {
+        if s.xrc_symbol != symbol {
+            s.price = None;
+        }
+        s.xrc_symbol = symbol;
+    });
+    transactions::admin("set_xrc_price_symbol", Ok(()))
+}
+
+/// Fetches the USD price now instead of waiting for the next refresh. Only
+/// canister controllers may call this, since each fetch costs cycles.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::PriceUnavailable`: If no source is set or the fetch failed.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn refresh_usd_price() -> Result<UsdPrice, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    refresh(crate::now_secs()).await
+}
+
+/// Returns the price sources, staleness window and cached USD price.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_price_oracle() -> OracleState {
+    state()
+}
+
+/// Returns the pool's staked total, total value locked and rewards
+/// distributed to date in USD, at the cached price.
+///
+/// # Errors
+///
+/// * `DepositError::PriceUnavailable`: If there is no price within the
+///   staleness window, or the token's decimals are not known yet.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pool_stats_usd() -> Result<PoolStatsUsd, DepositError> {
+    let decimals = ledger::token_metadata()
+        .ok_or_else(|| DepositError::PriceUnavailable("token decimals unknown".to_string()))?
+        .decimals;
+    stats_usd(&stats::pool_stats(), &state(), decimals, crate::now_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_is_parsed_and_applied_within_window() {
+        let body = br#"{"data":{"base":"ICP","currency":"USD","amount":"7.123456789"}}"#;
+        assert_eq!(parse_price(body, "/data/amount"), Ok(712_345_678));
+        assert_eq!(parse_price(br#"{"usd":12.5}"#, "/usd"), Ok(1_250_000_000));
+        assert!(parse_price(br#"{"usd":"-1"}"#, "/usd").is_err());
+        assert!(parse_price(body, "/data/missing").is_err());
+
+        let stats = PoolStats {
+            total_staked: 300_000_000,
+            total_value_locked: 500_000_000,
+            total_rewards_distributed: 10_000_000,
+            ..PoolStats::default()
+        };
+        let mut state = OracleState::default();
+        assert!(stats_usd(&stats, &state, 8, 0).is_err());
+
+        state.price = Some(UsdPrice {
+            usd_e8s: 700_000_000,
+            fetched_at: 1_000,
+        });
+        let usd = stats_usd(&stats, &state, 8, 1_000 + state.max_age_secs).unwrap();
+        assert_eq!(usd.total_staked_usd_e8s, 2_100_000_000);
+        assert_eq!(usd.total_value_locked_usd_e8s, 3_500_000_000);
+        assert_eq!(usd.total_rewards_distributed_usd_e8s, 70_000_000);
+        assert!(stats_usd(&stats, &state, 8, 1_001 + state.max_age_secs).is_err());
+    }
+
+    #[test]
+    fn test_distributions_snapshot_a_fresh_price() {
+        let caller = Principal::from_slice(&[71]);
+        record_snapshot(1_000, caller, 50);
+        update(|s| {
+            s.price = Some(UsdPrice {
+                usd_e8s: 700_000_000,
+                fetched_at: 1_000,
+            })
+        });
+        record_snapshot(1_000, caller, 60);
+        record_snapshot(1_001 + state().max_age_secs, caller, 70);
+
+        let snapshots: Vec<_> = transactions::range(0, 10)
+            .into_iter()
+            .filter(|t| matches!(t.kind, TransactionKind::PriceSnapshot { .. }))
+            .collect();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].amount, 60);
+        assert_eq!(
+            snapshots[0].kind,
+            TransactionKind::PriceSnapshot {
+                usd_e8s: 700_000_000,
+                fetched_at: 1_000
+            }
+        );
+    }
+}
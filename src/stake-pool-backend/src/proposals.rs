@@ -0,0 +1,411 @@
+// src/proposals.rs
+//! Staker governance of pool parameters. Stakers propose a change, vote on
+//! it with their locked stake during the configured window, and a timer
+//! executes proposals that pass once voting closes.
+use crate::config;
+use crate::error::DepositError;
+use crate::governance::Vote;
+use crate::memory::{get_memory, Memory, PROPOSALS_MEMORY_ID, PROPOSAL_BALLOTS_MEMORY_ID};
+use crate::multipliers::{self, LockMultiplier, MultiplierSchedule};
+use crate::penalty::PenaltyCurve;
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// How often the timer looks for proposals whose voting closed.
+const TALLY_INTERVAL: Duration = Duration::from_secs(60);
+/// Shortest voting window the config accepts.
+pub const MIN_VOTING_SECS: u64 = 3_600;
+const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Parameter change a proposal makes if it passes.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProposalAction {
+    SetRewardMultipliers(Vec<LockMultiplier>),
+    SetEarlyExitPenalty(PenaltyCurve),
+    /// Approves a controller's `migrate_to` call for `successor`.
+    MigrateTo {
+        successor: Principal,
+    },
+}
+
+impl ProposalAction {
+    fn validate(&self) -> Result<(), DepositError> {
+        match self {
+            ProposalAction::SetRewardMultipliers(multipliers) => {
+                MultiplierSchedule(multipliers.clone()).validate()
+            }
+            ProposalAction::SetEarlyExitPenalty(curve) if !curve.is_valid() => Err(
+                DepositError::InvalidConfig("penalty rates must not exceed 10000 bps".to_string()),
+            ),
+            ProposalAction::SetEarlyExitPenalty(_) => Ok(()),
+            ProposalAction::MigrateTo { successor } if *successor == Principal::anonymous() => {
+                Err(DepositError::InvalidArgument(
+                    "cannot migrate to the anonymous principal".to_string(),
+                ))
+            }
+            ProposalAction::MigrateTo { .. } => Ok(()),
+        }
+    }
+
+    fn execute(&self, now: u64) -> Result<(), DepositError> {
+        match self {
+            ProposalAction::SetRewardMultipliers(multipliers) => {
+                multipliers::set_schedule(MultiplierSchedule(multipliers.clone()), now)
+            }
+            ProposalAction::SetEarlyExitPenalty(curve) => {
+                let mut config = config::get();
+                config.early_exit_penalty = curve.clone();
+                config.validate()?;
+                config::set(config);
+                Ok(())
+            }
+            // Checked by `migrate_to` through `migration_approved`.
+            ProposalAction::MigrateTo { .. } => Ok(()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProposalStatus {
+    Open,
+    Executed {
+        at: u64,
+    },
+    Rejected,
+    /// Passed, but the change could not be applied.
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Proposal {
+    pub id: u64,
+    pub proposer: Principal,
+    pub action: ProposalAction,
+    pub created_at: u64,
+    pub voting_ends_at: u64,
+    pub yes_weight: u64,
+    pub no_weight: u64,
+    /// Stake locked in the pool when the proposal was created. Quorum is
+    /// measured against it.
+    pub total_weight: u64,
+    pub status: ProposalStatus,
+}
+
+impl Proposal {
+    /// Whether the votes cast pass the proposal under `quorum_bps`.
+    pub fn passes(&self, quorum_bps: u16) -> bool {
+        let cast = self.yes_weight as u128 + self.no_weight as u128;
+        self.yes_weight > self.no_weight
+            && cast * BPS_DENOMINATOR as u128 >= self.total_weight as u128 * quorum_bps as u128
+    }
+}
+
+impl Storable for Proposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Proposal"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Proposal")
+    }
+}
+
+impl BoundedStorable for Proposal {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct BallotKey {
+    proposal_id: u64,
+    voter: Principal,
+}
+
+impl Storable for BallotKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode BallotKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode BallotKey")
+    }
+}
+
+impl BoundedStorable for BallotKey {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ballot {
+    pub vote: Vote,
+    pub weight: u64,
+}
+
+impl Storable for Ballot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Ballot"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Ballot")
+    }
+}
+
+impl BoundedStorable for Ballot {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PROPOSALS: RefCell<StableBTreeMap<u64, Proposal, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROPOSALS_MEMORY_ID)));
+
+    static BALLOTS: RefCell<StableBTreeMap<BallotKey, Ballot, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(PROPOSAL_BALLOTS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "proposals",
+            PROPOSALS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "proposal_ballots",
+            BALLOTS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn get(proposal_id: u64) -> Option<Proposal> {
+    PROPOSALS.with(|m| m.borrow().get(&proposal_id))
+}
+
+fn put(proposal: Proposal) {
+    PROPOSALS.with(|m| m.borrow_mut().insert(proposal.id, proposal));
+}
+
+pub fn create(
+    proposer: Principal,
+    action: ProposalAction,
+    now: u64,
+) -> Result<Proposal, DepositError> {
+    let Some(voting_secs) = config::get().proposal_voting_secs else {
+        return Err(DepositError::InvalidArgument(
+            "proposals are disabled".to_string(),
+        ));
+    };
+    if crate::principal_stake(proposer) == 0 {
+        return Err(DepositError::Unauthorized);
+    }
+    action.validate()?;
+    let id = PROPOSALS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let proposal = Proposal {
+        id,
+        proposer,
+        action,
+        created_at: now,
+        voting_ends_at: now + voting_secs,
+        yes_weight: 0,
+        no_weight: 0,
+        total_weight: crate::stats::total_staked(),
+        status: ProposalStatus::Open,
+    };
+    put(proposal.clone());
+    Ok(proposal)
+}
+
+/// Casts the vote of `voter`, weighted by the stake they hold at the time.
+pub fn vote(
+    voter: Principal,
+    proposal_id: u64,
+    vote: Vote,
+    now: u64,
+) -> Result<Proposal, DepositError> {
+    let mut proposal = get(proposal_id).ok_or_else(|| {
+        DepositError::InvalidArgument(format!("unknown proposal {}", proposal_id))
+    })?;
+    if proposal.status != ProposalStatus::Open || now >= proposal.voting_ends_at {
+        return Err(DepositError::InvalidArgument(format!(
+            "voting on proposal {} has closed",
+            proposal_id
+        )));
+    }
+    let key = BallotKey { proposal_id, voter };
+    if BALLOTS.with(|m| m.borrow().contains_key(&key)) {
+        return Err(DepositError::InvalidArgument(format!(
+            "already voted on proposal {}",
+            proposal_id
+        )));
+    }
+    let weight = crate::principal_stake(voter);
+    if weight == 0 {
+        return Err(DepositError::Unauthorized);
+    }
+    match vote {
+        Vote::Yes => proposal.yes_weight += weight,
+        Vote::No => proposal.no_weight += weight,
+    }
+    BALLOTS.with(|m| m.borrow_mut().insert(key, Ballot { vote, weight }));
+    put(proposal.clone());
+    Ok(proposal)
+}
+
+/// Whether an executed proposal approved migrating the pool to `successor`.
+pub fn migration_approved(successor: Principal) -> bool {
+    PROPOSALS.with(|m| {
+        m.borrow().iter().any(|(_, p)| {
+            p.action == ProposalAction::MigrateTo { successor }
+                && matches!(p.status, ProposalStatus::Executed { .. })
+        })
+    })
+}
+
+/// Decides every proposal whose voting closed by `now`, executing those that
+/// passed, and returns them.
+pub fn tally(now: u64) -> Vec<Proposal> {
+    let quorum_bps = config::get().proposal_quorum_bps;
+    let closed: Vec<Proposal> = PROPOSALS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.status == ProposalStatus::Open && now >= p.voting_ends_at)
+            .collect()
+    });
+    closed
+        .into_iter()
+        .map(|mut proposal| {
+            proposal.status = if !proposal.passes(quorum_bps) {
+                ProposalStatus::Rejected
+            } else {
+                match proposal.action.execute(now) {
+                    Ok(()) => ProposalStatus::Executed { at: now },
+                    Err(e) => ProposalStatus::Failed(format!("{:?}", e)),
+                }
+            };
+            put(proposal.clone());
+            proposal
+        })
+        .collect()
+}
+
+/// Starts the timer that closes proposals. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_tallies() {
+    ic_cdk_timers::set_timer_interval(TALLY_INTERVAL, || {
+        let now = crate::now_secs();
+        for proposal in tally(now) {
+            if matches!(proposal.status, ProposalStatus::Executed { .. }) {
+                transactions::record(
+                    now,
+                    ic_cdk::id(),
+                    TransactionKind::AdminChange {
+                        action: format!("proposal {}", proposal.id),
+                    },
+                    None,
+                    0,
+                    None,
+                );
+            }
+        }
+    });
+}
+
+/// Proposes a parameter change. The caller must hold stake in the pool.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If proposals are disabled.
+/// * `DepositError::Unauthorized`: If the caller holds no stake.
+/// * `DepositError::InvalidConfig`: If the proposed parameters are invalid.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn create_proposal(action: ProposalAction) -> Result<Proposal, DepositError> {
+    create(ic_cdk::caller(), action, crate::now_secs())
+}
+
+/// Votes on an open proposal with the caller's current stake. Each principal
+/// votes once.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the proposal is unknown, voting has
+///   closed, or the caller already voted.
+/// * `DepositError::Unauthorized`: If the caller holds no stake.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn vote_on_proposal(proposal_id: u64, vote: Vote) -> Result<Proposal, DepositError> {
+    self::vote(ic_cdk::caller(), proposal_id, vote, crate::now_secs())
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_proposal(proposal_id: u64) -> Option<Proposal> {
+    get(proposal_id)
+}
+
+/// Returns all proposals, oldest first.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_proposals() -> Vec<Proposal> {
+    PROPOSALS.with(|m| m.borrow().iter().map(|(_, p)| p).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_proposal_passes_with_quorum_and_executes() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let curve = PenaltyCurve {
+            start_bps: 1_000,
+            end_bps: 0,
+        };
+        let action = ProposalAction::SetEarlyExitPenalty(curve.clone());
+        assert!(create(alice, action.clone(), 0).is_err());
+
+        config::set(config::PoolConfig {
+            proposal_voting_secs: Some(86_400),
+            proposal_quorum_bps: 5_000,
+            ..config::get()
+        });
+        assert_eq!(
+            create(alice, action.clone(), 0),
+            Err(DepositError::Unauthorized)
+        );
+        crate::deposit_internal(alice, Subaccount([1; 32]), 90, 300, 0).unwrap();
+        crate::deposit_internal(bob, Subaccount([2; 32]), 90, 700, 0).unwrap();
+
+        let proposal = create(alice, action, 0).unwrap();
+        assert_eq!(proposal.total_weight, 1_000);
+        vote(alice, proposal.id, Vote::Yes, 10).unwrap();
+        assert!(vote(alice, proposal.id, Vote::No, 10).is_err());
+
+        // 30% turnout is below the 50% quorum.
+        assert!(tally(86_399).is_empty());
+        let decided = tally(86_400);
+        assert_eq!(decided[0].status, ProposalStatus::Rejected);
+        assert!(vote(bob, proposal.id, Vote::Yes, 86_400).is_err());
+
+        let proposal = create(bob, ProposalAction::SetEarlyExitPenalty(curve.clone()), 0).unwrap();
+        vote(bob, proposal.id, Vote::Yes, 10).unwrap();
+        vote(alice, proposal.id, Vote::No, 10).unwrap();
+        assert_eq!(
+            tally(86_400)[0].status,
+            ProposalStatus::Executed { at: 86_400 }
+        );
+        assert_eq!(config::get().early_exit_penalty, curve);
+    }
+}
@@ -0,0 +1,186 @@
+// src/rate_model.rs
+use crate::config;
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, RATE_STATE_MEMORY_ID};
+use crate::state_hash;
+use crate::STAKE_BALANCE_MAP;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// Length of a rate epoch. The rate is fixed for the duration of an epoch.
+pub const EPOCH_SECS: u64 = 86400;
+const FULL_BPS: u64 = 10_000;
+
+/// Kinked utilization curve. Below `kink_bps` the rate rises by `slope1_bps`
+/// over the whole range; above it the remaining utilization adds `slope2_bps`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateModel {
+    pub base_bps: u64,
+    pub slope1_bps: u64,
+    pub slope2_bps: u64,
+    pub kink_bps: u64,
+}
+
+impl Default for RateModel {
+    fn default() -> Self {
+        Self {
+            base_bps: 200,
+            slope1_bps: 400,
+            slope2_bps: 6_000,
+            kink_bps: 8_000,
+        }
+    }
+}
+
+impl RateModel {
+    pub fn is_valid(&self) -> bool {
+        self.kink_bps > 0 && self.kink_bps <= FULL_BPS
+    }
+
+    /// Annual reward rate in basis points at `utilization_bps`.
+    pub fn rate_bps(&self, utilization_bps: u64) -> u64 {
+        let utilization = utilization_bps.min(FULL_BPS);
+        if utilization <= self.kink_bps {
+            self.base_bps + utilization * self.slope1_bps / self.kink_bps
+        } else {
+            self.base_bps
+                + self.slope1_bps
+                + (utilization - self.kink_bps) * self.slope2_bps / (FULL_BPS - self.kink_bps)
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct RateState {
+    /// Pool funds currently deployed to downstream strategies and borrowers.
+    pub utilized: u64,
+    /// Epoch the rate below was computed for.
+    pub epoch: u64,
+    pub utilization_bps: u64,
+    pub rate_bps: u64,
+}
+
+impl Storable for RateState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode RateState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RateState")
+    }
+}
+
+thread_local! {
+    static RATE_STATE: RefCell<StableCell<RateState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(RATE_STATE_MEMORY_ID), RateState::default())
+            .expect("Failed to init rate state cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "rate_state",
+        RATE_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+fn get() -> RateState {
+    RATE_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn set(state: RateState) {
+    RATE_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist rate state");
+    });
+}
+
+fn total_stake() -> u128 {
+    STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s as u128).sum())
+}
+
+/// Recomputes utilization and rate if `now` falls in a later epoch than the
+/// one the current rate was computed for.
+pub fn roll_epoch(now: u64, total_stake: u128) -> RateState {
+    let mut state = get();
+    let epoch = now / EPOCH_SECS;
+    if epoch <= state.epoch {
+        return state;
+    }
+    state.epoch = epoch;
+    state.utilization_bps = (state.utilized as u128 * FULL_BPS as u128)
+        .checked_div(total_stake)
+        .map_or(0, |u| u.min(FULL_BPS as u128) as u64);
+    state.rate_bps = config::get().rate_model.rate_bps(state.utilization_bps);
+    set(state.clone());
+    state
+}
+
+/// Starts the epoch timer. Must be called from `init` and `post_upgrade`,
+/// since timers do not survive upgrades.
+pub fn start_epochs() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(EPOCH_SECS), || {
+        roll_epoch(crate::now_secs(), total_stake());
+    });
+}
+
+/// Reports the amount of pool funds deployed downstream. Takes effect when
+/// the next epoch starts. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn set_utilized_amount(amount: u64) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let mut state = get();
+    state.utilized = amount;
+    set(state);
+    crate::transactions::admin("set_utilized_amount", Ok(()))
+}
+
+/// Returns the reward rate for the current epoch together with the
+/// utilization it was derived from.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_current_rate() -> RateState {
+    get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinked_rate_curve() {
+        let model = RateModel::default();
+        assert_eq!(model.rate_bps(0), 200);
+        assert_eq!(model.rate_bps(4_000), 400);
+        assert_eq!(model.rate_bps(8_000), 600);
+        assert_eq!(model.rate_bps(9_000), 3_600);
+        assert_eq!(model.rate_bps(10_000), 6_600);
+    }
+
+    #[test]
+    fn test_rate_is_fixed_within_an_epoch() {
+        set(RateState {
+            utilized: 500,
+            ..RateState::default()
+        });
+        let state = roll_epoch(EPOCH_SECS, 1_000);
+        assert_eq!(state.utilization_bps, 5_000);
+        assert_eq!(state.rate_bps, 450);
+
+        set(RateState {
+            utilized: 1_000,
+            ..get()
+        });
+        assert_eq!(roll_epoch(EPOCH_SECS + 3600, 1_000).rate_bps, 450);
+        assert_eq!(roll_epoch(2 * EPOCH_SECS, 1_000).rate_bps, 6_600);
+    }
+}
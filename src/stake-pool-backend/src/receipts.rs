@@ -0,0 +1,334 @@
+// src/receipts.rs
+//! Deposit receipt token, an ICRC-1 ledger embedded in the pool. A deposit
+//! mints receipts for its amount to the depositor, who can transfer them
+//! freely; withdrawing the deposit burns them again, so the receipts must be
+//! back in the depositor's account by then.
+use crate::account_migration::rekey;
+use crate::error::DepositError;
+use crate::memory::{
+    get_memory, Memory, RECEIPT_BALANCES_MEMORY_ID, RECEIPT_LEDGER_MEMORY_ID,
+    RECEIPT_OUTSTANDING_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::UserKey;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{storable::Storable, StableBTreeMap, StableCell};
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const RECEIPT_NAME: &str = "Stake Pool Deposit Receipt";
+pub const RECEIPT_SYMBOL: &str = "stPOOL";
+pub const RECEIPT_DECIMALS: u8 = 8;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct ReceiptLedger {
+    total_supply: u64,
+    /// Index of the next mint, burn or transfer.
+    next_block: u64,
+}
+
+impl Storable for ReceiptLedger {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ReceiptLedger"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ReceiptLedger")
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+thread_local! {
+    static BALANCES: RefCell<StableBTreeMap<UserKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RECEIPT_BALANCES_MEMORY_ID)));
+
+    // Receipts minted for each deposit that have not been burned yet, keyed
+    // by deposit id. Deposits made before receipts existed have no entry and
+    // burn nothing.
+    static OUTSTANDING: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RECEIPT_OUTSTANDING_MEMORY_ID)));
+
+    static LEDGER: RefCell<StableCell<ReceiptLedger, Memory>> = RefCell::new(
+        StableCell::init(get_memory(RECEIPT_LEDGER_MEMORY_ID), ReceiptLedger::default())
+            .expect("Failed to init receipt ledger cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "receipt_balances",
+            BALANCES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "receipt_outstanding",
+            OUTSTANDING.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "receipt_ledger",
+            LEDGER.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    BALANCES.with(|m| rekey(&mut m.borrow_mut(), from, to, |a, b| a + b));
+}
+
+fn account_key(account: &Account) -> UserKey {
+    UserKey {
+        principal: account.owner,
+        subaccount: Subaccount(account.subaccount.unwrap_or([0; 32])),
+    }
+}
+
+pub fn balance_of(key: &UserKey) -> u64 {
+    BALANCES.with(|m| m.borrow().get(key).unwrap_or(0))
+}
+
+fn set_balance(key: &UserKey, balance: u64) {
+    BALANCES.with(|m| {
+        let mut m = m.borrow_mut();
+        if balance == 0 {
+            m.remove(key);
+        } else {
+            m.insert(key.clone(), balance);
+        }
+    });
+}
+
+fn ledger() -> ReceiptLedger {
+    LEDGER.with(|l| l.borrow().get().clone())
+}
+
+// Applies `f` to the ledger totals and returns the index of the new block.
+fn append_block(f: impl FnOnce(&mut ReceiptLedger)) -> u64 {
+    let mut ledger = ledger();
+    f(&mut ledger);
+    let block = ledger.next_block;
+    ledger.next_block += 1;
+    LEDGER.with(|l| {
+        l.borrow_mut()
+            .set(ledger)
+            .expect("Failed to persist receipt ledger");
+    });
+    block
+}
+
+fn outstanding(deposit_id: u64) -> u64 {
+    OUTSTANDING.with(|m| m.borrow().get(&deposit_id).unwrap_or(0))
+}
+
+/// Mints `amount` receipts to `key` for a new or restored deposit.
+pub fn issue(key: &UserKey, deposit_id: u64, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+    set_balance(key, balance_of(key) + amount);
+    let outstanding = outstanding(deposit_id) + amount;
+    OUTSTANDING.with(|m| m.borrow_mut().insert(deposit_id, outstanding));
+    append_block(|l| l.total_supply += amount);
+}
+
+// Receipts to burn when `amount` of a deposit is withdrawn.
+fn owed(deposit_id: u64, amount: u64) -> u64 {
+    outstanding(deposit_id).min(amount)
+}
+
+/// Fails if `key` no longer holds the receipts withdrawing `amount` of the
+/// deposit would burn.
+pub fn ensure_redeemable(key: &UserKey, deposit_id: u64, amount: u64) -> Result<(), DepositError> {
+    if balance_of(key) < owed(deposit_id, amount) {
+        Err(DepositError::InsufficientBalance)
+    } else {
+        Ok(())
+    }
+}
+
+/// Burns the receipts of `amount` withdrawn from a deposit. Must follow a
+/// successful `ensure_redeemable`.
+pub fn redeem(key: &UserKey, deposit_id: u64, amount: u64) {
+    let burned = owed(deposit_id, amount);
+    if burned == 0 {
+        return;
+    }
+    set_balance(key, balance_of(key) - burned);
+    let left = outstanding(deposit_id) - burned;
+    OUTSTANDING.with(|m| {
+        let mut m = m.borrow_mut();
+        if left == 0 {
+            m.remove(&deposit_id);
+        } else {
+            m.insert(deposit_id, left);
+        }
+    });
+    append_block(|l| l.total_supply -= burned);
+}
+
+/// Moves the receipts of a deposit handed to another account along with it.
+/// Must follow a successful `ensure_redeemable` for the whole deposit.
+pub fn move_with_deposit(from: &UserKey, to: &UserKey, deposit_id: u64) {
+    let amount = outstanding(deposit_id);
+    if amount == 0 {
+        return;
+    }
+    set_balance(from, balance_of(from) - amount);
+    set_balance(to, balance_of(to) + amount);
+    append_block(|_| {});
+}
+
+pub fn transfer(from: &UserKey, arg: TransferArg) -> Result<u64, TransferError> {
+    if arg.fee.as_ref().is_some_and(|fee| *fee != 0u64) {
+        return Err(TransferError::BadFee {
+            expected_fee: Nat::from(0u64),
+        });
+    }
+    let balance = balance_of(from);
+    let amount = u64::try_from(arg.amount.0)
+        .ok()
+        .filter(|amount| *amount <= balance)
+        .ok_or(TransferError::InsufficientFunds {
+            balance: Nat::from(balance),
+        })?;
+    let to = account_key(&arg.to);
+    set_balance(from, balance - amount);
+    set_balance(&to, balance_of(&to) + amount);
+    Ok(append_block(|_| {}))
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_name() -> String {
+    RECEIPT_NAME.to_string()
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_symbol() -> String {
+    RECEIPT_SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_decimals() -> u8 {
+    RECEIPT_DECIMALS
+}
+
+/// Receipt transfers are free.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_fee() -> Nat {
+    Nat::from(0u64)
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        (
+            "icrc1:name".to_string(),
+            MetadataValue::Text(RECEIPT_NAME.to_string()),
+        ),
+        (
+            "icrc1:symbol".to_string(),
+            MetadataValue::Text(RECEIPT_SYMBOL.to_string()),
+        ),
+        (
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(RECEIPT_DECIMALS)),
+        ),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(0u64))),
+    ]
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_total_supply() -> Nat {
+    Nat::from(ledger().total_supply)
+}
+
+/// Receipts are only minted by deposits and burned by withdrawals, so there
+/// is no minting account.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_balance_of(account: Account) -> Nat {
+    Nat::from(balance_of(&account_key(&account)))
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    vec![SupportedStandard {
+        name: "ICRC-1".to_string(),
+        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+    }]
+}
+
+/// Transfers deposit receipts from an account of the caller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let from = UserKey {
+        principal: ic_cdk::caller(),
+        subaccount: Subaccount(arg.from_subaccount.unwrap_or([0; 32])),
+    };
+    transfer(&from, arg).map(Nat::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts_must_be_returned_to_withdraw() {
+        let key = |b: u8| UserKey {
+            principal: Principal::from_slice(&[b]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let (alice, bob) = (key(31), key(32));
+        issue(&alice, 1, 1_000);
+        let arg = |amount: u64| TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: bob.principal,
+                subaccount: None,
+            },
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: Nat::from(amount),
+        };
+        assert!(matches!(
+            transfer(&alice, arg(1_001)),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+        transfer(&alice, arg(600)).unwrap();
+        assert_eq!(balance_of(&bob), 600);
+
+        assert_eq!(
+            ensure_redeemable(&alice, 1, 1_000),
+            Err(DepositError::InsufficientBalance)
+        );
+        assert_eq!(ensure_redeemable(&alice, 1, 400), Ok(()));
+        redeem(&alice, 1, 400);
+        assert_eq!((balance_of(&alice), outstanding(1)), (0, 600));
+        assert_eq!(ledger().total_supply, 600);
+        // Deposits without receipts burn nothing.
+        assert_eq!(ensure_redeemable(&alice, 2, 500), Ok(()));
+    }
+}
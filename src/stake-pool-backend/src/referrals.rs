@@ -0,0 +1,434 @@
+// src/referrals.rs
+//! Referral program. A deposit made with `deposit_with_referral` names the
+//! principal that brought the depositor in, who is then rewarded as the
+//! config's `referral_reward` says: with a share of the rewards the deposit
+//! earns, or with a one-time bonus paid from the treasury. A bonus vests
+//! with the lock of the deposit, so depositing and leaving right away earns
+//! nothing. A principal keeps the first referrer it names.
+use crate::compounding;
+use crate::config;
+use crate::error::DepositError;
+use crate::memory::{
+    get_memory, Memory, REFERRAL_TOTALS_MEMORY_ID, REFERRED_DEPOSITS_MEMORY_ID,
+    REFERRER_OF_MEMORY_ID, VESTING_BONUSES_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use crate::treasury;
+use crate::{Deposit, PrincipalKey, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReferralReward {
+    /// `bps` of the rewards a referred deposit earns go to the referrer
+    /// instead of the depositor.
+    RewardShare { bps: u16 },
+    /// `bps` of each referred deposit is paid to the referrer from the
+    /// treasury once the deposit's lock has run its course, through
+    /// `claim_referral_bonuses`. A deposit withdrawn or handed over before
+    /// then forfeits its bonus.
+    TreasuryBonus { bps: u16 },
+}
+
+impl ReferralReward {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            ReferralReward::RewardShare { bps } | ReferralReward::TreasuryBonus { bps } => {
+                *bps <= 10_000
+            }
+        }
+    }
+}
+
+/// What a principal earned by referring others.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReferralTotals {
+    /// Principals that named it as their referrer.
+    pub referred: u64,
+    pub referred_deposits: u64,
+    pub referred_amount: u64,
+    /// Reward shares credited to its pending rewards.
+    pub reward_shares: u64,
+    pub bonuses_paid: u64,
+}
+
+impl Storable for ReferralTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode ReferralTotals"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode ReferralTotals")
+    }
+}
+
+impl BoundedStorable for ReferralTotals {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReferralStats {
+    /// Principal that referred this one, if any.
+    pub referrer: Option<Principal>,
+    pub totals: ReferralTotals,
+    /// Bonuses waiting for the locks of referred deposits to run out.
+    pub bonuses_vesting: u64,
+}
+
+/// Treasury bonus for a referred deposit, payable once the deposit has
+/// stayed locked until `vests_at`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct VestingBonus {
+    pub referrer: Principal,
+    pub amount: u64,
+    pub vests_at: u64,
+}
+
+impl Storable for VestingBonus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode VestingBonus"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode VestingBonus")
+    }
+}
+
+impl BoundedStorable for VestingBonus {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Referred principal to its referrer.
+    static REFERRER_OF: RefCell<StableBTreeMap<PrincipalKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REFERRER_OF_MEMORY_ID)));
+
+    // Referred deposit id to the referrer, until the deposit is withdrawn.
+    static REFERRED_DEPOSITS: RefCell<StableBTreeMap<u64, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REFERRED_DEPOSITS_MEMORY_ID)));
+
+    static REFERRAL_TOTALS: RefCell<StableBTreeMap<PrincipalKey, ReferralTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REFERRAL_TOTALS_MEMORY_ID)));
+
+    // Referred deposit id to its bonus, until the bonus is paid or forfeited.
+    static VESTING_BONUSES: RefCell<StableBTreeMap<u64, VestingBonus, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(VESTING_BONUSES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "referrer_of",
+            REFERRER_OF.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "referred_deposits",
+            REFERRED_DEPOSITS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "referral_totals",
+            REFERRAL_TOTALS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "vesting_bonuses",
+            VESTING_BONUSES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+fn referrer_of(principal: Principal) -> Option<Principal> {
+    REFERRER_OF.with(|m| m.borrow().get(&PrincipalKey(principal)).map(|k| k.0))
+}
+
+fn update_totals(referrer: Principal, f: impl FnOnce(&mut ReferralTotals)) {
+    REFERRAL_TOTALS.with(|m| {
+        let mut m = m.borrow_mut();
+        let mut totals = m.get(&PrincipalKey(referrer)).unwrap_or_default();
+        f(&mut totals);
+        m.insert(PrincipalKey(referrer), totals);
+    });
+}
+
+/// Fails unless `caller` may name `referrer`: not itself, not a principal
+/// it referred, and no other referrer than the one it named before.
+pub fn check(caller: Principal, referrer: Principal) -> Result<(), DepositError> {
+    if referrer == caller || referrer == Principal::anonymous() {
+        return Err(DepositError::InvalidReferrer);
+    }
+    if referrer_of(referrer) == Some(caller) {
+        return Err(DepositError::InvalidReferrer);
+    }
+    if referrer_of(caller).is_some_and(|r| r != referrer) {
+        return Err(DepositError::InvalidReferrer);
+    }
+    Ok(())
+}
+
+/// Links a new deposit of `caller` to `referrer`.
+pub fn record(caller: Principal, referrer: Principal, deposit: &Deposit) {
+    let first = REFERRER_OF.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.contains_key(&PrincipalKey(caller)) {
+            return false;
+        }
+        m.insert(PrincipalKey(caller), PrincipalKey(referrer));
+        true
+    });
+    REFERRED_DEPOSITS.with(|m| m.borrow_mut().insert(deposit.id, PrincipalKey(referrer)));
+    update_totals(referrer, |t| {
+        t.referred += first as u64;
+        t.referred_deposits += 1;
+        t.referred_amount += deposit.amount;
+    });
+}
+
+/// Part of `reward` earned by deposit `deposit_id` that goes to its
+/// referrer.
+pub fn share_of(deposit_id: u64, reward: u64) -> u64 {
+    let Some(ReferralReward::RewardShare { bps }) = config::get().referral_reward else {
+        return 0;
+    };
+    if !REFERRED_DEPOSITS.with(|m| m.borrow().contains_key(&deposit_id)) {
+        return 0;
+    }
+    (reward as u128 * bps as u128 / 10_000) as u64
+}
+
+/// Credits the referrer's share of `reward` to its default account and
+/// returns what is left for the depositor.
+pub fn take_share(deposit_id: u64, reward: u64, now: u64) -> Result<u64, DepositError> {
+    let share = share_of(deposit_id, reward);
+    if share == 0 {
+        return Ok(reward);
+    }
+    let Some(referrer) = REFERRED_DEPOSITS.with(|m| m.borrow().get(&deposit_id)) else {
+        return Ok(reward);
+    };
+    let key = UserKey {
+        principal: referrer.0,
+        subaccount: Subaccount([0; 32]),
+    };
+    compounding::accrue(&key, share, now)?;
+    update_totals(referrer.0, |t| t.reward_shares += share);
+    Ok(reward - share)
+}
+
+/// Drops the referral of a deposit removed at `now`, along with its bonus
+/// unless that has vested.
+pub fn forget(deposit_id: u64, now: u64) {
+    REFERRED_DEPOSITS.with(|m| m.borrow_mut().remove(&deposit_id));
+    VESTING_BONUSES.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.get(&deposit_id).is_some_and(|b| now < b.vests_at) {
+            m.remove(&deposit_id);
+        }
+    });
+}
+
+/// Sets aside `bps` of `deposit` as a bonus for `referrer`, vesting when
+/// the deposit unlocks.
+pub fn vest_bonus(referrer: Principal, deposit: &Deposit, bps: u16) {
+    let amount = (deposit.amount as u128 * bps as u128 / 10_000) as u64;
+    if amount == 0 {
+        return;
+    }
+    let bonus = VestingBonus {
+        referrer,
+        amount,
+        vests_at: deposit.unlock_time(),
+    };
+    VESTING_BONUSES.with(|m| m.borrow_mut().insert(deposit.id, bonus));
+}
+
+// Bonuses of `referrer`, with the ids of their deposits, that vested by `now`.
+fn vested_of(referrer: Principal, now: u64) -> Vec<(u64, VestingBonus)> {
+    VESTING_BONUSES.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, b)| b.referrer == referrer && b.vests_at <= now)
+            .collect()
+    })
+}
+
+fn vesting_total(referrer: Principal) -> u64 {
+    VESTING_BONUSES.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, b)| b.referrer == referrer)
+            .map(|(_, b)| b.amount)
+            .sum()
+    })
+}
+
+/// Pays the caller the referral bonuses that vested, from the treasury, to
+/// its default account. A bonus the treasury cannot pay right now stays
+/// claimable.
+///
+/// # Returns
+///
+/// * The total paid.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If no bonus of the caller has vested.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn claim_referral_bonuses() -> Result<u64, DepositError> {
+    let referrer = ic_cdk::caller();
+    let vested = vested_of(referrer, crate::now_secs());
+    if vested.is_empty() {
+        return Err(DepositError::NoDepositFound);
+    }
+    let to = Account {
+        owner: referrer,
+        subaccount: None,
+    };
+    let mut paid = 0;
+    for (deposit_id, bonus) in vested {
+        // Taken out first so a concurrent claim cannot pay it twice.
+        VESTING_BONUSES.with(|m| m.borrow_mut().remove(&deposit_id));
+        match treasury::pay(to, bonus.amount).await {
+            Ok(block) => {
+                paid += bonus.amount;
+                update_totals(referrer, |t| t.bonuses_paid += bonus.amount);
+                transactions::record(
+                    crate::now_secs(),
+                    referrer,
+                    TransactionKind::ReferralBonus { referrer },
+                    None,
+                    bonus.amount,
+                    Some(block),
+                );
+            }
+            Err(_) => {
+                VESTING_BONUSES.with(|m| m.borrow_mut().insert(deposit_id, bonus));
+            }
+        }
+    }
+    Ok(paid)
+}
+
+/// Same as `deposit_funds` in the primary token, naming `referrer` as the
+/// principal that referred the caller.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the referral program is disabled.
+/// * `DepositError::InvalidReferrer`: If `referrer` is the caller, was
+///   referred by the caller, or differs from the caller's earlier referrer.
+/// * Any error of `deposit_funds`.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn deposit_with_referral(
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+    referrer: Principal,
+) -> Result<Deposit, DepositError> {
+    let Some(reward) = config::get().referral_reward else {
+        return Err(DepositError::InvalidArgument(
+            "the referral program is disabled".to_string(),
+        ));
+    };
+    let caller = ic_cdk::caller();
+    check(caller, referrer)?;
+    let deposit = crate::deposit_funds_v2(subaccount, lock_days, amount, None, None).await?;
+    record(caller, referrer, &deposit);
+    if let ReferralReward::TreasuryBonus { bps } = reward {
+        vest_bonus(referrer, &deposit, bps);
+    }
+    Ok(deposit)
+}
+
+/// Returns who referred `principal` and what it earned by referring others.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_referral_stats(principal: Principal) -> ReferralStats {
+    ReferralStats {
+        referrer: referrer_of(principal),
+        totals: REFERRAL_TOTALS
+            .with(|m| m.borrow().get(&PrincipalKey(principal)))
+            .unwrap_or_default(),
+        bonuses_vesting: vesting_total(principal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referrer_gets_a_share_of_rewards() {
+        let alice = Principal::from_slice(&[160]);
+        let bob = Principal::from_slice(&[161]);
+        let carol = Principal::from_slice(&[162]);
+        assert_eq!(check(alice, alice), Err(DepositError::InvalidReferrer));
+
+        let deposit = Deposit {
+            id: 70,
+            amount: 1_000,
+            timestamp: 0,
+            lock_period_days: 90,
+        };
+        check(bob, alice).unwrap();
+        record(bob, alice, &deposit);
+        // No referral cycles, and the first referrer sticks.
+        assert_eq!(check(alice, bob), Err(DepositError::InvalidReferrer));
+        assert_eq!(check(bob, carol), Err(DepositError::InvalidReferrer));
+        assert!(check(bob, alice).is_ok());
+
+        let mut config = config::get();
+        config.referral_reward = Some(ReferralReward::RewardShare { bps: 1_000 });
+        config::set(config);
+        assert_eq!(take_share(70, 500, 10), Ok(450));
+        assert_eq!(take_share(71, 500, 10), Ok(500));
+        let alice_key = UserKey {
+            principal: alice,
+            subaccount: Subaccount([0; 32]),
+        };
+        assert_eq!(compounding::pending_of(&alice_key).amount, 50);
+
+        let stats = get_referral_stats(alice);
+        assert_eq!(stats.totals.referred, 1);
+        assert_eq!(stats.totals.referred_amount, 1_000);
+        assert_eq!(stats.totals.reward_shares, 50);
+        assert_eq!(get_referral_stats(bob).referrer, Some(alice));
+    }
+
+    #[test]
+    fn test_treasury_bonus_vests_with_the_lock() {
+        let alice = Principal::from_slice(&[163]);
+        let bob = Principal::from_slice(&[164]);
+        let sub = Subaccount([1; 32]);
+        let day = 86400;
+
+        // Leaving right after depositing forfeits the bonus.
+        let early = crate::deposit_internal(bob, sub, 90, 1_000, 0).unwrap();
+        record(bob, alice, &early);
+        vest_bonus(alice, &early, 500);
+        assert_eq!(get_referral_stats(alice).bonuses_vesting, 50);
+        crate::early_withdraw_internal(bob, sub, early.id, day).unwrap();
+        assert_eq!(get_referral_stats(alice).bonuses_vesting, 0);
+
+        // A deposit that stays locked keeps it past its withdrawal.
+        let held = crate::deposit_internal(bob, sub, 90, 2_000, 0).unwrap();
+        record(bob, alice, &held);
+        vest_bonus(alice, &held, 500);
+        assert!(vested_of(alice, 89 * day).is_empty());
+        crate::withdraw_internal(bob, sub, held.id, 90 * day).unwrap();
+        let vested = vested_of(alice, 90 * day);
+        assert_eq!(vested.len(), 1);
+        assert_eq!((vested[0].0, vested[0].1.amount), (held.id, 100));
+        assert!(vested_of(bob, 90 * day).is_empty());
+    }
+}
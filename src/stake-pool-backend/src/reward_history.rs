@@ -0,0 +1,119 @@
+// src/reward_history.rs
+//! Log of reward distributions, kept alongside the value locked at the time so
+//! the pool's yield can be estimated from it.
+use crate::memory::{
+    get_memory, Memory, REWARD_HISTORY_DATA_MEMORY_ID, REWARD_HISTORY_INDEX_MEMORY_ID,
+};
+use crate::state_hash;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{storable::Storable, StableLog};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const SECS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+/// Period over which the trailing APY is measured.
+pub const APY_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RewardRecord {
+    pub timestamp: u64,
+    pub amount: u64,
+    /// Total value locked when the reward was distributed.
+    pub tvl: u64,
+    /// Rewards distributed up to and including this one.
+    pub cumulative: u64,
+}
+
+impl Storable for RewardRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode RewardRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RewardRecord")
+    }
+}
+
+thread_local! {
+    static REWARD_HISTORY: RefCell<StableLog<RewardRecord, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            get_memory(REWARD_HISTORY_INDEX_MEMORY_ID),
+            get_memory(REWARD_HISTORY_DATA_MEMORY_ID),
+        )
+        .expect("Failed to init reward history"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "reward_history",
+        REWARD_HISTORY.with(|s| state_hash::log_digest(&s.borrow())),
+    )]
+}
+
+fn last() -> Option<RewardRecord> {
+    REWARD_HISTORY.with(|log| {
+        let log = log.borrow();
+        log.len().checked_sub(1).and_then(|i| log.get(i))
+    })
+}
+
+/// Appends a distribution of `amount` made while `tvl` was locked.
+pub fn record(now: u64, amount: u64, tvl: u64) {
+    crate::stats::invalidate();
+    crate::epochs::record_distribution(amount, now);
+    let cumulative = last().map_or(0, |r| r.cumulative).saturating_add(amount);
+    REWARD_HISTORY.with(|log| {
+        log.borrow()
+            .append(&RewardRecord {
+                timestamp: now,
+                amount,
+                tvl,
+                cumulative,
+            })
+            .expect("Failed to append reward record");
+    });
+}
+
+pub fn last_distribution_at() -> Option<u64> {
+    last().map(|r| r.timestamp)
+}
+
+pub fn total_distributed() -> u64 {
+    last().map_or(0, |r| r.cumulative)
+}
+
+/// Annualized yield, in basis points, of the distributions made within the
+/// last `APY_WINDOW_SECS`. Each reward counts against the value locked when
+/// it was paid, so deposits made later do not dilute it.
+pub fn trailing_apy_bps(now: u64) -> u64 {
+    let since = now.saturating_sub(APY_WINDOW_SECS);
+    let window_bps: u128 = REWARD_HISTORY.with(|log| {
+        let log = log.borrow();
+        (0..log.len())
+            .rev()
+            .map_while(|i| log.get(i).filter(|r| r.timestamp >= since))
+            .filter(|r| r.tvl > 0)
+            .map(|r| r.amount as u128 * BPS_DENOMINATOR / r.tvl as u128)
+            .sum()
+    });
+    (window_bps * SECS_PER_YEAR as u128 / APY_WINDOW_SECS as u128).min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trailing_apy_ignores_old_rewards() {
+        record(0, 500, 10_000);
+        let now = 2 * APY_WINDOW_SECS;
+        record(now - 10, 100, 10_000);
+        record(now, 100, 20_000);
+
+        assert_eq!(total_distributed(), 700);
+        // 1% + 0.5% over 30 days, annualized.
+        assert_eq!(trailing_apy_bps(now), 150 * 365 / 30);
+    }
+}
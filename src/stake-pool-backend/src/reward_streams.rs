@@ -0,0 +1,221 @@
+// src/reward_streams.rs
+//! Rewards released linearly over a period instead of in one distribution.
+//! A timer drips what each stream has vested into the accrual index, so a
+//! deposit made right before a release earns only its share of that slice.
+use crate::error::DepositError;
+use crate::ledger;
+use crate::memory::{get_memory, Memory, REWARD_STREAMS_MEMORY_ID};
+use crate::state_hash;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const DRIP_INTERVAL: Duration = Duration::from_secs(600);
+pub const MAX_STREAM_DAYS: u32 = 3650;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RewardStream {
+    pub id: u64,
+    pub funder: Principal,
+    pub amount: u64,
+    /// Distributed so far.
+    pub released: u64,
+    pub start: u64,
+    pub duration_secs: u64,
+}
+
+impl RewardStream {
+    /// Vested by `now` but not released yet.
+    pub fn due(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.start).min(self.duration_secs);
+        let vested = self.amount as u128 * elapsed as u128 / self.duration_secs as u128;
+        (vested as u64).saturating_sub(self.released)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.released >= self.amount
+    }
+}
+
+impl Storable for RewardStream {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode RewardStream"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RewardStream")
+    }
+}
+
+impl BoundedStorable for RewardStream {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static REWARD_STREAMS: RefCell<StableBTreeMap<u64, RewardStream, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(REWARD_STREAMS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "reward_streams",
+        REWARD_STREAMS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+fn validate(amount: u64, duration_days: u32) -> Result<(), DepositError> {
+    if amount == 0 {
+        return Err(DepositError::InvalidArgument(
+            "stream amount must be positive".to_string(),
+        ));
+    }
+    if duration_days == 0 || duration_days > MAX_STREAM_DAYS {
+        return Err(DepositError::InvalidArgument(format!(
+            "stream duration must be 1 to {} days",
+            MAX_STREAM_DAYS
+        )));
+    }
+    Ok(())
+}
+
+pub fn create(
+    funder: Principal,
+    amount: u64,
+    duration_days: u32,
+    now: u64,
+) -> Result<RewardStream, DepositError> {
+    validate(amount, duration_days)?;
+    let id = REWARD_STREAMS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let stream = RewardStream {
+        id,
+        funder,
+        amount,
+        released: 0,
+        start: now,
+        duration_secs: duration_days as u64 * 86400,
+    };
+    REWARD_STREAMS.with(|m| m.borrow_mut().insert(id, stream.clone()));
+    Ok(stream)
+}
+
+fn active() -> Vec<RewardStream> {
+    REWARD_STREAMS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| !s.is_finished())
+            .collect()
+    })
+}
+
+/// Rewards of every stream vested by `now` but not released yet.
+pub fn total_due(now: u64) -> u64 {
+    active().iter().map(|s| s.due(now)).sum()
+}
+
+/// Held for streams but not distributed yet.
+pub fn total_unreleased() -> u64 {
+    active().iter().map(|s| s.amount - s.released).sum()
+}
+
+/// Books everything vested by `now` as released.
+pub fn mark_released(now: u64) {
+    let streams = active();
+    REWARD_STREAMS.with(|m| {
+        let mut m = m.borrow_mut();
+        for mut stream in streams {
+            stream.released += stream.due(now);
+            m.insert(stream.id, stream);
+        }
+    });
+}
+
+/// Distributes what the streams vested since the last drip. Nothing is booked
+/// as released when the distribution fails, so the next drip catches up.
+fn drip(now: u64) -> Result<u64, DepositError> {
+    let due = total_due(now);
+    if due == 0 {
+        return Ok(0);
+    }
+    crate::distribute_held_funds(due)?;
+    mark_released(now);
+    Ok(due)
+}
+
+/// Starts the drip timer. Must be called from `init` and `post_upgrade`,
+/// since timers do not survive upgrades.
+pub fn start_drips() {
+    ic_cdk_timers::set_timer_interval(DRIP_INTERVAL, || {
+        if let Err(e) = drip(crate::now_secs()) {
+            ic_cdk::println!("reward stream drip failed: {:?}", e);
+        }
+    });
+}
+
+/// Transfers `amount` from the caller's account and releases it to stakers
+/// linearly over `duration_days`, in slices distributed every ten minutes.
+/// Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the amount is zero or the duration is not 1 to 3650 days.
+/// * `DepositError::LedgerTransferFailed`: If the transfer from the caller failed.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn create_reward_schedule(
+    amount: u64,
+    duration_days: u32,
+) -> Result<RewardStream, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    validate(amount, duration_days)?;
+    let from = Account {
+        owner: caller,
+        subaccount: None,
+    };
+    crate::pull_funds(ledger::ledger_id(), from, amount).await?;
+    let result = create(caller, amount, duration_days, crate::now_secs());
+    crate::transactions::admin("create_reward_schedule", result)
+}
+
+/// Returns every reward stream, finished ones included.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_reward_schedules() -> Vec<RewardStream> {
+    REWARD_STREAMS.with(|m| m.borrow().iter().map(|(_, s)| s).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streams_vest_linearly() {
+        let funder = Principal::from_slice(&[90]);
+        assert!(create(funder, 1_000, 0, 0).is_err());
+        let stream = create(funder, 1_000, 10, 100).unwrap();
+        let day = 86400;
+
+        assert_eq!(total_due(100), 0);
+        assert_eq!(total_due(100 + day / 2), 50);
+        mark_released(100 + day / 2);
+        assert_eq!(total_due(100 + day / 2), 0);
+        assert_eq!(total_due(100 + 3 * day), 250);
+        assert_eq!(total_unreleased(), 950);
+
+        // Nothing vests past the end of the stream.
+        mark_released(100 + 20 * day);
+        assert_eq!(total_due(100 + 30 * day), 0);
+        assert_eq!(total_unreleased(), 0);
+        assert!(get_reward_schedules()[0].is_finished());
+        assert_eq!(get_reward_schedules()[0].id, stream.id);
+    }
+}
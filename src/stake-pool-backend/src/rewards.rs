@@ -0,0 +1,73 @@
+// src/rewards.rs
+use crate::Deposit;
+use candid::{CandidType, Deserialize};
+
+const FULL_WEIGHT_BPS: u64 = 10_000;
+
+/// Optional policy under which matured deposits left untouched lose reward
+/// weight over time. Principal is never affected, only the share of future
+/// reward distributions.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct InactivityDecay {
+    /// Days after maturity during which a deposit keeps its full weight.
+    pub grace_days: u32,
+    /// Days over which the weight then falls linearly to `floor_bps`.
+    pub decay_days: u32,
+    /// Weight left once the decay has run its course, in basis points.
+    pub floor_bps: u16,
+}
+
+impl InactivityDecay {
+    pub fn is_valid(&self) -> bool {
+        self.decay_days > 0 && self.floor_bps as u64 <= FULL_WEIGHT_BPS
+    }
+}
+
+/// Reward weight of `deposit` at `now`, in basis points of its amount.
+pub fn weight_bps(deposit: &Deposit, now: u64, decay: Option<&InactivityDecay>) -> u64 {
+    let Some(decay) = decay else {
+        return FULL_WEIGHT_BPS;
+    };
+    let decay_start = deposit
+        .unlock_time()
+        .saturating_add(decay.grace_days as u64 * 86400);
+    if now <= decay_start {
+        return FULL_WEIGHT_BPS;
+    }
+    let decay_secs = decay.decay_days as u64 * 86400;
+    let elapsed = (now - decay_start).min(decay_secs);
+    let floor = decay.floor_bps as u64;
+    FULL_WEIGHT_BPS - (FULL_WEIGHT_BPS - floor) * elapsed / decay_secs
+}
+
+/// Weighted stake of a single deposit, used to split reward distributions.
+pub fn weighted_amount(deposit: &Deposit, now: u64, decay: Option<&InactivityDecay>) -> u128 {
+    deposit.amount as u128 * weight_bps(deposit, now, decay) as u128 / FULL_WEIGHT_BPS as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matured_deposits_decay_after_grace_period() {
+        let deposit = Deposit {
+            id: 1,
+            amount: 1_000,
+            timestamp: 0,
+            lock_period_days: 90,
+        };
+        let decay = InactivityDecay {
+            grace_days: 30,
+            decay_days: 100,
+            floor_bps: 2_000,
+        };
+        let day = 86400;
+
+        assert_eq!(weighted_amount(&deposit, 200 * day, None), 1_000);
+        assert_eq!(weighted_amount(&deposit, 120 * day, Some(&decay)), 1_000);
+        assert_eq!(weighted_amount(&deposit, 170 * day, Some(&decay)), 600);
+        assert_eq!(weighted_amount(&deposit, 220 * day, Some(&decay)), 200);
+        assert_eq!(weighted_amount(&deposit, 1_000 * day, Some(&decay)), 200);
+    }
+}
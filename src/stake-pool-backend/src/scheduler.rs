@@ -0,0 +1,204 @@
+// src/scheduler.rs
+//! Automatic reward distribution. Funders send rewards to the pool's rewards
+//! subaccount; on every configured interval the balance is swept into the
+//! pool's main account and distributed like a `reward_pool` call.
+use crate::circuit_breaker;
+use crate::config;
+use crate::distribution;
+use crate::error::DepositError;
+use crate::ledger;
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, REWARD_SWEEP_MEMORY_ID};
+use crate::state_hash;
+use crate::status;
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::call;
+use ic_stable_structures::{storable::Storable, StableCell};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+/// How often the timer checks whether a sweep is due.
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+/// Shortest sweep interval the config accepts.
+pub const MIN_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// Subaccount of the pool that collects rewards for automatic distribution.
+pub const REWARDS_SUBACCOUNT: [u8; 32] = {
+    let tag = b"rewards";
+    let mut subaccount = [0u8; 32];
+    let mut i = 0;
+    while i < tag.len() {
+        subaccount[i] = tag[i];
+        i += 1;
+    }
+    subaccount
+};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SweepState {
+    pub last_sweep_at: Option<u64>,
+    /// Swept into the main account but not yet distributed, e.g. because no
+    /// one was staking. Retried on the next sweep.
+    pub undistributed: u64,
+}
+
+impl Storable for SweepState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode SweepState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode SweepState")
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RewardSchedule {
+    /// Account rewards should be sent to.
+    pub rewards_account: Account,
+    /// Disabled when `None`.
+    pub interval_secs: Option<u64>,
+    pub last_sweep_at: Option<u64>,
+    pub undistributed: u64,
+}
+
+thread_local! {
+    static SWEEP_STATE: RefCell<StableCell<SweepState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(REWARD_SWEEP_MEMORY_ID), SweepState::default())
+            .expect("Failed to init reward sweep cell"),
+    );
+
+    static SWEEPING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "reward_sweep",
+        SWEEP_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+pub fn state() -> SweepState {
+    SWEEP_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update(change: impl FnOnce(&mut SweepState)) {
+    let mut state = state();
+    change(&mut state);
+    SWEEP_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist reward sweep state");
+    });
+}
+
+pub fn rewards_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(REWARDS_SUBACCOUNT),
+    }
+}
+
+pub fn is_due(state: &SweepState, interval_secs: u64, now: u64) -> bool {
+    state
+        .last_sweep_at
+        .is_none_or(|at| now >= at.saturating_add(interval_secs))
+}
+
+// Moves the rewards subaccount balance, less the ledger fee, to the pool's
+// main account and returns the amount moved.
+async fn collect() -> Result<u64, DepositError> {
+    let balance = ledger::balance_of(rewards_account()).await?;
+    let fee = Nat::from(ledger::fee(ledger::ledger_id()).await?);
+    if balance <= fee {
+        return Ok(0);
+    }
+    let amount = balance - fee.clone();
+    let transfer_arg = TransferArg {
+        from_subaccount: Some(REWARDS_SUBACCOUNT),
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: amount.clone(),
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    let (res,): (Result<Nat, TransferError>,) = circuit_breaker::observe(
+        call(ledger::ledger_id(), "icrc1_transfer", (transfer_arg,)).await,
+    )
+    .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    res.map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(crate::block_index(amount))
+}
+
+async fn sweep(now: u64) -> Result<(), DepositError> {
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::RewardDistribution)?;
+    distribution::ensure_idle()?;
+    update(|s| s.last_sweep_at = Some(now));
+
+    let collected = collect().await?;
+    update(|s| s.undistributed += collected);
+    let amount = state().undistributed;
+    if amount > 0 {
+        crate::distribute_held_funds(amount)?;
+        update(|s| s.undistributed = 0);
+    }
+    Ok(())
+}
+
+/// Starts the sweep timer. Must be called from `init` and `post_upgrade`,
+/// since timers do not survive upgrades.
+pub fn start_sweeps() {
+    ic_cdk_timers::set_timer_interval(TICK_INTERVAL, || {
+        let Some(interval) = config::get().reward_sweep_interval_secs else {
+            return;
+        };
+        let now = crate::now_secs();
+        if SWEEPING.with(|s| s.get()) || !is_due(&state(), interval, now) {
+            return;
+        }
+        SWEEPING.with(|s| s.set(true));
+        ic_cdk::spawn(async move {
+            if let Err(e) = sweep(now).await {
+                ic_cdk::println!("reward sweep failed: {:?}", e);
+            }
+            SWEEPING.with(|s| s.set(false));
+        });
+    });
+}
+
+/// Returns where to send rewards for automatic distribution and the state of
+/// the sweeps.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_reward_schedule() -> RewardSchedule {
+    let state = state();
+    RewardSchedule {
+        rewards_account: rewards_account(),
+        interval_secs: config::get().reward_sweep_interval_secs,
+        last_sweep_at: state.last_sweep_at,
+        undistributed: state.undistributed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_due_after_interval() {
+        let mut state = SweepState::default();
+        assert!(is_due(&state, 3_600, 0));
+
+        state.last_sweep_at = Some(1_000);
+        assert!(!is_due(&state, 3_600, 4_599));
+        assert!(is_due(&state, 3_600, 4_600));
+        assert_eq!(&REWARDS_SUBACCOUNT[..8], b"rewards\0");
+    }
+}
@@ -0,0 +1,259 @@
+// src/sharding.rs
+//! Partitioning of accounts across shards: pool canisters running this
+//! interface on the same ledger. A principal that holds nothing here is
+//! placed on this canister or one of the shards by rendezvous hashing the
+//! first time it deposits, and deposits of principals placed on a shard are
+//! refused with `DepositError::WrongShard` so clients call the shard
+//! instead. Placements on shards are kept, so registering a shard never
+//! moves accounts that already hold state. Each shard distributes rewards to
+//! its own stakers; this canister sums the stats of all of them.
+use crate::certification::CertifiedPoolStats;
+use crate::error::DepositError;
+use crate::memory::{get_memory, Memory, SHARDS_MEMORY_ID, SHARD_ASSIGNMENTS_MEMORY_ID};
+use crate::state_hash;
+use crate::stats::{self, PoolStats};
+use crate::PrincipalKey;
+use candid::Principal;
+use ic_cdk::call;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+pub const MAX_SHARDS: u64 = 64;
+
+thread_local! {
+    // Storage canisters holding account state, with their registration time.
+    static SHARDS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARDS_MEMORY_ID)));
+
+    // Principals placed on a shard, with the shard holding their accounts.
+    static ASSIGNMENTS: RefCell<StableBTreeMap<PrincipalKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARD_ASSIGNMENTS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "shards",
+            SHARDS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "shard_assignments",
+            ASSIGNMENTS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn shards() -> Vec<Principal> {
+    SHARDS.with(|m| m.borrow().iter().map(|(k, _)| k.0).collect())
+}
+
+fn score(shard: Principal, principal: Principal) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(shard.as_slice());
+    hasher.update(principal.as_slice());
+    hasher.finalize().into()
+}
+
+/// Picks the shard for `principal` by rendezvous hashing, so registering a
+/// new shard only moves the accounts that hash highest to it.
+pub fn shard_for(principal: Principal, shards: &[Principal]) -> Option<Principal> {
+    shards
+        .iter()
+        .copied()
+        .max_by_key(|&shard| score(shard, principal))
+}
+
+fn assignment(principal: Principal) -> Option<Principal> {
+    ASSIGNMENTS.with(|m| m.borrow().get(&PrincipalKey(principal)).map(|k| k.0))
+}
+
+/// Canister `principal` keeps its accounts on, with `this` being this
+/// canister, without placing it.
+pub fn home_of(principal: Principal, this: Principal) -> Principal {
+    if let Some(shard) = assignment(principal) {
+        return shard;
+    }
+    if crate::principal_stake(principal) > 0 {
+        return this;
+    }
+    let mut candidates = shards();
+    candidates.push(this);
+    shard_for(principal, &candidates).unwrap_or(this)
+}
+
+/// Fails with `DepositError::WrongShard` unless the accounts of `principal`
+/// live on `this` canister, placing the principal first if it holds
+/// nothing anywhere yet.
+pub fn route(principal: Principal, this: Principal) -> Result<(), DepositError> {
+    let home = home_of(principal, this);
+    if home == this {
+        return Ok(());
+    }
+    ASSIGNMENTS.with(|m| {
+        m.borrow_mut()
+            .insert(PrincipalKey(principal), PrincipalKey(home))
+    });
+    Err(DepositError::WrongShard { shard: home })
+}
+
+/// Same as `route` for the running canister. Free of system calls while
+/// no shards are registered.
+pub fn ensure_local(principal: Principal) -> Result<(), DepositError> {
+    if SHARDS.with(|m| m.borrow().is_empty()) && assignment(principal).is_none() {
+        return Ok(());
+    }
+    route(principal, ic_cdk::id())
+}
+
+pub fn register(shard: Principal, now: u64) -> Result<(), DepositError> {
+    SHARDS.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.len() >= MAX_SHARDS && !m.contains_key(&PrincipalKey(shard)) {
+            return Err(DepositError::InvalidArgument(format!(
+                "at most {} shards are supported",
+                MAX_SHARDS
+            )));
+        }
+        m.insert(PrincipalKey(shard), now);
+        Ok(())
+    })
+}
+
+fn add_stats(total: &mut PoolStats, shard: &PoolStats) {
+    total.total_staked += shard.total_staked;
+    total.staker_count += shard.staker_count;
+    total.deposit_count += shard.deposit_count;
+    total.liquid_underlying += shard.liquid_underlying;
+    total.liquid_supply += shard.liquid_supply;
+    // Weight each canister's yield by the value it has locked.
+    let weighted = total.trailing_apy_bps as u128 * total.total_value_locked as u128
+        + shard.trailing_apy_bps as u128 * shard.total_value_locked as u128;
+    let tvl = total.total_value_locked as u128 + shard.total_value_locked as u128;
+    if let Some(apy) = weighted.checked_div(tvl) {
+        total.trailing_apy_bps = apy as u64;
+    }
+    total.total_value_locked += shard.total_value_locked;
+    total.total_rewards_distributed += shard.total_rewards_distributed;
+    for tier in &shard.tiers {
+        match total
+            .tiers
+            .iter_mut()
+            .find(|t| t.lock_days == tier.lock_days)
+        {
+            Some(t) => {
+                t.deposit_count += tier.deposit_count;
+                t.total_amount += tier.total_amount;
+            }
+            None => total.tiers.push(tier.clone()),
+        }
+    }
+}
+
+/// Registers a storage canister as a shard for account state. Shards run this
+/// canister's interface. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If 64 shards are already registered.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn register_shard(shard: Principal) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("register_shard", register(shard, crate::now_secs()))
+}
+
+/// Returns the registered shards.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_shards() -> Vec<Principal> {
+    shards()
+}
+
+/// Returns the shard `principal` keeps its accounts on, or will be placed on
+/// by its first deposit, or `None` if that is this canister.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_shard_for(principal: Principal) -> Option<Principal> {
+    let this = ic_cdk::id();
+    Some(home_of(principal, this)).filter(|&home| home != this)
+}
+
+/// Returns the pool stats of this canister summed with those of every shard.
+///
+/// # Errors
+///
+/// * `DepositError::ShardUnavailable`: If a shard could not be queried.
+#[ic_cdk::query(composite = true)]
+#[candid::candid_method(composite_query)]
+pub async fn get_sharded_pool_stats() -> Result<PoolStats, DepositError> {
+    let mut total = stats::pool_stats();
+    for shard in shards() {
+        let (shard_stats,): (CertifiedPoolStats,) = call(shard, "get_pool_stats", ())
+            .await
+            .map_err(|e| DepositError::ShardUnavailable {
+                shard,
+                reason: format!("{:?}", e),
+            })?;
+        add_stats(&mut total, &shard_stats.stats);
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adding_a_shard_only_moves_accounts_to_it() {
+        let shards: Vec<Principal> = (1..=3u8).map(|i| Principal::from_slice(&[i, 1])).collect();
+        let users: Vec<Principal> = (0..200u8)
+            .map(|i| Principal::self_authenticating([i; 32]))
+            .collect();
+        let before: Vec<Principal> = users
+            .iter()
+            .map(|&u| shard_for(u, &shards).unwrap())
+            .collect();
+        assert!(shards.iter().all(|s| before.contains(s)));
+
+        let new_shard = Principal::from_slice(&[4, 1]);
+        let mut grown = shards.clone();
+        grown.push(new_shard);
+        for (user, old) in users.iter().zip(before) {
+            let now = shard_for(*user, &grown).unwrap();
+            assert!(now == old || now == new_shard);
+        }
+        assert_eq!(shard_for(users[0], &[]), None);
+    }
+
+    #[test]
+    fn test_deposits_are_routed_to_the_home_shard() {
+        let this = Principal::from_slice(&[9, 9]);
+        let shard = Principal::from_slice(&[9, 1]);
+        let staker = Principal::self_authenticating([1; 32]);
+        crate::deposit_internal(staker, ic_ledger_types::Subaccount([0; 32]), 90, 100, 0).unwrap();
+        register(shard, 0).unwrap();
+
+        // Accounts holding state stay; new ones are spread over both.
+        assert_eq!(route(staker, this), Ok(()));
+        let homes: Vec<Principal> = (2..40u8)
+            .map(|i| home_of(Principal::self_authenticating([i; 32]), this))
+            .collect();
+        assert!(homes.contains(&this) && homes.contains(&shard));
+        let newcomer = (2..40u8)
+            .map(|i| Principal::self_authenticating([i; 32]))
+            .find(|&p| home_of(p, this) == shard)
+            .unwrap();
+        assert_eq!(
+            route(newcomer, this),
+            Err(DepositError::WrongShard { shard })
+        );
+
+        // The placement sticks when another shard joins.
+        for i in 2..20u8 {
+            register(Principal::from_slice(&[9, i]), 0).unwrap();
+        }
+        assert_eq!(home_of(newcomer, this), shard);
+    }
+}
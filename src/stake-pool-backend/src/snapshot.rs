@@ -0,0 +1,489 @@
+// src/snapshot.rs
+use crate::certification;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, SNAPSHOTS_MEMORY_ID, SNAPSHOT_LEAVES_MEMORY_ID};
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use crate::{
+    compounding, cycles, distribution, ledger, liquid, price_oracle, reward_history, stats, status,
+    UserKey, STAKE_BALANCE_MAP,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+
+/// Voting weights frozen at `taken_at`, committed to by the Merkle `root`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StakeSnapshot {
+    pub id: u64,
+    pub taken_at: u64,
+    pub root: Vec<u8>,
+    pub leaf_count: u64,
+    pub total_weight: u64,
+}
+
+impl Storable for StakeSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode StakeSnapshot"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode StakeSnapshot")
+    }
+}
+
+impl BoundedStorable for StakeSnapshot {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotLeaf {
+    pub key: UserKey,
+    pub weight: u64,
+}
+
+impl SnapshotLeaf {
+    /// `sha256(0x00 || len(principal) || principal || subaccount || weight_be)`.
+    pub fn hash(&self) -> [u8; 32] {
+        let principal = self.key.principal.as_slice();
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_TAG, principal.len() as u8]);
+        hasher.update(principal);
+        hasher.update(self.key.subaccount.0);
+        hasher.update(self.weight.to_be_bytes());
+        hasher.finalize().into()
+    }
+}
+
+impl Storable for SnapshotLeaf {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode SnapshotLeaf"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode SnapshotLeaf")
+    }
+}
+
+impl BoundedStorable for SnapshotLeaf {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A sibling hash on the path from a leaf to the root.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProofStep {
+    pub hash: Vec<u8>,
+    /// Whether the sibling is the left input of the parent node.
+    pub is_left: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotProof {
+    pub snapshot_id: u64,
+    pub leaf: SnapshotLeaf,
+    pub path: Vec<ProofStep>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct CertifiedSnapshot {
+    pub snapshot: StakeSnapshot,
+    /// Root of the certified pool stats and stake balances. The certified
+    /// data is `node(snapshot.root, state_root)`.
+    pub state_root: Vec<u8>,
+    /// System certificate over the canister's certified data.
+    pub certificate: Option<Vec<u8>>,
+}
+
+/// Outcome of a distribution against a snapshot.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotDistribution {
+    pub snapshot_id: u64,
+    /// Rewards credited to the snapshot's accounts.
+    pub distributed: u64,
+    pub recipients: u64,
+    /// Rounding remainder, carried into the next regular distribution.
+    pub residual: u64,
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<StableBTreeMap<u64, StakeSnapshot, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SNAPSHOTS_MEMORY_ID)));
+
+    // Keyed by (snapshot id, leaf index).
+    static SNAPSHOT_LEAVES: RefCell<StableBTreeMap<(u64, u64), SnapshotLeaf, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SNAPSHOT_LEAVES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "snapshots",
+            SNAPSHOTS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "snapshot_leaves",
+            SNAPSHOT_LEAVES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub(crate) fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Hashes one level of the tree into the next. An odd last node is carried
+/// up unchanged.
+pub(crate) fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+pub fn merkle_path(leaves: &[[u8; 32]], mut index: usize) -> Vec<ProofStep> {
+    let mut path = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            path.push(ProofStep {
+                hash: level[sibling].to_vec(),
+                is_left: sibling < index,
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    path
+}
+
+/// Voting weight of every account: its stake plus the underlying value of
+/// its stTokens.
+fn current_weights() -> Vec<SnapshotLeaf> {
+    let mut weights: BTreeMap<UserKey, u64> =
+        STAKE_BALANCE_MAP.with(|m| m.borrow().iter().collect());
+    let state = liquid::state();
+    for (key, st_amount) in liquid::balances() {
+        *weights.entry(key).or_default() += state.to_underlying(st_amount);
+    }
+    weights
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .map(|(key, weight)| SnapshotLeaf { key, weight })
+        .collect()
+}
+
+fn leaves_of(snapshot_id: u64) -> Vec<SnapshotLeaf> {
+    SNAPSHOT_LEAVES.with(|m| {
+        m.borrow()
+            .range((snapshot_id, 0)..=(snapshot_id, u64::MAX))
+            .map(|(_, leaf)| leaf)
+            .collect()
+    })
+}
+
+pub fn take(now: u64) -> StakeSnapshot {
+    let leaves = current_weights();
+    let hashes: Vec<[u8; 32]> = leaves.iter().map(SnapshotLeaf::hash).collect();
+    let id = SNAPSHOTS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let snapshot = StakeSnapshot {
+        id,
+        taken_at: now,
+        root: merkle_root(&hashes).to_vec(),
+        leaf_count: leaves.len() as u64,
+        total_weight: leaves.iter().map(|l| l.weight).sum(),
+    };
+    SNAPSHOT_LEAVES.with(|m| {
+        let mut m = m.borrow_mut();
+        for (index, leaf) in leaves.into_iter().enumerate() {
+            m.insert((id, index as u64), leaf);
+        }
+    });
+    SNAPSHOTS.with(|m| m.borrow_mut().insert(id, snapshot.clone()));
+    snapshot
+}
+
+pub fn latest() -> Option<StakeSnapshot> {
+    SNAPSHOTS.with(|m| m.borrow().last_key_value().map(|(_, s)| s))
+}
+
+pub fn proof(snapshot_id: u64, key: &UserKey) -> Option<SnapshotProof> {
+    let leaves = leaves_of(snapshot_id);
+    let index = leaves.iter().position(|l| l.key == *key)?;
+    let hashes: Vec<[u8; 32]> = leaves.iter().map(SnapshotLeaf::hash).collect();
+    Some(SnapshotProof {
+        snapshot_id,
+        leaf: leaves[index].clone(),
+        path: merkle_path(&hashes, index),
+    })
+}
+
+/// Splits `amount` across the accounts of a snapshot by their frozen weight.
+/// Returns the shares and the rounding remainder.
+pub fn allocate(snapshot_id: u64, amount: u64) -> Result<(Vec<(UserKey, u64)>, u64), DepositError> {
+    let snapshot = SNAPSHOTS
+        .with(|m| m.borrow().get(&snapshot_id))
+        .ok_or_else(|| DepositError::InvalidArgument(format!("no snapshot {}", snapshot_id)))?;
+    if snapshot.total_weight == 0 {
+        return Err(DepositError::NoStakerFound);
+    }
+    let shares: Vec<(UserKey, u64)> = leaves_of(snapshot_id)
+        .into_iter()
+        .map(|leaf| {
+            let share = amount as u128 * leaf.weight as u128 / snapshot.total_weight as u128;
+            (leaf.key, share as u64)
+        })
+        .filter(|(_, share)| *share > 0)
+        .collect();
+    let allocated: u64 = shares.iter().map(|(_, share)| share).sum();
+    Ok((shares, amount - allocated))
+}
+
+/// Credits `amount`, already held by the pool, to the pending rewards of a
+/// snapshot's accounts, whatever their stake is now.
+pub fn distribute_internal(
+    snapshot_id: u64,
+    amount: u64,
+    now: u64,
+) -> Result<SnapshotDistribution, DepositError> {
+    let (shares, residual) = allocate(snapshot_id, amount)?;
+    for (key, share) in &shares {
+        compounding::credit_pending(key, *share);
+        events::record(
+            now,
+            EventKind::Rewarded {
+                key: key.clone(),
+                amount: *share,
+            },
+        );
+    }
+    distribution::set_residual(distribution::residual() + residual);
+    reward_history::record(now, amount - residual, stats::total_value_locked());
+    Ok(SnapshotDistribution {
+        snapshot_id,
+        distributed: amount - residual,
+        recipients: shares.len() as u64,
+        residual,
+    })
+}
+
+async fn distribute_from_snapshot_internal(
+    caller: Principal,
+    snapshot_id: u64,
+    amount: u64,
+) -> Result<SnapshotDistribution, DepositError> {
+    // Fails before the pull for an unknown or empty snapshot.
+    allocate(snapshot_id, amount)?;
+    let from = Account {
+        owner: caller,
+        subaccount: None,
+    };
+    let block = crate::pull_funds(ledger::ledger_id(), from, amount).await?;
+    let now = crate::now_secs();
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::RewardDistribution,
+        None,
+        amount,
+        Some(block),
+    );
+    price_oracle::record_snapshot(now, caller, amount);
+    distribute_internal(snapshot_id, amount, now)
+}
+
+/// Freezes the current voting weight of every account and certifies the
+/// Merkle root of the result. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn take_stake_snapshot() -> Result<StakeSnapshot, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let snapshot = take(crate::now_secs());
+    certification::certify();
+    Ok(snapshot)
+}
+
+/// Freezes the current stake of every account for `distribute_from_snapshot`.
+/// Takes the same snapshot as `take_stake_snapshot`. Only canister
+/// controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn snapshot_stakes() -> Result<StakeSnapshot, DepositError> {
+    take_stake_snapshot()
+}
+
+/// Distributes `amount` across the accounts of a snapshot in proportion to
+/// their weight when it was taken, while deposits and withdrawals go on. The
+/// reward is transferred from the caller's account and credited to each
+/// account's pending rewards, collected with `claim_rewards`.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If there is no snapshot with this ID.
+/// * `DepositError::NoStakerFound`: If the snapshot holds no weight.
+/// * `DepositError::LedgerTransferFailed`: If the transfer from the caller failed.
+/// * `DepositError::WithdrawalsOnly`: If the pool is too low on cycles to safely run a distribution.
+/// * `DepositError::DistributionInProgress`: If another distribution round has not finished yet.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn distribute_from_snapshot(
+    snapshot_id: u64,
+    amount: u64,
+) -> Result<SnapshotDistribution, DepositError> {
+    cycles::check_headroom();
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::RewardDistribution)?;
+    distribution::begin_round()?;
+    let result = distribute_from_snapshot_internal(ic_cdk::caller(), snapshot_id, amount).await;
+    distribution::end_round();
+    result
+}
+
+/// Returns the latest snapshot together with the certificate over its root.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_certified_snapshot() -> Option<CertifiedSnapshot> {
+    latest().map(|snapshot| CertifiedSnapshot {
+        snapshot,
+        state_root: certification::state_root()
+            .map(|root| root.to_vec())
+            .unwrap_or_default(),
+        certificate: ic_cdk::api::data_certificate(),
+    })
+}
+
+/// Returns a snapshot by id.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_stake_snapshot(snapshot_id: u64) -> Option<StakeSnapshot> {
+    SNAPSHOTS.with(|m| m.borrow().get(&snapshot_id))
+}
+
+/// Returns the weight of `key` in a snapshot and the Merkle path proving it
+/// against the snapshot root.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_snapshot_proof(snapshot_id: u64, key: UserKey) -> Option<SnapshotProof> {
+    proof(snapshot_id, &key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    // What an off-chain verifier does with a proof.
+    fn verify(leaf: &SnapshotLeaf, path: &[ProofStep], root: &[u8]) -> bool {
+        let mut hash = leaf.hash();
+        for step in path {
+            let Ok(sibling) = <[u8; 32]>::try_from(step.hash.as_slice()) else {
+                return false;
+            };
+            hash = if step.is_left {
+                node_hash(&sibling, &hash)
+            } else {
+                node_hash(&hash, &sibling)
+            };
+        }
+        hash.as_slice() == root
+    }
+
+    #[test]
+    fn test_snapshot_proofs_verify_against_root() {
+        for i in 1..=5u8 {
+            crate::deposit_internal(
+                Principal::from_slice(&[i]),
+                Subaccount([i; 32]),
+                90,
+                i as u64 * 100,
+                0,
+            )
+            .unwrap();
+        }
+        let snapshot = take(1_000);
+        assert_eq!(snapshot.leaf_count, 5);
+        assert_eq!(snapshot.total_weight, 1_500);
+
+        for i in 1..=5u8 {
+            let key = UserKey {
+                principal: Principal::from_slice(&[i]),
+                subaccount: Subaccount([i; 32]),
+            };
+            let proof = proof(snapshot.id, &key).unwrap();
+            assert_eq!(proof.leaf.weight, i as u64 * 100);
+            assert!(verify(&proof.leaf, &proof.path, &snapshot.root));
+
+            let forged = SnapshotLeaf {
+                weight: proof.leaf.weight + 1,
+                ..proof.leaf.clone()
+            };
+            assert!(!verify(&forged, &proof.path, &snapshot.root));
+        }
+    }
+
+    #[test]
+    fn test_distribution_uses_frozen_weights() {
+        let key = |i: u8| UserKey {
+            principal: Principal::from_slice(&[80 + i]),
+            subaccount: Subaccount([i; 32]),
+        };
+        for (i, amount) in [(1, 100), (2, 200)] {
+            crate::deposit_internal(key(i).principal, key(i).subaccount, 90, amount, 0).unwrap();
+        }
+        let snapshot = take(1_000);
+        // Stake added after the snapshot earns nothing from it.
+        crate::deposit_internal(key(1).principal, key(1).subaccount, 90, 5_000, 1_001).unwrap();
+        crate::deposit_internal(key(3).principal, key(3).subaccount, 90, 5_000, 1_001).unwrap();
+
+        let residual = distribution::residual();
+        let result = distribute_internal(snapshot.id, 1_000, 1_002).unwrap();
+        assert_eq!((result.distributed, result.recipients), (999, 2));
+        assert_eq!(distribution::residual(), residual + 1);
+        let pending = |i| compounding::pending_of(&key(i)).amount;
+        assert_eq!((pending(1), pending(2), pending(3)), (333, 666, 0));
+
+        assert!(distribute_internal(snapshot.id + 1, 1_000, 1_002).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+// src/state_hash.rs
+use crate::error::DepositError;
+use crate::memory::Memory;
+use crate::{
+    account_migration, accrual, allowlist, analytics, archive, canister_stakers, compounding,
+    config, dedup, denylist, dissolve, distribution, epochs, escheat, events, governance, icrc3,
+    ledger, liquid, loyalty, maintenance, maturity, multipliers, neurons, notifications, op_locks,
+    pending_withdrawals, position_import, positions, price_oracle, proposals, rate_model, receipts,
+    referrals, reward_history, reward_streams, scheduler, sharding, snapshot, status, storage,
+    teams, tokens, transactions, treasury, unlocks, unstaking, validators, withdrawal_queue,
+};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell, StableLog,
+};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+type Digests = Vec<(&'static str, [u8; 32])>;
+
+/// Every owner of stable state, digested one per timer tick.
+const SOURCES: &[fn() -> Digests] = &[
+    crate::state_digests,
+    account_migration::state_digests,
+    accrual::state_digests,
+    allowlist::state_digests,
+    analytics::state_digests,
+    archive::state_digests,
+    canister_stakers::state_digests,
+    compounding::state_digests,
+    config::state_digests,
+    dedup::state_digests,
+    denylist::state_digests,
+    dissolve::state_digests,
+    distribution::state_digests,
+    epochs::state_digests,
+    escheat::state_digests,
+    events::state_digests,
+    governance::state_digests,
+    icrc3::state_digests,
+    ledger::state_digests,
+    liquid::state_digests,
+    loyalty::state_digests,
+    maintenance::state_digests,
+    maturity::state_digests,
+    multipliers::state_digests,
+    neurons::state_digests,
+    notifications::state_digests,
+    op_locks::state_digests,
+    pending_withdrawals::state_digests,
+    position_import::state_digests,
+    positions::state_digests,
+    price_oracle::state_digests,
+    proposals::state_digests,
+    rate_model::state_digests,
+    receipts::state_digests,
+    referrals::state_digests,
+    reward_history::state_digests,
+    reward_streams::state_digests,
+    scheduler::state_digests,
+    sharding::state_digests,
+    snapshot::state_digests,
+    status::state_digests,
+    storage::state_digests,
+    teams::state_digests,
+    tokens::state_digests,
+    transactions::state_digests,
+    treasury::state_digests,
+    unlocks::state_digests,
+    unstaking::state_digests,
+    validators::state_digests,
+    withdrawal_queue::state_digests,
+];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StructureDigest {
+    pub name: String,
+    pub digest: Vec<u8>,
+    pub computed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateHash {
+    /// Hash over all structure digests, ordered by name. `None` until every
+    /// structure has been digested since the last install or upgrade.
+    pub hash: Option<Vec<u8>>,
+    pub structures: Vec<StructureDigest>,
+}
+
+thread_local! {
+    static DIGESTS: RefCell<BTreeMap<&'static str, ([u8; 32], u64)>> =
+        const { RefCell::new(BTreeMap::new()) };
+    static NEXT_SOURCE: RefCell<usize> = const { RefCell::new(0) };
+    /// Whether every source has been digested at least once.
+    static COMPLETE: RefCell<bool> = const { RefCell::new(false) };
+}
+
+fn hash_entry(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+/// Digest of a map's entries in key order, independent of its node layout.
+pub(crate) fn map_digest<K, V>(map: &StableBTreeMap<K, V, Memory>) -> [u8; 32]
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let mut hasher = Sha256::new();
+    for (key, value) in map.iter() {
+        hash_entry(&mut hasher, &key.to_bytes());
+        hash_entry(&mut hasher, &value.to_bytes());
+    }
+    hasher.finalize().into()
+}
+
+pub(crate) fn cell_digest<T: Storable>(cell: &StableCell<T, Memory>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hash_entry(&mut hasher, &cell.get().to_bytes());
+    hasher.finalize().into()
+}
+
+pub(crate) fn log_digest<T: Storable>(log: &StableLog<T, Memory, Memory>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for entry in log.iter() {
+        hash_entry(&mut hasher, &entry.to_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn record(digests: Digests, now: u64) {
+    DIGESTS.with(|d| {
+        let mut d = d.borrow_mut();
+        for (name, digest) in digests {
+            d.insert(name, (digest, now));
+        }
+    });
+}
+
+/// Digests the next source of stable state.
+pub fn refresh_next(now: u64) {
+    let index = NEXT_SOURCE.with(|n| {
+        let mut n = n.borrow_mut();
+        let index = *n;
+        *n = (index + 1) % SOURCES.len();
+        index
+    });
+    record(SOURCES[index](), now);
+    if index == SOURCES.len() - 1 {
+        COMPLETE.with(|c| *c.borrow_mut() = true);
+    }
+}
+
+pub fn refresh_all(now: u64) {
+    for source in SOURCES {
+        record(source(), now);
+    }
+    COMPLETE.with(|c| *c.borrow_mut() = true);
+}
+
+pub fn current() -> StateHash {
+    DIGESTS.with(|d| {
+        let d = d.borrow();
+        let complete = COMPLETE.with(|c| *c.borrow());
+        let hash = complete.then(|| {
+            let mut hasher = Sha256::new();
+            for (name, (digest, _)) in d.iter() {
+                hash_entry(&mut hasher, name.as_bytes());
+                hasher.update(digest);
+            }
+            hasher.finalize().to_vec()
+        });
+        StateHash {
+            hash,
+            structures: d
+                .iter()
+                .map(|(name, (digest, computed_at))| StructureDigest {
+                    name: name.to_string(),
+                    digest: digest.to_vec(),
+                    computed_at: *computed_at,
+                })
+                .collect(),
+        }
+    })
+}
+
+/// Starts refreshing one source of state per tick. Must be called from
+/// `init` and `post_upgrade`, since timers do not survive upgrades.
+pub fn start_refresh() {
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, || refresh_next(crate::now_secs()));
+}
+
+/// Returns a deterministic hash over all stable state together with the
+/// digest of each structure. Digests are refreshed one source at a time, so
+/// structures may have been digested at different times; compare the hash
+/// only between canisters with no writes in between, or after
+/// `refresh_state_hash`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_state_hash() -> StateHash {
+    current()
+}
+
+/// Digests all stable state at once. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn refresh_state_hash() -> Result<StateHash, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    refresh_all(crate::now_secs());
+    Ok(current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_state_hash_tracks_state() {
+        for _ in 1..SOURCES.len() {
+            refresh_next(0);
+        }
+        assert_eq!(current().hash, None);
+        refresh_next(0);
+        let empty = current().hash.unwrap();
+        refresh_all(0);
+        assert_eq!(current().hash.unwrap(), empty);
+
+        crate::deposit_internal(Principal::anonymous(), Subaccount([1u8; 32]), 90, 100, 0).unwrap();
+        assert_eq!(current().hash.unwrap(), empty);
+        for _ in 0..SOURCES.len() {
+            refresh_next(1);
+        }
+        let changed = current().hash.unwrap();
+        assert_ne!(changed, empty);
+        refresh_all(2);
+        assert_eq!(current().hash.unwrap(), changed);
+    }
+}
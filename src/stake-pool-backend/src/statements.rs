@@ -0,0 +1,511 @@
+// src/statements.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind, PoolEvent};
+use crate::UserKey;
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use std::collections::BTreeMap;
+
+/// Most entries an exported statement may hold, to stay well within the
+/// size of a query reply.
+pub const MAX_EXPORT_ENTRIES: usize = 10_000;
+
+/// Activity of one subaccount during a statement period. Amounts balance as
+/// `opening_stake + deposits - withdrawals - penalties - slashed - escheated = closing_stake`;
+/// rewards are paid out or held for compounding and do not count as stake.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountStatement {
+    pub subaccount: Subaccount,
+    pub opening_stake: u64,
+    pub deposits: u64,
+    /// Amounts paid out, net of early-exit penalties.
+    pub withdrawals: u64,
+    pub rewards: u64,
+    pub penalties: u64,
+    pub slashed: u64,
+    /// Matured deposits moved to unclaimed funds.
+    pub escheated: u64,
+    pub closing_stake: u64,
+}
+
+impl AccountStatement {
+    fn empty(subaccount: Subaccount) -> Self {
+        Self {
+            subaccount,
+            opening_stake: 0,
+            deposits: 0,
+            withdrawals: 0,
+            rewards: 0,
+            penalties: 0,
+            slashed: 0,
+            escheated: 0,
+            closing_stake: 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum StatementFormat {
+    Csv,
+    Json,
+}
+
+/// A deposit, withdrawal or reward of one of the caller's subaccounts, as
+/// exported by `export_statement`.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct StatementEntry {
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Hex-encoded.
+    pub subaccount: String,
+    pub deposit_id: Option<u64>,
+    pub amount: u64,
+    /// Early-exit penalty kept out of `amount`.
+    pub penalty: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MonthlyStatement {
+    pub year: u16,
+    pub month: u8,
+    /// Start (inclusive) and end (exclusive) of the period, in seconds.
+    pub period_start: u64,
+    pub period_end: u64,
+    pub accounts: Vec<AccountStatement>,
+}
+
+// Days since 1970-01-01 of the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Start and end of a calendar month (UTC) in seconds since the epoch.
+pub fn month_bounds(year: u16, month: u8) -> Result<(u64, u64), DepositError> {
+    if year < 1970 || !(1..=12).contains(&month) {
+        return Err(DepositError::InvalidArgument(format!(
+            "no statement period {}-{:02}",
+            year, month
+        )));
+    }
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let start = days_from_civil(year as i64, month, 1) as u64 * 86400;
+    let end = days_from_civil(next_year as i64, next_month, 1) as u64 * 86400;
+    Ok((start, end))
+}
+
+// Statement entry of `key`, if it belongs to `principal` and the period has started.
+fn entry<'a>(
+    statements: &'a mut BTreeMap<Subaccount, AccountStatement>,
+    in_period: bool,
+    principal: Principal,
+    key: &UserKey,
+) -> Option<&'a mut AccountStatement> {
+    (in_period && key.principal == principal).then(|| {
+        statements
+            .entry(key.subaccount)
+            .or_insert_with(|| AccountStatement::empty(key.subaccount))
+    })
+}
+
+/// Replays `events` to build the statements of `principal`'s subaccounts for
+/// the period `[start, end)`. Stakes of all accounts are tracked so that
+/// slashes are attributed the same way `slash_pool` applies them.
+pub fn build_statements(
+    events: impl Iterator<Item = PoolEvent>,
+    principal: Principal,
+    start: u64,
+    end: u64,
+) -> Vec<AccountStatement> {
+    let mut stakes: BTreeMap<UserKey, u64> = BTreeMap::new();
+    let mut statements: BTreeMap<Subaccount, AccountStatement> = BTreeMap::new();
+    let mut in_period = false;
+
+    let open = |stakes: &BTreeMap<UserKey, u64>,
+                statements: &mut BTreeMap<Subaccount, AccountStatement>| {
+        for (key, stake) in stakes.iter().filter(|(k, _)| k.principal == principal) {
+            statements.insert(
+                key.subaccount,
+                AccountStatement {
+                    opening_stake: *stake,
+                    ..AccountStatement::empty(key.subaccount)
+                },
+            );
+        }
+    };
+
+    for event in events {
+        if event.timestamp >= end {
+            break;
+        }
+        if !in_period && event.timestamp >= start {
+            open(&stakes, &mut statements);
+            in_period = true;
+        }
+
+        match &event.kind {
+            EventKind::Deposited { key, amount, .. } => {
+                *stakes.entry(key.clone()).or_default() += amount;
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.deposits += amount;
+                }
+            }
+            EventKind::Withdrawn { key, amount, .. } => {
+                let stake = stakes.entry(key.clone()).or_default();
+                *stake = stake.saturating_sub(*amount);
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.withdrawals += amount;
+                }
+            }
+            EventKind::WithdrawalReverted { key, amount, .. } => {
+                *stakes.entry(key.clone()).or_default() += amount;
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.withdrawals = s.withdrawals.saturating_sub(*amount);
+                }
+            }
+            EventKind::EarlyWithdrawn {
+                key,
+                amount,
+                penalty,
+                ..
+            } => {
+                let stake = stakes.entry(key.clone()).or_default();
+                *stake = stake.saturating_sub(*amount);
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.withdrawals += amount - penalty;
+                    s.penalties += penalty;
+                }
+            }
+            EventKind::Escheated { key, amount, .. } => {
+                let stake = stakes.entry(key.clone()).or_default();
+                *stake = stake.saturating_sub(*amount);
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.escheated += amount;
+                }
+            }
+            // Counted as a withdrawal by the sender and a deposit by the
+            // recipient, so both statements still balance.
+            EventKind::DepositTransferred {
+                from, to, amount, ..
+            } => {
+                let stake = stakes.entry(from.clone()).or_default();
+                *stake = stake.saturating_sub(*amount);
+                *stakes.entry(to.clone()).or_default() += amount;
+                if let Some(s) = entry(&mut statements, in_period, principal, from) {
+                    s.withdrawals += amount;
+                }
+                if let Some(s) = entry(&mut statements, in_period, principal, to) {
+                    s.deposits += amount;
+                }
+            }
+            EventKind::Rewarded { key, amount } => {
+                if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                    s.rewards += amount;
+                }
+            }
+            EventKind::PoolSlashed { amount } => {
+                let total: u128 = stakes.values().map(|s| *s as u128).sum();
+                if total == 0 {
+                    continue;
+                }
+                for (key, stake) in stakes.iter_mut() {
+                    let slash = (((*stake as u128 * *amount as u128) / total) as u64).min(*stake);
+                    *stake -= slash;
+                    if let Some(s) = entry(&mut statements, in_period, principal, key) {
+                        s.slashed += slash;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if !in_period {
+        open(&stakes, &mut statements);
+    }
+
+    statements
+        .into_values()
+        .map(|mut s| {
+            s.closing_stake = stakes
+                .get(&UserKey {
+                    principal,
+                    subaccount: s.subaccount,
+                })
+                .copied()
+                .unwrap_or(0);
+            s
+        })
+        .filter(|s| *s != AccountStatement::empty(s.subaccount))
+        .collect()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Lists the deposits, withdrawals and rewards of `principal`'s subaccounts
+/// recorded in `events` during `[from, to)`, oldest first.
+pub fn statement_entries(
+    events: impl Iterator<Item = PoolEvent>,
+    principal: Principal,
+    from: u64,
+    to: u64,
+) -> Vec<StatementEntry> {
+    let mut entries = Vec::new();
+    for event in events {
+        if event.timestamp >= to {
+            break;
+        }
+        if event.timestamp < from {
+            continue;
+        }
+        let mut push = |kind, key: &UserKey, deposit_id, amount, penalty| {
+            if key.principal == principal {
+                entries.push(StatementEntry {
+                    timestamp: event.timestamp,
+                    kind,
+                    subaccount: hex(&key.subaccount.0),
+                    deposit_id,
+                    amount,
+                    penalty,
+                });
+            }
+        };
+        match &event.kind {
+            EventKind::Deposited {
+                key,
+                deposit_id,
+                amount,
+            } => push("deposit", key, Some(*deposit_id), *amount, 0),
+            EventKind::Withdrawn {
+                key,
+                deposit_id,
+                amount,
+            } => push("withdrawal", key, Some(*deposit_id), *amount, 0),
+            EventKind::WithdrawalReverted {
+                key,
+                deposit_id,
+                amount,
+            } => push("withdrawal_reverted", key, Some(*deposit_id), *amount, 0),
+            EventKind::EarlyWithdrawn {
+                key,
+                deposit_id,
+                amount,
+                penalty,
+            } => push(
+                "early_withdrawal",
+                key,
+                Some(*deposit_id),
+                amount - penalty,
+                *penalty,
+            ),
+            EventKind::Escheated {
+                key,
+                deposit_id,
+                amount,
+            } => push("escheat", key, Some(*deposit_id), *amount, 0),
+            EventKind::DepositTransferred {
+                from,
+                to,
+                deposit_id,
+                amount,
+            } => {
+                push("transfer_out", from, Some(*deposit_id), *amount, 0);
+                push("transfer_in", to, Some(*deposit_id), *amount, 0);
+            }
+            EventKind::Rewarded { key, amount } => push("reward", key, None, *amount, 0),
+            _ => {}
+        }
+    }
+    entries
+}
+
+/// Serializes `entries` as CSV with a header row, or as a JSON array.
+pub fn format_entries(entries: &[StatementEntry], format: StatementFormat) -> String {
+    match format {
+        StatementFormat::Json => {
+            serde_json::to_string(entries).expect("Failed to encode statement entries")
+        }
+        StatementFormat::Csv => {
+            let mut csv = String::from("timestamp,type,subaccount,deposit_id,amount,penalty\n");
+            for e in entries {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{}\n",
+                    e.timestamp,
+                    e.kind,
+                    e.subaccount,
+                    e.deposit_id.map_or(String::new(), |id| id.to_string()),
+                    e.amount,
+                    e.penalty
+                ));
+            }
+            csv
+        }
+    }
+}
+
+/// Returns a summary of every subaccount of the caller for the given calendar
+/// month (UTC), built from the pool event log.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If `month` is not between 1 and 12 or `year` is before 1970.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_statement(year: u16, month: u8) -> Result<MonthlyStatement, DepositError> {
+    let (period_start, period_end) = month_bounds(year, month)?;
+    let log = (0..events::len()).filter_map(events::get);
+    Ok(MonthlyStatement {
+        year,
+        month,
+        period_start,
+        period_end,
+        accounts: build_statements(log, ic_cdk::caller(), period_start, period_end),
+    })
+}
+
+/// Returns the caller's deposits, withdrawals and rewards between `from_ts`
+/// (inclusive) and `to_ts` (exclusive), in seconds, as CSV or JSON text
+/// ready to be offered as a download. Amounts of early withdrawals are net
+/// of the penalty, which has its own column.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If `from_ts` is after `to_ts`, or the
+///   period holds more than 10000 entries.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn export_statement(
+    format: StatementFormat,
+    from_ts: u64,
+    to_ts: u64,
+) -> Result<String, DepositError> {
+    if from_ts > to_ts {
+        return Err(DepositError::InvalidArgument(
+            "the period must not end before it starts".to_string(),
+        ));
+    }
+    let log = (0..events::len()).filter_map(events::get);
+    let entries = statement_entries(log, ic_cdk::caller(), from_ts, to_ts);
+    if entries.len() > MAX_EXPORT_ENTRIES {
+        return Err(DepositError::InvalidArgument(format!(
+            "the period holds more than {} entries, export a shorter one",
+            MAX_EXPORT_ENTRIES
+        )));
+    }
+    Ok(format_entries(&entries, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_month_bounds() {
+        assert_eq!(month_bounds(1970, 1), Ok((0, 31 * 86400)));
+        // 2024-02-01 to 2024-03-01, a leap year.
+        assert_eq!(month_bounds(2024, 2), Ok((1_706_745_600, 1_709_251_200)));
+        assert_eq!(month_bounds(2024, 12).unwrap().1, 1_735_689_600);
+        assert!(month_bounds(2024, 13).is_err());
+    }
+
+    #[test]
+    fn test_statement_balances() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let key = |principal| UserKey {
+            principal,
+            subaccount: Subaccount([0u8; 32]),
+        };
+        let event = |timestamp, kind| PoolEvent {
+            seq: 0,
+            timestamp,
+            kind,
+        };
+        let log = vec![
+            event(
+                5,
+                EventKind::Deposited {
+                    key: key(alice),
+                    deposit_id: 1,
+                    amount: 1_000,
+                },
+            ),
+            event(
+                6,
+                EventKind::Deposited {
+                    key: key(bob),
+                    deposit_id: 2,
+                    amount: 1_000,
+                },
+            ),
+            event(
+                10,
+                EventKind::Rewarded {
+                    key: key(alice),
+                    amount: 50,
+                },
+            ),
+            event(12, EventKind::PoolSlashed { amount: 200 }),
+            event(
+                15,
+                EventKind::EarlyWithdrawn {
+                    key: key(alice),
+                    deposit_id: 1,
+                    amount: 900,
+                    penalty: 90,
+                },
+            ),
+            event(
+                25,
+                EventKind::Deposited {
+                    key: key(alice),
+                    deposit_id: 3,
+                    amount: 7,
+                },
+            ),
+        ];
+
+        let entries = statement_entries(log.clone().into_iter(), alice, 10, 20);
+        assert_eq!(entries.len(), 2);
+        let zeros = "00".repeat(32);
+        assert_eq!(
+            format_entries(&entries, StatementFormat::Csv),
+            format!(
+                "timestamp,type,subaccount,deposit_id,amount,penalty\n\
+                 10,reward,{zeros},,50,0\n\
+                 15,early_withdrawal,{zeros},1,810,90\n"
+            )
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_entries(&entries, StatementFormat::Json)).unwrap();
+        assert_eq!(json[1]["type"], "early_withdrawal");
+        assert_eq!(json[1]["penalty"], 90);
+
+        let statements = build_statements(log.into_iter(), alice, 10, 20);
+        assert_eq!(
+            statements,
+            vec![AccountStatement {
+                subaccount: Subaccount([0u8; 32]),
+                opening_stake: 1_000,
+                deposits: 0,
+                withdrawals: 810,
+                rewards: 50,
+                penalties: 90,
+                slashed: 100,
+                escheated: 0,
+                closing_stake: 0,
+            }]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// src/stats.rs
+use crate::certification::{self, CertifiedPoolStats};
+use crate::tiers::{self, TierStats};
+use crate::{liquid, reward_history, UserKey, DEPOSITS, STAKE_BALANCE_MAP};
+use candid::{CandidType, Deserialize};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+/// Maximum age of cached aggregates, even when no change invalidated them.
+const CACHE_TTL_SECS: u64 = 10;
+pub const MAX_LEADERBOARD_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct PoolStats {
+    pub total_staked: u64,
+    pub staker_count: u64,
+    pub deposit_count: u64,
+    pub liquid_underlying: u64,
+    pub liquid_supply: u64,
+    /// Staked deposits plus the liquid pool's underlying.
+    pub total_value_locked: u64,
+    pub tiers: Vec<TierDeposits>,
+    pub total_rewards_distributed: u64,
+    /// Annualized yield of the rewards distributed over the last 30 days.
+    pub trailing_apy_bps: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TierDeposits {
+    pub lock_days: u16,
+    pub deposit_count: u64,
+    pub total_amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub key: UserKey,
+    pub stake: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Aggregates {
+    generation: u64,
+    computed_at: u64,
+    pool_stats: PoolStats,
+    tier_stats: Vec<TierStats>,
+    leaderboard: Vec<LeaderboardEntry>,
+}
+
+thread_local! {
+    // Bumped on every state change that can affect the aggregates.
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+    static CACHE: RefCell<Option<Aggregates>> = const { RefCell::new(None) };
+}
+
+/// Marks cached aggregates as stale. Called from the stable-state writers the
+/// aggregates are derived from.
+pub fn invalidate() {
+    GENERATION.with(|g| g.set(g.get() + 1));
+}
+
+pub(crate) fn total_staked() -> u64 {
+    STAKE_BALANCE_MAP.with(|map| map.borrow().iter().map(|(_, s)| s).sum())
+}
+
+pub fn total_value_locked() -> u64 {
+    total_staked() + liquid::state().total_underlying
+}
+
+fn compute_pool_stats(tier_stats: &[TierStats], now: u64) -> PoolStats {
+    let (staker_count, total_staked) = STAKE_BALANCE_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|(_, s)| *s > 0)
+            .fold((0, 0), |(n, total), (_, s)| (n + 1, total + s))
+    });
+    let deposit_count = DEPOSITS.with(|m| m.borrow().len());
+    let liquid = liquid::state();
+    PoolStats {
+        total_staked,
+        staker_count,
+        deposit_count,
+        liquid_underlying: liquid.total_underlying,
+        liquid_supply: liquid.total_supply,
+        total_value_locked: total_staked + liquid.total_underlying,
+        tiers: tier_stats
+            .iter()
+            .map(|t| TierDeposits {
+                lock_days: t.lock_days,
+                deposit_count: t.deposit_count,
+                total_amount: t.total_amount,
+            })
+            .collect(),
+        total_rewards_distributed: reward_history::total_distributed(),
+        trailing_apy_bps: reward_history::trailing_apy_bps(now),
+    }
+}
+
+fn compute_leaderboard() -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = STAKE_BALANCE_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|(_, s)| *s > 0)
+            .map(|(key, stake)| LeaderboardEntry { key, stake })
+            .collect()
+    });
+    entries.sort_by(|a, b| b.stake.cmp(&a.stake).then_with(|| a.key.cmp(&b.key)));
+    entries.truncate(MAX_LEADERBOARD_SIZE);
+    entries
+}
+
+fn compute(now: u64) -> Aggregates {
+    let tier_stats = tiers::compute_tier_stats();
+    Aggregates {
+        generation: GENERATION.with(|g| g.get()),
+        computed_at: now,
+        pool_stats: compute_pool_stats(&tier_stats, now),
+        tier_stats,
+        leaderboard: compute_leaderboard(),
+    }
+}
+
+fn cached(now: u64) -> Option<Aggregates> {
+    let generation = GENERATION.with(|g| g.get());
+    CACHE.with(|c| {
+        c.borrow()
+            .as_ref()
+            .filter(|a| a.generation == generation && now - a.computed_at <= CACHE_TTL_SECS)
+            .cloned()
+    })
+}
+
+/// Recomputes the cached aggregates if they are stale.
+pub fn refresh(now: u64) {
+    if cached(now).is_none() {
+        let fresh = compute(now);
+        CACHE.with(|c| *c.borrow_mut() = Some(fresh));
+    }
+}
+
+// Queries cannot persist a recomputed cache, so a stale cache is bypassed
+// until the refresh timer catches up.
+fn aggregates(now: u64) -> Aggregates {
+    cached(now).unwrap_or_else(|| compute(now))
+}
+
+/// Starts refreshing the cache every TTL. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_refresh() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(CACHE_TTL_SECS), || {
+        refresh(crate::now_secs())
+    });
+}
+
+/// Pool-wide totals, deposits per lock tier, rewards distributed to date and
+/// the trailing APY, served from a cache refreshed every few seconds.
+pub fn pool_stats() -> PoolStats {
+    aggregates(crate::now_secs()).pool_stats
+}
+
+/// Returns pool-wide totals, deposits per lock tier, rewards distributed to
+/// date and the trailing APY as of the last certification, with the path to
+/// the certified data and the certificate over it.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pool_stats() -> CertifiedPoolStats {
+    certification::certified_pool_stats()
+}
+
+/// Returns the deposits currently held in each lock tier and the neurons
+/// backing it, served from a cache refreshed every few seconds.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_tier_stats() -> Vec<TierStats> {
+    aggregates(crate::now_secs()).tier_stats
+}
+
+/// Returns up to `limit` (capped at 100) accounts with the largest stake.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_leaderboard(limit: u64) -> Vec<LeaderboardEntry> {
+    let mut leaderboard = aggregates(crate::now_secs()).leaderboard;
+    leaderboard.truncate(limit as usize);
+    leaderboard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_cache_is_invalidated_by_state_changes() {
+        let principal = Principal::anonymous();
+        crate::deposit_internal(principal, Subaccount([1u8; 32]), 90, 100, 0).unwrap();
+        refresh(0);
+        assert_eq!(cached(5).unwrap().pool_stats.total_staked, 100);
+        assert!(cached(CACHE_TTL_SECS + 1).is_none());
+
+        crate::deposit_internal(principal, Subaccount([2u8; 32]), 90, 300, 0).unwrap();
+        assert!(cached(5).is_none());
+        refresh(5);
+        let stats = cached(5).unwrap();
+        assert_eq!(stats.pool_stats.staker_count, 2);
+        assert_eq!(stats.pool_stats.total_value_locked, 400);
+        let tier = stats.pool_stats.tiers.iter().find(|t| t.lock_days == 90);
+        assert_eq!(
+            tier.map(|t| (t.deposit_count, t.total_amount)),
+            Some((2, 400))
+        );
+        assert_eq!(stats.leaderboard[0].stake, 300);
+    }
+}
@@ -0,0 +1,178 @@
+// src/status.rs
+use crate::circuit_breaker::HaltReason;
+use crate::config;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, POOL_STATUS_MEMORY_ID};
+use crate::state_hash;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Operating mode of the pool.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PoolStatus {
+    /// All operations are accepted.
+    #[default]
+    Active,
+    /// Only withdrawals of matured deposits are accepted. Entered automatically
+    /// when the canister runs low on cycles.
+    WithdrawalsOnly,
+    /// The pool moved to a successor canister and only redirects callers there.
+    Migrated { successor: Principal },
+    /// Deposits and reward distributions are halted by the circuit breaker
+    /// until a controller resumes the pool. Withdrawals are still accepted.
+    Halted { reason: HaltReason },
+    /// Stopped by a controller. Deposits and reward distributions are
+    /// refused; withdrawals only if the config allows them while paused.
+    Paused,
+}
+
+impl Storable for PoolStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode PoolStatus"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode PoolStatus")
+    }
+}
+
+thread_local! {
+    static POOL_STATUS: RefCell<StableCell<PoolStatus, Memory>> = RefCell::new(
+        StableCell::init(get_memory(POOL_STATUS_MEMORY_ID), PoolStatus::default())
+            .expect("Failed to init pool status cell"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "pool_status",
+        POOL_STATUS.with(|s| state_hash::cell_digest(&s.borrow())),
+    )]
+}
+
+pub fn get() -> PoolStatus {
+    POOL_STATUS.with(|s| *s.borrow().get())
+}
+
+pub fn set(status: PoolStatus) {
+    POOL_STATUS.with(|s| {
+        s.borrow_mut()
+            .set(status)
+            .expect("Failed to persist pool status");
+    });
+}
+
+/// Fails unless the pool accepts new deposits and reward distributions.
+pub fn ensure_active() -> Result<(), DepositError> {
+    match get() {
+        PoolStatus::Active => Ok(()),
+        PoolStatus::WithdrawalsOnly => Err(DepositError::WithdrawalsOnly),
+        PoolStatus::Halted { .. } => Err(DepositError::Halted),
+        PoolStatus::Paused => Err(DepositError::Paused),
+        PoolStatus::Migrated { successor } => Err(DepositError::Migrated { successor }),
+    }
+}
+
+/// Fails unless the pool still accepts withdrawals.
+pub fn ensure_withdrawals_allowed() -> Result<(), DepositError> {
+    match get() {
+        PoolStatus::Active | PoolStatus::WithdrawalsOnly | PoolStatus::Halted { .. } => Ok(()),
+        PoolStatus::Paused if config::get().withdrawals_while_paused => Ok(()),
+        PoolStatus::Paused => Err(DepositError::Paused),
+        PoolStatus::Migrated { successor } => Err(DepositError::Migrated { successor }),
+    }
+}
+
+fn transition(next: PoolStatus, now: u64) {
+    let current = get();
+    set(next);
+    events::record(
+        now,
+        EventKind::PoolStatusChanged {
+            from: current,
+            to: next,
+        },
+    );
+}
+
+pub fn pause_at(now: u64) -> Result<(), DepositError> {
+    match get() {
+        PoolStatus::Paused => Ok(()),
+        PoolStatus::Migrated { successor } => Err(DepositError::Migrated { successor }),
+        _ => {
+            transition(PoolStatus::Paused, now);
+            Ok(())
+        }
+    }
+}
+
+pub fn unpause_at(now: u64) -> Result<(), DepositError> {
+    if get() != PoolStatus::Paused {
+        return Err(DepositError::InvalidArgument(
+            "pool is not paused".to_string(),
+        ));
+    }
+    transition(PoolStatus::Active, now);
+    Ok(())
+}
+
+/// Stops deposits and reward distributions in an emergency. Withdrawals stay
+/// open if `withdrawals_while_paused` is set. Only canister controllers may
+/// call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn pause() -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("pause", pause_at(crate::now_secs()))
+}
+
+/// Returns a paused pool to normal operation. Only canister controllers may
+/// call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the pool is not paused.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn unpause() -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    crate::transactions::admin("unpause", unpause_at(crate::now_secs()))
+}
+
+/// Returns the current operating mode of the pool.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pool_status() -> PoolStatus {
+    get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_blocks_deposits_and_optionally_withdrawals() {
+        pause_at(0).unwrap();
+        assert_eq!(ensure_active(), Err(DepositError::Paused));
+        assert_eq!(ensure_withdrawals_allowed(), Ok(()));
+
+        config::set(config::PoolConfig {
+            withdrawals_while_paused: false,
+            ..config::PoolConfig::default()
+        });
+        assert_eq!(ensure_withdrawals_allowed(), Err(DepositError::Paused));
+
+        unpause_at(1).unwrap();
+        assert_eq!(get(), PoolStatus::Active);
+        assert!(unpause_at(2).is_err());
+    }
+}
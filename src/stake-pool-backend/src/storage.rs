@@ -0,0 +1,383 @@
+// src/storage.rs
+//! Version of the stable memory layout, and the migrations that bring state
+//! written by an older release up to it. A release that changes a stored
+//! type or map layout bumps `SCHEMA_VERSION` and appends a migration;
+//! `post_upgrade` runs the ones the canister has not applied yet, in order.
+use crate::memory::{
+    get_memory, Memory, DEPOSIT_MAP_MEMORY_ID, LEGACY_TRANSACTIONS_COPIED_MEMORY_ID,
+    SCHEMA_VERSION_MEMORY_ID, TOKEN_DEPOSITS_MEMORY_ID, TRANSACTION_LOG_DATA_MEMORY_ID,
+    TRANSACTION_LOG_INDEX_MEMORY_ID,
+};
+use crate::tokens::{self, TokenKey};
+use crate::transactions::{self, Transaction};
+use crate::{accrual, state_hash, upgrade, Deposit, DepositKey, UserKey, DEPOSITS};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell, StableLog,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub const SCHEMA_VERSION: u32 = 3;
+// Legacy transactions copied per message by the migration to version 3.
+const COPY_BATCH: u64 = 10_000;
+
+struct Migration {
+    /// Version the state is at once the migration ran.
+    to: u32,
+    run: fn(u64),
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 1,
+        run: migrate_to_v1,
+    },
+    Migration {
+        to: 2,
+        run: migrate_to_v2,
+    },
+    Migration {
+        to: 3,
+        run: migrate_to_v3,
+    },
+];
+
+/// Deposits of one account, as version 1 stored them in a single value. A
+/// list longer than a few deposits did not fit the bound.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct DepositList(Vec<Deposit>);
+
+impl Storable for DepositList {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Deposit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Deposit")
+    }
+}
+
+impl BoundedStorable for DepositList {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Canisters installed before the version was stored read 0.
+    static STORED_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SCHEMA_VERSION_MEMORY_ID), 0)
+            .expect("Failed to init schema version"),
+    );
+
+    // Emptied by the migration to version 2.
+    static LEGACY_DEPOSIT_MAP: RefCell<StableBTreeMap<UserKey, DepositList, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEPOSIT_MAP_MEMORY_ID)));
+
+    static LEGACY_TOKEN_DEPOSITS: RefCell<StableBTreeMap<TokenKey, DepositList, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TOKEN_DEPOSITS_MEMORY_ID)));
+
+    // Copied out by the migration to version 3. A log cannot drop entries,
+    // so it could not give up archived transactions.
+    static LEGACY_TRANSACTION_LOG: RefCell<StableLog<Transaction, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            get_memory(TRANSACTION_LOG_INDEX_MEMORY_ID),
+            get_memory(TRANSACTION_LOG_DATA_MEMORY_ID),
+        )
+        .expect("Failed to init transaction log"),
+    );
+
+    // Entries of the legacy transaction log copied so far.
+    static LEGACY_TRANSACTIONS_COPIED: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LEGACY_TRANSACTIONS_COPIED_MEMORY_ID), 0)
+            .expect("Failed to init legacy transactions cursor"),
+    );
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "schema_version",
+            STORED_VERSION.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "legacy_deposit_map",
+            LEGACY_DEPOSIT_MAP.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "legacy_token_deposits",
+            LEGACY_TOKEN_DEPOSITS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "legacy_transaction_log",
+            LEGACY_TRANSACTION_LOG.with(|s| state_hash::log_digest(&s.borrow())),
+        ),
+        (
+            "legacy_transactions_copied",
+            LEGACY_TRANSACTIONS_COPIED.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn stored_version() -> u32 {
+    STORED_VERSION.with(|c| *c.borrow().get())
+}
+
+fn set_stored_version(version: u32) {
+    STORED_VERSION.with(|c| {
+        c.borrow_mut()
+            .set(version)
+            .expect("Failed to persist schema version");
+    });
+}
+
+// Releases before accrual accounting was stored.
+fn migrate_to_v1(now: u64) {
+    accrual::backfill(now);
+}
+
+// Takes every entry out of a map.
+fn drain<K, V>(map: &mut StableBTreeMap<K, V, Memory>) -> Vec<(K, V)>
+where
+    K: Storable + BoundedStorable + Ord + Clone,
+    V: Storable + BoundedStorable,
+{
+    let keys: Vec<K> = map.iter().map(|(k, _)| k).collect();
+    keys.into_iter()
+        .filter_map(|k| map.remove(&k).map(|v| (k, v)))
+        .collect()
+}
+
+// Splits the per-account deposit lists into one entry per deposit, then
+// recovers the deposit id counter those releases did not store from them.
+fn migrate_to_v2(now: u64) {
+    for (user, list) in LEGACY_DEPOSIT_MAP.with(|m| drain(&mut m.borrow_mut())) {
+        DEPOSITS.with(|m| {
+            let mut m = m.borrow_mut();
+            for deposit in list.0 {
+                m.insert(DepositKey::new(&user, deposit.id), deposit);
+            }
+        });
+    }
+    for (key, list) in LEGACY_TOKEN_DEPOSITS.with(|m| drain(&mut m.borrow_mut())) {
+        for deposit in list.0 {
+            tokens::put_deposit(&key, deposit);
+        }
+    }
+    upgrade::recover_deposit_id_counter();
+    // The backfill of version 1 read the lists through the new layout and
+    // found none of them.
+    accrual::backfill(now);
+}
+
+/// Entries of the legacy transaction log. Transactions recorded since follow
+/// them, so their ids stay unique.
+pub(crate) fn legacy_transaction_count() -> u64 {
+    LEGACY_TRANSACTION_LOG.with(|l| l.borrow().len())
+}
+
+fn copied_transactions() -> u64 {
+    LEGACY_TRANSACTIONS_COPIED.with(|c| *c.borrow().get())
+}
+
+/// Whether the migration to version 3 is still copying the legacy log.
+pub(crate) fn copying_transactions() -> bool {
+    copied_transactions() < legacy_transaction_count()
+}
+
+/// A transaction of the legacy log the migration to version 3 has not
+/// copied yet.
+pub(crate) fn uncopied_transaction(id: u64) -> Option<Transaction> {
+    if id < copied_transactions() {
+        return None;
+    }
+    LEGACY_TRANSACTION_LOG.with(|l| l.borrow().get(id))
+}
+
+// Copies up to `limit` more entries of the legacy log. Returns whether any
+// are left.
+fn copy_transactions(limit: u64) -> bool {
+    let len = legacy_transaction_count();
+    let from = copied_transactions();
+    let to = from.saturating_add(limit).min(len);
+    for id in from..to {
+        if let Some(tx) = LEGACY_TRANSACTION_LOG.with(|l| l.borrow().get(id)) {
+            transactions::insert(tx);
+        }
+    }
+    LEGACY_TRANSACTIONS_COPIED.with(|c| {
+        c.borrow_mut()
+            .set(to)
+            .expect("Failed to persist legacy transactions cursor");
+    });
+    to < len
+}
+
+fn copy_transactions_step() {
+    if copy_transactions(COPY_BATCH) {
+        ic_cdk_timers::set_timer(Duration::ZERO, copy_transactions_step);
+    }
+}
+
+// Moves the transaction log into a map archived entries can be removed from.
+// Entries are copied in place of a drain since the log cannot shrink. A log
+// longer than `COPY_BATCH` is copied by `migrate`'s timer; reads fall back to
+// it until then.
+fn migrate_to_v3(_now: u64) {
+    copy_transactions(COPY_BATCH);
+}
+
+/// Marks a fresh install as already at the current layout.
+pub fn init_version() {
+    set_stored_version(SCHEMA_VERSION);
+}
+
+/// Runs every migration newer than the stored version. Traps if the stored
+/// version is newer than this release, since its layout cannot be read.
+pub fn migrate(now: u64) {
+    let stored = stored_version();
+    if stored > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "stable memory is at schema version {}, newer than {}",
+            stored, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.to > stored) {
+        (migration.run)(now);
+        set_stored_version(migration.to);
+    }
+    // The rest of a long transaction log, also after an upgrade cut the copy
+    // short.
+    if copying_transactions() {
+        ic_cdk_timers::set_timer(Duration::ZERO, copy_transactions_step);
+    }
+}
+
+/// Returns the schema version of the stored state.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_schema_version() -> u32 {
+    stored_version()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_migrations_lead_to_the_current_version() {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|m| m.to).collect();
+        assert_eq!(versions, (1..=SCHEMA_VERSION).collect::<Vec<_>>());
+
+        // State as version 1 stored it.
+        let user = UserKey {
+            principal: Principal::from_slice(&[130]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let deposit = |id| Deposit {
+            id,
+            amount: 100,
+            timestamp: 0,
+            lock_period_days: 90,
+        };
+        LEGACY_DEPOSIT_MAP.with(|m| {
+            m.borrow_mut()
+                .insert(user.clone(), DepositList(vec![deposit(4), deposit(2)]))
+        });
+        let tx = Transaction {
+            id: 0,
+            timestamp: 5,
+            caller: user.principal,
+            kind: transactions::TransactionKind::Deposit,
+            account: Some(user.clone()),
+            amount: 100,
+            block_index: Some(3),
+            token: None,
+        };
+        LEGACY_TRANSACTION_LOG.with(|l| l.borrow().append(&tx).unwrap());
+
+        assert_eq!(stored_version(), 0);
+        migrate(0);
+        assert_eq!(stored_version(), SCHEMA_VERSION);
+        let ids: Vec<u64> = crate::deposits_of(&user).iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![2, 4]);
+        assert!(LEGACY_DEPOSIT_MAP.with(|m| m.borrow().is_empty()));
+        assert_eq!(transactions::of_principal(user.principal, 0, 10), vec![tx]);
+        assert_eq!(transactions::len(), 1);
+        // Nothing is run twice.
+        migrate(0);
+        assert_eq!(get_schema_version(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_long_transaction_log_is_copied_in_batches() {
+        let caller = Principal::from_slice(&[133]);
+        let tx = |id| Transaction {
+            id,
+            timestamp: 5,
+            caller,
+            kind: transactions::TransactionKind::Deposit,
+            account: None,
+            amount: 100,
+            block_index: None,
+            token: None,
+        };
+        LEGACY_TRANSACTION_LOG.with(|l| {
+            for id in 0..3 {
+                l.borrow().append(&tx(id)).unwrap();
+            }
+        });
+
+        assert!(copy_transactions(2));
+        assert!(copying_transactions());
+        // Reads reach the rest in the legacy log, and new ids follow it.
+        assert_eq!(transactions::get(2), Some(tx(2)));
+        assert_eq!(transactions::len(), 3);
+        let id = transactions::record(
+            6,
+            caller,
+            transactions::TransactionKind::Deposit,
+            None,
+            1,
+            None,
+        );
+        assert_eq!(id, 3);
+
+        assert!(!copy_transactions(2));
+        assert!(!copying_transactions());
+        assert_eq!(transactions::of_principal(caller, 0, 10).len(), 4);
+        assert_eq!(transactions::get(1), Some(tx(1)));
+    }
+
+    #[test]
+    fn test_upgrade_from_baseline_recovers_the_deposit_id_counter() {
+        // The baseline release kept the counter on the heap and no event log,
+        // so the deposit lists are all that is left of it.
+        let user = |b| UserKey {
+            principal: Principal::from_slice(&[b]),
+            subaccount: Subaccount([0; 32]),
+        };
+        let deposit = |id| Deposit {
+            id,
+            amount: 100,
+            timestamp: 0,
+            lock_period_days: 90,
+        };
+        LEGACY_DEPOSIT_MAP.with(|m| {
+            let mut m = m.borrow_mut();
+            m.insert(user(131), DepositList(vec![deposit(3), deposit(9)]));
+            m.insert(user(132), DepositList(vec![deposit(5)]));
+        });
+        assert_eq!(crate::deposit_id_counter(), 0);
+
+        migrate(0);
+        assert_eq!(crate::deposit_id_counter(), 9);
+        let next =
+            crate::deposit_internal(user(132).principal, Subaccount([0; 32]), 90, 10, 0).unwrap();
+        assert_eq!(next.id, 10);
+    }
+}
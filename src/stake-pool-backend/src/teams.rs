@@ -0,0 +1,338 @@
+// src/teams.rs
+use crate::error::DepositError;
+use crate::maintenance::{self, Operation};
+use crate::memory::{get_memory, Memory, TEAMS_MEMORY_ID};
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use crate::{
+    allowlist, compounding, config, cycles, denylist, memory_guard, neurons, status, tiers,
+    Deposit, UserKey,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const MAX_TEAM_MEMBERS: usize = 10;
+const BPS_DENOMINATOR: u64 = 10_000;
+const TEAM_TAG: &[u8] = b"team";
+/// Class byte of derived principals, which no key pair controls.
+const DERIVED_ID_CLASS: u8 = 0x03;
+/// Members are paid to the default subaccount of their principal.
+const MEMBER_SUBACCOUNT: Subaccount = Subaccount([0u8; 32]);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TeamMember {
+    pub principal: Principal,
+    pub share_bps: u16,
+}
+
+/// A position owned jointly by its members in fixed shares.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Team {
+    pub id: u64,
+    pub members: Vec<TeamMember>,
+    pub created_at: u64,
+}
+
+impl Team {
+    pub fn is_member(&self, principal: Principal) -> bool {
+        self.members.iter().any(|m| m.principal == principal)
+    }
+}
+
+impl Storable for Team {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Team"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Team")
+    }
+}
+
+impl BoundedStorable for Team {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemberPayout {
+    pub principal: Principal,
+    pub amount: u64,
+    /// `false` if the transfer failed and the amount was added to the
+    /// member's pending rewards instead, to be collected with `claim_rewards`.
+    pub transferred: bool,
+}
+
+thread_local! {
+    static TEAMS: RefCell<StableBTreeMap<u64, Team, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TEAMS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![("teams", TEAMS.with(|s| state_hash::map_digest(&s.borrow())))]
+}
+
+/// The account holding a team's deposits in the pool's books.
+pub fn team_key(team_id: u64) -> UserKey {
+    let mut bytes = TEAM_TAG.to_vec();
+    bytes.extend_from_slice(&team_id.to_be_bytes());
+    bytes.push(DERIVED_ID_CLASS);
+    UserKey {
+        principal: Principal::from_slice(&bytes),
+        subaccount: MEMBER_SUBACCOUNT,
+    }
+}
+
+pub fn get(team_id: u64) -> Option<Team> {
+    TEAMS.with(|m| m.borrow().get(&team_id))
+}
+
+fn member_team(team_id: u64, caller: Principal) -> Result<Team, DepositError> {
+    match get(team_id) {
+        Some(team) if team.is_member(caller) => Ok(team),
+        Some(_) => Err(DepositError::Unauthorized),
+        None => Err(DepositError::InvalidArgument(format!(
+            "unknown team {}",
+            team_id
+        ))),
+    }
+}
+
+pub fn create(
+    creator: Principal,
+    members: Vec<TeamMember>,
+    now: u64,
+) -> Result<Team, DepositError> {
+    let total: u64 = members.iter().map(|m| m.share_bps as u64).sum();
+    let mut principals: Vec<Principal> = members.iter().map(|m| m.principal).collect();
+    principals.sort();
+    principals.dedup();
+    if members.len() < 2
+        || members.len() > MAX_TEAM_MEMBERS
+        || principals.len() != members.len()
+        || members.iter().any(|m| m.share_bps == 0)
+        || total != BPS_DENOMINATOR
+    {
+        return Err(DepositError::InvalidArgument(format!(
+            "a team needs 2 to {} distinct members with positive shares adding up to 10000 bps",
+            MAX_TEAM_MEMBERS
+        )));
+    }
+    if !principals.contains(&creator) {
+        return Err(DepositError::InvalidArgument(
+            "the creator must be a member".to_string(),
+        ));
+    }
+    let id = TEAMS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    let team = Team {
+        id,
+        members,
+        created_at: now,
+    };
+    TEAMS.with(|m| m.borrow_mut().insert(id, team.clone()));
+    Ok(team)
+}
+
+/// Splits `amount` by share. The last member receives the rounding remainder.
+pub fn split(team: &Team, amount: u64) -> Vec<(Principal, u64)> {
+    let mut remaining = amount;
+    let last = team.members.len() - 1;
+    team.members
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let share = if i == last {
+                remaining
+            } else {
+                (amount as u128 * m.share_bps as u128 / BPS_DENOMINATOR as u128) as u64
+            };
+            remaining -= share;
+            (m.principal, share)
+        })
+        .collect()
+}
+
+async fn pay_members(team: &Team, amount: u64) -> Vec<MemberPayout> {
+    let mut payouts = Vec::with_capacity(team.members.len());
+    for (principal, share) in split(team, amount) {
+        let transferred = share == 0
+            || crate::transfer_net(
+                crate::ledger::ledger_id(),
+                principal,
+                MEMBER_SUBACCOUNT,
+                share,
+            )
+            .await
+            .is_ok();
+        if !transferred {
+            let key = UserKey {
+                principal,
+                subaccount: MEMBER_SUBACCOUNT,
+            };
+            compounding::credit_pending(&key, share);
+        }
+        payouts.push(MemberPayout {
+            principal,
+            amount: share,
+            transferred,
+        });
+    }
+    payouts
+}
+
+/// Creates a team position shared by `members`. The caller must be one of
+/// them.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the members are not 2 to 10 distinct
+///   principals, including the caller, whose shares add up to 10000 bps.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn create_team(members: Vec<TeamMember>) -> Result<Team, DepositError> {
+    create(ic_cdk::caller(), members, crate::now_secs())
+}
+
+/// Deposits funds from the caller's subaccount into a team position. The
+/// deposit belongs to the team, not to the contributing member.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a member of the team.
+/// * Any error of `deposit_funds`.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn contribute_to_team(
+    team_id: u64,
+    subaccount: Subaccount,
+    lock_days: u16,
+    amount: u64,
+) -> Result<Deposit, DepositError> {
+    cycles::check_headroom();
+    status::ensure_active()?;
+    maintenance::ensure_available(Operation::Deposits)?;
+    let caller = ic_cdk::caller();
+    member_team(team_id, caller)?;
+    denylist::ensure_not_denied(caller)?;
+    allowlist::ensure_allowed(caller)?;
+    let now = crate::now_secs();
+    memory_guard::ensure_deposit_capacity(now)?;
+    tiers::ensure_open(lock_days)?;
+    let key = team_key(team_id);
+    crate::ensure_deposit_allowed(key.principal, amount)?;
+    let from = Account {
+        owner: caller,
+        subaccount: Some(subaccount.0),
+    };
+    let block = crate::pull_funds(crate::ledger::ledger_id(), from, amount).await?;
+    let deposit = crate::deposit_internal(key.principal, key.subaccount, lock_days, amount, now)?;
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::Deposit,
+        Some(key),
+        amount,
+        Some(block),
+    );
+    if config::get().neuron_staking_enabled {
+        neurons::stake_deposit(lock_days, amount).await;
+    }
+    Ok(deposit)
+}
+
+/// Withdraws a matured team deposit and pays it out to the members by share.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a member of the team.
+/// * `DepositError::NoDepositFound`: If the team has no such deposit.
+/// * `DepositError::LockPeriodNotExpired`: If the lock period has not expired.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn withdraw_team_deposit(
+    team_id: u64,
+    deposit_id: u64,
+) -> Result<Vec<MemberPayout>, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let team = member_team(team_id, ic_cdk::caller())?;
+    let key = team_key(team_id);
+    let amount =
+        crate::withdraw_internal(key.principal, key.subaccount, deposit_id, crate::now_secs())?;
+    Ok(pay_members(&team, amount).await)
+}
+
+/// Pays the rewards the team position earned out to the members by share.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a member of the team.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn claim_team_rewards(team_id: u64) -> Result<Vec<MemberPayout>, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Claims)?;
+    let team = member_team(team_id, ic_cdk::caller())?;
+    let key = team_key(team_id);
+    crate::accrual::settle_account(&key, crate::now_secs())?;
+    let amount = compounding::take_pending(&key);
+    Ok(pay_members(&team, amount).await)
+}
+
+/// Returns a team with its members and shares.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_team(team_id: u64) -> Option<Team> {
+    get(team_id)
+}
+
+/// Returns the teams the caller is a member of.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_my_teams() -> Vec<Team> {
+    let caller = ic_cdk::caller();
+    TEAMS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, t)| t)
+            .filter(|t| t.is_member(caller))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(byte: u8, share_bps: u16) -> TeamMember {
+        TeamMember {
+            principal: Principal::from_slice(&[byte]),
+            share_bps,
+        }
+    }
+
+    #[test]
+    fn test_team_shares_and_split() {
+        let alice = Principal::from_slice(&[1]);
+        assert!(create(alice, vec![member(1, 5_000), member(2, 4_000)], 0).is_err());
+        assert!(create(alice, vec![member(2, 5_000), member(3, 5_000)], 0).is_err());
+        assert!(create(alice, vec![member(1, 5_000), member(1, 5_000)], 0).is_err());
+
+        let team = create(alice, vec![member(1, 3_333), member(2, 6_667)], 0).unwrap();
+        assert_eq!(get(team.id), Some(team.clone()));
+        assert_eq!(
+            split(&team, 1_000),
+            vec![(alice, 333), (Principal::from_slice(&[2]), 667)]
+        );
+
+        let key = team_key(team.id);
+        assert_ne!(team_key(team.id + 1), key);
+    }
+}
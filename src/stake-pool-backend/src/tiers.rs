@@ -0,0 +1,104 @@
+// src/tiers.rs
+use crate::config;
+use crate::error::DepositError;
+use crate::neurons;
+use crate::{DEPOSITS, VALID_LOCKS};
+use candid::{CandidType, Deserialize};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TierStats {
+    pub lock_days: u16,
+    /// Closed tiers accept no new deposits; existing ones run to maturity.
+    pub closed: bool,
+    pub deposit_count: u64,
+    pub total_amount: u64,
+    /// Pool neurons backing the tier and the deposits staked in them.
+    pub neuron_ids: Vec<u64>,
+    pub neuron_staked: u64,
+}
+
+pub fn is_closed(lock_days: u16) -> bool {
+    config::get().closed_lock_tiers.contains(&lock_days)
+}
+
+/// Fails unless new deposits may be made with a lock of `lock_days`.
+pub fn ensure_open(lock_days: u16) -> Result<(), DepositError> {
+    if !VALID_LOCKS.contains(&lock_days) {
+        return Err(DepositError::InvalidLockPeriod);
+    }
+    if is_closed(lock_days) {
+        return Err(DepositError::LockTierClosed);
+    }
+    Ok(())
+}
+
+/// Counts the deposits held in each lock tier and the neurons backing it.
+pub fn compute_tier_stats() -> Vec<TierStats> {
+    let closed = config::get().closed_lock_tiers;
+    let mut stats: Vec<TierStats> = VALID_LOCKS
+        .iter()
+        .map(|&lock_days| TierStats {
+            lock_days,
+            closed: closed.contains(&lock_days),
+            deposit_count: 0,
+            total_amount: 0,
+            neuron_ids: Vec::new(),
+            neuron_staked: 0,
+        })
+        .collect();
+
+    for neuron in neurons::all() {
+        if let Some(tier) = stats
+            .iter_mut()
+            .find(|t| Some(t.lock_days) == neuron.lock_days)
+        {
+            tier.neuron_ids.push(neuron.neuron_id);
+            tier.neuron_staked += neuron.staked;
+        }
+    }
+
+    DEPOSITS.with(|map| {
+        for (_, deposit) in map.borrow().iter() {
+            if let Some(tier) = stats
+                .iter_mut()
+                .find(|t| t.lock_days == deposit.lock_period_days)
+            {
+                tier.deposit_count += 1;
+                tier.total_amount += deposit.amount;
+            }
+        }
+    });
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::{execute_batch, AdminOp, ConfigPatch};
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_closed_tier_keeps_existing_deposits() {
+        let principal = Principal::anonymous();
+        crate::deposit_internal(principal, Subaccount([1u8; 32]), 180, 500, 0).unwrap();
+
+        execute_batch(vec![AdminOp::PatchConfig(Box::new(ConfigPatch {
+            closed_lock_tiers: Some(vec![180]),
+            ..ConfigPatch::default()
+        }))]);
+
+        assert_eq!(ensure_open(180), Err(DepositError::LockTierClosed));
+        assert_eq!(ensure_open(90), Ok(()));
+        let tier = compute_tier_stats()
+            .into_iter()
+            .find(|t| t.lock_days == 180)
+            .unwrap();
+        assert!(tier.closed);
+        assert_eq!((tier.deposit_count, tier.total_amount), (1, 500));
+        assert_eq!(
+            crate::withdraw_internal(principal, Subaccount([1u8; 32]), 1, 180 * 86400),
+            Ok(500)
+        );
+    }
+}
@@ -0,0 +1,425 @@
+// src/tokens.rs
+//! Staking of ICRC tokens other than the pool's primary ledger. Controllers
+//! register the ledgers the pool accepts; deposits of those tokens are locked
+//! like primary deposits and kept per (ledger, principal, subaccount), but do
+//! not share in reward distributions, which are paid in the primary token.
+use crate::error::DepositError;
+use crate::memory::{
+    get_memory, Memory, TOKEN_DEPOSIT_ENTRIES_MEMORY_ID, TOKEN_REGISTRY_MEMORY_ID,
+    TOKEN_STAKES_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use crate::{ledger, Deposit, PrincipalKey, UserKey, VALID_LOCKS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegisteredToken {
+    pub ledger: Principal,
+    pub added_at: u64,
+}
+
+impl Storable for RegisteredToken {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode RegisteredToken"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RegisteredToken")
+    }
+}
+
+impl BoundedStorable for RegisteredToken {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Position key of a registered token.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TokenKey {
+    pub token: Principal,
+    pub principal: Principal,
+    pub subaccount: Subaccount,
+}
+
+impl Storable for TokenKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode TokenKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode TokenKey")
+    }
+}
+
+impl BoundedStorable for TokenKey {
+    const MAX_SIZE: u32 = 140;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Stable-memory key of a token deposit, sorted like `DepositKey`.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TokenDepositKey {
+    pub key: TokenKey,
+    pub deposit_id: u64,
+}
+
+impl TokenDepositKey {
+    pub fn new(key: &TokenKey, deposit_id: u64) -> Self {
+        Self {
+            key: key.clone(),
+            deposit_id,
+        }
+    }
+}
+
+impl Storable for TokenDepositKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode TokenDepositKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode TokenDepositKey")
+    }
+}
+
+impl BoundedStorable for TokenDepositKey {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TOKEN_REGISTRY: RefCell<StableBTreeMap<PrincipalKey, RegisteredToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TOKEN_REGISTRY_MEMORY_ID)));
+
+    static TOKEN_DEPOSITS: RefCell<StableBTreeMap<TokenDepositKey, Deposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TOKEN_DEPOSIT_ENTRIES_MEMORY_ID)));
+
+    static TOKEN_STAKES: RefCell<StableBTreeMap<TokenKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TOKEN_STAKES_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "token_registry",
+            TOKEN_REGISTRY.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "token_deposits",
+            TOKEN_DEPOSITS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "token_stakes",
+            TOKEN_STAKES.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn is_primary(token: Principal) -> bool {
+    ledger::ledger_canister() == Some(token)
+}
+
+pub fn is_registered(token: Principal) -> bool {
+    TOKEN_REGISTRY.with(|m| m.borrow().contains_key(&PrincipalKey(token)))
+}
+
+pub fn registered() -> Vec<RegisteredToken> {
+    TOKEN_REGISTRY.with(|m| m.borrow().iter().map(|(_, t)| t).collect())
+}
+
+fn total_stake(token: Principal) -> u64 {
+    TOKEN_STAKES.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(key, _)| key.token == token)
+            .map(|(_, s)| s)
+            .sum()
+    })
+}
+
+pub fn add(token: Principal, now: u64) -> Result<(), DepositError> {
+    if is_primary(token) {
+        return Err(DepositError::InvalidArgument(
+            "the primary ledger is always accepted".to_string(),
+        ));
+    }
+    TOKEN_REGISTRY.with(|m| {
+        m.borrow_mut().insert(
+            PrincipalKey(token),
+            RegisteredToken {
+                ledger: token,
+                added_at: now,
+            },
+        )
+    });
+    Ok(())
+}
+
+/// Unregisters `token`. Refused while deposits of it remain, since they could
+/// no longer be withdrawn.
+pub fn remove(token: Principal) -> Result<(), DepositError> {
+    if !is_registered(token) {
+        return Err(DepositError::UnsupportedToken);
+    }
+    if total_stake(token) > 0 {
+        return Err(DepositError::InvalidArgument(
+            "the pool still holds deposits of this token".to_string(),
+        ));
+    }
+    TOKEN_REGISTRY.with(|m| m.borrow_mut().remove(&PrincipalKey(token)));
+    Ok(())
+}
+
+pub fn deposits_of(key: &TokenKey) -> Vec<Deposit> {
+    let range = TokenDepositKey::new(key, 0)..=TokenDepositKey::new(key, u64::MAX);
+    TOKEN_DEPOSITS.with(|m| m.borrow().range(range).map(|(_, d)| d).collect())
+}
+
+pub fn stake_of(key: &TokenKey) -> u64 {
+    TOKEN_STAKES.with(|m| m.borrow().get(key).unwrap_or(0))
+}
+
+fn add_stake(key: &TokenKey, amount: u64) {
+    TOKEN_STAKES.with(|m| {
+        let mut m = m.borrow_mut();
+        let current = m.get(key).unwrap_or(0);
+        m.insert(key.clone(), current + amount);
+    });
+}
+
+pub(crate) fn put_deposit(key: &TokenKey, deposit: Deposit) {
+    TOKEN_DEPOSITS.with(|m| {
+        m.borrow_mut()
+            .insert(TokenDepositKey::new(key, deposit.id), deposit)
+    });
+}
+
+/// Highest id among the token deposits still held.
+pub(crate) fn max_deposit_id() -> Option<u64> {
+    TOKEN_DEPOSITS.with(|m| m.borrow().iter().map(|(_, d)| d.id).max())
+}
+
+fn push_deposit(key: &TokenKey, deposit: Deposit) {
+    let amount = deposit.amount;
+    put_deposit(key, deposit);
+    add_stake(key, amount);
+}
+
+pub(crate) fn deposit_internal(
+    key: &TokenKey,
+    lock_days: u16,
+    amount: u64,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    if !is_registered(key.token) {
+        return Err(DepositError::UnsupportedToken);
+    }
+    if !VALID_LOCKS.contains(&lock_days) {
+        return Err(DepositError::InvalidLockPeriod);
+    }
+    let id = crate::deposit_id_counter() + 1;
+    crate::set_deposit_id_counter(id);
+    let deposit = Deposit {
+        id,
+        amount,
+        timestamp: now,
+        lock_period_days: lock_days,
+    };
+    push_deposit(key, deposit.clone());
+    Ok(deposit)
+}
+
+pub(crate) fn withdraw_internal(
+    key: &TokenKey,
+    deposit_id: u64,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    let entry = TokenDepositKey::new(key, deposit_id);
+    let deposit = TOKEN_DEPOSITS
+        .with(|m| m.borrow().get(&entry))
+        .ok_or(DepositError::NoDepositFound)?;
+    if now < deposit.unlock_time() {
+        return Err(DepositError::LockPeriodNotExpired);
+    }
+    TOKEN_DEPOSITS.with(|m| m.borrow_mut().remove(&entry));
+    let removed = deposit;
+    TOKEN_STAKES.with(|m| {
+        let mut m = m.borrow_mut();
+        let current = m.get(key).unwrap_or(0);
+        m.insert(key.clone(), current.saturating_sub(removed.amount));
+    });
+    Ok(removed)
+}
+
+fn account_of(key: &TokenKey) -> UserKey {
+    UserKey {
+        principal: key.principal,
+        subaccount: key.subaccount,
+    }
+}
+
+/// Pulls `amount` of `key.token` from the caller and locks it.
+pub(crate) async fn deposit(
+    key: TokenKey,
+    lock_days: u16,
+    amount: u64,
+    now: u64,
+) -> Result<Deposit, DepositError> {
+    if !is_registered(key.token) {
+        return Err(DepositError::UnsupportedToken);
+    }
+    let from = Account {
+        owner: key.principal,
+        subaccount: Some(key.subaccount.0),
+    };
+    let block = crate::pull_funds(key.token, from, amount).await?;
+    let deposit = deposit_internal(&key, lock_days, amount, now)?;
+    transactions::record_in(
+        key.token,
+        now,
+        key.principal,
+        TransactionKind::Deposit,
+        Some(account_of(&key)),
+        amount,
+        Some(block),
+    );
+    Ok(deposit)
+}
+
+/// Pays out a matured deposit of `key.token` to `destination`, or to `key`
+/// when `None`, net of that ledger's fee. The deposit is restored if the
+/// transfer fails.
+pub(crate) async fn withdraw(
+    key: TokenKey,
+    deposit_id: u64,
+    now: u64,
+    destination: Option<Account>,
+) -> Result<u64, DepositError> {
+    let deposit = withdraw_internal(&key, deposit_id, now)?;
+    let to = destination.unwrap_or(Account {
+        owner: key.principal,
+        subaccount: Some(key.subaccount.0),
+    });
+    match crate::transfer_net_to(key.token, to, deposit.amount).await {
+        Ok((block, received)) => {
+            crate::record_destination(&account_of(&key), deposit_id, destination, now);
+            transactions::record_in(
+                key.token,
+                now,
+                key.principal,
+                TransactionKind::Withdrawal,
+                Some(account_of(&key)),
+                deposit.amount,
+                Some(block),
+            );
+            Ok(received)
+        }
+        Err(e) => {
+            push_deposit(&key, deposit);
+            Err(e)
+        }
+    }
+}
+
+/// Registers an ICRC ledger whose tokens the pool accepts for staking. Only
+/// canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If `token` is the pool's primary ledger.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn add_token(token: Principal) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    transactions::admin("add_token", add(token, crate::now_secs()))
+}
+
+/// Stops accepting a registered token. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::UnsupportedToken`: If `token` is not registered.
+/// * `DepositError::InvalidArgument`: If deposits of `token` remain.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn remove_token(token: Principal) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    transactions::admin("remove_token", remove(token))
+}
+
+/// Returns the registered tokens besides the primary ledger.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_tokens() -> Vec<RegisteredToken> {
+    registered()
+}
+
+/// Returns the caller's deposits of `token` made from `subaccount`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_token_deposits(token: Principal, subaccount: Subaccount) -> Vec<Deposit> {
+    deposits_of(&TokenKey {
+        token,
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+/// Returns the caller's stake in `token` for `subaccount`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_token_stake(token: Principal, subaccount: Subaccount) -> u64 {
+    stake_of(&TokenKey {
+        token,
+        principal: ic_cdk::caller(),
+        subaccount,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_deposits_are_kept_per_ledger() {
+        let ckbtc = Principal::from_slice(&[10]);
+        let ckusdc = Principal::from_slice(&[11]);
+        let key = |token| TokenKey {
+            token,
+            principal: Principal::from_slice(&[1]),
+            subaccount: Subaccount([0; 32]),
+        };
+        assert_eq!(
+            deposit_internal(&key(ckbtc), 90, 100, 0),
+            Err(DepositError::UnsupportedToken)
+        );
+
+        add(ckbtc, 0).unwrap();
+        add(ckusdc, 0).unwrap();
+        let d = deposit_internal(&key(ckbtc), 90, 100, 0).unwrap();
+        deposit_internal(&key(ckusdc), 90, 500, 0).unwrap();
+        assert_eq!(stake_of(&key(ckbtc)), 100);
+        assert_eq!(stake_of(&key(ckusdc)), 500);
+        assert!(remove(ckbtc).is_err());
+
+        assert_eq!(
+            withdraw_internal(&key(ckbtc), d.id, 0),
+            Err(DepositError::LockPeriodNotExpired)
+        );
+        withdraw_internal(&key(ckbtc), d.id, d.unlock_time()).unwrap();
+        assert_eq!(stake_of(&key(ckbtc)), 0);
+        assert_eq!(remove(ckbtc), Ok(()));
+        assert_eq!(registered().len(), 1);
+    }
+}
@@ -0,0 +1,395 @@
+// src/transactions.rs
+//! Append-only audit trail of pool operations. Unlike the event log, every
+//! entry names the principal that initiated it and, for operations that moved
+//! tokens, the ledger block of the transfer. The oldest entries move to
+//! archive canisters; the queries below fetch them from there.
+use crate::archive;
+use crate::events::MAX_EVENTS_PER_PAGE;
+use crate::memory::{get_memory, Memory, TRANSACTIONS_MEMORY_ID, USER_TRANSACTIONS_MEMORY_ID};
+use crate::state_hash;
+use crate::storage;
+use crate::UserKey;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Range;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    EarlyWithdrawal,
+    /// Stake kept by the pool on an early withdrawal.
+    Penalty,
+    RewardDistribution,
+    RewardClaim,
+    /// Rewards restaked as a new deposit without leaving the pool.
+    Compound,
+    /// Funds given to the treasury.
+    Donation,
+    /// A deposit of `from` was handed to the transaction's account.
+    DepositTransfer {
+        from: UserKey,
+        deposit_id: u64,
+    },
+    /// USD price of the token at a reward distribution, with 8 decimals.
+    /// The entry's amount is the reward distributed.
+    PriceSnapshot {
+        usd_e8s: u64,
+        fetched_at: u64,
+    },
+    /// A controller changed the pool's configuration or state.
+    AdminChange {
+        action: String,
+    },
+    /// Treasury bonus paid to `referrer` for a deposit of the caller.
+    ReferralBonus {
+        referrer: Principal,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub id: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub kind: TransactionKind,
+    /// The account whose position changed, if any.
+    pub account: Option<UserKey>,
+    pub amount: u64,
+    pub block_index: Option<u64>,
+    /// Ledger of the tokens moved, unless it is the pool's primary ledger.
+    pub token: Option<Principal>,
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Transaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Transaction")
+    }
+}
+
+impl BoundedStorable for Transaction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Index key of a transaction in the per-principal view.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct UserTransaction {
+    principal: Principal,
+    id: u64,
+}
+
+impl Storable for UserTransaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = vec![0u8; 38];
+        bytes[0] = principal.len() as u8;
+        bytes[1..1 + principal.len()].copy_from_slice(principal);
+        bytes[30..].copy_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        UserTransaction {
+            principal: Principal::from_slice(&bytes[1..1 + len]),
+            id: u64::from_be_bytes(bytes[30..38].try_into().expect("invalid id bytes")),
+        }
+    }
+}
+
+impl BoundedStorable for UserTransaction {
+    const MAX_SIZE: u32 = 38;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    // Transactions not archived yet, keyed by id.
+    static TRANSACTIONS: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TRANSACTIONS_MEMORY_ID)));
+
+    // Every principal a transaction concerns, with the transaction id.
+    static USER_TRANSACTIONS: RefCell<StableBTreeMap<UserTransaction, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(USER_TRANSACTIONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "transactions",
+            TRANSACTIONS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "user_transactions",
+            USER_TRANSACTIONS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+/// Appends a transaction in the primary token and returns its id.
+pub fn record(
+    timestamp: u64,
+    caller: Principal,
+    kind: TransactionKind,
+    account: Option<UserKey>,
+    amount: u64,
+    block_index: Option<u64>,
+) -> u64 {
+    append(timestamp, caller, kind, account, amount, block_index, None)
+}
+
+/// Appends a transaction in a registered token and returns its id.
+pub fn record_in(
+    token: Principal,
+    timestamp: u64,
+    caller: Principal,
+    kind: TransactionKind,
+    account: Option<UserKey>,
+    amount: u64,
+    block_index: Option<u64>,
+) -> u64 {
+    append(
+        timestamp,
+        caller,
+        kind,
+        account,
+        amount,
+        block_index,
+        Some(token),
+    )
+}
+
+fn append(
+    timestamp: u64,
+    caller: Principal,
+    kind: TransactionKind,
+    account: Option<UserKey>,
+    amount: u64,
+    block_index: Option<u64>,
+    token: Option<Principal>,
+) -> u64 {
+    let id = len();
+    insert(Transaction {
+        id,
+        timestamp,
+        caller,
+        kind,
+        account,
+        amount,
+        block_index,
+        token,
+    });
+    crate::icrc3::sync_hashes(1);
+    id
+}
+
+// Principals whose view lists the transaction.
+fn index_keys(tx: &Transaction) -> Vec<UserTransaction> {
+    let owner = tx.account.as_ref().map(|a| a.principal);
+    std::iter::once(tx.caller)
+        .chain(owner.filter(|o| *o != tx.caller))
+        .map(|principal| UserTransaction {
+            principal,
+            id: tx.id,
+        })
+        .collect()
+}
+
+/// Stores a transaction under its id and indexes it by principal.
+pub(crate) fn insert(tx: Transaction) {
+    USER_TRANSACTIONS.with(|m| {
+        let mut m = m.borrow_mut();
+        for key in index_keys(&tx) {
+            m.insert(key, ());
+        }
+    });
+    TRANSACTIONS.with(|m| m.borrow_mut().insert(tx.id, tx));
+}
+
+/// Drops transactions handed to an archive.
+pub(crate) fn prune(ids: Range<u64>) {
+    for id in ids {
+        let Some(tx) = TRANSACTIONS.with(|m| m.borrow_mut().remove(&id)) else {
+            continue;
+        };
+        USER_TRANSACTIONS.with(|m| {
+            let mut m = m.borrow_mut();
+            for key in index_keys(&tx) {
+                m.remove(&key);
+            }
+        });
+    }
+}
+
+/// Records a successful admin call by the current caller and passes its
+/// result through.
+pub fn admin<T>(
+    action: &str,
+    result: Result<T, crate::error::DepositError>,
+) -> Result<T, crate::error::DepositError> {
+    if result.is_ok() {
+        record(
+            crate::now_secs(),
+            ic_cdk::caller(),
+            TransactionKind::AdminChange {
+                action: action.to_string(),
+            },
+            None,
+            0,
+            None,
+        );
+    }
+    result
+}
+
+/// Transactions recorded so far, archived or not, including those of the
+/// legacy log. Archiving always leaves the newest ones in place.
+pub fn len() -> u64 {
+    TRANSACTIONS
+        .with(|m| m.borrow().last_key_value().map_or(0, |(id, _)| id + 1))
+        .max(storage::legacy_transaction_count())
+}
+
+pub fn get(id: u64) -> Option<Transaction> {
+    TRANSACTIONS
+        .with(|m| m.borrow().get(&id))
+        .or_else(|| storage::uncopied_transaction(id))
+}
+
+/// Transactions with ids `offset..offset + limit` still held by the pool.
+pub fn range(offset: u64, limit: u64) -> Vec<Transaction> {
+    TRANSACTIONS.with(|m| {
+        m.borrow()
+            .range(offset..offset.saturating_add(limit))
+            .map(|(_, tx)| tx)
+            .collect()
+    })
+}
+
+pub fn of_principal(principal: Principal, offset: u64, limit: u64) -> Vec<Transaction> {
+    let ids: Vec<u64> = USER_TRANSACTIONS.with(|m| {
+        m.borrow()
+            .range(
+                UserTransaction { principal, id: 0 }..=UserTransaction {
+                    principal,
+                    id: u64::MAX,
+                },
+            )
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(key, _)| key.id)
+            .collect()
+    });
+    ids.into_iter().filter_map(get).collect()
+}
+
+/// Returns up to `limit` transactions (capped at 100) starting at `offset`,
+/// including archived ones.
+#[ic_cdk::query(composite = true)]
+#[candid::candid_method(composite_query)]
+pub async fn get_transactions(offset: u64, limit: u64) -> Vec<Transaction> {
+    let ids = offset..offset.saturating_add(limit.min(MAX_EVENTS_PER_PAGE));
+    let mut transactions = Vec::new();
+    for archive in archive::records() {
+        transactions.extend(archive::fetch(&archive, ids.clone()).await);
+    }
+    transactions.extend(range(ids.start, ids.end - ids.start));
+    transactions
+}
+
+/// Returns up to `limit` of the caller's transactions (capped at 100),
+/// skipping the first `offset`, oldest first, including archived ones.
+#[ic_cdk::query(composite = true)]
+#[candid::candid_method(composite_query)]
+pub async fn get_my_transactions(offset: u64, limit: u64) -> Vec<Transaction> {
+    let caller = ic_cdk::caller();
+    let (mut offset, mut limit) = (offset, limit.min(MAX_EVENTS_PER_PAGE));
+    let mut transactions = Vec::new();
+    for archive in archive::records() {
+        if limit == 0 {
+            break;
+        }
+        let page = archive::fetch_of(&archive, caller, offset, limit).await;
+        offset = offset.saturating_sub(page.total);
+        limit -= (page.transactions.len() as u64).min(limit);
+        transactions.extend(page.transactions);
+    }
+    transactions.extend(of_principal(caller, offset, limit));
+    transactions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_per_user_view_includes_account_owner() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let alice_key = UserKey {
+            principal: alice,
+            subaccount: Subaccount([0; 32]),
+        };
+        record(
+            1,
+            alice,
+            TransactionKind::Deposit,
+            Some(alice_key.clone()),
+            100,
+            Some(7),
+        );
+        record(
+            2,
+            bob,
+            TransactionKind::RewardDistribution,
+            None,
+            50,
+            Some(8),
+        );
+        // A controller retrying alice's withdrawal.
+        record(
+            3,
+            bob,
+            TransactionKind::Withdrawal,
+            Some(alice_key),
+            100,
+            Some(9),
+        );
+
+        assert_eq!(range(0, 10).len(), 3);
+        let ids: Vec<u64> = of_principal(alice, 0, 10).iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(of_principal(bob, 1, 10)[0].block_index, Some(9));
+
+        // The largest entry fits the storage bound.
+        let long = Principal::from_slice(&[7; 29]);
+        let key = UserKey {
+            principal: long,
+            subaccount: Subaccount([7; 32]),
+        };
+        let tx = Transaction {
+            id: u64::MAX,
+            timestamp: u64::MAX,
+            caller: long,
+            kind: TransactionKind::DepositTransfer {
+                from: key.clone(),
+                deposit_id: u64::MAX,
+            },
+            account: Some(key),
+            amount: u64::MAX,
+            block_index: Some(u64::MAX),
+            token: Some(long),
+        };
+        assert!(tx.to_bytes().len() <= Transaction::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,625 @@
+// src/treasury.rs
+//! Funds the pool keeps for itself: early-exit penalties and donations. They
+//! arrive in the pool's main account and are swept to the treasury
+//! subaccount before anything is paid out of it.
+use crate::circuit_breaker;
+use crate::config;
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::ledger;
+use crate::memory::{
+    get_memory, Memory, DISBURSEMENTS_MEMORY_ID, TREASURY_HISTORY_MEMORY_ID,
+    TREASURY_STATE_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::transactions::{self, TransactionKind};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub const MAX_PURPOSE_LEN: usize = 64;
+pub const MAX_HISTORY_DAYS: u64 = 366;
+const TREASURY_TAG: &[u8] = b"treasury";
+
+/// Subaccount of the canister holding the treasury.
+pub const TREASURY_SUBACCOUNT: [u8; 32] = {
+    let mut subaccount = [0u8; 32];
+    let mut i = 0;
+    while i < TREASURY_TAG.len() {
+        subaccount[i] = TREASURY_TAG[i];
+        i += 1;
+    }
+    subaccount
+};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum InflowSource {
+    Fee,
+    Penalty,
+    /// Rounding remainders of reward distributions. No longer produced: they
+    /// are carried into the next distribution instead.
+    Dust,
+    Donation,
+}
+
+/// Funds the pool holds on its own account, separate from staker funds.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct TreasuryState {
+    pub balance: u64,
+    pub fees: u64,
+    pub penalties: u64,
+    pub dust: u64,
+    pub donations: u64,
+    pub disbursed: u64,
+    /// Part of `balance` still in the pool's main account.
+    pub unswept: u64,
+    /// Ledger fees paid sweeping inflows to the treasury subaccount.
+    pub sweep_fees: u64,
+}
+
+impl Storable for TreasuryState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode TreasuryState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode TreasuryState")
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DisbursementStatus {
+    Pending,
+    /// Approved, with its transfer in flight.
+    Executing,
+    Executed {
+        block_index: Nat,
+    },
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Disbursement {
+    pub id: u64,
+    pub to: Account,
+    pub amount: u64,
+    pub purpose: String,
+    pub proposed_at: u64,
+    /// Controllers that approved, the proposer first.
+    pub approvals: Vec<Principal>,
+    pub status: DisbursementStatus,
+}
+
+impl Storable for Disbursement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Disbursement"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Disbursement")
+    }
+}
+
+impl BoundedStorable for Disbursement {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TreasuryReport {
+    pub state: TreasuryState,
+    /// End-of-day balances, oldest first.
+    pub history: Vec<(u64, u64)>,
+}
+
+thread_local! {
+    static TREASURY_STATE: RefCell<StableCell<TreasuryState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(TREASURY_STATE_MEMORY_ID), TreasuryState::default())
+            .expect("Failed to init treasury state cell"),
+    );
+
+    // Balance at the end of each day, keyed by the start of the day.
+    static TREASURY_HISTORY: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TREASURY_HISTORY_MEMORY_ID)));
+
+    static DISBURSEMENTS: RefCell<StableBTreeMap<u64, Disbursement, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DISBURSEMENTS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "treasury_state",
+            TREASURY_STATE.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "treasury_history",
+            TREASURY_HISTORY.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "disbursements",
+            DISBURSEMENTS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn state() -> TreasuryState {
+    TREASURY_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update(now: u64, f: impl FnOnce(&mut TreasuryState)) {
+    let mut state = state();
+    f(&mut state);
+    let balance = state.balance;
+    TREASURY_STATE.with(|s| {
+        s.borrow_mut()
+            .set(state)
+            .expect("Failed to persist treasury state");
+    });
+    TREASURY_HISTORY.with(|h| h.borrow_mut().insert(now - now % 86400, balance));
+}
+
+/// Books `amount` the pool keeps for itself.
+pub fn credit(source: InflowSource, amount: u64, now: u64) {
+    if amount == 0 {
+        return;
+    }
+    update(now, |s| {
+        s.balance += amount;
+        s.unswept += amount;
+        match source {
+            InflowSource::Fee => s.fees += amount,
+            InflowSource::Penalty => s.penalties += amount,
+            InflowSource::Dust => s.dust += amount,
+            InflowSource::Donation => s.donations += amount,
+        }
+    });
+}
+
+/// Takes `amount` off the balance for a payout.
+pub fn reserve(amount: u64, now: u64) -> Result<(), DepositError> {
+    if amount == 0 {
+        return Err(DepositError::InvalidArgument(
+            "amount must be positive".to_string(),
+        ));
+    }
+    if state().balance < amount {
+        return Err(DepositError::InsufficientBalance);
+    }
+    update(now, |s| {
+        s.balance -= amount;
+        s.disbursed += amount;
+    });
+    Ok(())
+}
+
+/// Returns a reserved amount whose payout failed to the balance.
+pub fn release(amount: u64, now: u64) {
+    update(now, |s| {
+        s.balance += amount;
+        s.disbursed -= amount;
+    });
+}
+
+/// Books a sweep of the unswept inflows, of which the ledger takes `fee`.
+/// Returns the amount to move, or `None` if it would not cover the fee.
+pub fn begin_sweep(fee: u64, now: u64) -> Option<u64> {
+    let state = state();
+    let amount = state.unswept;
+    if amount <= fee || state.balance < fee {
+        return None;
+    }
+    update(now, |s| {
+        s.unswept -= amount;
+        s.balance -= fee;
+        s.sweep_fees += fee;
+    });
+    Some(amount)
+}
+
+/// Undoes `begin_sweep` after a failed transfer.
+pub fn revert_sweep(amount: u64, fee: u64, now: u64) {
+    update(now, |s| {
+        s.unswept += amount;
+        s.balance += fee;
+        s.sweep_fees -= fee;
+    });
+}
+
+pub fn history(days: u64) -> Vec<(u64, u64)> {
+    TREASURY_HISTORY.with(|h| {
+        let h = h.borrow();
+        let skip = h.len().saturating_sub(days);
+        h.iter().skip(skip as usize).collect()
+    })
+}
+
+fn get_disbursement(id: u64) -> Result<Disbursement, DepositError> {
+    DISBURSEMENTS
+        .with(|m| m.borrow().get(&id))
+        .ok_or_else(|| DepositError::InvalidArgument(format!("unknown disbursement {}", id)))
+}
+
+fn put_disbursement(d: Disbursement) {
+    DISBURSEMENTS.with(|m| m.borrow_mut().insert(d.id, d));
+}
+
+pub fn propose(
+    proposer: Principal,
+    to: Account,
+    amount: u64,
+    purpose: String,
+    now: u64,
+) -> Result<u64, DepositError> {
+    if amount == 0 || purpose.is_empty() || purpose.len() > MAX_PURPOSE_LEN {
+        return Err(DepositError::InvalidArgument(format!(
+            "amount must be positive and purpose between 1 and {} bytes",
+            MAX_PURPOSE_LEN
+        )));
+    }
+    let id = DISBURSEMENTS.with(|m| m.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+    put_disbursement(Disbursement {
+        id,
+        to,
+        amount,
+        purpose,
+        proposed_at: now,
+        approvals: vec![proposer],
+        status: DisbursementStatus::Pending,
+    });
+    Ok(id)
+}
+
+/// Adds `approver` to a pending disbursement. Returns the disbursement once
+/// it has enough approvals to execute, marked as executing and with its
+/// amount taken off the balance.
+pub fn approve(
+    approver: Principal,
+    id: u64,
+    now: u64,
+) -> Result<Option<Disbursement>, DepositError> {
+    let mut d = get_disbursement(id)?;
+    if d.status != DisbursementStatus::Pending {
+        return Err(DepositError::InvalidArgument(
+            "disbursement is not pending".to_string(),
+        ));
+    }
+    if !d.approvals.contains(&approver) {
+        d.approvals.push(approver);
+    }
+    let required = config::get().treasury_approvals_required as usize;
+    if d.approvals.len() < required {
+        put_disbursement(d);
+        return Ok(None);
+    }
+    if state().balance < d.amount {
+        put_disbursement(d);
+        return Err(DepositError::InsufficientBalance);
+    }
+    update(now, |s| {
+        s.balance -= d.amount;
+        s.disbursed += d.amount;
+    });
+    d.status = DisbursementStatus::Executing;
+    put_disbursement(d.clone());
+    Ok(Some(d))
+}
+
+/// Records the transfer outcome of an approved disbursement, returning its
+/// amount to the balance if the transfer failed.
+pub fn settle(mut d: Disbursement, result: Result<Nat, String>, now: u64) -> Disbursement {
+    d.status = match result {
+        Ok(block_index) => {
+            events::record(
+                now,
+                EventKind::TreasuryDisbursed {
+                    disbursement_id: d.id,
+                    amount: d.amount,
+                },
+            );
+            DisbursementStatus::Executed { block_index }
+        }
+        Err(e) => {
+            release(d.amount, now);
+            DisbursementStatus::Failed(e)
+        }
+    };
+    put_disbursement(d.clone());
+    d
+}
+
+fn treasury_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(TREASURY_SUBACCOUNT),
+    }
+}
+
+// Sends `amount` out of a subaccount of the pool, of which `fee` goes to the
+// ledger, so the pool parts with exactly `amount`.
+async fn transfer(
+    from_subaccount: Option<[u8; 32]>,
+    to: Account,
+    amount: u64,
+    fee: u64,
+    memo: Option<Memo>,
+) -> Result<Nat, String> {
+    let transfer_arg = TransferArg {
+        to,
+        amount: (amount - fee).into(),
+        fee: Some(fee.into()),
+        memo,
+        created_at_time: None,
+        from_subaccount,
+    };
+    let (res,): (Result<Nat, TransferError>,) = circuit_breaker::observe(
+        call(ledger::ledger_id(), "icrc1_transfer", (transfer_arg,)).await,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    res.map_err(|e| format!("{:?}", e))
+}
+
+// Moves the unswept inflows to the treasury subaccount. Amounts too small to
+// cover the ledger fee are left for a later sweep.
+async fn sweep() -> Result<(), String> {
+    let fee = ledger::fee(ledger::ledger_id())
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let Some(amount) = begin_sweep(fee, crate::now_secs()) else {
+        return Ok(());
+    };
+    let result = transfer(None, treasury_account(), amount, fee, None).await;
+    if result.is_err() {
+        revert_sweep(amount, fee, crate::now_secs());
+    }
+    result.map(|_| ())
+}
+
+// Pays a reserved `amount` out of the treasury subaccount, net of the ledger
+// fee.
+async fn pay_out(to: Account, amount: u64, memo: Option<Memo>) -> Result<Nat, String> {
+    let fee = ledger::payout_fee(ledger::ledger_id(), amount)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    transfer(Some(TREASURY_SUBACCOUNT), to, amount, fee, memo).await
+}
+
+/// Pays `amount` out of the treasury to `to`, net of the ledger fee, and
+/// returns the ledger block. The amount stays in the treasury on failure.
+pub(crate) async fn pay(to: Account, amount: u64) -> Result<u64, DepositError> {
+    pay_with_memo(to, amount, None).await
+}
+
+/// Same as `pay`, tagging the ledger transfer with `memo`.
+pub(crate) async fn pay_with_memo(
+    to: Account,
+    amount: u64,
+    memo: Option<Memo>,
+) -> Result<u64, DepositError> {
+    sweep().await.map_err(DepositError::LedgerTransferFailed)?;
+    reserve(amount, crate::now_secs())?;
+    match pay_out(to, amount, memo).await {
+        Ok(block) => Ok(crate::block_index(block)),
+        Err(e) => {
+            release(amount, crate::now_secs());
+            Err(DepositError::LedgerTransferFailed(e))
+        }
+    }
+}
+
+/// Proposes paying `amount` from the treasury to `to`, tagged with `purpose`.
+/// The proposal counts as the proposer's approval. Only canister controllers
+/// may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the amount is zero or the purpose is
+///   empty or longer than 64 bytes.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn propose_disbursement(
+    to: Account,
+    amount: u64,
+    purpose: String,
+) -> Result<u64, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    propose(caller, to, amount, purpose, crate::now_secs())
+}
+
+/// Approves a pending disbursement and executes it once the configured
+/// number of controllers approved it. The recipient receives the amount net
+/// of the ledger fee. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the disbursement does not exist or is not pending.
+/// * `DepositError::InsufficientBalance`: If the treasury cannot cover it yet.
+/// * `DepositError::LedgerTransferFailed`: If the inflows could not be swept
+///   to the treasury subaccount.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn approve_disbursement(id: u64) -> Result<Disbursement, DepositError> {
+    let caller = ic_cdk::caller();
+    crate::ensure_controller(caller)?;
+    sweep().await.map_err(DepositError::LedgerTransferFailed)?;
+    match approve(caller, id, crate::now_secs())? {
+        Some(d) => {
+            let result = pay_out(d.to, d.amount, None).await;
+            Ok(settle(d, result, crate::now_secs()))
+        }
+        None => get_disbursement(id),
+    }
+}
+
+/// Cancels a pending disbursement. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the disbursement does not exist or is not pending.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn cancel_disbursement(id: u64) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let mut d = get_disbursement(id)?;
+    if d.status != DisbursementStatus::Pending {
+        return Err(DepositError::InvalidArgument(
+            "disbursement is not pending".to_string(),
+        ));
+    }
+    d.status = DisbursementStatus::Cancelled;
+    put_disbursement(d);
+    Ok(())
+}
+
+/// Pays `amount` out of the treasury to `to`, net of the ledger fee, without
+/// going through a disbursement proposal. Only canister controllers may call
+/// this.
+///
+/// # Returns
+///
+/// * The ledger block of the transfer.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the amount is zero.
+/// * `DepositError::InsufficientBalance`: If the treasury cannot cover it.
+/// * `DepositError::LedgerTransferFailed`: If the inflows could not be swept
+///   to the treasury subaccount or the transfer fails. The amount stays in
+///   the treasury.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn withdraw_treasury(to: Account, amount: u64) -> Result<u64, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    let result = pay(to, amount).await;
+    transactions::admin("withdraw_treasury", result)
+}
+
+/// Donates `amount` from the caller's default account to the treasury, under
+/// the pool's ICRC-2 allowance. The ledger fee is charged on top.
+///
+/// # Returns
+///
+/// * The ledger block of the transfer.
+///
+/// # Errors
+///
+/// * `DepositError::InvalidArgument`: If the amount is zero.
+/// * `DepositError::LedgerTransferFailed`: If the transfer from the caller fails.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn donate_to_treasury(amount: u64) -> Result<u64, DepositError> {
+    if amount == 0 {
+        return Err(DepositError::InvalidArgument(
+            "amount must be positive".to_string(),
+        ));
+    }
+    let caller = ic_cdk::caller();
+    let from = Account {
+        owner: caller,
+        subaccount: None,
+    };
+    let block = crate::pull_funds(ledger::ledger_id(), from, amount).await?;
+    let now = crate::now_secs();
+    credit(InflowSource::Donation, amount, now);
+    transactions::record(
+        now,
+        caller,
+        TransactionKind::Donation,
+        None,
+        amount,
+        Some(block),
+    );
+    Ok(block)
+}
+
+/// Returns the treasury balance, including inflows not yet swept to the
+/// treasury subaccount.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_treasury_balance() -> u64 {
+    state().balance
+}
+
+/// Returns treasury inflows by source and up to `days` (capped at 366) of
+/// end-of-day balances.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_treasury_report(days: u64) -> TreasuryReport {
+    TreasuryReport {
+        state: state(),
+        history: history(days.min(MAX_HISTORY_DAYS)),
+    }
+}
+
+/// Returns all disbursements, oldest first.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_disbursements() -> Vec<Disbursement> {
+    DISBURSEMENTS.with(|m| m.borrow().iter().map(|(_, d)| d).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disbursement_needs_approvals_and_balance() {
+        credit(InflowSource::Penalty, 300, 86400);
+        credit(InflowSource::Dust, 5, 2 * 86400 + 10);
+        assert_eq!(state().balance, 305);
+        assert_eq!(history(10), vec![(86400, 300), (2 * 86400, 305)]);
+
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let to = Account {
+            owner: Principal::from_slice(&[3]),
+            subaccount: None,
+        };
+        let id = propose(alice, to, 200, "audit".to_string(), 0).unwrap();
+        assert_eq!(approve(alice, id, 0), Ok(None));
+
+        let approved = approve(bob, id, 3 * 86400).unwrap().unwrap();
+        assert_eq!(state().balance, 105);
+        // A retry while the transfer is in flight does not pay it again.
+        assert_eq!(
+            get_disbursement(id).unwrap().status,
+            DisbursementStatus::Executing
+        );
+        assert!(approve(alice, id, 3 * 86400).is_err());
+        assert_eq!((state().balance, state().disbursed), (105, 200));
+        let failed = settle(approved, Err("rejected".to_string()), 3 * 86400);
+        assert!(matches!(failed.status, DisbursementStatus::Failed(_)));
+        assert_eq!(state().balance, 305);
+        assert_eq!(state().disbursed, 0);
+        assert!(approve(bob, id, 3 * 86400).is_err());
+    }
+
+    #[test]
+    fn test_sweep_books_the_ledger_fee() {
+        credit(InflowSource::Donation, 8, 0);
+        assert_eq!(begin_sweep(10, 0), None);
+        credit(InflowSource::Penalty, 100, 0);
+        assert_eq!(begin_sweep(10, 0), Some(108));
+        assert_eq!((state().balance, state().unswept), (98, 0));
+
+        revert_sweep(108, 10, 0);
+        assert_eq!((state().balance, state().unswept), (108, 108));
+        assert_eq!(state().sweep_fees, 0);
+        assert_eq!(reserve(109, 0), Err(DepositError::InsufficientBalance));
+        reserve(108, 0).unwrap();
+        assert_eq!(state().balance, 0);
+    }
+}
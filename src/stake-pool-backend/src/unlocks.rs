@@ -0,0 +1,174 @@
+// src/unlocks.rs
+//! Periodic scan for deposits whose lock expired. Each is marked matured
+//! once and its owner gets a maturity notification through the event log,
+//! so frontends can show it as ready to withdraw without polling deposits.
+use crate::memory::{get_memory, Memory, MATURED_DEPOSITS_MEMORY_ID};
+use crate::notifications::{self, Notification};
+use crate::state_hash;
+use crate::{DepositKey, UserKey, DEPOSITS};
+use candid::{CandidType, Deserialize};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const SCAN_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Matured {
+    key: UserKey,
+    matured_at: u64,
+}
+
+impl Storable for Matured {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Matured"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Matured")
+    }
+}
+
+impl BoundedStorable for Matured {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaturedDeposit {
+    pub subaccount: Subaccount,
+    pub deposit_id: u64,
+    /// When the scan first saw the deposit unlocked.
+    pub matured_at: u64,
+}
+
+thread_local! {
+    // Keyed by deposit id. Entries of withdrawn or relocked deposits are
+    // dropped by the next scan.
+    static MATURED: RefCell<StableBTreeMap<u64, Matured, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(MATURED_DEPOSITS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "matured_deposits",
+        MATURED.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+/// Marks deposits that unlocked since the last scan as matured and notifies
+/// their owners. Returns the ids newly marked.
+pub fn scan(now: u64) -> Vec<u64> {
+    let unlocked: BTreeMap<u64, UserKey> = DEPOSITS.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, d)| now >= d.unlock_time())
+            .map(|(key, d)| (d.id, key.user))
+            .collect()
+    });
+
+    let mut marked = Vec::new();
+    MATURED.with(|m| {
+        let mut m = m.borrow_mut();
+        let stale: Vec<u64> = m
+            .iter()
+            .filter(|(id, _)| !unlocked.contains_key(id))
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            m.remove(&id);
+        }
+        for (id, key) in &unlocked {
+            if !m.contains_key(id) {
+                m.insert(
+                    *id,
+                    Matured {
+                        key: key.clone(),
+                        matured_at: now,
+                    },
+                );
+                marked.push(*id);
+            }
+        }
+    });
+
+    for id in &marked {
+        notifications::dispatch(
+            unlocked[id].principal,
+            Notification::Maturity { deposit_id: *id },
+            now,
+        );
+    }
+    marked
+}
+
+/// Starts the hourly unlock scan. Must be called from `init` and
+/// `post_upgrade`, since timers do not survive upgrades.
+pub fn start_scans() {
+    ic_cdk_timers::set_timer_interval(SCAN_INTERVAL, || {
+        scan(crate::now_secs());
+    });
+}
+
+/// Returns the caller's deposits the unlock scan found ready to withdraw.
+/// Deposits unlocked since the last scan show up within an hour.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_matured_deposits() -> Vec<MaturedDeposit> {
+    let caller = ic_cdk::caller();
+    let deposit_ids: Vec<u64> = DEPOSITS.with(|m| {
+        m.borrow()
+            .range(DepositKey::range_of_principal(caller))
+            .map(|(key, _)| key.deposit_id)
+            .collect()
+    });
+    MATURED.with(|m| {
+        let m = m.borrow();
+        deposit_ids
+            .into_iter()
+            .filter_map(|deposit_id| {
+                m.get(&deposit_id).map(|matured| MaturedDeposit {
+                    subaccount: matured.key.subaccount,
+                    deposit_id,
+                    matured_at: matured.matured_at,
+                })
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_scan_marks_each_maturity_once() {
+        let principal = Principal::from_slice(&[61]);
+        let subaccount = Subaccount([61u8; 32]);
+        let day = 86400;
+        let short = crate::deposit_internal(principal, subaccount, 90, 100, 0).unwrap();
+        let long = crate::deposit_internal(principal, subaccount, 180, 100, 0).unwrap();
+
+        assert!(scan(89 * day).is_empty());
+        assert_eq!(scan(90 * day), vec![short.id]);
+        assert!(scan(91 * day).is_empty());
+
+        // Relocking starts a new lock, which matures again later.
+        crate::locks::relock_internal(principal, short.id, 90, 91 * day).unwrap();
+        assert_eq!(scan(180 * day), vec![long.id]);
+        assert_eq!(scan(181 * day), vec![short.id]);
+
+        let notified = crate::events::range(0, crate::events::len())
+            .into_iter()
+            .filter(|e| matches!(e.kind, crate::events::EventKind::Notified { .. }))
+            .count();
+        assert_eq!(notified, 3);
+    }
+}
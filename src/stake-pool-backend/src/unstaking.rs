@@ -0,0 +1,245 @@
+// src/unstaking.rs
+use crate::error::DepositError;
+use crate::events::{self, EventKind};
+use crate::governance::{self, Command, CommandResponse, Operation};
+use crate::memory::{get_memory, Memory, DISSOLVING_NEURONS_MEMORY_ID};
+use crate::neurons::{self, PoolNeuron, MIN_NEURON_STAKE_E8S};
+use crate::state_hash;
+use crate::{ledger, withdrawal_queue};
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::call;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const LIQUIDITY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A neuron split off a pool neuron and dissolving to fund queued withdrawals.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DissolvingNeuron {
+    pub neuron_id: u64,
+    pub parent_id: u64,
+    pub amount: u64,
+    pub dissolves_at: u64,
+}
+
+impl Storable for DissolvingNeuron {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode DissolvingNeuron"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode DissolvingNeuron")
+    }
+}
+
+impl BoundedStorable for DissolvingNeuron {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DISSOLVING_NEURONS: RefCell<StableBTreeMap<u64, DissolvingNeuron, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DISSOLVING_NEURONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![(
+        "dissolving_neurons",
+        DISSOLVING_NEURONS.with(|s| state_hash::map_digest(&s.borrow())),
+    )]
+}
+
+pub fn dissolving() -> Vec<DissolvingNeuron> {
+    DISSOLVING_NEURONS.with(|m| m.borrow().iter().map(|(_, n)| n).collect())
+}
+
+/// Amount queued withdrawals need beyond what is liquid or already dissolving.
+pub fn shortfall(outstanding: u64, liquid: u64, dissolving: u64) -> u64 {
+    outstanding.saturating_sub(liquid.saturating_add(dissolving))
+}
+
+/// Picks the neuron to split `amount` off: among neurons that keep the minimum
+/// stake after the split, the one with the shortest tier, so liquidity arrives
+/// soonest, and the smallest stake among those.
+pub fn plan_split(neurons: Vec<PoolNeuron>, amount: u64) -> Option<PoolNeuron> {
+    if amount < MIN_NEURON_STAKE_E8S {
+        return None;
+    }
+    neurons
+        .into_iter()
+        .filter(|n| n.lock_days.is_some())
+        .filter(|n| n.staked >= amount.saturating_add(MIN_NEURON_STAKE_E8S))
+        .min_by_key(|n| (n.lock_days, n.staked))
+}
+
+pub(crate) async fn liquid_balance() -> Result<u64, DepositError> {
+    let pool_account = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let (balance,): (Nat,) = call(ledger::ledger_id(), "icrc1_balance_of", (pool_account,))
+        .await
+        .map_err(|e| DepositError::LedgerTransferFailed(format!("{:?}", e)))?;
+    Ok(u64::try_from(balance.0).unwrap_or(u64::MAX))
+}
+
+async fn split_and_dissolve(
+    parent: &PoolNeuron,
+    amount: u64,
+    now: u64,
+) -> Result<(), DepositError> {
+    let created =
+        match governance::manage_neuron(parent.neuron_id, Command::Split { amount_e8s: amount })
+            .await?
+        {
+            CommandResponse::Split {
+                created_neuron_id: Some(id),
+            } => id.id,
+            _ => {
+                return Err(DepositError::GovernanceCallFailed(
+                    "split returned no neuron".to_string(),
+                ))
+            }
+        };
+    neurons::remove_staked(parent.neuron_id, amount);
+    let lock_secs = parent.lock_days.unwrap_or(0) as u64 * 86400;
+    DISSOLVING_NEURONS.with(|m| {
+        m.borrow_mut().insert(
+            created,
+            DissolvingNeuron {
+                neuron_id: created,
+                parent_id: parent.neuron_id,
+                amount,
+                dissolves_at: now + lock_secs,
+            },
+        )
+    });
+    events::record(
+        now,
+        EventKind::NeuronSplit {
+            parent_id: parent.neuron_id,
+            neuron_id: created,
+            amount,
+        },
+    );
+
+    let command = Command::Configure {
+        operation: Some(Operation::StartDissolving {}),
+    };
+    governance::manage_neuron(created, command).await?;
+    Ok(())
+}
+
+/// Disburses dissolved neurons and pays queued withdrawals from the proceeds.
+async fn disburse_dissolved(now: u64) {
+    let due: Vec<DissolvingNeuron> = dissolving()
+        .into_iter()
+        .filter(|n| n.dissolves_at <= now)
+        .collect();
+    for neuron in due {
+        match governance::manage_neuron(neuron.neuron_id, Command::Disburse {}).await {
+            Ok(_) => {
+                DISSOLVING_NEURONS.with(|m| m.borrow_mut().remove(&neuron.neuron_id));
+                if let Err(e) = withdrawal_queue::process(neuron.amount).await {
+                    ic_cdk::println!("processing withdrawal queue failed: {:?}", e);
+                }
+            }
+            Err(e) => ic_cdk::println!("disbursing neuron {} failed: {:?}", neuron.neuron_id, e),
+        }
+    }
+}
+
+async fn run_liquidity_check() {
+    let now = crate::now_secs();
+    disburse_dissolved(now).await;
+
+    let outstanding = withdrawal_queue::outstanding_total();
+    if outstanding == 0 {
+        return;
+    }
+    let liquid = match liquid_balance().await {
+        Ok(balance) => balance,
+        Err(e) => {
+            ic_cdk::println!("reading pool balance failed: {:?}", e);
+            return;
+        }
+    };
+    let in_flight: u64 = dissolving().iter().map(|n| n.amount).sum();
+    let needed = shortfall(outstanding, liquid, in_flight);
+    if needed == 0 {
+        // Liquid funds cover the queue.
+        if let Err(e) = withdrawal_queue::process(liquid).await {
+            ic_cdk::println!("processing withdrawal queue failed: {:?}", e);
+        }
+        return;
+    }
+    let amount = needed.max(MIN_NEURON_STAKE_E8S);
+    let Some(parent) = plan_split(neurons::all(), amount) else {
+        ic_cdk::println!("no pool neuron large enough to split off {}", amount);
+        return;
+    };
+    if let Err(e) = split_and_dissolve(&parent, amount, now).await {
+        ic_cdk::println!("splitting neuron {} failed: {:?}", parent.neuron_id, e);
+    }
+}
+
+/// Starts the periodic withdrawal liquidity check. Must be called from `init`
+/// and `post_upgrade`, since timers do not survive upgrades.
+pub fn start_liquidity_checks() {
+    ic_cdk_timers::set_timer_interval(LIQUIDITY_CHECK_INTERVAL, || {
+        ic_cdk::spawn(run_liquidity_check())
+    });
+}
+
+/// Returns the neurons dissolving to fund queued withdrawals.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_dissolving_neurons() -> Vec<DissolvingNeuron> {
+    dissolving()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neuron(neuron_id: u64, lock_days: Option<u16>, staked: u64) -> PoolNeuron {
+        PoolNeuron {
+            neuron_id,
+            registered_at: 0,
+            lock_days,
+            account: vec![0u8; 32],
+            staked,
+        }
+    }
+
+    #[test]
+    fn test_split_prefers_shortest_tier_that_fits() {
+        let e8s = 100_000_000;
+        assert_eq!(shortfall(10 * e8s, 4 * e8s, 3 * e8s), 3 * e8s);
+        assert_eq!(shortfall(5 * e8s, 4 * e8s, 3 * e8s), 0);
+
+        let pool = vec![
+            neuron(1, Some(180), 100 * e8s),
+            neuron(2, Some(90), 3 * e8s),
+            neuron(3, Some(90), 50 * e8s),
+            neuron(4, None, 1_000 * e8s),
+        ];
+        assert_eq!(plan_split(pool.clone(), e8s).map(|n| n.neuron_id), Some(2));
+        // Neuron 2 would drop below the minimum stake.
+        assert_eq!(
+            plan_split(pool.clone(), 3 * e8s).map(|n| n.neuron_id),
+            Some(3)
+        );
+        assert_eq!(
+            plan_split(pool.clone(), 60 * e8s).map(|n| n.neuron_id),
+            Some(1)
+        );
+        assert_eq!(plan_split(pool, 500 * e8s).map(|n| n.neuron_id), None);
+    }
+}
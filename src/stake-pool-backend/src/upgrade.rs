@@ -0,0 +1,144 @@
+// src/upgrade.rs
+use crate::circuit_breaker::{self, BreakerRuntime};
+use crate::cycles::{self, CyclesRuntime};
+use crate::events::{self, EventKind};
+use crate::memory::{get_memory, Memory, UPGRADE_STATE_MEMORY_ID};
+use crate::memory_guard;
+use crate::tokens;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{storable::Storable, StableCell};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Heap state carried over an upgrade. Caches that are rebuilt on their own
+/// (stats, state hash digests) and the per-module instruction metrics are
+/// left out.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct RuntimeState {
+    pub circuit_breaker: BreakerRuntime,
+    pub memory_alert_raised: bool,
+    /// `None` when saved by a release that did not carry it over.
+    pub cycles: Option<CyclesRuntime>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct SavedState(Option<RuntimeState>);
+
+impl Storable for SavedState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode RuntimeState"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode RuntimeState")
+    }
+}
+
+thread_local! {
+    // Only holds a value between `pre_upgrade` and `post_upgrade`.
+    static UPGRADE_STATE: RefCell<StableCell<SavedState, Memory>> = RefCell::new(
+        StableCell::init(get_memory(UPGRADE_STATE_MEMORY_ID), SavedState::default())
+            .expect("Failed to init upgrade state cell"),
+    );
+}
+
+fn set_saved(state: SavedState) {
+    UPGRADE_STATE.with(|c| {
+        c.borrow_mut()
+            .set(state)
+            .expect("Failed to persist upgrade state");
+    });
+}
+
+pub fn runtime_state() -> RuntimeState {
+    RuntimeState {
+        circuit_breaker: circuit_breaker::runtime_state(),
+        memory_alert_raised: memory_guard::alert_raised(),
+        cycles: Some(cycles::runtime_state()),
+    }
+}
+
+pub fn save_runtime_state() {
+    set_saved(SavedState(Some(runtime_state())));
+}
+
+/// Restores the state saved by the previous `pre_upgrade`, if any.
+pub fn restore_runtime_state() {
+    let Some(state) = UPGRADE_STATE.with(|c| c.borrow().get().0.clone()) else {
+        return;
+    };
+    circuit_breaker::restore_runtime_state(state.circuit_breaker);
+    memory_guard::restore_alert_raised(state.memory_alert_raised);
+    if let Some(state) = state.cycles {
+        cycles::restore_runtime_state(state);
+    }
+    set_saved(SavedState::default());
+}
+
+/// Releases built before the counter was stable lost it on every upgrade.
+/// Moves it past every deposit still held and every deposit id the event log
+/// has seen, so ids stay unique. Those releases kept no event log, so the
+/// held deposits must already be out of the legacy lists when this runs.
+pub fn recover_deposit_id_counter() {
+    if crate::deposit_id_counter() != 0 {
+        return;
+    }
+    let held = crate::DEPOSITS.with(|m| m.borrow().iter().map(|(_, d)| d.id).max());
+    let logged = (0..events::len())
+        .filter_map(events::get)
+        .filter_map(|e| match e.kind {
+            EventKind::Deposited { deposit_id, .. } => Some(deposit_id),
+            _ => None,
+        })
+        .max();
+    let max_id = held
+        .into_iter()
+        .chain(tokens::max_deposit_id())
+        .chain(logged)
+        .max();
+    if let Some(max_id) = max_id {
+        crate::set_deposit_id_counter(max_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_upgrade_round_trip() {
+        circuit_breaker::record_ledger_call(false, 10);
+        circuit_breaker::record_ledger_call(true, 11);
+        memory_guard::restore_alert_raised(true);
+        cycles::restore_runtime_state(CyclesRuntime {
+            low_cycles_alerted: true,
+            last_top_up: Some(5),
+            pending_top_up: None,
+        });
+        let before = runtime_state();
+        save_runtime_state();
+
+        // An upgrade starts with fresh heap state.
+        circuit_breaker::restore_runtime_state(BreakerRuntime::default());
+        memory_guard::restore_alert_raised(false);
+        cycles::restore_runtime_state(CyclesRuntime::default());
+        restore_runtime_state();
+        assert_eq!(runtime_state(), before);
+        assert_eq!(
+            UPGRADE_STATE.with(|c| c.borrow().get().clone()),
+            SavedState(None)
+        );
+
+        let deposit = |i| {
+            crate::deposit_internal(Principal::anonymous(), Subaccount([i; 32]), 90, 10, 0).unwrap()
+        };
+        deposit(1);
+        let last = deposit(2);
+        crate::set_deposit_id_counter(0);
+        recover_deposit_id_counter();
+        let next = deposit(3);
+        assert_eq!(next.id, last.id + 1);
+    }
+}
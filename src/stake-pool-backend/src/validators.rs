@@ -0,0 +1,314 @@
+// src/validators.rs
+//! Registry of validators stakers can delegate their deposits to. The pool
+//! keeps the per-validator totals so stake can later be routed to neurons or
+//! node providers in proportion.
+use crate::error::DepositError;
+use crate::locks;
+use crate::memory::{get_memory, Memory, DELEGATIONS_MEMORY_ID, VALIDATORS_MEMORY_ID};
+use crate::state_hash;
+use crate::transactions;
+use crate::{PrincipalKey, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+pub const MAX_VALIDATOR_NAME_LEN: usize = 64;
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Validator {
+    pub principal: Principal,
+    pub name: String,
+    pub registered_at: u64,
+}
+
+impl Storable for Validator {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Validator"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Validator")
+    }
+}
+
+impl BoundedStorable for Validator {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Delegation {
+    validator: Principal,
+    key: UserKey,
+}
+
+impl Storable for Delegation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode Delegation"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode Delegation")
+    }
+}
+
+impl BoundedStorable for Delegation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ValidatorStats {
+    pub validator: Principal,
+    pub name: String,
+    pub delegated_amount: u64,
+    pub deposit_count: u64,
+    /// Share of all delegated stake.
+    pub share_bps: u64,
+}
+
+thread_local! {
+    static VALIDATORS: RefCell<StableBTreeMap<PrincipalKey, Validator, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(VALIDATORS_MEMORY_ID)));
+
+    // Keyed by deposit id. A withdrawn deposit simply no longer counts.
+    static DELEGATIONS: RefCell<StableBTreeMap<u64, Delegation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DELEGATIONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "validators",
+            VALIDATORS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "delegations",
+            DELEGATIONS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+pub fn get(principal: Principal) -> Option<Validator> {
+    VALIDATORS.with(|m| m.borrow().get(&PrincipalKey(principal)))
+}
+
+pub fn register(principal: Principal, name: String, now: u64) -> Result<(), DepositError> {
+    if name.is_empty() || name.len() > MAX_VALIDATOR_NAME_LEN {
+        return Err(DepositError::InvalidArgument(format!(
+            "validator name must be 1 to {} bytes",
+            MAX_VALIDATOR_NAME_LEN
+        )));
+    }
+    let validator = Validator {
+        principal,
+        name,
+        registered_at: get(principal).map_or(now, |v| v.registered_at),
+    };
+    VALIDATORS.with(|m| m.borrow_mut().insert(PrincipalKey(principal), validator));
+    Ok(())
+}
+
+/// Removes a validator nothing is delegated to any more.
+pub fn unregister(principal: Principal) -> Result<(), DepositError> {
+    if get(principal).is_none() {
+        return Err(unknown_validator(principal));
+    }
+    if stats().iter().any(|s| s.validator == principal) {
+        return Err(DepositError::InvalidArgument(
+            "stake is still delegated to this validator".to_string(),
+        ));
+    }
+    VALIDATORS.with(|m| m.borrow_mut().remove(&PrincipalKey(principal)));
+    Ok(())
+}
+
+fn unknown_validator(principal: Principal) -> DepositError {
+    DepositError::InvalidArgument(format!("unknown validator {}", principal))
+}
+
+/// Tags a deposit of `key` with `validator`, or clears its tag when `None`.
+pub fn delegate(
+    key: UserKey,
+    deposit_id: u64,
+    validator: Option<Principal>,
+) -> Result<(), DepositError> {
+    crate::find_deposit(&key, deposit_id)?;
+    match validator {
+        Some(validator) => {
+            if get(validator).is_none() {
+                return Err(unknown_validator(validator));
+            }
+            DELEGATIONS.with(|m| {
+                m.borrow_mut()
+                    .insert(deposit_id, Delegation { validator, key })
+            });
+        }
+        None => {
+            DELEGATIONS.with(|m| m.borrow_mut().remove(&deposit_id));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    DELEGATIONS.with(|m| {
+        let mut m = m.borrow_mut();
+        let moved: Vec<(u64, Delegation)> =
+            m.iter().filter(|(_, d)| d.key.principal == from).collect();
+        for (deposit_id, mut delegation) in moved {
+            delegation.key.principal = to;
+            m.insert(deposit_id, delegation);
+        }
+    });
+}
+
+/// Keeps the delegation of a deposit handed to `key`.
+pub fn rekey_deposit(deposit_id: u64, key: UserKey) {
+    DELEGATIONS.with(|m| {
+        let mut m = m.borrow_mut();
+        if let Some(mut delegation) = m.get(&deposit_id) {
+            delegation.key = key;
+            m.insert(deposit_id, delegation);
+        }
+    });
+}
+
+/// Validator a deposit is delegated to, if any.
+pub fn delegation_of(deposit_id: u64) -> Option<Principal> {
+    DELEGATIONS.with(|m| m.borrow().get(&deposit_id).map(|d| d.validator))
+}
+
+/// Totals of the deposits delegated to each validator that has any.
+pub fn stats() -> Vec<ValidatorStats> {
+    let mut totals: BTreeMap<Principal, (u64, u64)> = BTreeMap::new();
+    DELEGATIONS.with(|m| {
+        for (deposit_id, delegation) in m.borrow().iter() {
+            if let Ok(deposit) = crate::find_deposit(&delegation.key, deposit_id) {
+                let total = totals.entry(delegation.validator).or_default();
+                total.0 += deposit.amount;
+                total.1 += 1;
+            }
+        }
+    });
+    let delegated: u128 = totals.values().map(|(amount, _)| *amount as u128).sum();
+    VALIDATORS.with(|m| {
+        m.borrow()
+            .iter()
+            .map(|(_, v)| v)
+            .filter_map(|v| {
+                let (amount, count) = totals.get(&v.principal).copied()?;
+                Some(ValidatorStats {
+                    validator: v.principal,
+                    name: v.name,
+                    delegated_amount: amount,
+                    deposit_count: count,
+                    share_bps: (amount as u128 * BPS_DENOMINATOR / delegated) as u64,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Registers a validator, or renames one. Only canister controllers may call
+/// this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the name is empty or too long.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn register_validator(principal: Principal, name: String) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    transactions::admin(
+        "register_validator",
+        register(principal, name, crate::now_secs()),
+    )
+}
+
+/// Removes a validator. Only canister controllers may call this.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::InvalidArgument`: If the validator is unknown or still has stake delegated.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn remove_validator(principal: Principal) -> Result<(), DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    transactions::admin("remove_validator", unregister(principal))
+}
+
+/// Delegates one of the caller's deposits to a registered validator, or
+/// clears the delegation when `validator` is `None`. The deposit itself is
+/// not affected.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the caller holds no deposit with this ID.
+/// * `DepositError::InvalidArgument`: If the validator is not registered.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn delegate_deposit(deposit_id: u64, validator: Option<Principal>) -> Result<(), DepositError> {
+    let key = locks::owner_key(ic_cdk::caller(), deposit_id).ok_or(DepositError::NoDepositFound)?;
+    delegate(key, deposit_id, validator)
+}
+
+/// Returns the registered validators.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_validators() -> Vec<Validator> {
+    VALIDATORS.with(|m| m.borrow().iter().map(|(_, v)| v).collect())
+}
+
+/// Returns the stake delegated to each validator that has any.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_validator_stats() -> Vec<ValidatorStats> {
+    stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Subaccount;
+
+    #[test]
+    fn test_delegated_totals() {
+        let alice = Principal::from_slice(&[21]);
+        let subaccount = Subaccount([21u8; 32]);
+        let key = UserKey {
+            principal: alice,
+            subaccount,
+        };
+        let (v1, v2) = (
+            Principal::from_slice(&[1, 1]),
+            Principal::from_slice(&[2, 2]),
+        );
+        register(v1, "one".to_string(), 0).unwrap();
+        register(v2, "two".to_string(), 0).unwrap();
+        let d1 = crate::deposit_internal(alice, subaccount, 90, 300, 0).unwrap();
+        let d2 = crate::deposit_internal(alice, subaccount, 90, 100, 0).unwrap();
+
+        assert!(delegate(key.clone(), d1.id, Some(alice)).is_err());
+        delegate(key.clone(), d1.id, Some(v1)).unwrap();
+        delegate(key.clone(), d2.id, Some(v2)).unwrap();
+        let shares: Vec<(u64, u64)> = stats()
+            .iter()
+            .map(|s| (s.delegated_amount, s.share_bps))
+            .collect();
+        assert_eq!(shares, vec![(300, 7_500), (100, 2_500)]);
+        assert!(unregister(v2).is_err());
+
+        crate::withdraw_internal(alice, subaccount, d2.id, 90 * 86400).unwrap();
+        assert_eq!(stats().len(), 1);
+        assert_eq!(unregister(v2), Ok(()));
+    }
+}
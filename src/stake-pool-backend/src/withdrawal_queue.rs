@@ -0,0 +1,655 @@
+// src/withdrawal_queue.rs
+use crate::config;
+use crate::error::DepositError;
+use crate::maintenance::{self, Operation};
+use crate::memory::{
+    get_memory, Memory, WITHDRAWAL_DESTINATIONS_MEMORY_ID, WITHDRAWAL_OUTFLOW_MEMORY_ID,
+    WITHDRAWAL_QUEUE_MEMORY_ID,
+};
+use crate::state_hash;
+use crate::{status, UserKey};
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::{
+    storable::{BoundedStorable, Storable},
+    StableBTreeMap, StableCell,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// How often queued withdrawals are paid out under the hourly outflow limit.
+const OUTFLOW_PROCESS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Order in which queued withdrawals are served when the pool processes the queue.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Oldest request first. Processing stops at the first request that does
+    /// not fit in the available liquidity.
+    #[default]
+    Fifo,
+    /// Smallest outstanding amount first, ties broken by age.
+    SmallestFirst,
+    /// Every open request receives the same fraction of its outstanding
+    /// amount when liquidity does not cover them all.
+    ProRata,
+}
+
+/// What a queued withdrawal pays out.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum WithdrawalSource {
+    Deposit {
+        deposit_id: u64,
+    },
+    /// Redemption of burned liquid staking tokens.
+    Redemption {
+        st_amount: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawalRequest {
+    pub id: u64,
+    pub key: UserKey,
+    pub source: WithdrawalSource,
+    pub amount: u64,
+    pub filled: u64,
+    pub requested_at: u64,
+}
+
+impl WithdrawalRequest {
+    pub fn outstanding(&self) -> u64 {
+        self.amount - self.filled
+    }
+}
+
+impl Storable for WithdrawalRequest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode WithdrawalRequest"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode WithdrawalRequest")
+    }
+}
+
+impl BoundedStorable for WithdrawalRequest {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WithdrawalState {
+    Queued,
+    PartiallyFilled,
+    Filled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawalRequestStatus {
+    pub request: WithdrawalRequest,
+    pub state: WithdrawalState,
+    /// Policy the queue is currently processed with.
+    pub policy: QueuePolicy,
+    /// Place in the processing order under `policy`, `None` once filled.
+    /// Under `ProRata` every open request shares position 0.
+    pub position: Option<u64>,
+    /// Account fills are paid to. `None` pays the request's own account.
+    pub destination: Option<Account>,
+}
+
+/// Result of `withdraw_funds`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum WithdrawalOutcome {
+    /// Paid out right away, net of the ledger fee.
+    Paid { amount: u64 },
+    /// Held back by the hourly outflow limit and queued.
+    Queued { request_id: u64 },
+}
+
+/// Amount paid out during the hour starting at `hour * 3600`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+struct OutflowWindow {
+    hour: u64,
+    amount: u64,
+}
+
+impl Storable for OutflowWindow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("Failed to encode OutflowWindow"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("Failed to decode OutflowWindow")
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct WithdrawalFill {
+    pub request_id: u64,
+    pub amount: u64,
+}
+
+// Account a queued withdrawal is paid to instead of its own. Kept apart from
+// `WithdrawalRequest`, which has no room for it within its size bound.
+#[derive(Clone, Debug, PartialEq)]
+struct Destination(Account);
+
+impl Storable for Destination {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self.0).expect("Failed to encode Destination"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Destination(candid::decode_one(&bytes).expect("Failed to decode Destination"))
+    }
+}
+
+impl BoundedStorable for Destination {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static WITHDRAWAL_QUEUE: RefCell<StableBTreeMap<u64, WithdrawalRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WITHDRAWAL_QUEUE_MEMORY_ID)));
+
+    static OUTFLOW: RefCell<StableCell<OutflowWindow, Memory>> = RefCell::new(
+        StableCell::init(get_memory(WITHDRAWAL_OUTFLOW_MEMORY_ID), OutflowWindow::default())
+            .expect("Failed to init outflow cell"),
+    );
+
+    // Keyed by request id.
+    static DESTINATIONS: RefCell<StableBTreeMap<u64, Destination, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(WITHDRAWAL_DESTINATIONS_MEMORY_ID)));
+}
+
+pub(crate) fn state_digests() -> Vec<(&'static str, [u8; 32])> {
+    vec![
+        (
+            "withdrawal_queue",
+            WITHDRAWAL_QUEUE.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+        (
+            "withdrawal_outflow",
+            OUTFLOW.with(|s| state_hash::cell_digest(&s.borrow())),
+        ),
+        (
+            "withdrawal_destinations",
+            DESTINATIONS.with(|s| state_hash::map_digest(&s.borrow())),
+        ),
+    ]
+}
+
+fn destination_of(request_id: u64) -> Option<Account> {
+    DESTINATIONS.with(|m| m.borrow().get(&request_id).map(|d| d.0))
+}
+
+fn outflow_this_hour(now: u64) -> u64 {
+    let window = OUTFLOW.with(|o| o.borrow().get().clone());
+    if window.hour == now / 3600 {
+        window.amount
+    } else {
+        0
+    }
+}
+
+fn set_outflow(hour: u64, amount: u64) {
+    OUTFLOW.with(|o| {
+        o.borrow_mut()
+            .set(OutflowWindow { hour, amount })
+            .expect("Failed to persist outflow");
+    });
+}
+
+/// Counts `amount` towards the outflow of the current hour.
+pub fn record_outflow(amount: u64, now: u64) {
+    set_outflow(now / 3600, outflow_this_hour(now).saturating_add(amount));
+}
+
+// Takes back outflow booked at `booked_at` for a transfer that did not go
+// through. Nothing to do once that hour has passed.
+fn release_outflow(amount: u64, booked_at: u64) {
+    let window = OUTFLOW.with(|o| o.borrow().get().clone());
+    if window.hour == booked_at / 3600 {
+        set_outflow(window.hour, window.amount.saturating_sub(amount));
+    }
+}
+
+/// Whether a withdrawal of `amount` has to wait in the queue under the hourly
+/// outflow limit. While anything is queued, new withdrawals line up behind it.
+pub fn must_queue(amount: u64, now: u64) -> bool {
+    config::get().max_hourly_outflow.is_some_and(|limit| {
+        !open_requests().is_empty() || outflow_this_hour(now).saturating_add(amount) > limit
+    })
+}
+
+/// Amount the queue may pay out with `used` already paid this hour. A request
+/// larger than the whole limit goes through alone, in an hour without other
+/// outflow.
+fn hourly_allowance(limit: u64, used: u64, next_request: u64) -> u64 {
+    if used == 0 {
+        limit.max(next_request)
+    } else {
+        limit.saturating_sub(used)
+    }
+}
+
+pub fn enqueue(
+    key: UserKey,
+    source: WithdrawalSource,
+    amount: u64,
+    now: u64,
+    destination: Option<Account>,
+) -> u64 {
+    WITHDRAWAL_QUEUE.with(|q| {
+        let mut q = q.borrow_mut();
+        let id = q.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        q.insert(
+            id,
+            WithdrawalRequest {
+                id,
+                key,
+                source,
+                amount,
+                filled: 0,
+                requested_at: now,
+            },
+        );
+        if let Some(destination) = destination {
+            DESTINATIONS.with(|m| m.borrow_mut().insert(id, Destination(destination)));
+        }
+        id
+    })
+}
+
+pub(crate) fn rekey_principal(from: Principal, to: Principal) {
+    WITHDRAWAL_QUEUE.with(|q| {
+        let mut q = q.borrow_mut();
+        let moved: Vec<WithdrawalRequest> = q
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.key.principal == from)
+            .collect();
+        for mut request in moved {
+            request.key.principal = to;
+            q.insert(request.id, request);
+        }
+    });
+}
+
+/// Total amount still owed to queued withdrawals.
+pub fn outstanding_total() -> u64 {
+    open_requests().iter().map(|r| r.outstanding()).sum()
+}
+
+/// Requests of `key`, oldest first.
+pub fn requests_of(key: &UserKey) -> Vec<WithdrawalRequest> {
+    WITHDRAWAL_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.key == *key)
+            .collect()
+    })
+}
+
+fn open_requests() -> Vec<WithdrawalRequest> {
+    WITHDRAWAL_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.outstanding() > 0)
+            .collect()
+    })
+}
+
+/// Sorts open requests into the order `policy` serves them in.
+fn processing_order(
+    policy: QueuePolicy,
+    mut open: Vec<WithdrawalRequest>,
+) -> Vec<WithdrawalRequest> {
+    if policy == QueuePolicy::SmallestFirst {
+        open.sort_by_key(|r| (r.outstanding(), r.id));
+    }
+    open
+}
+
+/// Splits `liquidity` across the open requests according to `policy`.
+pub fn allocate(
+    policy: QueuePolicy,
+    open: Vec<WithdrawalRequest>,
+    liquidity: u64,
+) -> Vec<WithdrawalFill> {
+    let open = processing_order(policy, open);
+    match policy {
+        QueuePolicy::Fifo | QueuePolicy::SmallestFirst => {
+            let mut remaining = liquidity;
+            open.iter()
+                .map_while(|r| {
+                    let amount = r.outstanding();
+                    (amount <= remaining).then(|| {
+                        remaining -= amount;
+                        WithdrawalFill {
+                            request_id: r.id,
+                            amount,
+                        }
+                    })
+                })
+                .collect()
+        }
+        QueuePolicy::ProRata => {
+            let total: u128 = open.iter().map(|r| r.outstanding() as u128).sum();
+            open.iter()
+                .map(|r| WithdrawalFill {
+                    request_id: r.id,
+                    amount: if total <= liquidity as u128 {
+                        r.outstanding()
+                    } else {
+                        (r.outstanding() as u128 * liquidity as u128 / total) as u64
+                    },
+                })
+                .filter(|f| f.amount > 0)
+                .collect()
+        }
+    }
+}
+
+fn update_filled(request_id: u64, f: impl FnOnce(u64) -> u64) {
+    WITHDRAWAL_QUEUE.with(|q| {
+        let mut q = q.borrow_mut();
+        if let Some(mut request) = q.get(&request_id) {
+            request.filled = f(request.filled);
+            q.insert(request_id, request);
+        }
+    });
+}
+
+fn record_fill(fill: &WithdrawalFill) {
+    update_filled(fill.request_id, |filled| filled + fill.amount);
+}
+
+fn revert_fill(fill: &WithdrawalFill) {
+    update_filled(fill.request_id, |filled| filled - fill.amount);
+}
+
+/// Place in the queue of the withdrawal of `deposit_id`, `None` if it is not
+/// queued or already paid.
+pub fn deposit_position(deposit_id: u64) -> Option<u64> {
+    let source = WithdrawalSource::Deposit { deposit_id };
+    let request = WITHDRAWAL_QUEUE.with(|q| {
+        q.borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .find(|r| r.source == source)
+    })?;
+    request_status(request.id)?.position
+}
+
+pub fn request_status(request_id: u64) -> Option<WithdrawalRequestStatus> {
+    let request = WITHDRAWAL_QUEUE.with(|q| q.borrow().get(&request_id))?;
+    let policy = config::get().withdrawal_queue_policy;
+    let state = match request.filled {
+        0 => WithdrawalState::Queued,
+        f if f < request.amount => WithdrawalState::PartiallyFilled,
+        _ => WithdrawalState::Filled,
+    };
+    let position = match (state, policy) {
+        (WithdrawalState::Filled, _) => None,
+        (_, QueuePolicy::ProRata) => Some(0),
+        _ => processing_order(policy, open_requests())
+            .iter()
+            .position(|r| r.id == request_id)
+            .map(|p| p as u64),
+    };
+    Some(WithdrawalRequestStatus {
+        request,
+        state,
+        policy,
+        position,
+        destination: destination_of(request_id),
+    })
+}
+
+/// Queue the withdrawal of a matured deposit instead of paying it out
+/// immediately. The deposit stops earning rewards as soon as it is queued.
+///
+/// # Arguments
+///
+/// * `subaccount`: The subaccount from which the deposit was created.
+/// * `deposit_id`: The ID of the deposit to withdraw.
+///
+/// # Returns
+///
+/// * The ID of the withdrawal request.
+///
+/// # Errors
+///
+/// * `DepositError::NoDepositFound`: If the deposit ID is not found.
+/// * `DepositError::LockPeriodNotExpired`: If the lock period has not expired.
+/// * `DepositError::InsufficientBalance`: If the deposit's receipt tokens are no longer in the account.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub fn request_withdrawal(subaccount: Subaccount, deposit_id: u64) -> Result<u64, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+    let principal = ic_cdk::caller();
+    let now = crate::now_secs();
+    let amount = crate::withdraw_internal(principal, subaccount, deposit_id, now)?;
+    let key = UserKey {
+        principal,
+        subaccount,
+    };
+    Ok(enqueue(
+        key,
+        WithdrawalSource::Deposit { deposit_id },
+        amount,
+        now,
+        None,
+    ))
+}
+
+/// Returns a withdrawal request together with its state and its place in the
+/// queue under the configured processing policy.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_withdrawal_request(request_id: u64) -> Option<WithdrawalRequestStatus> {
+    request_status(request_id)
+}
+
+/// Returns the place in the withdrawal queue of the withdrawal of
+/// `deposit_id`, where 0 is paid next. `None` if it is not queued or already
+/// paid.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_withdrawal_queue_position(deposit_id: u64) -> Option<u64> {
+    deposit_position(deposit_id)
+}
+
+/// Pays out queued withdrawals using at most `liquidity` tokens, in the order
+/// given by the configured `QueuePolicy`. Only canister controllers may call this.
+///
+/// # Returns
+///
+/// * The fills that were transferred. Processing stops at the first failed transfer.
+///
+/// # Errors
+///
+/// * `DepositError::Unauthorized`: If the caller is not a controller.
+/// * `DepositError::Migrated`: If the pool moved to a successor canister.
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+pub async fn process_withdrawal_queue(liquidity: u64) -> Result<Vec<WithdrawalFill>, DepositError> {
+    crate::ensure_controller(ic_cdk::caller())?;
+    process(liquidity).await
+}
+
+/// Pays out queued withdrawals using at most `liquidity` tokens.
+pub async fn process(liquidity: u64) -> Result<Vec<WithdrawalFill>, DepositError> {
+    status::ensure_withdrawals_allowed()?;
+    maintenance::ensure_available(Operation::Withdrawals)?;
+
+    let policy = config::get().withdrawal_queue_policy;
+    let fills = allocate(policy, open_requests(), liquidity);
+    // Book every fill before the first await so a concurrent call cannot pay
+    // the same amount twice; fills that are not transferred are reverted.
+    fills.iter().for_each(record_fill);
+    let booked_at = crate::now_secs();
+    record_outflow(fills.iter().map(|f| f.amount).sum(), booked_at);
+    let revert = |fill: &WithdrawalFill| {
+        revert_fill(fill);
+        release_outflow(fill.amount, booked_at);
+    };
+
+    let mut completed = Vec::new();
+    let mut pending = fills.into_iter();
+    for fill in pending.by_ref() {
+        let Some(request) = WITHDRAWAL_QUEUE.with(|q| q.borrow().get(&fill.request_id)) else {
+            continue;
+        };
+        let to = destination_of(request.id).unwrap_or(Account {
+            owner: request.key.principal,
+            subaccount: Some(request.key.subaccount.0),
+        });
+        if crate::transfer_net_to(crate::ledger::ledger_id(), to, fill.amount)
+            .await
+            .is_err()
+        {
+            revert(&fill);
+            break;
+        }
+        completed.push(fill);
+    }
+    pending.for_each(|fill| revert(&fill));
+    Ok(completed)
+}
+
+async fn process_within_limit() {
+    let config = config::get();
+    let Some(limit) = config.max_hourly_outflow else {
+        return;
+    };
+    let open = processing_order(config.withdrawal_queue_policy, open_requests());
+    let Some(next) = open.first() else {
+        return;
+    };
+    let now = crate::now_secs();
+    let liquidity = hourly_allowance(limit, outflow_this_hour(now), next.outstanding());
+    if let Err(e) = process(liquidity).await {
+        ic_cdk::println!("processing withdrawal queue failed: {:?}", e);
+    }
+}
+
+/// Starts paying out withdrawals queued by the hourly outflow limit. Must be
+/// called from `init` and `post_upgrade`, since timers do not survive upgrades.
+pub fn start_processing() {
+    ic_cdk_timers::set_timer_interval(OUTFLOW_PROCESS_INTERVAL, || {
+        ic_cdk::spawn(process_within_limit())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn queue(amounts: &[u64]) -> Vec<WithdrawalRequest> {
+        amounts
+            .iter()
+            .map(|&amount| {
+                let key = UserKey {
+                    principal: Principal::anonymous(),
+                    subaccount: Subaccount([0u8; 32]),
+                };
+                let id = enqueue(
+                    key,
+                    WithdrawalSource::Deposit { deposit_id: 1 },
+                    amount,
+                    0,
+                    None,
+                );
+                WITHDRAWAL_QUEUE.with(|q| q.borrow().get(&id).unwrap())
+            })
+            .collect()
+    }
+
+    fn fills(f: Vec<WithdrawalFill>) -> Vec<(u64, u64)> {
+        f.into_iter().map(|f| (f.request_id, f.amount)).collect()
+    }
+
+    #[test]
+    fn test_allocation_policies() {
+        let open = queue(&[500, 100, 300]);
+
+        assert_eq!(
+            fills(allocate(QueuePolicy::Fifo, open.clone(), 700)),
+            vec![(1, 500), (2, 100)]
+        );
+        assert_eq!(
+            fills(allocate(QueuePolicy::SmallestFirst, open.clone(), 450)),
+            vec![(2, 100), (3, 300)]
+        );
+        assert_eq!(
+            fills(allocate(QueuePolicy::ProRata, open.clone(), 450)),
+            vec![(1, 250), (2, 50), (3, 150)]
+        );
+        assert_eq!(
+            fills(allocate(QueuePolicy::ProRata, open, 10_000)),
+            vec![(1, 500), (2, 100), (3, 300)]
+        );
+    }
+
+    #[test]
+    fn test_status_reflects_policy_and_fills() {
+        queue(&[500, 100]);
+        assert_eq!(request_status(2).unwrap().position, Some(1));
+
+        let mut config = config::get();
+        config.withdrawal_queue_policy = QueuePolicy::SmallestFirst;
+        config::set(config);
+        assert_eq!(request_status(2).unwrap().position, Some(0));
+
+        record_fill(&WithdrawalFill {
+            request_id: 1,
+            amount: 200,
+        });
+        let status = request_status(1).unwrap();
+        assert_eq!(status.state, WithdrawalState::PartiallyFilled);
+        assert_eq!(status.policy, QueuePolicy::SmallestFirst);
+
+        record_fill(&WithdrawalFill {
+            request_id: 2,
+            amount: 100,
+        });
+        assert_eq!(request_status(2).unwrap().state, WithdrawalState::Filled);
+        assert_eq!(request_status(2).unwrap().position, None);
+        assert_eq!(request_status(1).unwrap().position, Some(0));
+    }
+
+    #[test]
+    fn test_hourly_outflow_limit() {
+        let hour = 3_600 * 100;
+        assert!(!must_queue(u64::MAX, hour));
+
+        let mut config = config::get();
+        config.max_hourly_outflow = Some(1_000);
+        config::set(config);
+        record_outflow(800, hour);
+        assert!(!must_queue(200, hour + 10));
+        assert!(must_queue(201, hour + 10));
+        // The window resets every hour.
+        assert!(!must_queue(1_000, hour + 3_600));
+
+        release_outflow(800, hour);
+        assert_eq!(outflow_this_hour(hour), 0);
+        assert_eq!(hourly_allowance(1_000, 800, 500), 200);
+        assert_eq!(hourly_allowance(1_000, 0, 5_000), 5_000);
+
+        queue(&[100]);
+        assert!(must_queue(1, hour));
+        assert_eq!(deposit_position(1), Some(0));
+        assert_eq!(deposit_position(2), None);
+    }
+}
@@ -0,0 +1,155 @@
+// src/xrc.rs
+//! Client of the IC Exchange Rate Canister, which prices the staked token
+//! from rates the subnet fetched itself, without the pool making outcalls.
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::call_with_payment128;
+
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+// Fee of a rate request; the part the canister does not charge is refunded.
+const XRC_CYCLES: u128 = 1_000_000_000;
+const USD_DECIMALS: u32 = 8;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Asset {
+    pub symbol: String,
+    pub class: AssetClass,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GetExchangeRateRequest {
+    pub base_asset: Asset,
+    pub quote_asset: Asset,
+    pub timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExchangeRateMetadata {
+    pub decimals: u32,
+    pub base_asset_num_queried_sources: u64,
+    pub base_asset_num_received_rates: u64,
+    pub quote_asset_num_queried_sources: u64,
+    pub quote_asset_num_received_rates: u64,
+    pub standard_deviation: u64,
+    pub forex_timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExchangeRate {
+    pub base_asset: Asset,
+    pub quote_asset: Asset,
+    pub timestamp: u64,
+    pub rate: u64,
+    pub metadata: ExchangeRateMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct OtherError {
+    pub code: u32,
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(OtherError),
+}
+
+/// Converts a rate with `decimals` decimals to units of 10^-8.
+pub fn rate_to_e8s(rate: &ExchangeRate) -> u64 {
+    let decimals = rate.metadata.decimals;
+    let e8s = if decimals >= USD_DECIMALS {
+        rate.rate as u128 / 10u128.pow(decimals - USD_DECIMALS)
+    } else {
+        rate.rate as u128 * 10u128.pow(USD_DECIMALS - decimals)
+    };
+    e8s.min(u64::MAX as u128) as u64
+}
+
+/// Asks the exchange rate canister for the latest `symbol`/USD rate and
+/// returns it in units of 10^-8 USD.
+pub async fn fetch_usd_e8s(symbol: &str) -> Result<u64, String> {
+    let request = GetExchangeRateRequest {
+        base_asset: Asset {
+            symbol: symbol.to_string(),
+            class: AssetClass::Cryptocurrency,
+        },
+        quote_asset: Asset {
+            symbol: "USD".to_string(),
+            class: AssetClass::FiatCurrency,
+        },
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER_ID).expect("invalid exchange rate canister id");
+    let (reply,): (Result<ExchangeRate, ExchangeRateError>,) =
+        call_with_payment128(xrc, "get_exchange_rate", (request,), XRC_CYCLES)
+            .await
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+    let rate = reply.map_err(|e| format!("{:?}", e))?;
+    match rate_to_e8s(&rate) {
+        0 => Err(format!("zero {}/USD rate", symbol)),
+        e8s => Ok(e8s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exchange_rate_is_scaled_to_e8s() {
+        let asset = |symbol: &str, class| Asset {
+            symbol: symbol.to_string(),
+            class,
+        };
+        // ICP at 7.512345678 USD, as the exchange rate canister reports it.
+        let rate = ExchangeRate {
+            base_asset: asset("ICP", AssetClass::Cryptocurrency),
+            quote_asset: asset("USD", AssetClass::FiatCurrency),
+            timestamp: 1_700_000_000,
+            rate: 7_512_345_678,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 7,
+                base_asset_num_received_rates: 6,
+                quote_asset_num_queried_sources: 10,
+                quote_asset_num_received_rates: 10,
+                standard_deviation: 12_345,
+                forex_timestamp: Some(1_699_920_000),
+            },
+        };
+        let reply = candid::encode_one(Ok::<_, ExchangeRateError>(rate.clone())).unwrap();
+        let (decoded,): (Result<ExchangeRate, ExchangeRateError>,) =
+            candid::decode_args(&reply).unwrap();
+        assert_eq!(rate_to_e8s(&decoded.unwrap()), 751_234_567);
+
+        let coarse = ExchangeRate {
+            rate: 75,
+            metadata: ExchangeRateMetadata {
+                decimals: 1,
+                ..rate.metadata
+            },
+            ..rate
+        };
+        assert_eq!(rate_to_e8s(&coarse), 750_000_000);
+    }
+}
@@ -0,0 +1,29 @@
+// build.rs
+//! Generates the candid types of `types.rs` from the backend's interface, so
+//! the client cannot drift from what the pool actually serves.
+use candid_parser::bindings::rust::{compile, Config, Target};
+use std::path::PathBuf;
+
+const DID: &str = "../stake-pool-backend/stake-pool-backend.did";
+
+fn main() {
+    println!("cargo:rerun-if-changed={DID}");
+    let (env, actor) = candid_parser::pretty_check_file(DID.as_ref())
+        .unwrap_or_else(|e| panic!("cannot parse {DID}: {e}"));
+    let mut config = Config::new();
+    config
+        .set_target(Target::CanisterCall)
+        .set_type_attributes("#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]".into());
+    let bindings = compile(&config, &env, &actor);
+    // Keep the type definitions only. The header's inner attribute cannot be
+    // included and the service stub calls through ic-cdk, which the client
+    // does not use.
+    let types: String = bindings
+        .lines()
+        .take_while(|line| !line.starts_with("pub struct Service("))
+        .filter(|line| !line.starts_with("#![") && !line.starts_with("use ic_cdk::"))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("pool_types.rs");
+    std::fs::write(out, types).unwrap();
+}
@@ -0,0 +1,808 @@
+// src/lib.rs
+//! Typed client for the stake pool canister.
+//!
+//! `PoolClient` encodes arguments, decodes replies into the types of
+//! [`types`] and separates transport failures from errors returned by the
+//! pool. Queries are retried on transient transport errors; updates are not,
+//! since retrying a deposit or withdrawal that may have executed is unsafe.
+//! Enable the `agent` feature for a transport backed by agent-rs.
+pub mod transport;
+pub mod types;
+
+use candid::types::value::{IDLArgs, IDLValue};
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{encode_args, Principal};
+use ic_ledger_types::Subaccount;
+use std::time::Duration;
+use transport::{Transport, TransportError};
+use types::*;
+
+/// Page size of the pool's paginated event and rate history queries.
+pub const MAX_EVENTS_PER_PAGE: u64 = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientError {
+    Transport(TransportError),
+    /// The reply did not match the expected candid types.
+    Candid(String),
+    /// The pool executed the call and returned an error.
+    Pool(DepositError),
+    /// A `_v2` endpoint executed the call and returned an error.
+    PoolV2(PoolError),
+}
+
+impl From<TransportError> for ClientError {
+    fn from(e: TransportError) -> Self {
+        ClientError::Transport(e)
+    }
+}
+
+impl From<candid::Error> for ClientError {
+    fn from(e: candid::Error) -> Self {
+        ClientError::Candid(e.to_string())
+    }
+}
+
+impl From<DepositError> for ClientError {
+    fn from(e: DepositError) -> Self {
+        ClientError::Pool(e)
+    }
+}
+
+impl From<PoolError> for ClientError {
+    fn from(e: PoolError) -> Self {
+        ClientError::PoolV2(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+pub struct PoolClient<T> {
+    transport: T,
+    retry: RetryPolicy,
+}
+
+impl<T: Transport> PoolClient<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn query_raw<A: ArgumentEncoder>(
+        &self,
+        method: &str,
+        args: A,
+    ) -> Result<Vec<u8>, ClientError> {
+        let arg = encode_args(args)?;
+        let mut attempt = 0;
+        loop {
+            match self.transport.query(method, arg.clone()).await {
+                Err(e) if e.transient && attempt + 1 < self.retry.max_attempts => {
+                    self.transport.sleep(self.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                reply => return Ok(reply?),
+            }
+        }
+    }
+
+    /// Calls any query method by name, for endpoints without a typed method.
+    pub async fn query<A, R>(&self, method: &str, args: A) -> Result<R, ClientError>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        let reply = self.query_raw(method, args).await?;
+        Ok(candid::decode_args(&reply)?)
+    }
+
+    /// Calls any update method by name, for endpoints without a typed method.
+    pub async fn update<A, R>(&self, method: &str, args: A) -> Result<R, ClientError>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        let reply = self.transport.update(method, encode_args(args)?).await?;
+        Ok(candid::decode_args(&reply)?)
+    }
+
+    async fn query_one<A, R>(&self, method: &str, args: A) -> Result<R, ClientError>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> candid::Deserialize<'a> + candid::CandidType,
+    {
+        let (reply,): (R,) = self.query(method, args).await?;
+        Ok(reply)
+    }
+
+    async fn query_result<A, R>(&self, method: &str, args: A) -> Result<R, ClientError>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> candid::Deserialize<'a> + candid::CandidType,
+    {
+        let (reply,): (Result<R, DepositError>,) = self.query(method, args).await?;
+        Ok(reply?)
+    }
+
+    async fn update_result<A, R>(&self, method: &str, args: A) -> Result<R, ClientError>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> candid::Deserialize<'a> + candid::CandidType,
+    {
+        let (reply,): (Result<R, DepositError>,) = self.update(method, args).await?;
+        Ok(reply?)
+    }
+
+    // Staking
+
+    pub async fn deposit(
+        &self,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+        amount: u64,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result(
+            "deposit_funds",
+            (
+                subaccount,
+                lock_period_days,
+                amount,
+                None::<Vec<u8>>,
+                None::<Principal>,
+            ),
+        )
+        .await
+    }
+
+    /// Same as `deposit`, with allowance, balance and other ledger failures
+    /// reported as typed `PoolError`s.
+    pub async fn deposit_v2(
+        &self,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+        amount: u64,
+    ) -> Result<Deposit, ClientError> {
+        let (reply,): (Result<Deposit, PoolError>,) = self
+            .update(
+                "deposit_funds_v2",
+                (
+                    subaccount,
+                    lock_period_days,
+                    amount,
+                    None::<Vec<u8>>,
+                    None::<Principal>,
+                ),
+            )
+            .await?;
+        Ok(reply?)
+    }
+
+    /// Deposits under an idempotency key of up to 32 bytes. Retrying with the
+    /// same key within 24 hours returns the original deposit, so unlike other
+    /// updates this one is safe to repeat after a transient failure.
+    pub async fn deposit_idempotent(
+        &self,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+        amount: u64,
+        idempotency_key: Vec<u8>,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result(
+            "deposit_funds",
+            (
+                subaccount,
+                lock_period_days,
+                amount,
+                Some(idempotency_key),
+                None::<Principal>,
+            ),
+        )
+        .await
+    }
+
+    /// Deposits naming `referrer` as the principal that referred the caller.
+    /// Fails with `DepositError::InvalidReferrer` for a self-referral, a
+    /// referral cycle, or a referrer other than the caller's first one.
+    pub async fn deposit_with_referral(
+        &self,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+        amount: u64,
+        referrer: Principal,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result(
+            "deposit_with_referral",
+            (subaccount, lock_period_days, amount, referrer),
+        )
+        .await
+    }
+
+    /// Pays the caller the treasury bonuses of deposits it referred whose
+    /// locks have run out, and returns the total paid.
+    pub async fn claim_referral_bonuses(&self) -> Result<u64, ClientError> {
+        self.update_result("claim_referral_bonuses", ()).await
+    }
+
+    /// Allowance to approve, and balance to hold, before depositing
+    /// `amount`, ledger fees included. `None` until the pool has read the
+    /// ledger fee.
+    pub async fn required_approval(
+        &self,
+        amount: u64,
+    ) -> Result<Option<RequiredApproval>, ClientError> {
+        self.query_one("get_required_approval", (amount,)).await
+    }
+
+    /// Account to transfer to before calling `notify_deposit`, for wallets
+    /// that cannot approve the pool.
+    pub async fn deposit_address(&self, subaccount: Subaccount) -> Result<Account, ClientError> {
+        self.query_one("get_deposit_address", (subaccount,)).await
+    }
+
+    /// Stakes what was transferred to the deposit address of `subaccount`.
+    pub async fn notify_deposit(
+        &self,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result("notify_deposit", (subaccount, lock_period_days))
+            .await
+    }
+
+    /// Withdraws a matured deposit. Past the pool's hourly outflow limit the
+    /// withdrawal is queued instead of paid out.
+    pub async fn withdraw(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+    ) -> Result<WithdrawalOutcome, ClientError> {
+        self.update_result(
+            "withdraw_funds",
+            (subaccount, deposit_id, None::<Principal>, None::<Account>),
+        )
+        .await
+    }
+
+    /// Withdraws a matured deposit straight to `destination`, such as an
+    /// exchange deposit address, instead of the subaccount it came from.
+    pub async fn withdraw_to(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+        destination: Account,
+    ) -> Result<WithdrawalOutcome, ClientError> {
+        self.update_result(
+            "withdraw_funds",
+            (subaccount, deposit_id, None::<Principal>, Some(destination)),
+        )
+        .await
+    }
+
+    /// Makes several deposits in one call; returns the result of each.
+    pub async fn deposit_batch(
+        &self,
+        requests: Vec<DepositRequest>,
+    ) -> Result<Vec<Result<Deposit, DepositError>>, ClientError> {
+        self.update_result("deposit_funds_batch", (requests,)).await
+    }
+
+    /// Withdraws several deposits in one call; returns the outcome of each.
+    pub async fn withdraw_batch(
+        &self,
+        deposit_ids: Vec<u64>,
+    ) -> Result<Vec<Result<WithdrawalOutcome, DepositError>>, ClientError> {
+        self.update_result("withdraw_funds_batch", (deposit_ids,))
+            .await
+    }
+
+    /// Deposits tokens of a ledger registered with the pool besides its
+    /// primary one.
+    pub async fn deposit_token(
+        &self,
+        token: Principal,
+        subaccount: Subaccount,
+        lock_period_days: u16,
+        amount: u64,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result(
+            "deposit_funds",
+            (
+                subaccount,
+                lock_period_days,
+                amount,
+                None::<Vec<u8>>,
+                Some(token),
+            ),
+        )
+        .await
+    }
+
+    /// Withdraws a matured deposit of a registered token.
+    pub async fn withdraw_token(
+        &self,
+        token: Principal,
+        subaccount: Subaccount,
+        deposit_id: u64,
+    ) -> Result<WithdrawalOutcome, ClientError> {
+        self.update_result("withdraw_funds", (subaccount, deposit_id, Some(token)))
+            .await
+    }
+
+    /// Retries the ledger transfer of a withdrawal left pending by a failed
+    /// call and returns the amount paid out.
+    pub async fn retry_withdrawal(&self, deposit_id: u64) -> Result<u64, ClientError> {
+        self.update_result("retry_withdrawal", (deposit_id,)).await
+    }
+
+    /// Withdraws `amount` from a matured deposit and returns what is left in it.
+    pub async fn withdraw_partial(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+        amount: u64,
+    ) -> Result<u64, ClientError> {
+        self.update_result("withdraw_partial", (subaccount, deposit_id, amount))
+            .await
+    }
+
+    /// Extends the lock of an active deposit to a longer period.
+    pub async fn extend_lock(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+        new_lock_days: u16,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result("extend_lock", (subaccount, deposit_id, new_lock_days))
+            .await
+    }
+
+    /// Locks a matured deposit again, starting now.
+    pub async fn relock(&self, deposit_id: u64, lock_days: u16) -> Result<Deposit, ClientError> {
+        self.update_result("relock", (deposit_id, lock_days)).await
+    }
+
+    /// Hands a deposit over to another account without unlocking it.
+    pub async fn transfer_deposit(
+        &self,
+        deposit_id: u64,
+        to_principal: Principal,
+        to_subaccount: Subaccount,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result(
+            "transfer_deposit",
+            (deposit_id, to_principal, to_subaccount),
+        )
+        .await
+    }
+
+    /// Moves a deposit from its fixed lock to dissolve mode.
+    pub async fn enter_dissolve_mode(&self, deposit_id: u64) -> Result<DissolveState, ClientError> {
+        self.update_result("enter_dissolve_mode", (deposit_id,))
+            .await
+    }
+
+    /// Starts running down the dissolve delay of a deposit.
+    pub async fn start_dissolving(&self, deposit_id: u64) -> Result<DissolveState, ClientError> {
+        self.update_result("start_dissolving", (deposit_id,)).await
+    }
+
+    /// Stops a dissolving deposit, keeping the delay it had left.
+    pub async fn stop_dissolving(&self, deposit_id: u64) -> Result<DissolveState, ClientError> {
+        self.update_result("stop_dissolving", (deposit_id,)).await
+    }
+
+    /// Delegates a deposit to a registered validator, or clears the
+    /// delegation when `validator` is `None`.
+    pub async fn delegate_deposit(
+        &self,
+        deposit_id: u64,
+        validator: Option<Principal>,
+    ) -> Result<(), ClientError> {
+        self.update_result("delegate_deposit", (deposit_id, validator))
+            .await
+    }
+
+    pub async fn validators(&self) -> Result<Vec<Validator>, ClientError> {
+        self.query_one("get_validators", ()).await
+    }
+
+    pub async fn validator_stats(&self) -> Result<Vec<ValidatorStats>, ClientError> {
+        self.query_one("get_validator_stats", ()).await
+    }
+
+    pub async fn early_withdraw(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+    ) -> Result<u64, ClientError> {
+        self.update_result("early_withdraw", (subaccount, deposit_id))
+            .await
+    }
+
+    /// Queues a withdrawal and returns the request id.
+    pub async fn request_withdrawal(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+    ) -> Result<u64, ClientError> {
+        self.update_result("request_withdrawal", (subaccount, deposit_id))
+            .await
+    }
+
+    pub async fn claim_rewards(&self, subaccount: Subaccount) -> Result<u64, ClientError> {
+        self.update_result("claim_rewards", (subaccount,)).await
+    }
+
+    /// Donates to the pool's treasury; returns the ledger block.
+    pub async fn donate_to_treasury(&self, amount: u64) -> Result<u64, ClientError> {
+        self.update_result("donate_to_treasury", (amount,)).await
+    }
+
+    /// Restakes the subaccount's rewards as a new deposit.
+    pub async fn compound_rewards(
+        &self,
+        subaccount: Subaccount,
+        lock_days: u16,
+    ) -> Result<Deposit, ClientError> {
+        self.update_result("compound_rewards", (subaccount, lock_days))
+            .await
+    }
+
+    pub async fn set_compounding_prefs(
+        &self,
+        subaccount: Subaccount,
+        prefs: CompoundingPrefs,
+    ) -> Result<(), ClientError> {
+        self.update_result("set_compounding_prefs", (subaccount, prefs))
+            .await
+    }
+
+    /// Stakes `amount` for liquid staking tokens and returns the amount minted.
+    pub async fn stake_liquid(
+        &self,
+        subaccount: Subaccount,
+        amount: u64,
+    ) -> Result<u64, ClientError> {
+        self.update_result("stake_liquid", (subaccount, amount))
+            .await
+    }
+
+    /// Burns `st_amount` liquid staking tokens and returns the request id.
+    pub async fn request_redeem(
+        &self,
+        subaccount: Subaccount,
+        st_amount: u64,
+    ) -> Result<u64, ClientError> {
+        self.update_result("request_redeem", (subaccount, st_amount))
+            .await
+    }
+
+    pub async fn reclaim_unclaimed(&self, subaccount: Subaccount) -> Result<u64, ClientError> {
+        self.update_result("reclaim_unclaimed", (subaccount,)).await
+    }
+
+    // Account queries
+
+    pub async fn preview_withdraw(
+        &self,
+        subaccount: Subaccount,
+        deposit_id: u64,
+    ) -> Result<WithdrawPreview, ClientError> {
+        self.query_result("preview_withdraw", (subaccount, deposit_id))
+            .await
+    }
+
+    pub async fn deposits(&self) -> Result<Vec<(Subaccount, Deposit)>, ClientError> {
+        self.query_one("get_deposits_by_user", ()).await
+    }
+
+    /// The caller's deposits with their unlock time, lock status and accrued
+    /// rewards.
+    pub async fn deposit_views(&self) -> Result<Vec<(Subaccount, DepositView)>, ClientError> {
+        self.query_one("get_deposit_views", ()).await
+    }
+
+    /// The caller's certified stake balance, with its path to the certified
+    /// data and the certificate.
+    pub async fn stake_balance(
+        &self,
+        subaccount: Subaccount,
+    ) -> Result<CertifiedBalance, ClientError> {
+        self.query_one("get_stake_balance", (subaccount,)).await
+    }
+
+    pub async fn full_balance(&self, subaccount: Subaccount) -> Result<FullBalance, ClientError> {
+        self.query_result("get_full_balance", (subaccount,)).await
+    }
+
+    pub async fn pending_rewards(
+        &self,
+        subaccount: Subaccount,
+    ) -> Result<PendingRewards, ClientError> {
+        self.query_one("get_pending_rewards", (subaccount,)).await
+    }
+
+    pub async fn compounding_prefs(
+        &self,
+        subaccount: Subaccount,
+    ) -> Result<CompoundingPrefs, ClientError> {
+        self.query_one("get_compounding_prefs", (subaccount,)).await
+    }
+
+    pub async fn liquid_balance(&self, subaccount: Subaccount) -> Result<u64, ClientError> {
+        self.query_one("get_liquid_balance", (subaccount,)).await
+    }
+
+    pub async fn withdrawal_request(
+        &self,
+        request_id: u64,
+    ) -> Result<Option<WithdrawalRequestStatus>, ClientError> {
+        self.query_one("get_withdrawal_request", (request_id,))
+            .await
+    }
+
+    /// Place of the withdrawal of `deposit_id` in the queue, 0 being next.
+    pub async fn withdrawal_queue_position(
+        &self,
+        deposit_id: u64,
+    ) -> Result<Option<u64>, ClientError> {
+        self.query_one("get_withdrawal_queue_position", (deposit_id,))
+            .await
+    }
+
+    pub async fn redemptions(
+        &self,
+        subaccount: Subaccount,
+    ) -> Result<Vec<WithdrawalRequestStatus>, ClientError> {
+        self.query_one("get_redemptions", (subaccount,)).await
+    }
+
+    pub async fn positions_of(&self, principal: Principal) -> Result<Vec<Position>, ClientError> {
+        self.query_result("get_positions_of", (principal,)).await
+    }
+
+    // Pool queries
+
+    pub async fn pool_status(&self) -> Result<PoolStatus, ClientError> {
+        self.query_one("get_pool_status", ()).await
+    }
+
+    /// The certified pool stats, with their path to the certified data and
+    /// the certificate.
+    pub async fn pool_stats(&self) -> Result<CertifiedPoolStats, ClientError> {
+        self.query_one("get_pool_stats", ()).await
+    }
+
+    /// The caller's deposits the pool's unlock scan found ready to withdraw.
+    pub async fn matured_deposits(&self) -> Result<Vec<MaturedDeposit>, ClientError> {
+        self.query_one("get_matured_deposits", ()).await
+    }
+
+    /// Pool totals in USD at the pool's cached token price.
+    pub async fn pool_stats_usd(&self) -> Result<PoolStatsUsd, ClientError> {
+        self.query_result("get_pool_stats_usd", ()).await
+    }
+
+    pub async fn treasury_balance(&self) -> Result<u64, ClientError> {
+        self.query_one("get_treasury_balance", ()).await
+    }
+
+    /// Cycles, memory and activity of the pool canister.
+    pub async fn canister_metrics(&self) -> Result<CanisterMetrics, ClientError> {
+        self.query_one("get_canister_metrics", ()).await
+    }
+
+    pub async fn leaderboard(&self, limit: u64) -> Result<Vec<LeaderboardEntry>, ClientError> {
+        self.query_one("get_leaderboard", (limit,)).await
+    }
+
+    pub async fn exchange_rate(&self) -> Result<ExchangeRate, ClientError> {
+        self.query_one("get_exchange_rate", ()).await
+    }
+
+    pub async fn state_hash(&self) -> Result<StateHash, ClientError> {
+        self.query_one("get_state_hash", ()).await
+    }
+
+    pub async fn events(&self, start: u64, limit: u64) -> Result<Vec<PoolEvent>, ClientError> {
+        let reply = self.query_raw("get_events", (start, limit)).await?;
+        let args = IDLArgs::from_bytes(&reply)?;
+        let malformed = || ClientError::Candid("malformed get_events reply".to_string());
+        match args.args.first() {
+            Some(IDLValue::Vec(items)) => items
+                .iter()
+                .map(|item| PoolEvent::from_value(item).ok_or_else(malformed))
+                .collect(),
+            _ => Err(malformed()),
+        }
+    }
+
+    pub async fn exchange_rate_history(
+        &self,
+        start: u64,
+        limit: u64,
+    ) -> Result<Vec<ExchangeRate>, ClientError> {
+        self.query_one("get_exchange_rate_history", (start, limit))
+            .await
+    }
+
+    /// Changes since `since_seq`. Only controllers and read replicas may call this.
+    pub async fn export_changes(&self, since_seq: u64) -> Result<StateChanges, ClientError> {
+        self.query_result("export_changes", (since_seq,)).await
+    }
+
+    // Pagination
+
+    /// Fetches every event from `start` on, page by page.
+    pub async fn all_events(&self, start: u64) -> Result<Vec<PoolEvent>, ClientError> {
+        let mut events = Vec::new();
+        loop {
+            let page = self
+                .events(start + events.len() as u64, MAX_EVENTS_PER_PAGE)
+                .await?;
+            let last_page = (page.len() as u64) < MAX_EVENTS_PER_PAGE;
+            events.extend(page);
+            if last_page {
+                return Ok(events);
+            }
+        }
+    }
+
+    /// Fetches the whole exchange rate history from `start` on, page by page.
+    pub async fn all_exchange_rates(&self, start: u64) -> Result<Vec<ExchangeRate>, ClientError> {
+        let mut rates = Vec::new();
+        loop {
+            let page = self
+                .exchange_rate_history(start + rates.len() as u64, MAX_EVENTS_PER_PAGE)
+                .await?;
+            let last_page = (page.len() as u64) < MAX_EVENTS_PER_PAGE;
+            rates.extend(page);
+            if last_page {
+                return Ok(rates);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::types::value::IDLField;
+    use candid::types::Label;
+    use candid::Decode;
+    use std::future::Future;
+    use std::sync::Mutex;
+
+    /// Serves `get_events` from `events` and fails the first `failures` calls.
+    struct MockTransport {
+        events: Vec<PoolEvent>,
+        failures: Mutex<u32>,
+        calls: Mutex<u32>,
+    }
+
+    impl Transport for MockTransport {
+        fn query(
+            &self,
+            method: &str,
+            arg: Vec<u8>,
+        ) -> impl Future<Output = Result<Vec<u8>, TransportError>> + Send {
+            *self.calls.lock().unwrap() += 1;
+            let mut failures = self.failures.lock().unwrap();
+            let reply = if *failures > 0 {
+                *failures -= 1;
+                Err(TransportError {
+                    message: "timeout".to_string(),
+                    transient: true,
+                })
+            } else {
+                assert_eq!(method, "get_events");
+                let (start, limit) = Decode!(&arg, u64, u64).unwrap();
+                let page = self
+                    .events
+                    .iter()
+                    .skip(start as usize)
+                    .take(limit as usize)
+                    .map(|e| {
+                        let mut fields = vec![
+                            IDLField {
+                                id: Label::Named("seq".to_string()),
+                                val: IDLValue::Nat64(e.seq),
+                            },
+                            IDLField {
+                                id: Label::Named("timestamp".to_string()),
+                                val: IDLValue::Nat64(e.timestamp),
+                            },
+                            IDLField {
+                                id: Label::Named("kind".to_string()),
+                                val: e.kind.clone(),
+                            },
+                        ];
+                        fields.sort_by_key(|f| f.id.get_id());
+                        IDLValue::Record(fields)
+                    })
+                    .collect();
+                Ok(IDLArgs::new(&[IDLValue::Vec(page)]).to_bytes().unwrap())
+            };
+            async move { reply }
+        }
+
+        async fn update(&self, method: &str, _arg: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+            Err(TransportError {
+                message: format!("the mock serves no update methods, called {method}"),
+                transient: false,
+            })
+        }
+
+        async fn sleep(&self, _duration: Duration) {}
+    }
+
+    #[tokio::test]
+    async fn test_all_events_pages_and_retries() {
+        let events: Vec<PoolEvent> = (0..250)
+            .map(|seq| PoolEvent {
+                seq,
+                timestamp: seq,
+                kind: IDLValue::Text(format!("event {seq}")),
+            })
+            .collect();
+        let client = PoolClient::new(MockTransport {
+            events: events.clone(),
+            failures: Mutex::new(2),
+            calls: Mutex::new(0),
+        });
+        assert_eq!(client.all_events(0).await.unwrap(), events);
+        assert_eq!(*client.transport.calls.lock().unwrap(), 5);
+
+        let client = client.with_retry(RetryPolicy::none());
+        *client.transport.failures.lock().unwrap() = 1;
+        assert!(matches!(
+            client.events(0, 10).await,
+            Err(ClientError::Transport(_))
+        ));
+        assert!(matches!(
+            client.deposit(Subaccount([0; 32]), 30, 100).await,
+            Err(ClientError::Transport(TransportError {
+                transient: false,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+// src/transport.rs
+use std::future::Future;
+use std::time::Duration;
+
+/// Failure to get a reply from the pool, as opposed to the pool replying with
+/// an error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportError {
+    pub message: String,
+    /// Whether the same call may succeed if retried, e.g. after a timeout or
+    /// while the canister is being upgraded.
+    pub transient: bool,
+}
+
+/// How raw candid calls reach the pool canister.
+pub trait Transport {
+    fn query(
+        &self,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>, TransportError>> + Send;
+
+    fn update(
+        &self,
+        method: &str,
+        arg: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>, TransportError>> + Send;
+
+    /// Waits between retries.
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+}
+
+#[cfg(feature = "agent")]
+pub use agent::AgentTransport;
+
+#[cfg(feature = "agent")]
+mod agent {
+    use super::{Transport, TransportError};
+    use candid::Principal;
+    use ic_agent::{Agent, AgentError};
+    use std::future::Future;
+    use std::time::Duration;
+
+    /// Calls the pool through an `ic_agent::Agent`.
+    pub struct AgentTransport {
+        pub agent: Agent,
+        pub canister_id: Principal,
+    }
+
+    fn to_transport_error(e: AgentError) -> TransportError {
+        let transient = matches!(
+            e,
+            AgentError::TimeoutWaitingForResponse() | AgentError::TransportError(_)
+        );
+        TransportError {
+            message: e.to_string(),
+            transient,
+        }
+    }
+
+    impl Transport for AgentTransport {
+        fn query(
+            &self,
+            method: &str,
+            arg: Vec<u8>,
+        ) -> impl Future<Output = Result<Vec<u8>, TransportError>> + Send {
+            let call = self
+                .agent
+                .query(&self.canister_id, method)
+                .with_arg(arg)
+                .call();
+            async move { call.await.map_err(to_transport_error) }
+        }
+
+        fn update(
+            &self,
+            method: &str,
+            arg: Vec<u8>,
+        ) -> impl Future<Output = Result<Vec<u8>, TransportError>> + Send {
+            let call = self
+                .agent
+                .update(&self.canister_id, method)
+                .with_arg(arg)
+                .call_and_wait();
+            async move { call.await.map_err(to_transport_error) }
+        }
+
+        fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+            tokio::time::sleep(duration)
+        }
+    }
+}
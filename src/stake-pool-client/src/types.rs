@@ -0,0 +1,102 @@
+// src/types.rs
+//! Candid types of the pool interface used by the typed client methods.
+//! They are generated at build time from `stake-pool-backend.did`, so they
+//! always match what the pool serves.
+use candid::idl_hash;
+use candid::types::value::IDLValue;
+
+mod generated {
+    #![allow(dead_code, unused_imports, clippy::all)]
+    include!(concat!(env!("OUT_DIR"), "/pool_types.rs"));
+}
+
+pub use generated::*;
+
+impl DepositError {
+    /// Stable numeric code of the error, as the pool assigns it.
+    pub fn code(&self) -> u32 {
+        match self {
+            DepositError::InvalidLockPeriod => 1001,
+            DepositError::LockPeriodNotExpired => 1002,
+            DepositError::NoDepositFound => 1003,
+            DepositError::LedgerTransferFailed(_) => 1004,
+            DepositError::NoStakerFound => 1005,
+            DepositError::Unauthorized => 1006,
+            DepositError::WithdrawalsOnly => 1007,
+            DepositError::MemoryLimitReached => 1008,
+            DepositError::DistributionInProgress { .. } => 1009,
+            DepositError::Migrated { .. } => 1010,
+            DepositError::Denied => 1011,
+            DepositError::InvalidConfig(_) => 1012,
+            DepositError::InvalidArgument(_) => 1013,
+            DepositError::LockTierClosed => 1014,
+            DepositError::GovernanceCallFailed(_) => 1015,
+            DepositError::InsufficientBalance => 1016,
+            DepositError::Halted => 1017,
+            DepositError::UnderMaintenance { .. } => 1018,
+            DepositError::BelowMinimumStake { .. } => 1019,
+            DepositError::Paused => 1020,
+            DepositError::OperationInProgress => 1021,
+            DepositError::UnsupportedToken => 1022,
+            DepositError::BelowLedgerFee { .. } => 1023,
+            DepositError::AmountTooLow { .. } => 1024,
+            DepositError::UserCapReached { .. } => 1025,
+            DepositError::PoolCapReached { .. } => 1026,
+            DepositError::NotAllowlisted => 1027,
+            DepositError::PriceUnavailable(_) => 1028,
+            DepositError::ConcurrentOperation => 1029,
+            DepositError::InvalidReferrer => 1030,
+            DepositError::InvalidDestination => 1031,
+            DepositError::WrongShard { .. } => 1032,
+            DepositError::ShardUnavailable { .. } => 1033,
+        }
+    }
+}
+
+impl PoolError {
+    /// Stable numeric code of the error, as the pool assigns it.
+    pub fn code(&self) -> u32 {
+        match self {
+            PoolError::InsufficientAllowance { .. } => 2001,
+            PoolError::InsufficientFunds { .. } => 2002,
+            PoolError::LedgerError(LedgerError::TransferFrom(_)) => 2003,
+            PoolError::LedgerError(LedgerError::Transfer(_)) => 2004,
+            PoolError::LedgerError(LedgerError::Unreachable(_)) => 2005,
+            PoolError::Paused => DepositError::Paused.code(),
+            PoolError::Unauthorized => DepositError::Unauthorized.code(),
+            PoolError::Deposit(e) => e.code(),
+        }
+    }
+}
+
+/// A pool event. `kind` is left untyped so that clients keep decoding the log
+/// when the pool adds event kinds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub kind: IDLValue,
+}
+
+impl PoolEvent {
+    pub fn from_value(value: &IDLValue) -> Option<Self> {
+        let IDLValue::Record(fields) = value else {
+            return None;
+        };
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|f| f.id.get_id() == idl_hash(name))
+                .map(|f| &f.val)
+        };
+        let nat64 = |name: &str| match field(name)? {
+            IDLValue::Nat64(n) => Some(*n),
+            _ => None,
+        };
+        Some(PoolEvent {
+            seq: nat64("seq")?,
+            timestamp: nat64("timestamp")?,
+            kind: field("kind")?.clone(),
+        })
+    }
+}
@@ -0,0 +1,264 @@
+// src/lib.rs
+//! Read replica of the stake pool. Follows the pool's change feed
+//! (`export_changes`) and serves account, stats and leaderboard queries so
+//! they do not load the canister that custodies funds.
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use ic_ledger_types::Subaccount;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+/// Pages of changes fetched per sync, to bound the work of one timer tick.
+const MAX_PAGES_PER_SYNC: usize = 10;
+pub const MAX_LEADERBOARD_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct UserKey {
+    pub principal: Principal,
+    pub subaccount: Subaccount,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Deposit {
+    pub id: u64,
+    pub amount: u64,
+    pub timestamp: u64,
+    pub lock_period_days: u16,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountSnapshot {
+    pub key: UserKey,
+    pub deposits: Vec<Deposit>,
+    pub stake_balance: u64,
+}
+
+/// Mirrors `StateChanges` of the pool interface.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateChanges {
+    pub since_seq: u64,
+    pub next_seq: u64,
+    pub full_snapshot: bool,
+    pub accounts: Vec<AccountSnapshot>,
+    pub deposit_id_counter: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReplicaStats {
+    pub total_staked: u64,
+    pub staker_count: u64,
+    pub deposit_count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub key: UserKey,
+    pub stake: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SyncStatus {
+    pub pool: Principal,
+    pub next_seq: u64,
+    pub last_synced_at: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Replica {
+    accounts: BTreeMap<UserKey, AccountSnapshot>,
+    next_seq: u64,
+    last_synced_at: u64,
+    last_error: Option<String>,
+}
+
+impl Replica {
+    fn apply(&mut self, changes: StateChanges) {
+        if changes.full_snapshot {
+            self.accounts.clear();
+        }
+        for account in changes.accounts {
+            if account.deposits.is_empty() && account.stake_balance == 0 {
+                self.accounts.remove(&account.key);
+            } else {
+                self.accounts.insert(account.key.clone(), account);
+            }
+        }
+        self.next_seq = changes.next_seq;
+    }
+
+    fn stats(&self) -> ReplicaStats {
+        self.accounts
+            .values()
+            .fold(ReplicaStats::default(), |mut stats, account| {
+                stats.total_staked += account.stake_balance;
+                stats.staker_count += (account.stake_balance > 0) as u64;
+                stats.deposit_count += account.deposits.len() as u64;
+                stats
+            })
+    }
+
+    fn leaderboard(&self, limit: usize) -> Vec<LeaderboardEntry> {
+        let mut entries: Vec<LeaderboardEntry> = self
+            .accounts
+            .values()
+            .filter(|a| a.stake_balance > 0)
+            .map(|a| LeaderboardEntry {
+                key: a.key.clone(),
+                stake: a.stake_balance,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.stake.cmp(&a.stake).then_with(|| a.key.cmp(&b.key)));
+        entries.truncate(limit.min(MAX_LEADERBOARD_SIZE));
+        entries
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    // Rebuilt from the change feed after every install or upgrade.
+    static REPLICA: RefCell<Replica> = RefCell::new(Replica::default());
+}
+
+fn pool() -> Principal {
+    POOL.with(|p| p.borrow().expect("pool canister not set"))
+}
+
+fn record_error(error: String) {
+    REPLICA.with(|r| r.borrow_mut().last_error = Some(error));
+}
+
+async fn sync() {
+    for _ in 0..MAX_PAGES_PER_SYNC {
+        let since = REPLICA.with(|r| r.borrow().next_seq);
+        let result: Result<(Result<StateChanges, Reserved>,), _> =
+            ic_cdk::call(pool(), "export_changes", (since,)).await;
+        let changes = match result {
+            Ok((Ok(changes),)) => changes,
+            Ok((Err(_),)) => {
+                return record_error("export_changes rejected the replica".to_string())
+            }
+            Err(e) => return record_error(format!("{:?}", e)),
+        };
+        let done = changes.next_seq == since;
+        REPLICA.with(|r| {
+            let mut r = r.borrow_mut();
+            r.apply(changes);
+            r.last_synced_at = ic_cdk::api::time() / 1_000_000_000;
+            r.last_error = None;
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+fn start(pool: Principal) {
+    POOL.with(|p| *p.borrow_mut() = Some(pool));
+    ic_cdk_timers::set_timer_interval(SYNC_INTERVAL, || ic_cdk::spawn(sync()));
+}
+
+/// `pool` is the stake pool canister to follow. The replica must be listed in
+/// the pool's `read_replicas` config to read its change feed.
+#[ic_cdk::init]
+fn init(pool: Principal) {
+    start(pool);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(pool: Principal) {
+    start(pool);
+}
+
+/// Returns totals over all replicated accounts.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_pool_stats() -> ReplicaStats {
+    REPLICA.with(|r| r.borrow().stats())
+}
+
+/// Returns up to `limit` (capped at 100) accounts with the largest stake.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_leaderboard(limit: u64) -> Vec<LeaderboardEntry> {
+    REPLICA.with(|r| r.borrow().leaderboard(limit as usize))
+}
+
+/// Returns the replicated deposits and stake of every subaccount of `principal`.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_accounts_of(principal: Principal) -> Vec<AccountSnapshot> {
+    REPLICA.with(|r| {
+        r.borrow()
+            .accounts
+            .values()
+            .filter(|a| a.key.principal == principal)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Returns how far the replica has caught up with the pool.
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+pub fn get_sync_status() -> SyncStatus {
+    REPLICA.with(|r| {
+        let r = r.borrow();
+        SyncStatus {
+            pool: pool(),
+            next_seq: r.next_seq,
+            last_synced_at: r.last_synced_at,
+            last_error: r.last_error.clone(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(sub: u8, stake: u64) -> AccountSnapshot {
+        AccountSnapshot {
+            key: UserKey {
+                principal: Principal::anonymous(),
+                subaccount: Subaccount([sub; 32]),
+            },
+            deposits: (stake > 0)
+                .then_some(Deposit {
+                    id: sub as u64,
+                    amount: stake,
+                    timestamp: 0,
+                    lock_period_days: 90,
+                })
+                .into_iter()
+                .collect(),
+            stake_balance: stake,
+        }
+    }
+
+    fn changes(next_seq: u64, full_snapshot: bool, accounts: Vec<AccountSnapshot>) -> StateChanges {
+        StateChanges {
+            since_seq: 0,
+            next_seq,
+            full_snapshot,
+            accounts,
+            deposit_id_counter: 0,
+        }
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let mut replica = Replica::default();
+        replica.apply(changes(2, false, vec![account(1, 100), account(2, 300)]));
+        assert_eq!(replica.stats().total_staked, 400);
+        assert_eq!(replica.leaderboard(1)[0].stake, 300);
+
+        replica.apply(changes(3, false, vec![account(1, 0)]));
+        assert_eq!(replica.stats().staker_count, 1);
+
+        replica.apply(changes(4, true, vec![account(3, 50)]));
+        assert_eq!(replica.accounts.len(), 1);
+        assert_eq!(replica.next_seq, 4);
+    }
+}
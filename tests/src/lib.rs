@@ -0,0 +1,3 @@
+// src/lib.rs
+//! PocketIC integration tests of the stake pool, deployed next to an ICRC-2
+//! ledger. The tests live under `tests/`; this crate has no code of its own.
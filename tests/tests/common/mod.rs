@@ -0,0 +1,229 @@
+// tests/common/mod.rs
+//! Deploys the pool next to an ICRC-2 ledger on a fresh PocketIC instance.
+//!
+//! The pool's wasm is read from `STAKE_POOL_WASM`, by default the release
+//! build of `stake-pool-backend` in the workspace's target directory. The
+//! ledger's wasm is read from `ICRC1_LEDGER_WASM`, by default
+//! `ic-icrc1-ledger.wasm.gz` next to this crate's manifest.
+#![allow(dead_code)]
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{encode_one, CandidType, Nat, Principal};
+use ic_ledger_types::Subaccount;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use pocket_ic::{query_candid_as, update_candid_as, PocketIc};
+use stake_pool_client::types::InitArgs;
+use std::time::Duration;
+
+/// Fee of the ledger the pool is deployed on.
+pub const FEE: u64 = 10_000;
+/// Subaccount stakers deposit from and are paid to.
+pub const SUBACCOUNT: Subaccount = Subaccount([1; 32]);
+pub const DAY: Duration = Duration::from_secs(86_400);
+
+const CYCLES: u128 = 10_000_000_000_000;
+
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(Box<LedgerInitArgs>),
+    Upgrade(Option<LedgerUpgradeArgs>),
+}
+
+// The fields of the ledger's `InitArgs` the pool relies on; the ledger
+// defaults the optional ones left out.
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+struct LedgerUpgradeArgs {
+    transfer_fee: Option<Nat>,
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+fn read_wasm(var: &str, default: &str) -> Vec<u8> {
+    let path = std::env::var(var)
+        .unwrap_or_else(|_| format!("{}/{}", env!("CARGO_MANIFEST_DIR"), default));
+    std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("cannot read {path} ({e}); set {var} or see the README"))
+}
+
+/// The account of `owner` stakers use, on `SUBACCOUNT`.
+pub fn account(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: Some(SUBACCOUNT.0),
+    }
+}
+
+pub struct Env {
+    pub pic: PocketIc,
+    pub pool: Principal,
+    pub ledger: Principal,
+    pub controller: Principal,
+}
+
+impl Env {
+    /// Deploys a ledger on which each account of `balances` holds its
+    /// amount, and a pool staking that ledger's token.
+    pub fn deploy(balances: &[(Account, u64)]) -> Self {
+        let pic = PocketIc::new();
+        let controller = Principal::self_authenticating(b"controller");
+
+        let ledger = pic.create_canister_with_settings(Some(controller), None);
+        pic.add_cycles(ledger, CYCLES);
+        let arg = LedgerArg::Init(Box::new(LedgerInitArgs {
+            minting_account: Account {
+                owner: controller,
+                subaccount: None,
+            },
+            transfer_fee: Nat::from(FEE),
+            token_symbol: "TKN".to_string(),
+            token_name: "Token".to_string(),
+            metadata: Vec::new(),
+            initial_balances: balances
+                .iter()
+                .map(|(account, amount)| (*account, Nat::from(*amount)))
+                .collect(),
+            feature_flags: Some(FeatureFlags { icrc2: true }),
+            archive_options: ArchiveOptions {
+                num_blocks_to_archive: 1_000,
+                trigger_threshold: 2_000,
+                controller_id: controller,
+            },
+        }));
+        pic.install_canister(
+            ledger,
+            read_wasm("ICRC1_LEDGER_WASM", "ic-icrc1-ledger.wasm.gz"),
+            encode_one(arg).unwrap(),
+            Some(controller),
+        );
+
+        let pool = pic.create_canister_with_settings(Some(controller), None);
+        pic.add_cycles(pool, CYCLES);
+        let init = Some(InitArgs {
+            ledger_canister: Some(ledger),
+        });
+        pic.install_canister(
+            pool,
+            read_wasm(
+                "STAKE_POOL_WASM",
+                "../target/wasm32-unknown-unknown/release/stake_pool_backend.wasm",
+            ),
+            encode_one(init).unwrap(),
+            Some(controller),
+        );
+
+        Env {
+            pic,
+            pool,
+            ledger,
+            controller,
+        }
+    }
+
+    /// Calls update `method` of the pool as `sender`.
+    pub fn update<A, R>(&self, sender: Principal, method: &str, args: A) -> R
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        update_candid_as(&self.pic, self.pool, sender, method, args)
+            .unwrap_or_else(|e| panic!("{method} was rejected: {e:?}"))
+    }
+
+    /// Calls query `method` of the pool as `sender`.
+    pub fn query<A, R>(&self, sender: Principal, method: &str, args: A) -> R
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        query_candid_as(&self.pic, self.pool, sender, method, args)
+            .unwrap_or_else(|e| panic!("{method} was rejected: {e:?}"))
+    }
+
+    pub fn balance_of(&self, account: Account) -> u64 {
+        let (balance,): (Nat,) = query_candid_as(
+            &self.pic,
+            self.ledger,
+            account.owner,
+            "icrc1_balance_of",
+            (account,),
+        )
+        .expect("icrc1_balance_of was rejected");
+        balance.0.try_into().unwrap()
+    }
+
+    /// Balance of the pool's main account, where deposits and rewards go.
+    pub fn pool_balance(&self) -> u64 {
+        self.balance_of(Account {
+            owner: self.pool,
+            subaccount: None,
+        })
+    }
+
+    /// Lets the pool pull `amount` from `from`. Costs `from` the ledger fee.
+    pub fn approve(&self, from: Account, amount: u64) {
+        let args = ApproveArgs {
+            from_subaccount: from.subaccount,
+            spender: Account {
+                owner: self.pool,
+                subaccount: None,
+            },
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, ApproveError>,) =
+            update_candid_as(&self.pic, self.ledger, from.owner, "icrc2_approve", (args,))
+                .expect("icrc2_approve was rejected");
+        result.expect("the ledger refused the approval");
+    }
+
+    /// Changes the ledger's transfer fee by upgrading it in place.
+    pub fn set_ledger_fee(&self, fee: u64) {
+        let arg = LedgerArg::Upgrade(Some(LedgerUpgradeArgs {
+            transfer_fee: Some(Nat::from(fee)),
+        }));
+        self.pic
+            .upgrade_canister(
+                self.ledger,
+                read_wasm("ICRC1_LEDGER_WASM", "ic-icrc1-ledger.wasm.gz"),
+                encode_one(arg).unwrap(),
+                Some(self.controller),
+            )
+            .expect("the ledger upgrade failed");
+    }
+
+    /// Moves time forward and lets the pool's timers run.
+    pub fn advance(&self, duration: Duration) {
+        self.pic.advance_time(duration);
+        for _ in 0..5 {
+            self.pic.tick();
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// tests/deposit_reward_withdraw.rs
+mod common;
+
+use candid::Principal;
+use common::{account, Env, DAY, FEE, SUBACCOUNT};
+use icrc_ledger_types::icrc1::account::Account;
+use stake_pool_client::types::{
+    CertifiedBalance, Deposit, DepositError, RewardDistributionReport, WithdrawalOutcome,
+};
+
+const START: u64 = 1_000_000_000;
+const DEPOSIT: u64 = 500_000_000;
+const REWARD: u64 = 20_000_000;
+
+#[test]
+fn test_deposit_reward_and_withdraw_move_ledger_balances() {
+    let staker = Principal::self_authenticating(b"staker");
+    let funder = Principal::self_authenticating(b"funder");
+    // Rewards are pulled from the funder's default account.
+    let env = Env::deploy(&[(account(staker), START), (Account::from(funder), START)]);
+
+    // The staker pays one fee for the approval and one for the transfer, and
+    // the pool receives the whole deposit.
+    env.approve(account(staker), DEPOSIT + FEE);
+    let (deposit,): (Result<Deposit, DepositError>,) = env.update(
+        staker,
+        "deposit_funds",
+        (
+            SUBACCOUNT,
+            90u16,
+            DEPOSIT,
+            None::<Vec<u8>>,
+            None::<Principal>,
+        ),
+    );
+    let deposit = deposit.unwrap();
+    assert_eq!(deposit.amount, DEPOSIT);
+    assert_eq!(env.balance_of(account(staker)), START - DEPOSIT - 2 * FEE);
+    assert_eq!(env.pool_balance(), DEPOSIT);
+
+    // The certified balance catches up once the tree is refreshed.
+    env.advance(DAY);
+    let (stake,): (CertifiedBalance,) = env.query(staker, "get_stake_balance", (SUBACCOUNT,));
+    assert_eq!(stake.balance, DEPOSIT);
+    assert!(!stake.path.is_empty());
+
+    env.approve(Account::from(funder), REWARD + FEE);
+    let (report,): (Result<RewardDistributionReport, DepositError>,) =
+        env.update(funder, "reward_pool", (REWARD,));
+    let report = report.unwrap();
+    assert!(report.complete);
+    assert_eq!(report.total_distributed + report.skipped_dust, REWARD);
+    assert_eq!(
+        env.balance_of(Account::from(funder)),
+        START - REWARD - 2 * FEE
+    );
+    assert_eq!(env.pool_balance(), DEPOSIT + REWARD);
+
+    // The only staker is owed the whole distribution, up to index rounding,
+    // and receives it net of the fee the pool pays.
+    let before = env.balance_of(account(staker));
+    let (claimed,): (Result<u64, DepositError>,) =
+        env.update(staker, "claim_rewards", (SUBACCOUNT,));
+    let claimed = claimed.unwrap();
+    assert!(report.total_distributed - (claimed + FEE) <= 1);
+    assert_eq!(env.balance_of(account(staker)), before + claimed);
+
+    // Withdrawing before the lock ends is refused and moves nothing.
+    let (early,): (Result<WithdrawalOutcome, DepositError>,) = env.update(
+        staker,
+        "withdraw_funds",
+        (SUBACCOUNT, deposit.id, None::<Principal>, None::<Account>),
+    );
+    assert_eq!(early, Err(DepositError::LockPeriodNotExpired));
+
+    env.advance(90 * DAY);
+    let before = env.balance_of(account(staker));
+    let (outcome,): (Result<WithdrawalOutcome, DepositError>,) = env.update(
+        staker,
+        "withdraw_funds",
+        (SUBACCOUNT, deposit.id, None::<Principal>, None::<Account>),
+    );
+    assert_eq!(
+        outcome,
+        Ok(WithdrawalOutcome::Paid {
+            amount: DEPOSIT - FEE
+        })
+    );
+    assert_eq!(env.balance_of(account(staker)), before + DEPOSIT - FEE);
+    // Left with the rounding of the rewards, after paying a fee per payout.
+    assert_eq!(env.pool_balance(), REWARD - claimed - FEE);
+}
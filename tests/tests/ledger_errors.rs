@@ -0,0 +1,115 @@
+// tests/ledger_errors.rs
+mod common;
+
+use candid::{Nat, Principal};
+use common::{account, Env, FEE, SUBACCOUNT};
+use ic_ledger_types::Subaccount;
+use pocket_ic::update_candid_as;
+use stake_pool_client::types::{
+    Deposit, DepositError, LedgerError, PoolError, TransferFromError, UserKey,
+};
+
+const DEPOSIT: u64 = 500_000_000;
+
+fn deposit_v2(env: &Env, sender: Principal) -> Result<Deposit, PoolError> {
+    let (result,): (Result<Deposit, PoolError>,) = env.update(
+        sender,
+        "deposit_funds_v2",
+        (
+            SUBACCOUNT,
+            90u16,
+            DEPOSIT,
+            None::<Vec<u8>>,
+            None::<Principal>,
+        ),
+    );
+    result
+}
+
+#[test]
+fn test_deposits_report_the_ledgers_refusals() {
+    let staker = Principal::self_authenticating(b"staker");
+    let poor = Principal::self_authenticating(b"poor");
+    let env = Env::deploy(&[(account(staker), 1_000_000_000), (account(poor), 3 * FEE)]);
+
+    assert_eq!(
+        deposit_v2(&env, staker),
+        Err(PoolError::InsufficientAllowance {
+            required: DEPOSIT + FEE,
+            allowance: 0,
+        })
+    );
+    // Version 1 folds the ledger's error into a string.
+    let (result,): (Result<Deposit, DepositError>,) = env.update(
+        staker,
+        "deposit_funds",
+        (
+            SUBACCOUNT,
+            90u16,
+            DEPOSIT,
+            None::<Vec<u8>>,
+            None::<Principal>,
+        ),
+    );
+    assert!(
+        matches!(&result, Err(DepositError::LedgerTransferFailed(m)) if m.contains("InsufficientAllowance")),
+        "{result:?}"
+    );
+
+    env.approve(account(poor), DEPOSIT + FEE);
+    assert_eq!(
+        deposit_v2(&env, poor),
+        Err(PoolError::InsufficientFunds {
+            required: DEPOSIT + FEE,
+            available: 2 * FEE,
+        })
+    );
+
+    // The pool still offers the fee it cached, which the ledger refuses
+    // with the new one. The pool takes note of it, so a retry goes through.
+    env.approve(account(staker), DEPOSIT + 2 * FEE);
+    env.set_ledger_fee(2 * FEE);
+    assert_eq!(
+        deposit_v2(&env, staker),
+        Err(PoolError::LedgerError(LedgerError::TransferFrom(
+            TransferFromError::BadFee {
+                expected_fee: Nat::from(2 * FEE)
+            }
+        )))
+    );
+    assert_eq!(env.balance_of(account(staker)), 1_000_000_000 - FEE);
+    assert_eq!(deposit_v2(&env, staker).unwrap().amount, DEPOSIT);
+    assert_eq!(
+        env.balance_of(account(staker)),
+        1_000_000_000 - FEE - DEPOSIT - 2 * FEE
+    );
+    assert_eq!(env.pool_balance(), DEPOSIT);
+}
+
+#[test]
+fn test_only_controllers_may_slash_the_pool() {
+    let staker = Principal::self_authenticating(b"staker");
+    let env = Env::deploy(&[(account(staker), 1_000_000_000)]);
+    env.approve(account(staker), DEPOSIT + FEE);
+    deposit_v2(&env, staker).unwrap();
+    let receiver = UserKey {
+        principal: staker,
+        subaccount: serde_bytes::ByteBuf::from(SUBACCOUNT.0.to_vec()),
+    };
+
+    // Turned away by the ingress filter before the call runs.
+    let refused: Result<(Result<bool, DepositError>,), _> = update_candid_as(
+        &env.pic,
+        env.pool,
+        staker,
+        "slash_pool",
+        (100_000_000u64, receiver.clone()),
+    );
+    assert!(refused.is_err());
+
+    let (slashed,): (Result<bool, DepositError>,) =
+        env.update(env.controller, "slash_pool", (100_000_000u64, receiver));
+    assert_eq!(slashed, Ok(true));
+    let (deposits,): (Vec<(Subaccount, Deposit)>,) = env.query(staker, "get_deposits_by_user", ());
+    assert_eq!(deposits[0].1.amount, DEPOSIT - 100_000_000);
+}